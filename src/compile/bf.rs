@@ -0,0 +1,562 @@
+// Compiles programs into plain BF, for cells of 8 bits which wrap around
+//
+// Functions are inlined into `@main`, so the stack of tapes has to be known
+// while compiling: every loop must leave the stack as it found it. Tapes are
+// laid out one after another on the single BF tape, after a workspace used for
+// arithmetic between them. Each cell of a tape takes up a slot of three BF
+// cells:
+//
+//   [marker, value, temp]
+//
+// Markers are 1 for the cells to the left of the tape's pointer and 0 from its
+// pointer onwards, and every tape starts with a separator slot whose marker is
+// 0. This way the pointer of any tape can be found by scanning its markers from
+// the separator, and the separator found again by scanning back, however far
+// the pointer has moved. Temps are used to carry values along a tape between
+// its pointer and separator, and are 0 otherwise, other than in the slot just
+// past the end of each tape whose temp is 1, so literals can stop there.
+
+use super::err::CompileError;
+use crate::parse::analysis::{ENTRY_FUNC, INTRINSIC_PREFIX};
+use crate::parse::err::FilePos;
+use crate::parse::lexer::{Op, StackOp};
+use crate::parse::parser::{Func, OpNode};
+
+/// Lines of the output are wrapped at this width
+pub const LINE_WIDTH: usize = 80;
+
+// lanes of a slot, and how many cells a slot takes up
+const MARKER: usize = 0;
+const VALUE: usize = 1;
+const TEMP: usize = 2;
+const SLOT: usize = 3;
+
+// cells of the workspace, to the left of every tape
+const LHS: usize = 0;
+const RHS: usize = 1;
+const RESULT: usize = 2;
+const SCRATCH: [usize; 6] = [3, 4, 5, 6, 7, 8];
+const WORKSPACE: usize = 9;
+
+// cell of a slot (counting separators) on the BF tape
+fn addr(slot: usize, lane: usize) -> usize {
+    WORKSPACE + slot * SLOT + lane
+}
+
+/// Compiles a program into BF, starting from `@main`
+pub fn compile(funcs: &[Func]) -> Result<String, CompileError> {
+    let main = funcs
+        .iter()
+        .find(|func| func.name == ENTRY_FUNC)
+        .ok_or(CompileError::NoEntry)?;
+
+    let mut compiler = Compiler {
+        funcs,
+        out: Emitter::default(),
+        // the base tape has no cells, but is given one so it has a pointer
+        tapes: vec![Tape { start: 0, len: 1 }],
+        selected: 0,
+        calls: vec![ENTRY_FUNC],
+    };
+    compiler.out.pos = Some(0);
+    compiler.out.goto(addr(1, VALUE));
+    compiler.out.pos = None;
+
+    compiler.nodes(children(&main.node))?;
+    Ok(wrap(&simplify(&compiler.out.code)))
+}
+
+fn children(node: &OpNode) -> &[OpNode] {
+    match node {
+        OpNode::Root(children) | OpNode::Loop(children, _) => children,
+        _ => &[],
+    }
+}
+
+// removes moves and additions undone straight after (eg. `><` or `+-`)
+fn simplify(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    for c in code.chars() {
+        match (out.chars().last(), c) {
+            (Some('<'), '>') | (Some('>'), '<') | (Some('+'), '-') | (Some('-'), '+') => {
+                out.pop();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// splits code into lines no longer than LINE_WIDTH
+fn wrap(code: &str) -> String {
+    let mut out = String::default();
+    for line in code.as_bytes().chunks(LINE_WIDTH) {
+        out += &String::from_utf8_lossy(line);
+        out += "\n";
+    }
+    out
+}
+
+/// Tape laid out on the BF tape, as its separator's slot and how many cells
+/// follow it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Tape {
+    start: usize,
+    len: usize,
+}
+
+impl Tape {
+    // slot just past the last cell of the tape, marking its end
+    fn last(&self) -> usize {
+        self.start + 1 + self.len
+    }
+
+    // first slot free for another tape
+    fn end(&self) -> usize {
+        self.last() + 1
+    }
+}
+
+#[derive(Default)]
+struct Emitter {
+    code: String,
+    /// Cell the pointer is at, None while it is at the pointer of the selected
+    /// tape (which is only known when the program runs)
+    pos: Option<usize>,
+}
+
+impl Emitter {
+    fn raw(&mut self, code: &str) {
+        self.code += code;
+    }
+
+    fn goto(&mut self, cell: usize) {
+        let pos = self.pos.expect("pointer must be at a known cell");
+        if cell > pos {
+            self.code += &">".repeat(cell - pos);
+        } else {
+            self.code += &"<".repeat(pos - cell);
+        }
+        self.pos = Some(cell);
+    }
+
+    // adds to the cell the pointer is at, wrapping around at 256
+    fn add(&mut self, n: u8) {
+        if n <= 128 {
+            self.code += &"+".repeat(n as usize);
+        } else {
+            self.code += &"-".repeat(256 - n as usize);
+        }
+    }
+
+    fn add_at(&mut self, cell: usize, n: u8) {
+        self.goto(cell);
+        self.add(n);
+    }
+
+    fn clear(&mut self, cell: usize) {
+        self.goto(cell);
+        self.raw("[-]");
+    }
+
+    // empties a cell, adding it to each of the others (multiplied by n)
+    fn transfer(&mut self, from: usize, to: &[(usize, u8)]) {
+        self.while_nonzero(from, |out| {
+            out.add(255);
+            for (cell, n) in to {
+                out.add_at(*cell, *n);
+            }
+        });
+    }
+
+    fn copy(&mut self, from: usize, to: usize, temp: usize) {
+        self.transfer(from, &[(to, 1), (temp, 1)]);
+        self.transfer(temp, &[(from, 1)]);
+    }
+
+    fn while_nonzero<F: FnOnce(&mut Self)>(&mut self, cell: usize, body: F) {
+        self.goto(cell);
+        self.raw("[");
+        body(self);
+        self.goto(cell);
+        self.raw("]");
+    }
+
+    // halves x, leaving what was left over in bit
+    fn halve(&mut self, x: usize, bit: usize, half: usize, temp: usize) {
+        self.while_nonzero(x, |out| {
+            out.add(255);
+            // flips bit, counting every time it goes back to 0
+            out.transfer(bit, &[(temp, 1)]);
+            out.add_at(bit, 1);
+            out.while_nonzero(temp, |out| {
+                out.add(255);
+                out.add_at(bit, 255);
+                out.add_at(half, 1);
+            });
+        });
+        self.transfer(half, &[(x, 1)]);
+    }
+
+    // sets RESULT to LHS <op> RHS, emptying both
+    fn arithmetic(&mut self, op: &StackOp) {
+        let [count, flag, temp, bit1, bit2, sum] = SCRATCH;
+        match op {
+            StackOp::Sub => {
+                self.transfer(LHS, &[(RESULT, 1)]);
+                self.transfer(RHS, &[(RESULT, 255)]);
+            }
+            StackOp::Mul => {
+                self.while_nonzero(LHS, |out| {
+                    out.add(255);
+                    out.copy(RHS, RESULT, temp);
+                });
+                self.clear(RHS);
+            }
+            StackOp::Div => {
+                // counts down from RHS for every unit of LHS, adding one to
+                // the result each time the count reaches 0
+                self.copy(RHS, count, temp);
+                self.while_nonzero(LHS, |out| {
+                    out.add(255);
+                    out.add_at(count, 255);
+                    out.add_at(flag, 1);
+                    out.while_nonzero(count, |out| {
+                        out.add_at(flag, 255);
+                        out.transfer(count, &[(temp, 1)]);
+                    });
+                    out.transfer(temp, &[(count, 1)]);
+                    out.while_nonzero(flag, |out| {
+                        out.add(255);
+                        out.add_at(RESULT, 1);
+                        out.copy(RHS, count, temp);
+                    });
+                });
+                self.clear(RHS);
+                self.clear(count);
+            }
+            StackOp::BitAnd | StackOp::BitOr | StackOp::BitXor => {
+                // one bit at a time, from the lowest
+                for i in 0..8 {
+                    self.halve(LHS, bit1, count, temp);
+                    self.halve(RHS, bit2, count, temp);
+                    self.transfer(bit1, &[(sum, 1)]);
+                    self.transfer(bit2, &[(sum, 1)]);
+
+                    // flag is set to the bit of the result, from the sum of bits
+                    self.while_nonzero(sum, |out| match op {
+                        StackOp::BitAnd => {
+                            out.add(255);
+                            out.transfer(sum, &[(flag, 1)]);
+                        }
+                        StackOp::BitOr => {
+                            out.raw("[-]");
+                            out.add_at(flag, 1);
+                        }
+                        _ => {
+                            out.add(255);
+                            out.add_at(flag, 1);
+                            out.transfer(sum, &[(flag, 255)]);
+                        }
+                    });
+                    self.transfer(flag, &[(RESULT, 1 << i)]);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+struct Compiler<'a> {
+    funcs: &'a [Func],
+    out: Emitter,
+    /// Stack of tapes, in the order they are in the program's stack
+    tapes: Vec<Tape>,
+    selected: usize,
+    /// Functions being inlined, innermost last
+    calls: Vec<&'a str>,
+}
+
+impl<'a> Compiler<'a> {
+    fn nodes(&mut self, nodes: &'a [OpNode]) -> Result<(), CompileError> {
+        for node in nodes {
+            match node {
+                OpNode::Operation(op, pos) => self.op(op, *pos)?,
+                OpNode::FuncCall(name, pos) => self.call(name, *pos)?,
+                OpNode::Loop(children, pos) => {
+                    let (tapes, selected) = (self.tapes.clone(), self.selected);
+                    self.out.raw("[");
+                    self.nodes(children)?;
+                    self.out.raw("]");
+
+                    if self.tapes != tapes || self.selected != selected {
+                        return Err(CompileError::LoopChangesStack(*pos));
+                    }
+                }
+                OpNode::Root(children) => self.nodes(children)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn call(&mut self, name: &'a str, pos: FilePos) -> Result<(), CompileError> {
+        if name.starts_with(INTRINSIC_PREFIX) {
+            return Err(CompileError::Intrinsic(name.to_string(), pos));
+        }
+        if self.calls.contains(&name) {
+            return Err(CompileError::Recursion(name.to_string(), pos));
+        }
+        let func = self
+            .funcs
+            .iter()
+            .find(|func| func.name == name)
+            .ok_or_else(|| CompileError::FunctionNotDefined(name.to_string(), pos))?;
+
+        self.calls.push(name);
+        self.nodes(children(&func.node))?;
+        self.calls.pop();
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op, pos: FilePos) -> Result<(), CompileError> {
+        match op {
+            Op::Inc => self.out.raw("+"),
+            Op::Dec => self.out.raw("-"),
+            Op::Dump => self.out.raw("."),
+            Op::Take => self.out.raw(","),
+            Op::PtrRight => self.out.raw("<+>>>>"),
+            Op::PtrLeft => self.out.raw("<<<<->"),
+            // subtracts from 255, using the marker as a temp
+            Op::BitNot => self.out.raw("[-<+>]-<[->-<]>"),
+            Op::Literal(lit) => self.literal(lit),
+            // BF programs cannot exit early, nor with a code
+            Op::Halt => return Err(CompileError::Halt(pos)),
+            Op::Debug => {}
+
+            Op::PushNew(size) => {
+                let start = self.tapes.iter().map(Tape::end).max().unwrap_or(0);
+                self.home();
+                let tape = Tape {
+                    start,
+                    len: (*size).max(1),
+                };
+                self.out.add_at(addr(tape.last(), TEMP), 1);
+                self.tapes.push(tape);
+
+                // the tape above is selected, which is only the new one if the
+                // top of the stack was selected
+                let above = self.selected + 1;
+                if above + 1 == self.tapes.len() {
+                    // tapes are all 0 until used, so the pointer is the first cell
+                    self.out.goto(addr(start + 1, VALUE));
+                    self.out.pos = None;
+                    self.selected = above;
+                } else {
+                    self.enter(above);
+                }
+            }
+            Op::PushOp(StackOp::Default) => {
+                let above = self.above(pos)?;
+                self.switch(above);
+            }
+            Op::PopOp(StackOp::Default) => {
+                let below = self.below(pos)?;
+                self.switch(below);
+            }
+            Op::HardPopOp(StackOp::Default) => {
+                let below = self.below(pos)?;
+                self.home();
+                self.delete(self.selected);
+                self.enter(below);
+            }
+            Op::PushOp(op) => {
+                let (curr, above) = (self.selected, self.above(pos)?);
+                self.combine(above, op, false);
+                self.switch(curr);
+            }
+            Op::PopOp(op) => {
+                let below = self.below(pos)?;
+                self.combine(below, op, false);
+            }
+            Op::HardPopOp(op) => {
+                let below = self.below(pos)?;
+                self.combine(below, op, true);
+            }
+        }
+        Ok(())
+    }
+
+    fn above(&self, pos: FilePos) -> Result<usize, CompileError> {
+        match self.selected + 1 {
+            above if above < self.tapes.len() => Ok(above),
+            _ => Err(CompileError::StackIndexOutOfBounds(pos)),
+        }
+    }
+
+    fn below(&self, pos: FilePos) -> Result<usize, CompileError> {
+        self.selected
+            .checked_sub(1)
+            .ok_or(CompileError::StackIndexCannotBeNegative(pos))
+    }
+
+    // writes each character into the cells from the pointer onwards, up to the
+    // end of the tape, leaving the pointer where it is
+    fn literal(&mut self, lit: &str) {
+        // the base tape has no cells to write to
+        if self.selected == 0 {
+            return;
+        }
+
+        // each cell is only written (and the next one looked at) if its temp
+        // is 0, by setting its marker as a flag cleared by the temp. The temp
+        // is taken away while inside, then given back once the cell is done
+        let mut code = String::default();
+        for c in lit.chars().rev() {
+            let mut value = Emitter::default();
+            value.raw("[-]");
+            value.add(u32::from(c) as u8);
+            if !code.is_empty() {
+                code = format!(">>>{}<<<", code);
+            }
+            code = format!("<+>>[-<<->>]<<[->>-<{}{}<]>>+<", value.code, code);
+        }
+        self.out.raw(&code);
+    }
+
+    // moves from the pointer of the selected tape to its separator's marker
+    fn home(&mut self) {
+        self.out.raw("<<<<[<<<]");
+        self.out.pos = Some(addr(self.tapes[self.selected].start, MARKER));
+    }
+
+    // moves from a known cell to the pointer of a tape, selecting it
+    fn enter(&mut self, idx: usize) {
+        self.out.goto(addr(self.tapes[idx].start, MARKER));
+        self.out.raw(">>>[>>>]>");
+        self.out.pos = None;
+        self.selected = idx;
+    }
+
+    fn switch(&mut self, idx: usize) {
+        self.home();
+        self.enter(idx);
+    }
+
+    // zeroes every cell of a tape (from a known cell) and removes it
+    fn delete(&mut self, idx: usize) {
+        let tape = self.tapes.remove(idx);
+        for slot in tape.start + 1..tape.end() {
+            self.out.clear(addr(slot, MARKER));
+            self.out.clear(addr(slot, VALUE));
+        }
+        self.out.clear(addr(tape.last(), TEMP));
+    }
+
+    // carries the value at the pointer of the selected tape into a cell of the
+    // workspace, leaving the pointer at the tape's separator
+    fn fetch(&mut self, cell: usize, keep: bool) {
+        if keep {
+            // copied through the marker, which is 0 at the pointer
+            self.out.raw("[->+<<+>]<[->+<]>");
+        } else {
+            self.out.raw("[->+<]");
+        }
+        self.out.raw(">[-<<<+>>>]<<<<<[>>[-<<<+>>>]<<<<<]");
+
+        let start = self.tapes[self.selected].start;
+        self.out.pos = Some(addr(start, MARKER));
+        self.out.transfer(addr(start, TEMP), &[(cell, 1)]);
+    }
+
+    // adds a cell of the workspace to the pointer of a tape, selecting it
+    fn deliver(&mut self, cell: usize, idx: usize) {
+        let start = self.tapes[idx].start;
+        self.out.transfer(cell, &[(addr(start, TEMP), 1)]);
+        self.out.goto(addr(start, TEMP));
+        self.out.raw("[->>>+<<<]>[>>[->>>+<<<]>]>>[-<+>]<");
+        self.out.pos = None;
+        self.selected = idx;
+    }
+
+    // sets the pointer of another tape to the pointer of the selected one <op>
+    // itself, selecting it (deleting the selected tape if asked to)
+    fn combine(&mut self, dest: usize, op: &StackOp, delete: bool) {
+        self.fetch(LHS, !delete);
+
+        // tapes are only deleted when moving down, so dest is still in place
+        if delete {
+            self.delete(self.selected);
+        }
+        self.enter(dest);
+
+        match op {
+            StackOp::Set => {
+                self.out.raw("[-]");
+                self.home();
+                self.deliver(LHS, dest);
+            }
+            StackOp::Add => {
+                self.home();
+                self.deliver(LHS, dest);
+            }
+            _ => {
+                self.fetch(RHS, false);
+                self.out.arithmetic(op);
+                self.deliver(RESULT, dest);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::{limits::Limits, testing, Runtime};
+    use crate::parse::bf;
+
+    // output of a program run by the interpreter with 8-bit cells, and of the
+    // BF it compiles to
+    fn outputs(src: &str) -> (String, String) {
+        let funcs = testing::parse(src);
+        let mut runtime = Runtime::new(funcs.clone());
+        runtime.set_cell_bits(bf::CELL_BITS);
+        let (_, expected) = testing::run(&mut runtime, "");
+
+        let code = compile(&funcs).unwrap();
+        let mut runtime = Runtime::new(bf::parse(&code).unwrap());
+        runtime.set_cell_bits(bf::CELL_BITS);
+        runtime.set_limits(Limits::none().max_steps(10_000_000));
+        let (res, actual) = testing::run(&mut runtime, "");
+        assert_eq!(res.unwrap(), 0);
+        (expected, actual)
+    }
+
+    #[test]
+    fn literal_stops_at_end_of_tape() {
+        let (expected, actual) = outputs(r#"@main { ^2 ^2 _ "ABCD" ^ [.>] }"#);
+        assert_eq!(expected, "");
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn literal_past_end_leaves_other_tapes() {
+        let src = r#"@main { ^4 ^4 ^4 &- _- _ ^ + [ > _ "kqg" "u" ^ < - ] }"#;
+        let (expected, actual) = outputs(src);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn literal_fits_tape() {
+        let (expected, actual) = outputs(r#"@main { ^6 > "Hey\0" [.>] ^3 "ab" . > . }"#);
+        assert_eq!(expected, "Heyab");
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn halt_is_refused() {
+        for src in &["@main { ^1 + ! }", "@main { ^1 + [ ! ] }"] {
+            let err = compile(&testing::parse(src)).unwrap_err();
+            assert!(matches!(err, CompileError::Halt(_)), "{}", src);
+        }
+    }
+}
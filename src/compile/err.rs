@@ -0,0 +1,58 @@
+use std::fmt::{self, Display};
+
+use crate::parse::err::FilePos;
+
+#[derive(Debug)]
+pub enum CompileError {
+    NoEntry,
+    FunctionNotDefined(String, FilePos),
+    Intrinsic(String, FilePos),
+    Recursion(String, FilePos),
+
+    // Stack Errors
+    StackIndexCannotBeNegative(FilePos),
+    StackIndexOutOfBounds(FilePos),
+    LoopChangesStack(FilePos),
+    TapeTooLarge(usize, FilePos),
+
+    Halt(FilePos),
+}
+
+// Custom Display for errors
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ERROR: ")?;
+        match self {
+            CompileError::NoEntry => f.write_str("Program has no '@main' function"),
+            CompileError::FunctionNotDefined(name, pos) => {
+                f.write_fmt(format_args!("{}: Function '@{}' is not defined", pos, name))
+            }
+            CompileError::Intrinsic(name, pos) => f.write_fmt(format_args!(
+                "{}: Intrinsic '@{}' cannot be compiled, as it is only known at runtime",
+                pos, name
+            )),
+            CompileError::Recursion(name, pos) => f.write_fmt(format_args!(
+                "{}: Function '@{}' calls itself, which cannot be inlined",
+                pos, name
+            )),
+            CompileError::StackIndexCannotBeNegative(pos) => {
+                f.write_fmt(format_args!("{}: Moves below the bottom of the stack", pos))
+            }
+            CompileError::StackIndexOutOfBounds(pos) => {
+                f.write_fmt(format_args!("{}: Moves past the top of the stack", pos))
+            }
+            CompileError::LoopChangesStack(pos) => f.write_fmt(format_args!(
+                "{}: Loop does not leave the stack of tapes (or the selected tape) as it found it",
+                pos
+            )),
+            CompileError::TapeTooLarge(size, pos) => f.write_fmt(format_args!(
+                "{}: Tape of {} cells is too large to compile",
+                pos, size
+            )),
+            CompileError::Halt(pos) => f.write_fmt(format_args!(
+                "{}: Cannot compile '!', as BF programs cannot exit with a code",
+                pos
+            )),
+        }
+    }
+}
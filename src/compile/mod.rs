@@ -0,0 +1,6 @@
+// Backends compiling parsed programs into other languages
+pub mod bf;
+pub mod err;
+pub mod native;
+pub mod wasm;
+pub mod x86;
@@ -0,0 +1,966 @@
+// Compiles programs into x86_64 machine code for Linux, as a static ELF
+// executable, or as GNU assembly which `as` and `ld` turn into the same program
+//
+// Every function becomes a subroutine, so calls and recursion work as they do
+// in the interpreter (on the machine's stack). The stack of tapes lives in
+// memory mapped when the program starts: a table of tapes, each as its cells'
+// address, length and pointer, followed by the cells of every tape one after
+// another. While the program runs, registers hold:
+//
+//   rbx   address of the table of tapes
+//   rbp   number of tapes in the stack
+//   r15   index of the selected tape
+//   r12   address of the selected tape's cells
+//   r13   pointer of the selected tape
+//   r14   length of the selected tape
+//
+// Characters are written and read as UTF-8 (storing 0 once input has ended),
+// and errors the interpreter would report are printed before exiting with 1.
+
+use super::err::CompileError;
+use super::x86::{Alu, Asm, Cond, Inst, Label, Mem, Operand, Reg, Size};
+use crate::parse::analysis::{ENTRY_FUNC, INTRINSIC_PREFIX};
+use crate::parse::err::FilePos;
+use crate::parse::lexer::{Op, StackOp};
+use crate::parse::parser::{Func, OpNode};
+
+/// Most tapes the stack can hold
+pub const MAX_TAPES: i64 = 1 << 16;
+
+// bytes taken up by a tape in the table: address, length and pointer
+const TAPE_SHIFT: u8 = 5;
+const LEN: i32 = 8;
+const PTR: i32 = 16;
+const TABLE: i64 = MAX_TAPES << TAPE_SHIFT;
+
+// bytes reserved for cells, which are only backed by memory once used
+const HEAP: i64 = 1 << 32;
+
+/// Most cells a tape can have
+pub const MAX_TAPE_SIZE: usize = (HEAP / 4) as usize;
+
+const TABLE_REG: Reg = Reg::Rbx;
+const STACK_LEN: Reg = Reg::Rbp;
+const CURR: Reg = Reg::R15;
+const VALS: Reg = Reg::R12;
+const PTR_REG: Reg = Reg::R13;
+const LEN_REG: Reg = Reg::R14;
+
+// system calls
+const SYS_READ: i64 = 0;
+const SYS_WRITE: i64 = 1;
+const SYS_MMAP: i64 = 9;
+const SYS_EXIT_GROUP: i64 = 231;
+
+// where the executable is loaded, after its ELF header and program header
+const BASE: u64 = 0x400000;
+const HEADERS: u64 = 64 + 56;
+
+const HEADER: &str = "\
+# Migraine program compiled for x86_64 Linux, which is built with:
+#
+#   as program.s -o program.o && ld program.o -o program
+#
+# Every function is a subroutine, and the stack of tapes lives in memory mapped
+# by _start: a table of tapes (32 bytes each: address of the cells, length and
+# pointer), followed by the cells of every tape one after another. Registers hold:
+#
+#   rbx   address of the table of tapes
+#   rbp   number of tapes in the stack
+#   r15   index of the selected tape
+#   r12   address of the selected tape's cells
+#   r13   pointer of the selected tape
+#   r14   length of the selected tape
+
+\t.text
+\t.globl\t_start
+";
+
+/// Compiled program, which can be written as an executable or as assembly
+pub struct Program {
+    asm: Asm,
+}
+
+impl Program {
+    /// GNU assembly of the program, annotated with where each part came from
+    pub fn asm(&self) -> String {
+        format!("{}{}", HEADER, self.asm.gnu())
+    }
+
+    /// Static ELF executable of the program
+    pub fn elf(&self) -> Vec<u8> {
+        let code = self.asm.encode();
+        let size = HEADERS + code.len() as u64;
+
+        let mut out = vec![0x7F, b'E', b'L', b'F', 2, 1, 1, 0];
+        out.extend([0; 8]);
+        // executable for x86_64, starting at _start
+        out.extend(2u16.to_le_bytes());
+        out.extend(0x3Eu16.to_le_bytes());
+        out.extend(1u32.to_le_bytes());
+        out.extend((BASE + HEADERS).to_le_bytes());
+        // program header straight after, no section headers
+        out.extend(64u64.to_le_bytes());
+        out.extend(0u64.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        for half in [64u16, 56, 1, 64, 0, 0] {
+            out.extend(half.to_le_bytes());
+        }
+
+        // a single readable and executable segment, loading the whole file
+        out.extend(1u32.to_le_bytes());
+        out.extend(5u32.to_le_bytes());
+        for word in [0, BASE, BASE, size, size, 0x1000] {
+            out.extend(word.to_le_bytes());
+        }
+
+        out.extend(code);
+        out
+    }
+}
+
+/// Compiles every function of a program, starting from main
+pub fn compile(funcs: &[Func]) -> Result<Program, CompileError> {
+    if !funcs.iter().any(|func| func.name == ENTRY_FUNC) {
+        return Err(CompileError::NoEntry);
+    }
+
+    let mut asm = Asm::default();
+    let start = asm.named_label("_start");
+    let labels = name_funcs(&mut asm, funcs);
+    let rt = Runtime::new(&mut asm);
+    let mut compiler = Compiler { asm, rt, labels };
+
+    compiler.start(start, funcs);
+    for (func, label) in funcs.iter().zip(compiler.labels.clone()) {
+        compiler.func(func, label.1)?;
+    }
+    compiler.runtime();
+
+    Ok(Program { asm: compiler.asm })
+}
+
+// labels of functions, named after them where they can be (only some
+// characters can be used)
+fn name_funcs(asm: &mut Asm, funcs: &[Func]) -> Vec<(String, Label)> {
+    let mut used = vec![];
+    funcs
+        .iter()
+        .map(|func| {
+            let mut name: String = func
+                .name
+                .chars()
+                .map(|c| match c {
+                    c if c.is_ascii_alphanumeric() || c == '_' => c,
+                    _ => '.',
+                })
+                .collect();
+            name = format!("fn.{}", name);
+            if used.contains(&name) {
+                name = format!("{}.{}", name, used.len());
+            }
+            used.push(name.clone());
+            (func.name.clone(), asm.named_label(&name))
+        })
+        .collect()
+}
+
+// labels of the subroutines and error messages compiled code uses
+struct Runtime {
+    select: Label,
+    save: Label,
+    push: Label,
+    pop: Label,
+    hard_pop: Label,
+    push_new: Label,
+    write: Label,
+    read: Label,
+    read_byte: Label,
+    exit: Label,
+    write_err: Label,
+    write_num: Label,
+    tape_out_of_bounds: Label,
+    tape_negative: Label,
+    stack_out_of_bounds: Label,
+    stack_negative: Label,
+    stack_full: Label,
+    no_memory: Label,
+}
+
+impl Runtime {
+    fn new(asm: &mut Asm) -> Self {
+        let mut label = |name: &str| asm.named_label(&format!("rt.{}", name));
+        Self {
+            select: label("select"),
+            save: label("save"),
+            push: label("push"),
+            pop: label("pop"),
+            hard_pop: label("hard_pop"),
+            push_new: label("push_new"),
+            write: label("write"),
+            read: label("read"),
+            read_byte: label("read_byte"),
+            exit: label("exit"),
+            write_err: label("write_err"),
+            write_num: label("write_num"),
+            tape_out_of_bounds: label("tape_out_of_bounds"),
+            tape_negative: label("tape_negative"),
+            stack_out_of_bounds: label("stack_out_of_bounds"),
+            stack_negative: label("stack_negative"),
+            stack_full: label("stack_full"),
+            no_memory: label("no_memory"),
+        }
+    }
+}
+
+struct Compiler {
+    asm: Asm,
+    rt: Runtime,
+    /// Label of every function, by name
+    labels: Vec<(String, Label)>,
+}
+
+impl Compiler {
+    // maps memory for the stack, then runs main
+    fn start(&mut self, start: Label, funcs: &[Func]) {
+        self.asm.bind(start);
+        self.comment("maps the table of tapes, and space for their cells after it");
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(SYS_MMAP));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Imm(0));
+        self.mov(Operand::Reg(Reg::Rsi), Operand::Imm(TABLE + HEAP));
+        // readable and writable, private, anonymous and not reserved
+        self.mov(Operand::Reg(Reg::Rdx), Operand::Imm(3));
+        self.mov(Operand::Reg(Reg::R10), Operand::Imm(0x4022));
+        self.mov(Operand::Reg(Reg::R8), Operand::Imm(-1));
+        self.mov(Operand::Reg(Reg::R9), Operand::Imm(0));
+        self.asm.push(Inst::Syscall);
+        self.alu(Alu::Cmp, Operand::Reg(Reg::Rax), Operand::Imm(-4096));
+        self.asm.push(Inst::Jcc(Cond::A, self.rt.no_memory));
+        self.mov(Operand::Reg(TABLE_REG), Operand::Reg(Reg::Rax));
+
+        self.comment("the root tape has no cells, which start after the table");
+        self.asm
+            .push(Inst::Lea(Reg::Rax, Mem::base(TABLE_REG, TABLE as i32)));
+        self.mov(
+            Operand::Mem(Mem::base(TABLE_REG, 0)),
+            Operand::Reg(Reg::Rax),
+        );
+        self.mov(Operand::Reg(STACK_LEN), Operand::Imm(1));
+        self.mov(Operand::Reg(CURR), Operand::Imm(0));
+        self.asm.push(Inst::Call(self.rt.select));
+
+        let main = funcs
+            .iter()
+            .position(|func| func.name == ENTRY_FUNC)
+            .unwrap();
+        self.asm.push(Inst::Call(self.labels[main].1));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Imm(0));
+        self.asm.push(Inst::Jmp(self.rt.exit));
+    }
+
+    fn func(&mut self, func: &Func, label: Label) -> Result<(), CompileError> {
+        self.comment("");
+        match &func.module {
+            Some(module) => self.comment(&format!(
+                "@{} at {} in module {}",
+                func.name, func.pos, module
+            )),
+            None => self.comment(&format!("@{} at {}", func.name, func.pos)),
+        }
+        self.asm.bind(label);
+        let children = match &func.node {
+            OpNode::Root(children) => children.as_slice(),
+            _ => &[],
+        };
+
+        // a call at the very end jumps to the callee, which returns for us, so
+        // recursion in tail position does not grow the native stack
+        if let Some((OpNode::FuncCall(name, pos), rest)) = children.split_last() {
+            if !name.starts_with(INTRINSIC_PREFIX) {
+                self.block(rest)?;
+                let callee = self.label(name, *pos)?;
+                self.comment(&format!("{}: @{} (tail call)", pos, name));
+                self.asm.push(Inst::Jmp(callee));
+                return Ok(());
+            }
+        }
+        self.block(children)?;
+        self.asm.push(Inst::Ret);
+        Ok(())
+    }
+
+    fn label(&self, name: &str, pos: FilePos) -> Result<Label, CompileError> {
+        self.labels
+            .iter()
+            .find(|(other, _)| other == name)
+            .map(|(_, label)| *label)
+            .ok_or_else(|| CompileError::FunctionNotDefined(name.to_string(), pos))
+    }
+
+    fn block(&mut self, nodes: &[OpNode]) -> Result<(), CompileError> {
+        let mut idx = 0;
+        while idx < nodes.len() {
+            // runs of +, -, > or < are done at once
+            let len = nodes[idx..]
+                .iter()
+                .take_while(|node| same_run(&nodes[idx], node))
+                .count();
+            if len == 0 {
+                self.node(&nodes[idx])?;
+                idx += 1;
+                continue;
+            }
+
+            let run = &nodes[idx..idx + len];
+            let text: String = run.iter().map(op_text).collect();
+            if let Some(pos) = run[0].pos() {
+                self.comment(&format!("{}: {}", pos, text));
+            }
+            match &run[0] {
+                OpNode::Operation(Op::Inc | Op::Dec, _) => {
+                    let change = run.iter().fold(0i32, |change, node| match node {
+                        OpNode::Operation(Op::Inc, _) => change.wrapping_add(1),
+                        _ => change.wrapping_sub(1),
+                    });
+                    self.load_cell();
+                    if change != 0 {
+                        self.alu_dword(Alu::Add, Operand::Reg(Reg::Rax), change as i64);
+                    }
+                    self.store_cell();
+                }
+                OpNode::Operation(Op::PtrRight, _) => {
+                    self.alu(Alu::Add, Operand::Reg(PTR_REG), Operand::Imm(len as i64));
+                }
+                _ => {
+                    self.alu(Alu::Cmp, Operand::Reg(PTR_REG), Operand::Imm(len as i64));
+                    self.asm.push(Inst::Jcc(Cond::B, self.rt.tape_negative));
+                    self.alu(Alu::Sub, Operand::Reg(PTR_REG), Operand::Imm(len as i64));
+                }
+            }
+            idx += len;
+        }
+        Ok(())
+    }
+
+    fn node(&mut self, node: &OpNode) -> Result<(), CompileError> {
+        match node {
+            OpNode::Loop(children, pos) => {
+                let (start, end) = (self.asm.label(), self.asm.label());
+                self.comment(&format!("{}: [", pos));
+                self.asm.bind(start);
+                self.load_cell();
+                self.asm.push(Inst::Test(Size::Dword, Reg::Rax, Reg::Rax));
+                self.asm.push(Inst::Jcc(Cond::E, end));
+                self.block(children)?;
+                self.asm.push(Inst::Jmp(start));
+                self.comment(&format!("] of loop at {}", pos));
+                self.asm.bind(end);
+            }
+            OpNode::FuncCall(name, pos) => {
+                if name.starts_with(INTRINSIC_PREFIX) {
+                    return Err(CompileError::Intrinsic(name.clone(), *pos));
+                }
+                let label = self.label(name, *pos)?;
+                self.comment(&format!("{}: @{}", pos, name));
+                self.asm.push(Inst::Call(label));
+            }
+            OpNode::Operation(op, pos) => {
+                self.comment(&format!("{}: {}", pos, op));
+                self.op(op, *pos)?;
+            }
+            OpNode::Root(children) => self.block(children)?,
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op, pos: FilePos) -> Result<(), CompileError> {
+        match op {
+            Op::BitNot => {
+                self.load_cell();
+                self.asm
+                    .push(Inst::Not(Size::Dword, Operand::Reg(Reg::Rax)));
+                self.store_cell();
+            }
+            Op::Literal(lit) => {
+                // characters past the end of the tape are left out
+                let end = self.asm.label();
+                for (idx, c) in lit.chars().enumerate() {
+                    self.asm
+                        .push(Inst::Lea(Reg::Rax, Mem::base(PTR_REG, idx as i32)));
+                    self.alu(Alu::Cmp, Operand::Reg(Reg::Rax), Operand::Reg(LEN_REG));
+                    self.asm.push(Inst::Jcc(Cond::Ae, end));
+                    self.asm.push(Inst::Mov(
+                        Size::Dword,
+                        Operand::Mem(Mem::indexed(VALS, Reg::Rax, 4)),
+                        Operand::Imm(u32::from(c) as i64),
+                    ));
+                }
+                self.asm.bind(end);
+            }
+            Op::Dump => {
+                self.load_cell();
+                self.asm.push(Inst::Call(self.rt.write));
+            }
+            Op::Take => {
+                self.load_cell();
+                self.asm.push(Inst::Call(self.rt.read));
+                self.store_cell();
+            }
+            Op::Halt => {
+                self.load_cell();
+                self.mov(Operand::Reg(Reg::Rdi), Operand::Reg(Reg::Rax));
+                self.asm.push(Inst::Jmp(self.rt.exit));
+            }
+            Op::Debug => {}
+
+            Op::PushNew(size) if *size > MAX_TAPE_SIZE => {
+                return Err(CompileError::TapeTooLarge(*size, pos))
+            }
+            Op::PushNew(size) => {
+                self.mov(Operand::Reg(Reg::Rcx), Operand::Imm(*size as i64));
+                self.asm.push(Inst::Call(self.rt.push_new));
+            }
+            Op::PushOp(StackOp::Default) => self.asm.push(Inst::Call(self.rt.push)),
+            Op::PopOp(StackOp::Default) => self.asm.push(Inst::Call(self.rt.pop)),
+            Op::HardPopOp(StackOp::Default) => self.asm.push(Inst::Call(self.rt.hard_pop)),
+
+            // the current cell is carried to the other tape in r8
+            Op::PushOp(stack_op) | Op::PopOp(stack_op) | Op::HardPopOp(stack_op) => {
+                let (other, back) = match op {
+                    Op::PushOp(_) => (self.rt.push, Some(self.rt.pop)),
+                    Op::PopOp(_) => (self.rt.pop, None),
+                    _ => (self.rt.hard_pop, None),
+                };
+                self.load_cell();
+                self.mov(Operand::Reg(Reg::R8), Operand::Reg(Reg::Rax));
+                self.asm.push(Inst::Call(other));
+                self.load_cell();
+                self.combine(stack_op);
+                self.store_cell();
+                if let Some(back) = back {
+                    self.asm.push(Inst::Call(back));
+                }
+            }
+            Op::Inc | Op::Dec | Op::PtrRight | Op::PtrLeft => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // sets eax to r8 <op> eax
+    fn combine(&mut self, op: &StackOp) {
+        let mov = |dest, src| Inst::Mov(Size::Dword, Operand::Reg(dest), src);
+        let alu = |alu: Alu| {
+            Inst::Alu(
+                alu,
+                Size::Dword,
+                Operand::Reg(Reg::Rax),
+                Operand::Reg(Reg::R8),
+            )
+        };
+        let code = match op {
+            StackOp::Set => vec![mov(Reg::Rax, Operand::Reg(Reg::R8))],
+            StackOp::Add => vec![alu(Alu::Add)],
+            StackOp::Sub => vec![
+                Inst::Alu(
+                    Alu::Sub,
+                    Size::Dword,
+                    Operand::Reg(Reg::R8),
+                    Operand::Reg(Reg::Rax),
+                ),
+                mov(Reg::Rax, Operand::Reg(Reg::R8)),
+            ],
+            StackOp::Mul => vec![Inst::Imul(Size::Dword, Reg::Rax, Operand::Reg(Reg::R8))],
+            StackOp::Div => vec![
+                mov(Reg::Rcx, Operand::Reg(Reg::Rax)),
+                mov(Reg::Rax, Operand::Reg(Reg::R8)),
+                mov(Reg::Rdx, Operand::Imm(0)),
+                Inst::Div(Size::Dword, Operand::Reg(Reg::Rcx)),
+            ],
+            StackOp::BitAnd => vec![alu(Alu::And)],
+            StackOp::BitOr => vec![alu(Alu::Or)],
+            StackOp::BitXor => vec![alu(Alu::Xor)],
+            StackOp::Default => vec![],
+        };
+        for inst in code {
+            self.asm.push(inst);
+        }
+    }
+
+    // loads the current cell into eax, failing if the pointer is past the end
+    fn load_cell(&mut self) {
+        self.alu(Alu::Cmp, Operand::Reg(PTR_REG), Operand::Reg(LEN_REG));
+        self.asm
+            .push(Inst::Jcc(Cond::Ae, self.rt.tape_out_of_bounds));
+        self.asm.push(Inst::Mov(
+            Size::Dword,
+            Operand::Reg(Reg::Rax),
+            Operand::Mem(Mem::indexed(VALS, PTR_REG, 4)),
+        ));
+    }
+
+    fn store_cell(&mut self) {
+        self.asm.push(Inst::Mov(
+            Size::Dword,
+            Operand::Mem(Mem::indexed(VALS, PTR_REG, 4)),
+            Operand::Reg(Reg::Rax),
+        ));
+    }
+
+    // subroutines compiled code calls, and the messages they print
+    fn runtime(&mut self) {
+        let rt = &self.rt;
+        let (select, save, push, pop, hard_pop) =
+            (rt.select, rt.save, rt.push, rt.pop, rt.hard_pop);
+        let entry = |disp| Mem {
+            base: TABLE_REG,
+            index: Some((Reg::Rax, 1)),
+            disp,
+        };
+
+        self.comment("");
+        self.comment("runtime: loads the selected tape into r12, r13 and r14");
+        self.asm.bind(select);
+        self.entry_offset(Reg::Rax, CURR);
+        self.mov(Operand::Reg(VALS), Operand::Mem(entry(0)));
+        self.mov(Operand::Reg(LEN_REG), Operand::Mem(entry(LEN)));
+        self.mov(Operand::Reg(PTR_REG), Operand::Mem(entry(PTR)));
+        self.asm.push(Inst::Ret);
+
+        self.comment("");
+        self.comment("runtime: stores the pointer of the selected tape back in the table");
+        self.asm.bind(save);
+        self.entry_offset(Reg::Rax, CURR);
+        self.mov(Operand::Mem(entry(PTR)), Operand::Reg(PTR_REG));
+        self.asm.push(Inst::Ret);
+
+        self.comment("");
+        self.comment("runtime: ^, selecting the tape above");
+        self.asm.bind(push);
+        self.asm.push(Inst::Call(save));
+        self.alu(Alu::Add, Operand::Reg(CURR), Operand::Imm(1));
+        self.alu(Alu::Cmp, Operand::Reg(CURR), Operand::Reg(STACK_LEN));
+        self.asm
+            .push(Inst::Jcc(Cond::Ae, self.rt.stack_out_of_bounds));
+        self.asm.push(Inst::Jmp(select));
+
+        self.comment("");
+        self.comment("runtime: _, selecting the tape below");
+        self.asm.bind(pop);
+        self.asm.push(Inst::Test(Size::Qword, CURR, CURR));
+        self.asm.push(Inst::Jcc(Cond::E, self.rt.stack_negative));
+        self.asm.push(Inst::Call(save));
+        self.alu(Alu::Sub, Operand::Reg(CURR), Operand::Imm(1));
+        self.asm.push(Inst::Jmp(select));
+
+        self.hard_pop(hard_pop);
+        self.push_new();
+        self.write();
+        self.read();
+        self.errors();
+    }
+
+    fn hard_pop(&mut self, hard_pop: Label) {
+        let (shift, done) = (self.asm.label(), self.asm.label());
+        let entry = |disp| Mem {
+            base: TABLE_REG,
+            index: Some((Reg::Rdx, 1)),
+            disp,
+        };
+
+        self.comment("");
+        self.comment("runtime: &, deleting the selected tape and selecting the one below");
+        self.asm.bind(hard_pop);
+        self.comment("moves the tapes above the selected one down over it");
+        self.mov(Operand::Reg(Reg::Rcx), Operand::Reg(CURR));
+        self.asm.bind(shift);
+        self.asm.push(Inst::Lea(Reg::Rax, Mem::base(Reg::Rcx, 1)));
+        self.alu(Alu::Cmp, Operand::Reg(Reg::Rax), Operand::Reg(STACK_LEN));
+        self.asm.push(Inst::Jcc(Cond::Ae, done));
+        self.entry_offset(Reg::Rdx, Reg::Rcx);
+        for disp in [0, LEN, PTR] {
+            self.mov(
+                Operand::Reg(Reg::Rsi),
+                Operand::Mem(entry(disp + (1 << TAPE_SHIFT))),
+            );
+            self.mov(Operand::Mem(entry(disp)), Operand::Reg(Reg::Rsi));
+        }
+        self.alu(Alu::Add, Operand::Reg(Reg::Rcx), Operand::Imm(1));
+        self.asm.push(Inst::Jmp(shift));
+        self.asm.bind(done);
+        self.alu(Alu::Sub, Operand::Reg(STACK_LEN), Operand::Imm(1));
+        self.asm.push(Inst::Test(Size::Qword, CURR, CURR));
+        self.asm.push(Inst::Jcc(Cond::E, self.rt.stack_negative));
+        self.alu(Alu::Sub, Operand::Reg(CURR), Operand::Imm(1));
+        self.asm.push(Inst::Jmp(self.rt.select));
+    }
+
+    fn push_new(&mut self) {
+        let entry = |disp| Mem {
+            base: TABLE_REG,
+            index: Some((Reg::Rax, 1)),
+            disp,
+        };
+
+        self.comment("");
+        self.comment("runtime: ^N, pushing a tape of rcx cells to the top of the stack");
+        self.asm.bind(self.rt.push_new);
+        self.alu(Alu::Cmp, Operand::Reg(STACK_LEN), Operand::Imm(MAX_TAPES));
+        self.asm.push(Inst::Jcc(Cond::Ae, self.rt.stack_full));
+
+        self.comment("its cells go after those of the top tape, if they fit");
+        self.asm.push(Inst::Lea(Reg::Rax, Mem::base(STACK_LEN, -1)));
+        self.asm.push(Inst::Shl(Size::Qword, Reg::Rax, TAPE_SHIFT));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Mem(entry(0)));
+        self.mov(Operand::Reg(Reg::Rdx), Operand::Mem(entry(LEN)));
+        self.asm
+            .push(Inst::Lea(Reg::Rdi, Mem::indexed(Reg::Rdi, Reg::Rdx, 4)));
+        self.asm
+            .push(Inst::Lea(Reg::Rdx, Mem::indexed(Reg::Rdi, Reg::Rcx, 4)));
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(TABLE + HEAP));
+        self.alu(Alu::Add, Operand::Reg(Reg::Rax), Operand::Reg(TABLE_REG));
+        self.alu(Alu::Cmp, Operand::Reg(Reg::Rdx), Operand::Reg(Reg::Rax));
+        self.asm.push(Inst::Jcc(Cond::A, self.rt.no_memory));
+
+        self.entry_offset(Reg::Rax, STACK_LEN);
+        self.mov(Operand::Mem(entry(0)), Operand::Reg(Reg::Rdi));
+        self.mov(Operand::Mem(entry(LEN)), Operand::Reg(Reg::Rcx));
+        self.mov(Operand::Mem(entry(PTR)), Operand::Imm(0));
+        self.alu(Alu::Add, Operand::Reg(STACK_LEN), Operand::Imm(1));
+
+        self.comment("cells may have been used by tapes since deleted");
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(0));
+        self.asm.push(Inst::RepStosd);
+        self.asm.push(Inst::Jmp(self.rt.push));
+    }
+
+    fn write(&mut self) {
+        let out = self.asm.label();
+        let buf = |idx| Mem::base(Reg::Rsp, idx);
+
+        self.comment("");
+        self.comment("runtime: ., writing eax to stdout as UTF-8");
+        self.asm.bind(self.rt.write);
+        self.alu(Alu::Sub, Operand::Reg(Reg::Rsp), Operand::Imm(8));
+
+        // bytes of each length of character
+        let lengths: [(i64, &[Utf8Byte]); 4] = [
+            (0x80, &[(0, 0x7F, 0)]),
+            (0x800, &[(6, 0x1F, 0xC0), (0, 0x3F, 0x80)]),
+            (
+                0x10000,
+                &[(12, 0x0F, 0xE0), (6, 0x3F, 0x80), (0, 0x3F, 0x80)],
+            ),
+            (
+                i64::MAX,
+                &[
+                    (18, 0x07, 0xF0),
+                    (12, 0x3F, 0x80),
+                    (6, 0x3F, 0x80),
+                    (0, 0x3F, 0x80),
+                ],
+            ),
+        ];
+        for (below, bytes) in lengths {
+            let next = self.asm.label();
+            if below != i64::MAX {
+                self.alu_dword(Alu::Cmp, Operand::Reg(Reg::Rax), below);
+                self.asm.push(Inst::Jcc(Cond::Ae, next));
+            }
+            for (idx, (shift, keep, set)) in bytes.iter().enumerate() {
+                self.asm.push(Inst::Mov(
+                    Size::Dword,
+                    Operand::Reg(Reg::Rcx),
+                    Operand::Reg(Reg::Rax),
+                ));
+                if *shift > 0 {
+                    self.asm.push(Inst::Shr(Size::Dword, Reg::Rcx, *shift));
+                }
+                self.alu_dword(Alu::And, Operand::Reg(Reg::Rcx), *keep);
+                if *set > 0 {
+                    self.alu_dword(Alu::Or, Operand::Reg(Reg::Rcx), *set);
+                }
+                self.asm.push(Inst::MovByte(buf(idx as i32), Reg::Rcx));
+            }
+            self.mov(Operand::Reg(Reg::Rdx), Operand::Imm(bytes.len() as i64));
+            self.asm.push(Inst::Jmp(out));
+            self.asm.bind(next);
+        }
+
+        self.asm.bind(out);
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(SYS_WRITE));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Imm(1));
+        self.mov(Operand::Reg(Reg::Rsi), Operand::Reg(Reg::Rsp));
+        self.asm.push(Inst::Syscall);
+        self.alu(Alu::Add, Operand::Reg(Reg::Rsp), Operand::Imm(8));
+        self.asm.push(Inst::Ret);
+    }
+
+    fn read(&mut self) {
+        let (eof, more, done) = (self.asm.label(), self.asm.label(), self.asm.label());
+
+        self.comment("");
+        self.comment("runtime: reads a byte from stdin into eax, -1 once input has ended");
+        self.asm.bind(self.rt.read_byte);
+        self.alu(Alu::Sub, Operand::Reg(Reg::Rsp), Operand::Imm(8));
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(SYS_READ));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Imm(0));
+        self.mov(Operand::Reg(Reg::Rsi), Operand::Reg(Reg::Rsp));
+        self.mov(Operand::Reg(Reg::Rdx), Operand::Imm(1));
+        self.asm.push(Inst::Syscall);
+        self.alu(Alu::Cmp, Operand::Reg(Reg::Rax), Operand::Imm(1));
+        let failed = self.asm.label();
+        self.asm.push(Inst::Jcc(Cond::Ne, failed));
+        self.asm
+            .push(Inst::MovzxByte(Reg::Rax, Mem::base(Reg::Rsp, 0)));
+        self.alu(Alu::Add, Operand::Reg(Reg::Rsp), Operand::Imm(8));
+        self.asm.push(Inst::Ret);
+        self.asm.bind(failed);
+        self.asm.push(Inst::Mov(
+            Size::Dword,
+            Operand::Reg(Reg::Rax),
+            Operand::Imm(-1),
+        ));
+        self.alu(Alu::Add, Operand::Reg(Reg::Rsp), Operand::Imm(8));
+        self.asm.push(Inst::Ret);
+
+        self.comment("");
+        self.comment(
+            "runtime: ,, reading a UTF-8 character from stdin into eax (0 once input has ended)",
+        );
+        self.asm.bind(self.rt.read);
+        self.asm.push(Inst::Call(self.rt.read_byte));
+        self.alu_dword(Alu::Cmp, Operand::Reg(Reg::Rax), -1);
+        self.asm.push(Inst::Jcc(Cond::E, eof));
+        self.alu_dword(Alu::Cmp, Operand::Reg(Reg::Rax), 0xC0);
+        self.asm.push(Inst::Jcc(Cond::B, done));
+
+        self.comment("bytes left in the character (r9), from its first byte's bits (r8)");
+        for (extra, below, keep) in [(1, 0xE0, 0x1F), (2, 0xF0, 0x0F), (3, 0, 0x07)] {
+            self.mov(Operand::Reg(Reg::R9), Operand::Imm(extra));
+            self.asm.push(Inst::Mov(
+                Size::Dword,
+                Operand::Reg(Reg::R8),
+                Operand::Reg(Reg::Rax),
+            ));
+            self.alu_dword(Alu::And, Operand::Reg(Reg::R8), keep);
+            if below > 0 {
+                self.alu_dword(Alu::Cmp, Operand::Reg(Reg::Rax), below);
+                self.asm.push(Inst::Jcc(Cond::B, more));
+            }
+        }
+        self.asm.bind(more);
+        let partial = self.asm.label();
+        self.asm.push(Inst::Call(self.rt.read_byte));
+        self.alu_dword(Alu::Cmp, Operand::Reg(Reg::Rax), -1);
+        self.asm.push(Inst::Jcc(Cond::E, partial));
+        self.asm.push(Inst::Shl(Size::Dword, Reg::R8, 6));
+        self.alu_dword(Alu::And, Operand::Reg(Reg::Rax), 0x3F);
+        self.asm.push(Inst::Alu(
+            Alu::Or,
+            Size::Dword,
+            Operand::Reg(Reg::R8),
+            Operand::Reg(Reg::Rax),
+        ));
+        self.alu(Alu::Sub, Operand::Reg(Reg::R9), Operand::Imm(1));
+        self.asm.push(Inst::Jcc(Cond::Ne, more));
+        self.asm.bind(partial);
+        self.asm.push(Inst::Mov(
+            Size::Dword,
+            Operand::Reg(Reg::Rax),
+            Operand::Reg(Reg::R8),
+        ));
+        self.asm.push(Inst::Jmp(done));
+        self.asm.bind(eof);
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(0));
+        self.asm.bind(done);
+        self.asm.push(Inst::Ret);
+    }
+
+    // exiting, and the errors the program can fail with
+    fn errors(&mut self) {
+        self.comment("");
+        self.comment("runtime: exits with the code in edi");
+        self.asm.bind(self.rt.exit);
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(SYS_EXIT_GROUP));
+        self.asm.push(Inst::Syscall);
+
+        self.comment("");
+        self.comment("runtime: writes rdx bytes from rsi to stderr");
+        self.asm.bind(self.rt.write_err);
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(SYS_WRITE));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Imm(2));
+        self.asm.push(Inst::Syscall);
+        self.asm.push(Inst::Ret);
+
+        self.write_num();
+
+        // messages the same as the interpreter's, with registers in between
+        let errors: [(Label, &[&str], &[Reg]); 6] = [
+            (
+                self.rt.tape_out_of_bounds,
+                &["\nERROR: Tape index ", " out of bounds of tape size ", "\n"],
+                &[PTR_REG, LEN_REG],
+            ),
+            (
+                self.rt.tape_negative,
+                &["\nERROR: TapeIndexCannotBeNegative\n"],
+                &[],
+            ),
+            (
+                self.rt.stack_out_of_bounds,
+                &[
+                    "\nERROR: Tape index ",
+                    " out of bounds of stack size ",
+                    "\n",
+                ],
+                &[CURR, STACK_LEN],
+            ),
+            (
+                self.rt.stack_negative,
+                &["\nERROR: StackIndexCannotBeNegative\n"],
+                &[],
+            ),
+            (
+                self.rt.stack_full,
+                &["\nERROR: Stack grew past ", " tapes\n"],
+                &[STACK_LEN],
+            ),
+            (
+                self.rt.no_memory,
+                &["\nERROR: Not enough memory for the cells of the stack\n"],
+                &[],
+            ),
+        ];
+
+        let mut messages = vec![];
+        for (label, parts, regs) in errors {
+            self.comment("");
+            self.asm.bind(label);
+            for (idx, part) in parts.iter().enumerate() {
+                let message = self.asm.label();
+                self.asm.push(Inst::LeaLabel(Reg::Rsi, message));
+                self.mov(Operand::Reg(Reg::Rdx), Operand::Imm(part.len() as i64));
+                self.asm.push(Inst::Call(self.rt.write_err));
+                messages.push((message, part.as_bytes().to_vec()));
+
+                if let Some(reg) = regs.get(idx) {
+                    self.mov(Operand::Reg(Reg::Rax), Operand::Reg(*reg));
+                    self.asm.push(Inst::Call(self.rt.write_num));
+                }
+            }
+            self.mov(Operand::Reg(Reg::Rdi), Operand::Imm(1));
+            self.asm.push(Inst::Jmp(self.rt.exit));
+        }
+
+        self.comment("");
+        self.comment("messages");
+        for (label, message) in messages {
+            self.asm.bind(label);
+            self.asm.push(Inst::Data(message));
+        }
+    }
+
+    fn write_num(&mut self) {
+        let digit = self.asm.label();
+        self.comment("");
+        self.comment("runtime: writes rax to stderr as a decimal number");
+        self.asm.bind(self.rt.write_num);
+        self.alu(Alu::Sub, Operand::Reg(Reg::Rsp), Operand::Imm(24));
+        self.asm.push(Inst::Lea(Reg::Rsi, Mem::base(Reg::Rsp, 24)));
+        self.mov(Operand::Reg(Reg::Rcx), Operand::Imm(10));
+        self.comment("digits are written backwards from the end of the buffer");
+        self.asm.bind(digit);
+        self.mov(Operand::Reg(Reg::Rdx), Operand::Imm(0));
+        self.asm
+            .push(Inst::Div(Size::Qword, Operand::Reg(Reg::Rcx)));
+        self.alu(Alu::Add, Operand::Reg(Reg::Rdx), Operand::Imm(b'0' as i64));
+        self.alu(Alu::Sub, Operand::Reg(Reg::Rsi), Operand::Imm(1));
+        self.asm
+            .push(Inst::MovByte(Mem::base(Reg::Rsi, 0), Reg::Rdx));
+        self.asm.push(Inst::Test(Size::Qword, Reg::Rax, Reg::Rax));
+        self.asm.push(Inst::Jcc(Cond::Ne, digit));
+        self.asm.push(Inst::Lea(Reg::Rdx, Mem::base(Reg::Rsp, 24)));
+        self.alu(Alu::Sub, Operand::Reg(Reg::Rdx), Operand::Reg(Reg::Rsi));
+        self.asm.push(Inst::Call(self.rt.write_err));
+        self.alu(Alu::Add, Operand::Reg(Reg::Rsp), Operand::Imm(24));
+        self.asm.push(Inst::Ret);
+    }
+
+    // sets dest to the offset of tape idx in the table
+    fn entry_offset(&mut self, dest: Reg, idx: Reg) {
+        self.mov(Operand::Reg(dest), Operand::Reg(idx));
+        self.asm.push(Inst::Shl(Size::Qword, dest, TAPE_SHIFT));
+    }
+
+    fn comment(&mut self, text: &str) {
+        self.asm.push(Inst::Comment(text.to_string()));
+    }
+
+    fn mov(&mut self, dest: Operand, src: Operand) {
+        self.asm.push(Inst::Mov(Size::Qword, dest, src));
+    }
+
+    fn alu(&mut self, op: Alu, dest: Operand, src: Operand) {
+        self.asm.push(Inst::Alu(op, Size::Qword, dest, src));
+    }
+
+    fn alu_dword(&mut self, op: Alu, dest: Operand, val: i64) {
+        self.asm
+            .push(Inst::Alu(op, Size::Dword, dest, Operand::Imm(val)));
+    }
+}
+
+// bits shifted away, then the bits kept and those set in a byte of UTF-8
+type Utf8Byte = (u8, i64, i64);
+
+fn op_text(node: &OpNode) -> String {
+    match node {
+        OpNode::Operation(op, _) => op.to_string(),
+        _ => String::default(),
+    }
+}
+
+fn same_run(first: &OpNode, node: &OpNode) -> bool {
+    matches!(
+        (first, node),
+        (
+            OpNode::Operation(Op::Inc | Op::Dec, _),
+            OpNode::Operation(Op::Inc | Op::Dec, _)
+        ) | (
+            OpNode::Operation(Op::PtrRight, _),
+            OpNode::Operation(Op::PtrRight, _)
+        ) | (
+            OpNode::Operation(Op::PtrLeft, _),
+            OpNode::Operation(Op::PtrLeft, _)
+        )
+    )
+}
+
+#[cfg(all(test, target_arch = "x86_64", target_os = "linux"))]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::process::{self, Command};
+
+    use super::*;
+    use crate::intepreter::testing;
+
+    #[test]
+    fn tail_calls_do_not_grow_stack() {
+        // millions of calls deep, far more than the machine's stack holds
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+        let src = fs::read_to_string(dir.join("countdown.migraine")).unwrap();
+        let elf = compile(&testing::parse(&src)).unwrap().elf();
+
+        let path = std::env::temp_dir().join(format!("migraine-countdown-{}", process::id()));
+        fs::write(&path, elf).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let out = Command::new(&path).output();
+        fs::remove_file(&path).unwrap();
+
+        let out = out.unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "0");
+        assert_eq!(out.status.code(), Some('0' as i32));
+    }
+}
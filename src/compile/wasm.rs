@@ -0,0 +1,1046 @@
+// Compiles programs into WebAssembly modules, as binary or as text (WAT)
+//
+// Every function becomes a WASM function, exported under its own name, so
+// calls and recursion work as they do in the interpreter. The stack of tapes
+// lives in linear memory: a table of tapes at the start, each as its cells'
+// address, length and pointer, then string literals, then the cells of every
+// tape one after another (memory grows as tapes are pushed).
+//
+// Modules import three functions from "env":
+//
+//   write(char: i32)     prints a character
+//   read() -> i32        reads a character, -1 once input has ended
+//   exit(code: i32)      stops the program, called by `!` (it must not return)
+//
+// Errors the interpreter would report (such as moving off the end of the stack)
+// trap instead.
+
+use std::collections::HashMap;
+
+use super::err::CompileError;
+use crate::parse::analysis::INTRINSIC_PREFIX;
+use crate::parse::err::FilePos;
+use crate::parse::lexer::{Op, StackOp};
+use crate::parse::parser::{Func, OpNode};
+
+/// Most tapes the stack can hold
+pub const MAX_TAPES: u32 = 4096;
+
+/// Most cells a tape can have, so its cells fit in 32-bit memory
+pub const MAX_TAPE_SIZE: usize = (u32::MAX / 4) as usize;
+
+// bytes taken up by a tape in the table: address, length and pointer
+const TAPE: u32 = 12;
+const LEN: u32 = 4;
+const PTR: u32 = 8;
+
+const LITERALS: u32 = MAX_TAPES * TAPE;
+const PAGE: u32 = 1 << 16;
+
+// globals, holding how many tapes are in the stack, the selected tape and the
+// address where the next tape's cells go
+const STACK_LEN: u32 = 0;
+const CURR: u32 = 1;
+const HEAP: u32 = 2;
+const GLOBALS: [&str; 3] = ["stack_len", "curr", "heap"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sig {
+    Void,
+    Param,
+    Params,
+    Result,
+}
+
+impl Sig {
+    const ALL: [Sig; 4] = [Sig::Void, Sig::Param, Sig::Params, Sig::Result];
+
+    fn params(&self) -> usize {
+        match self {
+            Sig::Param => 1,
+            Sig::Params => 2,
+            _ => 0,
+        }
+    }
+
+    fn results(&self) -> usize {
+        (*self == Sig::Result) as usize
+    }
+}
+
+// functions imported from the host, then the ones used to run operations
+const WRITE: u32 = 0;
+const READ: u32 = 1;
+const EXIT: u32 = 2;
+const IMPORTS: [(&str, Sig); 3] = [
+    ("write", Sig::Param),
+    ("read", Sig::Result),
+    ("exit", Sig::Param),
+];
+
+const DESC: u32 = 3;
+const ADDR: u32 = 4;
+const GET: u32 = 5;
+const SET: u32 = 6;
+const RIGHT: u32 = 7;
+const LEFT: u32 = 8;
+const PUSH: u32 = 9;
+const POP: u32 = 10;
+const PUSH_NEW: u32 = 11;
+const HARD_POP: u32 = 12;
+const LITERAL: u32 = 13;
+const DUMP: u32 = 14;
+const TAKE: u32 = 15;
+const FUNCS: u32 = 16;
+
+#[derive(Debug, Clone, Copy)]
+enum Instr {
+    Block,
+    Loop,
+    If,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Return,
+    Unreachable,
+    Call(u32),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Load(u32),
+    Store(u32),
+    MemorySize,
+    MemoryGrow,
+    Const(i32),
+    Eqz,
+    Eq,
+    LtS,
+    LtU,
+    GtU,
+    GeU,
+    Add,
+    Sub,
+    Mul,
+    DivU,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrU,
+}
+
+impl Instr {
+    fn text(&self, names: &[String]) -> String {
+        match self {
+            Instr::Block => "block".into(),
+            Instr::Loop => "loop".into(),
+            Instr::If => "if".into(),
+            Instr::End => "end".into(),
+            Instr::Br(depth) => format!("br {}", depth),
+            Instr::BrIf(depth) => format!("br_if {}", depth),
+            Instr::Return => "return".into(),
+            Instr::Unreachable => "unreachable".into(),
+            Instr::Call(func) => format!("call ${}", names[*func as usize]),
+            Instr::Select => "select".into(),
+            Instr::LocalGet(idx) => format!("local.get {}", idx),
+            Instr::LocalSet(idx) => format!("local.set {}", idx),
+            Instr::GlobalGet(idx) => format!("global.get ${}", GLOBALS[*idx as usize]),
+            Instr::GlobalSet(idx) => format!("global.set ${}", GLOBALS[*idx as usize]),
+            Instr::Load(0) => "i32.load".into(),
+            Instr::Load(offset) => format!("i32.load offset={}", offset),
+            Instr::Store(0) => "i32.store".into(),
+            Instr::Store(offset) => format!("i32.store offset={}", offset),
+            Instr::MemorySize => "memory.size".into(),
+            Instr::MemoryGrow => "memory.grow".into(),
+            Instr::Const(val) => format!("i32.const {}", val),
+            Instr::Eqz => "i32.eqz".into(),
+            Instr::Eq => "i32.eq".into(),
+            Instr::LtS => "i32.lt_s".into(),
+            Instr::LtU => "i32.lt_u".into(),
+            Instr::GtU => "i32.gt_u".into(),
+            Instr::GeU => "i32.ge_u".into(),
+            Instr::Add => "i32.add".into(),
+            Instr::Sub => "i32.sub".into(),
+            Instr::Mul => "i32.mul".into(),
+            Instr::DivU => "i32.div_u".into(),
+            Instr::And => "i32.and".into(),
+            Instr::Or => "i32.or".into(),
+            Instr::Xor => "i32.xor".into(),
+            Instr::Shl => "i32.shl".into(),
+            Instr::ShrU => "i32.shr_u".into(),
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        // blocks never leave values on the stack
+        const EMPTY: u8 = 0x40;
+        // loads and stores are of aligned 4 byte cells
+        const ALIGN: u32 = 2;
+
+        match self {
+            Instr::Block => out.extend([0x02, EMPTY]),
+            Instr::Loop => out.extend([0x03, EMPTY]),
+            Instr::If => out.extend([0x04, EMPTY]),
+            Instr::End => out.push(0x0b),
+            Instr::Br(depth) => {
+                out.push(0x0c);
+                unsigned(out, *depth);
+            }
+            Instr::BrIf(depth) => {
+                out.push(0x0d);
+                unsigned(out, *depth);
+            }
+            Instr::Return => out.push(0x0f),
+            Instr::Unreachable => out.push(0x00),
+            Instr::Call(func) => {
+                out.push(0x10);
+                unsigned(out, *func);
+            }
+            Instr::Select => out.push(0x1b),
+            Instr::LocalGet(idx) => {
+                out.push(0x20);
+                unsigned(out, *idx);
+            }
+            Instr::LocalSet(idx) => {
+                out.push(0x21);
+                unsigned(out, *idx);
+            }
+            Instr::GlobalGet(idx) => {
+                out.push(0x23);
+                unsigned(out, *idx);
+            }
+            Instr::GlobalSet(idx) => {
+                out.push(0x24);
+                unsigned(out, *idx);
+            }
+            Instr::Load(offset) => {
+                out.push(0x28);
+                unsigned(out, ALIGN);
+                unsigned(out, *offset);
+            }
+            Instr::Store(offset) => {
+                out.push(0x36);
+                unsigned(out, ALIGN);
+                unsigned(out, *offset);
+            }
+            Instr::MemorySize => out.extend([0x3f, 0x00]),
+            Instr::MemoryGrow => out.extend([0x40, 0x00]),
+            Instr::Const(val) => {
+                out.push(0x41);
+                signed(out, *val);
+            }
+            Instr::Eqz => out.push(0x45),
+            Instr::Eq => out.push(0x46),
+            Instr::LtS => out.push(0x48),
+            Instr::LtU => out.push(0x49),
+            Instr::GtU => out.push(0x4b),
+            Instr::GeU => out.push(0x4f),
+            Instr::Add => out.push(0x6a),
+            Instr::Sub => out.push(0x6b),
+            Instr::Mul => out.push(0x6c),
+            Instr::DivU => out.push(0x6e),
+            Instr::And => out.push(0x71),
+            Instr::Or => out.push(0x72),
+            Instr::Xor => out.push(0x73),
+            Instr::Shl => out.push(0x74),
+            Instr::ShrU => out.push(0x76),
+        }
+    }
+}
+
+// LEB128 encodings of integers
+fn unsigned(out: &mut Vec<u8>, mut val: u32) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(out: &mut Vec<u8>, mut val: i32) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    unsigned(out, name.len() as u32);
+    out.extend(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, body: Vec<u8>) {
+    out.push(id);
+    unsigned(out, body.len() as u32);
+    out.extend(body);
+}
+
+// string in WAT, with anything other than printable ASCII escaped
+fn quote(bytes: &[u8]) -> String {
+    let mut out = "\"".to_string();
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => out += &format!("\\{}", *byte as char),
+            0x20..=0x7e => out.push(*byte as char),
+            _ => out += &format!("\\{:02x}", byte),
+        }
+    }
+    out + "\""
+}
+
+struct Function {
+    /// Name in the text format (without its `$`)
+    name: String,
+    /// Name the function is exported as, None for the runtime's own functions
+    export: Option<String>,
+    sig: Sig,
+    /// Locals besides parameters
+    locals: u32,
+    body: Vec<Instr>,
+}
+
+/// Compiled WebAssembly module, which can be written as binary or text
+pub struct Module {
+    funcs: Vec<Function>,
+    /// String literals, as a cell for each character
+    literals: Vec<u8>,
+    heap: u32,
+}
+
+/// Compiles every function of a program into a module
+pub fn compile(funcs: &[Func]) -> Result<Module, CompileError> {
+    let indices: HashMap<&str, u32> = funcs
+        .iter()
+        .enumerate()
+        .map(|(idx, func)| (func.name.as_str(), FUNCS + idx as u32))
+        .collect();
+
+    let mut compiler = Compiler {
+        indices,
+        literals: vec![],
+        body: vec![],
+    };
+    let mut compiled = vec![];
+    for func in funcs {
+        compiler.body = vec![];
+        let children = match &func.node {
+            OpNode::Root(children) => children.as_slice(),
+            _ => &[],
+        };
+
+        // a call to itself at the very end jumps back to the start instead,
+        // so recursion in tail position does not grow the wasm stack
+        match children.split_last() {
+            Some((OpNode::FuncCall(name, _), rest)) if *name == func.name => {
+                compiler.body.push(Instr::Loop);
+                for child in rest {
+                    compiler.nodes(child)?;
+                }
+                compiler.body.extend([Instr::Br(0), Instr::End]);
+            }
+            _ => compiler.nodes(&func.node)?,
+        }
+        compiled.push(Function {
+            name: String::default(),
+            export: Some(func.name.clone()),
+            sig: Sig::Void,
+            // for the value carried between tapes by stack operations
+            locals: 1,
+            body: std::mem::take(&mut compiler.body),
+        });
+    }
+
+    let literals = compiler.literals;
+    // cells of tapes start after literals, aligned to a cell
+    let heap = LITERALS + (literals.len() as u32).div_ceil(4) * 4;
+
+    let mut funcs = runtime(heap);
+    funcs.extend(compiled);
+    name_funcs(&mut funcs);
+    Ok(Module {
+        funcs,
+        literals,
+        heap,
+    })
+}
+
+// gives every function a name in the text format, from its own name where it
+// can (only some characters can be used)
+fn name_funcs(funcs: &mut [Function]) {
+    let mut used: Vec<String> = IMPORTS
+        .iter()
+        .map(|(import, _)| format!("env.{}", import))
+        .collect();
+
+    for func in funcs.iter_mut() {
+        if let Some(export) = &func.export {
+            func.name = export
+                .chars()
+                .map(|c| match c {
+                    c if c.is_ascii_alphanumeric() || "!#%&'*+-./:<=>?@\\^_`|~".contains(c) => c,
+                    _ => '_',
+                })
+                .collect();
+        }
+        if used.contains(&func.name) {
+            func.name = format!("{}.{}", func.name, used.len());
+        }
+        used.push(func.name.clone());
+    }
+}
+
+// functions running operations on the stack of tapes, which compiled code calls
+fn runtime(heap: u32) -> Vec<Function> {
+    use Instr::*;
+
+    // traps if the condition on the top of the stack is true
+    const TRAP: [Instr; 3] = [If, Unreachable, End];
+
+    let func = |name: &str, sig, locals, body: Vec<Vec<Instr>>| Function {
+        name: format!("rt.{}", name),
+        export: None,
+        sig,
+        locals,
+        body: body.concat(),
+    };
+
+    vec![
+        // address of the selected tape in the table
+        func(
+            "desc",
+            Sig::Result,
+            0,
+            vec![vec![GlobalGet(CURR), Const(TAPE as i32), Mul]],
+        ),
+        // address of the current cell, trapping if it is off the end of the tape
+        func(
+            "addr",
+            Sig::Result,
+            1,
+            vec![
+                vec![Call(DESC), LocalSet(0)],
+                vec![LocalGet(0), Load(PTR), LocalGet(0), Load(LEN), GeU],
+                TRAP.to_vec(),
+                vec![LocalGet(0), Load(0), LocalGet(0), Load(PTR)],
+                vec![Const(2), Shl, Add],
+            ],
+        ),
+        func("get", Sig::Result, 0, vec![vec![Call(ADDR), Load(0)]]),
+        func(
+            "set",
+            Sig::Param,
+            0,
+            vec![vec![Call(ADDR), LocalGet(0), Store(0)]],
+        ),
+        func(
+            "right",
+            Sig::Void,
+            0,
+            vec![vec![
+                Call(DESC),
+                Call(DESC),
+                Load(PTR),
+                Const(1),
+                Add,
+                Store(PTR),
+            ]],
+        ),
+        func(
+            "left",
+            Sig::Void,
+            1,
+            vec![
+                vec![Call(DESC), LocalSet(0), LocalGet(0), Load(PTR), Eqz],
+                TRAP.to_vec(),
+                vec![
+                    LocalGet(0),
+                    LocalGet(0),
+                    Load(PTR),
+                    Const(1),
+                    Sub,
+                    Store(PTR),
+                ],
+            ],
+        ),
+        func(
+            "push",
+            Sig::Void,
+            0,
+            vec![
+                vec![GlobalGet(CURR), Const(1), Add, GlobalSet(CURR)],
+                vec![GlobalGet(CURR), GlobalGet(STACK_LEN), GeU],
+                TRAP.to_vec(),
+            ],
+        ),
+        func(
+            "pop",
+            Sig::Void,
+            0,
+            vec![
+                vec![GlobalGet(CURR), Eqz],
+                TRAP.to_vec(),
+                vec![GlobalGet(CURR), Const(1), Sub, GlobalSet(CURR)],
+            ],
+        ),
+        // pushes a tape of (param 0) cells to the top of the stack, then moves
+        // up (local 1 is the end of its cells, local 2 walks over them)
+        func(
+            "push_new",
+            Sig::Param,
+            2,
+            vec![
+                vec![GlobalGet(STACK_LEN), Const(MAX_TAPES as i32), GeU],
+                TRAP.to_vec(),
+                vec![
+                    GlobalGet(HEAP),
+                    LocalGet(0),
+                    Const(2),
+                    Shl,
+                    Add,
+                    LocalSet(1),
+                ],
+                // past the end of 32-bit memory
+                vec![LocalGet(1), GlobalGet(HEAP), LtU],
+                TRAP.to_vec(),
+                // grows memory by enough pages to fit the cells
+                vec![LocalGet(1), MemorySize, Const(16), Shl, GtU, If],
+                vec![LocalGet(1), MemorySize, Const(16), Shl, Sub],
+                vec![Const(PAGE as i32 - 1), Add, Const(16), ShrU],
+                vec![MemoryGrow, Const(-1), Eq],
+                TRAP.to_vec(),
+                vec![End],
+                // cells may have been used by tapes since deleted
+                vec![GlobalGet(HEAP), LocalSet(2), Block, Loop],
+                vec![LocalGet(2), LocalGet(1), GeU, BrIf(1)],
+                vec![LocalGet(2), Const(0), Store(0)],
+                vec![LocalGet(2), Const(4), Add, LocalSet(2), Br(0), End, End],
+                vec![GlobalGet(STACK_LEN), Const(TAPE as i32), Mul, LocalSet(2)],
+                vec![LocalGet(2), GlobalGet(HEAP), Store(0)],
+                vec![LocalGet(2), LocalGet(0), Store(LEN)],
+                vec![LocalGet(2), Const(0), Store(PTR)],
+                vec![LocalGet(1), GlobalSet(HEAP)],
+                vec![GlobalGet(STACK_LEN), Const(1), Add, GlobalSet(STACK_LEN)],
+                vec![Call(PUSH)],
+            ],
+        ),
+        // deletes the selected tape then moves down, moving the tapes above it
+        // down the table (local 0 walks over the table, local 1 is the end of a
+        // tape's cells)
+        func(
+            "hard_pop",
+            Sig::Void,
+            2,
+            vec![
+                vec![Call(DESC), LocalSet(0), Block, Loop],
+                vec![LocalGet(0), Const(TAPE as i32), Add],
+                vec![GlobalGet(STACK_LEN), Const(TAPE as i32), Mul, GeU, BrIf(1)],
+                vec![LocalGet(0), LocalGet(0), Load(TAPE), Store(0)],
+                vec![LocalGet(0), LocalGet(0), Load(TAPE + LEN), Store(LEN)],
+                vec![LocalGet(0), LocalGet(0), Load(TAPE + PTR), Store(PTR)],
+                vec![LocalGet(0), Const(TAPE as i32), Add, LocalSet(0), Br(0)],
+                vec![End, End],
+                vec![GlobalGet(STACK_LEN), Const(1), Sub, GlobalSet(STACK_LEN)],
+                // the next tape's cells go after the last cell of any tape left
+                vec![Const(heap as i32), GlobalSet(HEAP), Const(0), LocalSet(0)],
+                vec![Block, Loop],
+                vec![LocalGet(0), GlobalGet(STACK_LEN), Const(TAPE as i32), Mul],
+                vec![GeU, BrIf(1)],
+                vec![LocalGet(0), Load(0), LocalGet(0), Load(LEN), Const(2), Shl],
+                vec![Add, LocalSet(1)],
+                vec![LocalGet(1), GlobalGet(HEAP), GtU, If],
+                vec![LocalGet(1), GlobalSet(HEAP), End],
+                vec![LocalGet(0), Const(TAPE as i32), Add, LocalSet(0), Br(0)],
+                vec![End, End],
+                vec![Call(POP)],
+            ],
+        ),
+        // writes (param 1) characters from the address (param 0) into the cells
+        // from the pointer onwards, stopping at the end of the tape (local 2 is
+        // the selected tape, local 3 the cell written to, local 4 the cells left)
+        func(
+            "literal",
+            Sig::Params,
+            3,
+            vec![
+                vec![Call(DESC), LocalSet(2)],
+                vec![LocalGet(2), Load(PTR), LocalGet(2), Load(LEN), GeU],
+                vec![If, Return, End],
+                vec![
+                    LocalGet(2),
+                    Load(LEN),
+                    LocalGet(2),
+                    Load(PTR),
+                    Sub,
+                    LocalSet(4),
+                ],
+                vec![
+                    LocalGet(1),
+                    LocalGet(4),
+                    GtU,
+                    If,
+                    LocalGet(4),
+                    LocalSet(1),
+                    End,
+                ],
+                vec![LocalGet(2), Load(0), LocalGet(2), Load(PTR), Const(2), Shl],
+                vec![Add, LocalSet(3)],
+                vec![Block, Loop, LocalGet(1), Eqz, BrIf(1)],
+                vec![LocalGet(3), LocalGet(0), Load(0), Store(0)],
+                vec![LocalGet(3), Const(4), Add, LocalSet(3)],
+                vec![LocalGet(0), Const(4), Add, LocalSet(0)],
+                vec![LocalGet(1), Const(1), Sub, LocalSet(1), Br(0)],
+                vec![End, End],
+            ],
+        ),
+        func("dump", Sig::Void, 0, vec![vec![Call(GET), Call(WRITE)]]),
+        // the cell is set to 0 once input has ended
+        func(
+            "take",
+            Sig::Void,
+            1,
+            vec![
+                vec![Call(ADDR), Call(READ), LocalSet(0)],
+                vec![Const(0), LocalGet(0), LocalGet(0), Const(0), LtS, Select],
+                vec![Store(0)],
+            ],
+        ),
+    ]
+}
+
+struct Compiler<'a> {
+    /// Index of every function, by name
+    indices: HashMap<&'a str, u32>,
+    literals: Vec<u8>,
+    body: Vec<Instr>,
+}
+
+impl<'a> Compiler<'a> {
+    fn nodes(&mut self, node: &OpNode) -> Result<(), CompileError> {
+        use Instr::*;
+
+        match node {
+            OpNode::Root(children) => {
+                for child in children {
+                    self.nodes(child)?;
+                }
+            }
+            OpNode::Loop(children, _) => {
+                self.body.extend([Block, Loop, Call(GET), Eqz, BrIf(1)]);
+                for child in children {
+                    self.nodes(child)?;
+                }
+                self.body.extend([Br(0), End, End]);
+            }
+            OpNode::Operation(op, pos) => self.op(op, *pos)?,
+            OpNode::FuncCall(name, pos) => {
+                if name.starts_with(INTRINSIC_PREFIX) {
+                    return Err(CompileError::Intrinsic(name.clone(), *pos));
+                }
+                let idx = self
+                    .indices
+                    .get(name.as_str())
+                    .ok_or_else(|| CompileError::FunctionNotDefined(name.clone(), *pos))?;
+                self.body.push(Call(*idx));
+            }
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op, pos: FilePos) -> Result<(), CompileError> {
+        use Instr::*;
+
+        let code = match op {
+            Op::Inc => vec![Call(GET), Const(1), Add, Call(SET)],
+            Op::Dec => vec![Call(GET), Const(1), Sub, Call(SET)],
+            Op::BitNot => vec![Call(GET), Const(-1), Xor, Call(SET)],
+            Op::PtrRight => vec![Call(RIGHT)],
+            Op::PtrLeft => vec![Call(LEFT)],
+            Op::Dump => vec![Call(DUMP)],
+            Op::Take => vec![Call(TAKE)],
+            Op::Literal(lit) => {
+                let addr = LITERALS + self.literals.len() as u32;
+                for c in lit.chars() {
+                    self.literals.extend(u32::from(c).to_le_bytes());
+                }
+                let len = lit.chars().count() as i32;
+                vec![Const(addr as i32), Const(len), Call(LITERAL)]
+            }
+            Op::Halt => vec![Call(GET), Call(EXIT), Unreachable],
+            Op::Debug => vec![],
+
+            Op::PushNew(size) if *size > MAX_TAPE_SIZE => {
+                return Err(CompileError::TapeTooLarge(*size, pos))
+            }
+            Op::PushNew(size) => vec![Const(*size as i32), Call(PUSH_NEW)],
+            Op::PushOp(StackOp::Default) => vec![Call(PUSH)],
+            Op::PopOp(StackOp::Default) => vec![Call(POP)],
+            Op::HardPopOp(StackOp::Default) => vec![Call(HARD_POP)],
+
+            // the current cell is carried to the other tape in local 0
+            Op::PushOp(op) => [
+                vec![Call(GET), LocalSet(0), Call(PUSH)],
+                Self::combine(op),
+                vec![Call(POP)],
+            ]
+            .concat(),
+            Op::PopOp(op) => [vec![Call(GET), LocalSet(0), Call(POP)], Self::combine(op)].concat(),
+            Op::HardPopOp(op) => [
+                vec![Call(GET), LocalSet(0), Call(HARD_POP)],
+                Self::combine(op),
+            ]
+            .concat(),
+        };
+        self.body.extend(code);
+        Ok(())
+    }
+
+    // sets the current cell to local 0 <op> itself
+    fn combine(op: &StackOp) -> Vec<Instr> {
+        use Instr::*;
+
+        let instr = match op {
+            StackOp::Set => return vec![LocalGet(0), Call(SET)],
+            StackOp::Add => Add,
+            StackOp::Sub => Sub,
+            StackOp::Mul => Mul,
+            StackOp::Div => DivU,
+            StackOp::BitAnd => And,
+            StackOp::BitOr => Or,
+            StackOp::BitXor => Xor,
+            StackOp::Default => unreachable!(),
+        };
+        vec![LocalGet(0), Call(GET), instr, Call(SET)]
+    }
+}
+
+impl Module {
+    // pages of memory the module starts with, enough for the table and literals
+    fn pages(&self) -> u32 {
+        self.heap.div_ceil(PAGE).max(1)
+    }
+
+    // the memory is exported too, unless a function has already taken its name
+    fn exports_memory(&self) -> bool {
+        self.exports().all(|(_, export)| export != "memory")
+    }
+
+    fn exports(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.funcs.iter().enumerate().filter_map(|(idx, func)| {
+            let export = func.export.as_deref()?;
+            Some((IMPORTS.len() as u32 + idx as u32, export))
+        })
+    }
+
+    /// Module in the text format
+    pub fn wat(&self) -> String {
+        let mut out = "(module\n".to_string();
+        for (idx, sig) in Sig::ALL.iter().enumerate() {
+            out += &format!("  (type (;{};) (func{}))\n", idx, signature(*sig));
+        }
+        for (import, sig) in IMPORTS {
+            out += &format!(
+                "  (import \"env\" \"{0}\" (func $env.{0}{1}))\n",
+                import,
+                signature(sig)
+            );
+        }
+        match self.exports_memory() {
+            true => out += &format!("  (memory (export \"memory\") {})\n", self.pages()),
+            false => out += &format!("  (memory {})\n", self.pages()),
+        }
+        for (idx, global) in GLOBALS.iter().enumerate() {
+            out += &format!(
+                "  (global ${} (mut i32) (i32.const {}))\n",
+                global,
+                self.global(idx as u32)
+            );
+        }
+
+        let names: Vec<String> = IMPORTS
+            .iter()
+            .map(|(import, _)| format!("env.{}", import))
+            .chain(self.funcs.iter().map(|func| func.name.clone()))
+            .collect();
+        for func in self.funcs.iter() {
+            out += &format!("  (func ${}", func.name);
+            if let Some(export) = &func.export {
+                out += &format!(" (export {})", quote(export.as_bytes()));
+            }
+            out += &signature(func.sig);
+            if func.locals > 0 {
+                out += &format!(" (local{})", " i32".repeat(func.locals as usize));
+            }
+            out += "\n";
+
+            let mut depth = 2;
+            for instr in func.body.iter() {
+                if let Instr::End = instr {
+                    depth -= 1;
+                }
+                out += &format!("{}{}\n", "  ".repeat(depth), instr.text(&names));
+                if let Instr::Block | Instr::Loop | Instr::If = instr {
+                    depth += 1;
+                }
+            }
+            out.truncate(out.len() - 1);
+            out += ")\n";
+        }
+
+        if !self.literals.is_empty() {
+            out += &format!(
+                "  (data (i32.const {}) {})\n",
+                LITERALS,
+                quote(&self.literals)
+            );
+        }
+        out + ")\n"
+    }
+
+    /// Module in the binary format
+    pub fn binary(&self) -> Vec<u8> {
+        let mut out = b"\0asm".to_vec();
+        out.extend(1u32.to_le_bytes());
+
+        let mut types = vec![];
+        unsigned(&mut types, Sig::ALL.len() as u32);
+        for sig in Sig::ALL {
+            types.push(0x60);
+            for count in [sig.params(), sig.results()] {
+                unsigned(&mut types, count as u32);
+                types.extend(std::iter::repeat_n(I32, count));
+            }
+        }
+        section(&mut out, 1, types);
+
+        let mut imports = vec![];
+        unsigned(&mut imports, IMPORTS.len() as u32);
+        for (import, sig) in IMPORTS {
+            name(&mut imports, "env");
+            name(&mut imports, import);
+            imports.push(0x00);
+            unsigned(&mut imports, sig as u32);
+        }
+        section(&mut out, 2, imports);
+
+        let mut funcs = vec![];
+        unsigned(&mut funcs, self.funcs.len() as u32);
+        for func in self.funcs.iter() {
+            unsigned(&mut funcs, func.sig as u32);
+        }
+        section(&mut out, 3, funcs);
+
+        let mut memory = vec![1, 0x00];
+        unsigned(&mut memory, self.pages());
+        section(&mut out, 5, memory);
+
+        let mut globals = vec![];
+        unsigned(&mut globals, GLOBALS.len() as u32);
+        for idx in 0..GLOBALS.len() as u32 {
+            globals.extend([I32, 0x01]);
+            Instr::Const(self.global(idx) as i32).encode(&mut globals);
+            Instr::End.encode(&mut globals);
+        }
+        section(&mut out, 6, globals);
+
+        let mut exports = vec![];
+        let memory = self.exports_memory();
+        unsigned(&mut exports, self.exports().count() as u32 + memory as u32);
+        if memory {
+            name(&mut exports, "memory");
+            exports.extend([0x02, 0x00]);
+        }
+        for (idx, export) in self.exports() {
+            name(&mut exports, export);
+            exports.push(0x00);
+            unsigned(&mut exports, idx);
+        }
+        section(&mut out, 7, exports);
+
+        let mut code = vec![];
+        unsigned(&mut code, self.funcs.len() as u32);
+        for func in self.funcs.iter() {
+            let mut body = vec![];
+            if func.locals > 0 {
+                body.push(1);
+                unsigned(&mut body, func.locals);
+                body.push(I32);
+            } else {
+                body.push(0);
+            }
+            for instr in func.body.iter() {
+                instr.encode(&mut body);
+            }
+            Instr::End.encode(&mut body);
+
+            unsigned(&mut code, body.len() as u32);
+            code.extend(body);
+        }
+        section(&mut out, 10, code);
+
+        if !self.literals.is_empty() {
+            let mut data = vec![1, 0x00];
+            Instr::Const(LITERALS as i32).encode(&mut data);
+            Instr::End.encode(&mut data);
+            unsigned(&mut data, self.literals.len() as u32);
+            data.extend(&self.literals);
+            section(&mut out, 11, data);
+        }
+
+        // names of functions, for stack traces
+        let mut names = vec![];
+        unsigned(&mut names, (IMPORTS.len() + self.funcs.len()) as u32);
+        for (idx, (import, _)) in IMPORTS.iter().enumerate() {
+            unsigned(&mut names, idx as u32);
+            name(&mut names, &format!("env.{}", import));
+        }
+        for (idx, func) in self.funcs.iter().enumerate() {
+            unsigned(&mut names, (IMPORTS.len() + idx) as u32);
+            name(&mut names, &func.name);
+        }
+        let mut custom = vec![];
+        name(&mut custom, "name");
+        custom.push(1);
+        unsigned(&mut custom, names.len() as u32);
+        custom.extend(names);
+        section(&mut out, 0, custom);
+
+        out
+    }
+
+    fn global(&self, idx: u32) -> u32 {
+        match idx {
+            // the base tape, whose place in the table is left as 0s as it has no cells
+            STACK_LEN => 1,
+            HEAP => self.heap,
+            _ => 0,
+        }
+    }
+}
+
+const I32: u8 = 0x7f;
+
+fn signature(sig: Sig) -> String {
+    format!(
+        "{}{}",
+        " (param i32)".repeat(sig.params()),
+        " (result i32)".repeat(sig.results())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use wasmi::{Caller, Engine, Linker, Store};
+
+    use super::*;
+    use crate::intepreter::{testing, Runtime};
+
+    const INPUT: &str = "12\n";
+
+    // programs taking millions of steps, too slow to run unoptimised
+    const SLOW: [&str; 2] = ["countdown.migraine", "triangle.migraine"];
+
+    struct Host {
+        input: Vec<char>,
+        output: String,
+    }
+
+    // output of a compiled module run from main, along with its exit code (Err
+    // if it trapped)
+    fn run_wasm(funcs: &[Func], input: &str) -> (Result<i32, String>, String) {
+        let engine = Engine::default();
+        let binary = compile(funcs).unwrap().binary();
+        let module = wasmi::Module::new(&engine, &binary[..]).unwrap();
+        let host = Host {
+            input: input.chars().rev().collect(),
+            output: String::default(),
+        };
+        let mut store = Store::new(&engine, host);
+
+        let mut linker = <Linker<Host>>::new(&engine);
+        linker
+            .func_wrap("env", "write", |mut caller: Caller<Host>, c: i32| {
+                let c = char::from_u32(c as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+                caller.data_mut().output.push(c);
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "read", |mut caller: Caller<Host>| {
+                caller.data_mut().input.pop().map_or(-1, |c| c as i32)
+            })
+            .unwrap();
+        linker
+            .func_wrap("env", "exit", |_: Caller<Host>, code: i32| {
+                Err::<(), _>(wasmi::Error::i32_exit(code))
+            })
+            .unwrap();
+
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .unwrap();
+        let main = instance.get_typed_func::<(), ()>(&store, "main").unwrap();
+        let res = match main.call(&mut store, ()) {
+            Ok(()) => Ok(0),
+            Err(err) => err.i32_exit_status().ok_or_else(|| err.to_string()),
+        };
+        (res, store.into_data().output)
+    }
+
+    #[test]
+    fn test_programs_run_as_in_interpreter() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy();
+            if path.extension().is_none_or(|ext| ext != "migraine") || SLOW.contains(&&*name) {
+                continue;
+            }
+
+            let funcs = testing::parse(&fs::read_to_string(&path).unwrap());
+            let (expected, expected_out) = testing::run(&mut Runtime::new(funcs.clone()), INPUT);
+            let (res, out) = run_wasm(&funcs, INPUT);
+
+            assert_eq!(out, expected_out, "output of {}", path.display());
+            assert_eq!(res.ok(), expected.ok(), "exit code of {}", path.display());
+        }
+    }
+
+    #[test]
+    fn errors_trap() {
+        let funcs = testing::parse("@main { ^1 \"a\" . & & }");
+        let (res, out) = run_wasm(&funcs, "");
+        assert_eq!(out, "a");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_stack() {
+        // far deeper than wasmi allows calls to go
+        let funcs = testing::parse(testing::COUNTDOWN);
+        let (res, out) = run_wasm(&funcs, "");
+        assert_eq!(out, "0");
+        assert_eq!(res, Ok('0' as i32));
+    }
+
+    #[test]
+    fn halt_exits_with_cell() {
+        let funcs = testing::parse("@main { ^1 \"\\x\" ! \"y\" . }");
+        let (res, out) = run_wasm(&funcs, "");
+        assert_eq!(out, "");
+        assert_eq!(res, Ok('x' as i32));
+    }
+}
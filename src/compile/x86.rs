@@ -0,0 +1,557 @@
+// Instructions of x86_64 used by the native backends, along with their machine
+// code and how they are written in GNU (AT&T) assembly. Jumps and calls to
+// labels are always encoded with 32-bit offsets, where an assembler would
+// pick shorter ones when it can
+use std::fmt::Write;
+
+/// General purpose registers, numbered as they are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+const REG_NAMES: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "8", "9", "10", "11", "12", "13", "14", "15",
+];
+
+impl Reg {
+    // name in assembly, at the size given
+    fn name(self, size: Size) -> String {
+        let name = REG_NAMES[self as usize];
+        match (size, self.ext()) {
+            (Size::Qword, _) => format!("%r{}", name),
+            (Size::Dword, false) => format!("%e{}", name),
+            (Size::Dword, true) => format!("%r{}d", name),
+        }
+    }
+
+    // low 3 bits go in modrm, the 4th in the rex prefix
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn ext(self) -> bool {
+        self as u8 >= 8
+    }
+}
+
+/// Memory at base + index * scale + disp
+#[derive(Debug, Clone, Copy)]
+pub struct Mem {
+    pub base: Reg,
+    pub index: Option<(Reg, u8)>,
+    pub disp: i32,
+}
+
+impl Mem {
+    pub fn base(base: Reg, disp: i32) -> Self {
+        Self {
+            base,
+            index: None,
+            disp,
+        }
+    }
+
+    pub fn indexed(base: Reg, index: Reg, scale: u8) -> Self {
+        Self {
+            base,
+            index: Some((index, scale)),
+            disp: 0,
+        }
+    }
+}
+
+impl Mem {
+    fn text(&self) -> String {
+        let disp = if self.disp == 0 {
+            String::default()
+        } else {
+            self.disp.to_string()
+        };
+        match self.index {
+            Some((index, scale)) => format!(
+                "{}({},{},{})",
+                disp,
+                self.base.name(Size::Qword),
+                index.name(Size::Qword),
+                scale
+            ),
+            None => format!("{}({})", disp, self.base.name(Size::Qword)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    Reg(Reg),
+    Mem(Mem),
+    Imm(i64),
+}
+
+impl Operand {
+    fn text(&self, size: Size) -> String {
+        match self {
+            Operand::Reg(reg) => reg.name(size),
+            Operand::Mem(mem) => mem.text(),
+            Operand::Imm(val) => format!("${}", val),
+        }
+    }
+}
+
+/// Width of an operation, 32 bit operations clear the top half of registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Dword,
+    Qword,
+}
+
+impl Size {
+    fn suffix(self) -> char {
+        match self {
+            Size::Dword => 'l',
+            Size::Qword => 'q',
+        }
+    }
+}
+
+/// Arithmetic sharing an encoding, numbered as they are encoded
+#[derive(Debug, Clone, Copy)]
+pub enum Alu {
+    Add = 0,
+    Or = 1,
+    And = 4,
+    Sub = 5,
+    Xor = 6,
+    Cmp = 7,
+}
+
+impl Alu {
+    fn name(self) -> &'static str {
+        match self {
+            Alu::Add => "add",
+            Alu::Or => "or",
+            Alu::And => "and",
+            Alu::Sub => "sub",
+            Alu::Xor => "xor",
+            Alu::Cmp => "cmp",
+        }
+    }
+}
+
+/// Conditions of jumps, numbered as they are encoded
+#[derive(Debug, Clone, Copy)]
+pub enum Cond {
+    /// Below (unsigned)
+    B = 2,
+    /// Above or equal (unsigned)
+    Ae = 3,
+    E = 4,
+    Ne = 5,
+    /// Below or equal (unsigned)
+    Be = 6,
+    /// Above (unsigned)
+    A = 7,
+}
+
+impl Cond {
+    fn name(self) -> &'static str {
+        match self {
+            Cond::B => "b",
+            Cond::Ae => "ae",
+            Cond::E => "e",
+            Cond::Ne => "ne",
+            Cond::Be => "be",
+            Cond::A => "a",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Clone)]
+pub enum Inst {
+    Label(Label),
+    Mov(Size, Operand, Operand),
+    Lea(Reg, Mem),
+    Alu(Alu, Size, Operand, Operand),
+    Test(Size, Reg, Reg),
+    Not(Size, Operand),
+    Shl(Size, Reg, u8),
+    Shr(Size, Reg, u8),
+    /// Stores the low byte of rax, rcx, rdx or rbx
+    MovByte(Mem, Reg),
+    /// Loads a byte, zero extended
+    MovzxByte(Reg, Mem),
+    /// Address of a label, relative to the instruction
+    LeaLabel(Reg, Label),
+    Imul(Size, Reg, Operand),
+    /// Unsigned division of rdx:rax, leaving the quotient in rax
+    Div(Size, Operand),
+    Push(Reg),
+    Pop(Reg),
+    Call(Label),
+    CallReg(Reg),
+    Jmp(Label),
+    Jcc(Cond, Label),
+    Ret,
+    Syscall,
+    /// Fills rcx dwords from rdi with eax
+    RepStosd,
+    /// Line of text in assembly, which is left out of machine code
+    Comment(String),
+    /// Raw bytes, written as a string in assembly
+    Data(Vec<u8>),
+}
+
+/// Instructions being assembled, with labels they can jump to
+#[derive(Default)]
+pub struct Asm {
+    pub insts: Vec<Inst>,
+    /// Name of each label in assembly, local labels are numbered instead
+    labels: Vec<Option<String>>,
+}
+
+impl Asm {
+    /// New label, which is placed with bind
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// New label with a name in assembly, which should be unique
+    pub fn named_label(&mut self, name: &str) -> Label {
+        self.labels.push(Some(name.to_string()));
+        Label(self.labels.len() - 1)
+    }
+
+    fn label_name(&self, label: Label) -> String {
+        match &self.labels[label.0] {
+            Some(name) => name.clone(),
+            None => format!(".L{}", label.0),
+        }
+    }
+
+    /// Places label before the next instruction
+    pub fn bind(&mut self, label: Label) {
+        self.insts.push(Inst::Label(label));
+    }
+
+    pub fn push(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    /// Machine code of the instructions, to be run from any address
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        let mut labels = vec![0; self.labels.len()];
+        // offsets to fill in once every label is placed
+        let mut fixups = vec![];
+
+        for inst in &self.insts {
+            match inst {
+                Inst::Label(label) => labels[label.0] = out.len(),
+                Inst::Call(label) | Inst::Jmp(label) | Inst::Jcc(_, label) => {
+                    match inst {
+                        Inst::Call(_) => out.push(0xE8),
+                        Inst::Jmp(_) => out.push(0xE9),
+                        Inst::Jcc(cond, _) => out.extend([0x0F, 0x80 + *cond as u8]),
+                        _ => unreachable!(),
+                    }
+                    fixups.push((out.len(), *label));
+                    out.extend([0; 4]);
+                }
+                Inst::LeaLabel(dest, label) => {
+                    // rip relative, the offset being the end of the instruction
+                    out.extend([0x48 | (dest.ext() as u8) << 2, 0x8D, dest.low() << 3 | 5]);
+                    fixups.push((out.len(), *label));
+                    out.extend([0; 4]);
+                }
+                Inst::Comment(_) => {}
+                Inst::Data(bytes) => out.extend(bytes),
+                _ => encode(inst, &mut out),
+            }
+        }
+
+        for (at, label) in fixups {
+            let rel = labels[label.0] as i64 - (at + 4) as i64;
+            out[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        out
+    }
+
+    /// The instructions in GNU assembly, one to a line
+    pub fn gnu(&self) -> String {
+        let mut out = String::default();
+        for inst in &self.insts {
+            match inst {
+                Inst::Label(label) => {
+                    writeln!(out, "{}:", self.label_name(*label)).unwrap_or_default()
+                }
+                Inst::Comment(text) if text.is_empty() => out.push('\n'),
+                Inst::Comment(text) => writeln!(out, "\t# {}", text).unwrap_or_default(),
+                _ => {
+                    let (name, operands) = self.text(inst);
+                    if operands.is_empty() {
+                        writeln!(out, "\t{}", name).unwrap_or_default();
+                    } else {
+                        writeln!(out, "\t{:<8}{}", name, operands).unwrap_or_default();
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    // mnemonic and operands of an instruction, which are written source first
+    fn text(&self, inst: &Inst) -> (String, String) {
+        use Operand::*;
+
+        let op = |name: &str, size: Size| format!("{}{}", name, size.suffix());
+        let two = |src: String, dest: String| format!("{}, {}", src, dest);
+
+        match inst {
+            Inst::Mov(size, Reg(dest), Imm(val)) => {
+                // same choice of encoding as encode
+                if *size == Size::Dword || (0..=u32::MAX as i64).contains(val) {
+                    let val = *val as u32;
+                    (
+                        op("mov", Size::Dword),
+                        two(format!("${}", val), dest.name(Size::Dword)),
+                    )
+                } else if fits_i32(*val) {
+                    (
+                        op("mov", Size::Qword),
+                        two(format!("${}", val), dest.name(Size::Qword)),
+                    )
+                } else {
+                    (
+                        "movabsq".to_string(),
+                        two(format!("${}", val), dest.name(Size::Qword)),
+                    )
+                }
+            }
+            Inst::Mov(size, dest, Imm(val)) => {
+                let val = match size {
+                    Size::Dword => (*val as u32) as i64,
+                    Size::Qword => (*val as i32) as i64,
+                };
+                (op("mov", *size), two(format!("${}", val), dest.text(*size)))
+            }
+            Inst::Mov(size, dest, src) => {
+                (op("mov", *size), two(src.text(*size), dest.text(*size)))
+            }
+            Inst::Lea(dest, mem) => ("leaq".to_string(), two(mem.text(), dest.name(Size::Qword))),
+            Inst::LeaLabel(dest, label) => (
+                "leaq".to_string(),
+                two(
+                    format!("{}(%rip)", self.label_name(*label)),
+                    dest.name(Size::Qword),
+                ),
+            ),
+            Inst::Alu(alu, size, dest, src) => (
+                op(alu.name(), *size),
+                two(src.text(*size), dest.text(*size)),
+            ),
+            Inst::Test(size, a, b) => (op("test", *size), two(b.name(*size), a.name(*size))),
+            Inst::Not(size, dest) => (op("not", *size), dest.text(*size)),
+            Inst::Shl(size, dest, by) => {
+                (op("shl", *size), two(format!("${}", by), dest.name(*size)))
+            }
+            Inst::Shr(size, dest, by) => {
+                (op("shr", *size), two(format!("${}", by), dest.name(*size)))
+            }
+            Inst::MovByte(mem, src) => {
+                let src = format!("%{}l", &REG_NAMES[*src as usize][..1]);
+                ("movb".to_string(), two(src, mem.text()))
+            }
+            Inst::MovzxByte(dest, mem) => (
+                "movzbl".to_string(),
+                two(mem.text(), dest.name(Size::Dword)),
+            ),
+            Inst::Imul(size, dest, src) => {
+                (op("imul", *size), two(src.text(*size), dest.name(*size)))
+            }
+            Inst::Div(size, src) => (op("div", *size), src.text(*size)),
+            Inst::Push(reg) => ("pushq".to_string(), reg.name(Size::Qword)),
+            Inst::Pop(reg) => ("popq".to_string(), reg.name(Size::Qword)),
+            Inst::Call(label) => ("call".to_string(), self.label_name(*label)),
+            Inst::CallReg(reg) => ("call".to_string(), format!("*{}", reg.name(Size::Qword))),
+            Inst::Jmp(label) => ("jmp".to_string(), self.label_name(*label)),
+            Inst::Jcc(cond, label) => (format!("j{}", cond.name()), self.label_name(*label)),
+            Inst::Ret => ("ret".to_string(), String::default()),
+            Inst::Syscall => ("syscall".to_string(), String::default()),
+            Inst::RepStosd => ("rep stosl".to_string(), String::default()),
+            Inst::Data(bytes) => (".ascii".to_string(), quote(bytes)),
+            Inst::Label(_) | Inst::Comment(_) => unreachable!(),
+        }
+    }
+}
+
+// bytes as a string in assembly, escaping anything which is not printable
+fn quote(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(*byte as char);
+            }
+            0x20..=0x7E => out.push(*byte as char),
+            _ => write!(out, "\\{:03o}", byte).unwrap_or_default(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn fits_i8(val: i64) -> bool {
+    val == val as i8 as i64
+}
+
+fn fits_i32(val: i64) -> bool {
+    val == val as i32 as i64
+}
+
+fn encode(inst: &Inst, out: &mut Vec<u8>) {
+    use Operand::*;
+
+    match inst {
+        Inst::Mov(size, Reg(dest), Reg(src)) => rm(out, *size, &[0x89], *src as u8, &Reg(*dest)),
+        Inst::Mov(size, Reg(dest), Mem(mem)) => rm(out, *size, &[0x8B], *dest as u8, &Mem(*mem)),
+        Inst::Mov(size, Mem(mem), Reg(src)) => rm(out, *size, &[0x89], *src as u8, &Mem(*mem)),
+        Inst::Mov(size, Reg(dest), Imm(val)) => {
+            let val = *val;
+            if *size == Size::Dword || (0..=u32::MAX as i64).contains(&val) {
+                // 32 bit moves clear the top half, so are used where they can be
+                if dest.ext() {
+                    out.push(0x41);
+                }
+                out.push(0xB8 + dest.low());
+                out.extend((val as u32).to_le_bytes());
+            } else if fits_i32(val) {
+                rm(out, Size::Qword, &[0xC7], 0, &Reg(*dest));
+                out.extend((val as i32).to_le_bytes());
+            } else {
+                out.push(0x48 | dest.ext() as u8);
+                out.push(0xB8 + dest.low());
+                out.extend(val.to_le_bytes());
+            }
+        }
+        Inst::Mov(size, dest, Imm(val)) => {
+            rm(out, *size, &[0xC7], 0, dest);
+            out.extend((*val as i32).to_le_bytes());
+        }
+        Inst::Lea(dest, mem) => rm(out, Size::Qword, &[0x8D], *dest as u8, &Mem(*mem)),
+        Inst::Alu(op, size, dest, Imm(val)) => {
+            if fits_i8(*val) {
+                rm(out, *size, &[0x83], *op as u8, dest);
+                out.push(*val as i8 as u8);
+            } else {
+                rm(out, *size, &[0x81], *op as u8, dest);
+                out.extend((*val as i32).to_le_bytes());
+            }
+        }
+        Inst::Alu(op, size, Reg(dest), Mem(mem)) => {
+            let opcode = ((*op as u8) << 3) | 3;
+            rm(out, *size, &[opcode], *dest as u8, &Mem(*mem));
+        }
+        Inst::Alu(op, size, dest, Reg(src)) => {
+            let opcode = ((*op as u8) << 3) | 1;
+            rm(out, *size, &[opcode], *src as u8, dest);
+        }
+        Inst::Test(size, a, b) => rm(out, *size, &[0x85], *b as u8, &Reg(*a)),
+        Inst::Not(size, dest) => rm(out, *size, &[0xF7], 2, dest),
+        Inst::Shl(size, dest, by) => {
+            rm(out, *size, &[0xC1], 4, &Reg(*dest));
+            out.push(*by);
+        }
+        Inst::Shr(size, dest, by) => {
+            rm(out, *size, &[0xC1], 5, &Reg(*dest));
+            out.push(*by);
+        }
+        Inst::MovByte(mem, src) => rm(out, Size::Dword, &[0x88], *src as u8, &Mem(*mem)),
+        Inst::MovzxByte(dest, mem) => rm(out, Size::Dword, &[0x0F, 0xB6], *dest as u8, &Mem(*mem)),
+        Inst::Imul(size, dest, src) => rm(out, *size, &[0x0F, 0xAF], *dest as u8, src),
+        Inst::Div(size, src) => rm(out, *size, &[0xF7], 6, src),
+        Inst::Push(reg) | Inst::Pop(reg) => {
+            if reg.ext() {
+                out.push(0x41);
+            }
+            let opcode = if let Inst::Push(_) = inst { 0x50 } else { 0x58 };
+            out.push(opcode + reg.low());
+        }
+        Inst::CallReg(reg) => rm(out, Size::Dword, &[0xFF], 2, &Reg(*reg)),
+        Inst::Ret => out.push(0xC3),
+        Inst::Syscall => out.extend([0x0F, 0x05]),
+        Inst::RepStosd => out.extend([0xF3, 0xAB]),
+        _ => panic!("cannot encode {:?}", inst),
+    }
+}
+
+// writes an instruction taking a register (or opcode extension) as field and
+// a register or memory operand, with the rex prefix they need
+fn rm(out: &mut Vec<u8>, size: Size, opcode: &[u8], field: u8, operand: &Operand) {
+    let r = field >= 8;
+    let field = field & 7;
+    let (x, b) = match operand {
+        Operand::Reg(base) => (false, base.ext()),
+        Operand::Mem(mem) => (mem.index.is_some_and(|(idx, _)| idx.ext()), mem.base.ext()),
+        Operand::Imm(_) => panic!("immediate cannot be addressed"),
+    };
+
+    let rex = 0x40 | ((size == Size::Qword) as u8) << 3 | (r as u8) << 2 | (x as u8) << 1 | b as u8;
+    if rex != 0x40 {
+        out.push(rex);
+    }
+    out.extend(opcode);
+
+    let mem = match operand {
+        Operand::Reg(base) => {
+            out.push(0xC0 | field << 3 | base.low());
+            return;
+        }
+        Operand::Mem(mem) => mem,
+        Operand::Imm(_) => unreachable!(),
+    };
+
+    // rbp and r13 have no form without a displacement
+    let (mode, disp) = match mem.disp {
+        0 if mem.base.low() != 5 => (0, 0),
+        disp if fits_i8(disp as i64) => (1, 1),
+        _ => (2, 4),
+    };
+
+    // rsp and r12 as a base, or any index, need a sib byte
+    if mem.index.is_some() || mem.base.low() == 4 {
+        out.push(mode << 6 | field << 3 | 4);
+        let (index, scale) = match mem.index {
+            Some((index, scale)) => (index.low(), scale.trailing_zeros() as u8),
+            None => (4, 0),
+        };
+        out.push(scale << 6 | index << 3 | mem.base.low());
+    } else {
+        out.push(mode << 6 | field << 3 | mem.base.low());
+    }
+
+    match disp {
+        1 => out.push(mem.disp as i8 as u8),
+        4 => out.extend(mem.disp.to_le_bytes()),
+        _ => {}
+    }
+}
@@ -0,0 +1,504 @@
+use std::fs;
+use std::io::{stdin, stdout, BufRead, Write};
+
+use migraine::intepreter::{err::RuntimeError, snapshot::Snapshot, trace::node_str, Runtime};
+use migraine::parse::{
+    analysis::ENTRY_FUNC,
+    lexer::Op,
+    parser::{Func, OpNode},
+};
+
+const HELP: &str = "\
+s, step             Runs the next node, stepping into function calls
+n, next             Runs the next node, stepping over function calls
+o, out              Runs until the current function returns
+c, continue         Runs until a breakpoint, watchpoint, # or the end of the program
+rs, back            Steps back to before the last node was run
+rc, reverse         Steps back until a breakpoint or watchpoint, or as far as possible
+b, break <name>     Stops when function @name is called
+b, break <line>     Stops at a line of the main file
+w, watch <tape> <cell> [value]
+                    Stops when a cell changes (or changes to value)
+d, delete <number>  Removes a breakpoint or watchpoint
+i, info             Lists breakpoints and watchpoints
+p, print            Shows every cell of every tape in the stack
+bt, where           Shows the functions being run
+save <file>         Saves the state of the program to a file
+load <file>         Goes back to a state saved with save (or --snapshot)
+h, help             Shows this message
+q, quit             Exits the debugger
+An empty line repeats the last command
+
+Stepping back undoes changes to the stack, but not output already written or
+input already read";
+
+// how many steps can be stepped back through
+const HISTORY: usize = 1_000_000;
+
+enum Breakpoint {
+    Func(String),
+    Line(usize),
+}
+
+struct Watchpoint {
+    tape: usize,
+    cell: usize,
+    value: Option<u32>,
+    last: Option<u32>,
+}
+
+pub struct Debugger {
+    runtime: Runtime,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    finished: bool,
+}
+
+impl Debugger {
+    pub fn new(funcs: Vec<Func>) -> Self {
+        let mut runtime = Runtime::new(funcs);
+        runtime.set_history(HISTORY);
+
+        Self {
+            runtime,
+            breakpoints: vec![],
+            watchpoints: vec![],
+            finished: false,
+        }
+    }
+
+    /// Reads commands until the user quits
+    pub fn run(&mut self) {
+        self.run_from(&mut stdin().lock());
+    }
+
+    // reads commands from input until it ends or the user quits
+    fn run_from(&mut self, input: &mut dyn BufRead) {
+        if let Err(err) = self.runtime.enter_func(ENTRY_FUNC) {
+            return eprintln!("{}", err);
+        }
+        println!("Debugging (h for help)");
+        self.show_location();
+
+        let mut last = String::default();
+        loop {
+            print!("(debug) ");
+            stdout().flush().unwrap_or_default();
+
+            let mut line = String::default();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+
+            // empty line repeats the last command
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+
+            if !self.command(&line) {
+                return;
+            }
+            last = line;
+        }
+    }
+
+    // returns false when the debugger should exit
+    fn command(&mut self, line: &str) -> bool {
+        let mut args = line.split_whitespace();
+        let cmd = args.next().unwrap_or_default();
+        let args: Vec<&str> = args.collect();
+
+        match cmd {
+            "s" | "step" => self.run_while(|_| false),
+            "n" | "next" => {
+                let depth = self.runtime.frames().len();
+                self.run_while(|runtime| runtime.frames().len() > depth);
+            }
+            "o" | "out" => {
+                let depth = self.runtime.frames().len();
+                self.run_while(|runtime| runtime.frames().len() >= depth);
+            }
+            "c" | "continue" => self.run_while(|_| true),
+            "rs" | "back" => self.back_while(|_| false),
+            "rc" | "reverse" => self.back_while(|_| true),
+            "b" | "break" => match args.as_slice() {
+                [target] => {
+                    let target = target.trim_start_matches('@');
+                    self.breakpoints.push(match target.parse() {
+                        Ok(line) => Breakpoint::Line(line),
+                        Err(_) => Breakpoint::Func(target.to_string()),
+                    });
+                    self.show_points();
+                }
+                _ => eprintln!("ERROR: break needs a function name or line"),
+            },
+            "w" | "watch" => match Self::parse_nums(&args).as_slice() {
+                [tape, cell, value @ ..] if value.len() <= 1 => {
+                    self.watchpoints.push(Watchpoint {
+                        tape: *tape as usize,
+                        cell: *cell as usize,
+                        value: value.first().copied(),
+                        last: self.runtime.cell(*tape as usize, *cell as usize),
+                    });
+                    self.show_points();
+                }
+                _ => eprintln!("ERROR: watch needs a tape, a cell and an optional value"),
+            },
+            "d" | "delete" => match Self::parse_nums(&args).as_slice() {
+                [num] => {
+                    let num = *num as usize;
+                    if num < self.breakpoints.len() {
+                        self.breakpoints.remove(num);
+                    } else if num - self.breakpoints.len() < self.watchpoints.len() {
+                        self.watchpoints.remove(num - self.breakpoints.len());
+                    } else {
+                        eprintln!("ERROR: No breakpoint or watchpoint {}", num);
+                    }
+                    self.show_points();
+                }
+                _ => eprintln!("ERROR: delete needs a number (see info)"),
+            },
+            "i" | "info" => self.show_points(),
+            "p" | "print" => print!("{}", self.runtime.dump_stack(None)),
+            "bt" | "where" => {
+                for (idx, frame) in self.runtime.frames().iter().enumerate() {
+                    let pos = frame.node().and_then(|node| node.pos());
+                    match pos {
+                        Some(pos) => println!("#{} @{} at {}", idx, frame.func.name, pos),
+                        None => println!("#{} @{}", idx, frame.func.name),
+                    }
+                }
+            }
+            "save" => match args.as_slice() {
+                [path] => match fs::write(path, self.runtime.snapshot().to_string()) {
+                    Ok(_) => println!("Saved to '{}'", path),
+                    Err(err) => eprintln!("ERROR: Cannot write '{}': {}", path, err),
+                },
+                _ => eprintln!("ERROR: save needs a file"),
+            },
+            "load" => match args.as_slice() {
+                [path] => self.load(path),
+                _ => eprintln!("ERROR: load needs a file"),
+            },
+            "h" | "help" => println!("{}", HELP),
+            "q" | "quit" => return false,
+            _ => eprintln!("ERROR: Unknown command '{}' (h for help)", cmd),
+        }
+        true
+    }
+
+    fn load(&mut self, path: &str) {
+        let src = match fs::read_to_string(path) {
+            Ok(src) => src,
+            Err(err) => return eprintln!("ERROR: Cannot read '{}': {}", path, err),
+        };
+        let res = Snapshot::parse(&src).and_then(|snapshot| self.runtime.restore(&snapshot));
+        if let Err(err) = res {
+            return eprintln!("{}", err);
+        }
+
+        // watchpoints would otherwise stop straight away on the loaded values
+        for watch in self.watchpoints.iter_mut() {
+            watch.last = self.runtime.cell(watch.tape, watch.cell);
+        }
+        self.finished = self.runtime.frames().is_empty();
+        self.show_location();
+    }
+
+    fn parse_nums(args: &[&str]) -> Vec<u32> {
+        args.iter().filter_map(|arg| arg.parse().ok()).collect()
+    }
+
+    // steps at least once, then keeps going while keep_going returns true and
+    // no breakpoint or watchpoint was hit
+    fn run_while<T>(&mut self, keep_going: T)
+    where
+        T: Fn(&Runtime) -> bool,
+    {
+        if self.finished {
+            return println!("Program is not running");
+        }
+
+        loop {
+            let depth = self.runtime.frames().len();
+            let line = self.curr_line();
+
+            match self.runtime.step() {
+                Ok(_) if self.runtime.frames().is_empty() => {
+                    self.finished = true;
+                    return println!("\nProgram finished with code 0");
+                }
+                Ok(_) => {}
+                Err(RuntimeError::Exit(code)) => {
+                    self.finished = true;
+                    return println!("\nProgram finished with code {}", code);
+                }
+                Err(err) => {
+                    self.finished = true;
+                    eprintln!("{}", err);
+                    return self.show_location();
+                }
+            }
+
+            if self.hit(depth, line) || !keep_going(&self.runtime) {
+                return self.show_location();
+            }
+
+            // '#' in source stops like a breakpoint
+            if let Some(OpNode::Operation(Op::Debug, _)) = self.runtime.curr_node() {
+                println!("\nStopped at #");
+                return self.show_location();
+            }
+        }
+    }
+
+    // steps back at least once, then keeps going while keep_going returns true
+    // and no breakpoint or watchpoint was hit
+    fn back_while<T>(&mut self, keep_going: T)
+    where
+        T: Fn(&Runtime) -> bool,
+    {
+        loop {
+            let depth = self.runtime.frames().len();
+            let line = self.curr_line();
+
+            if !self.runtime.step_back() {
+                println!("\nAt the start of history");
+                return self.show_location();
+            }
+            self.finished = false;
+
+            if self.hit(depth, line) || !keep_going(&self.runtime) {
+                return self.show_location();
+            }
+        }
+    }
+
+    // checks every breakpoint and watchpoint after a step
+    fn hit(&mut self, prev_depth: usize, prev_line: Option<usize>) -> bool {
+        let mut hit = false;
+
+        for (idx, watch) in self.watchpoints.iter_mut().enumerate() {
+            let val = self.runtime.cell(watch.tape, watch.cell);
+            if val != watch.last && (watch.value.is_none() || watch.value == val) {
+                println!(
+                    "\nWatchpoint {}: tape {} cell {} changed from {:?} to {:?}",
+                    idx + self.breakpoints.len(),
+                    watch.tape,
+                    watch.cell,
+                    watch.last,
+                    val
+                );
+                hit = true;
+            }
+            watch.last = val;
+        }
+
+        let frame = match self.runtime.frames().last() {
+            Some(frame) => frame,
+            None => return hit,
+        };
+        let depth = self.runtime.frames().len();
+        let line = self.curr_line();
+
+        for (idx, point) in self.breakpoints.iter().enumerate() {
+            let hit_point = match point {
+                Breakpoint::Func(name) => depth > prev_depth && frame.func.name == *name,
+                // lines are only stopped at when first reached
+                Breakpoint::Line(at) => {
+                    line == Some(*at) && (prev_line != line || depth != prev_depth)
+                }
+            };
+            if hit_point {
+                println!("\nBreakpoint {}", idx);
+                hit = true;
+            }
+        }
+        hit
+    }
+
+    // line of the next node, if it is in the main file
+    fn curr_line(&self) -> Option<usize> {
+        let frame = self.runtime.frames().last()?;
+        if frame.func.module.is_some() {
+            return None;
+        }
+        Some(self.runtime.curr_node()?.pos()?.line)
+    }
+
+    fn show_points(&self) {
+        for (idx, point) in self.breakpoints.iter().enumerate() {
+            match point {
+                Breakpoint::Func(name) => println!("{}: break at @{}", idx, name),
+                Breakpoint::Line(line) => println!("{}: break at line {}", idx, line),
+            }
+        }
+        for (idx, watch) in self.watchpoints.iter().enumerate() {
+            let idx = idx + self.breakpoints.len();
+            match watch.value {
+                Some(val) => println!(
+                    "{}: watch tape {} cell {} for {}",
+                    idx, watch.tape, watch.cell, val
+                ),
+                None => println!("{}: watch tape {} cell {}", idx, watch.tape, watch.cell),
+            }
+        }
+    }
+
+    fn show_location(&self) {
+        let frame = match self.runtime.frames().last() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let node = match self.runtime.curr_node() {
+            Some(node) => node,
+            None => return,
+        };
+
+        let desc = node_str(node);
+        let pos = node.pos().map(|pos| pos.to_string()).unwrap_or_default();
+        let cell = match self.runtime.curr_val() {
+            Ok(val) => val.to_string(),
+            Err(_) => "-".to_string(),
+        };
+
+        println!("@{} {}: {}    (cell: {})", frame.func.name, pos, desc, cell);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{empty, sink};
+    use std::path::Path;
+
+    // @outer is called on lines 6 and 7, and calls @inner, whose body is on line 2
+    const SRC: &str = "@inner {
+  + +
+}
+@outer { @inner - }
+@main { ^1
+  @outer
+  @outer +
+  + }";
+
+    // as SRC, but with every call the last thing its caller does
+    const TAIL_SRC: &str = "@inner {
+  + +
+}
+@outer { - @inner }
+@main { ^1
+  @outer
+  @outer }";
+
+    // runs commands (one per line) in a debugger started at main
+    fn debug(commands: &str) -> Debugger {
+        debug_src(SRC, commands)
+    }
+
+    fn debug_src(src: &str, commands: &str) -> Debugger {
+        let funcs = migraine::parse::parse(src.to_string(), Path::new(".")).unwrap();
+        let mut debugger = Debugger::new(funcs);
+        debugger.runtime.set_io(Box::new(empty()), Box::new(sink()));
+        debugger.run_from(&mut commands.as_bytes());
+        debugger
+    }
+
+    // function and line the debugger has stopped at
+    fn at(debugger: &Debugger) -> (String, usize) {
+        let frame = debugger.runtime.frames().last().unwrap();
+        let pos = frame.node().and_then(|node| node.pos()).unwrap();
+        (frame.func.name.clone(), pos.line)
+    }
+
+    fn stack(debugger: &Debugger) -> Vec<&str> {
+        let frames = debugger.runtime.frames();
+        frames
+            .iter()
+            .map(|frame| frame.func.name.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn steps_into_over_and_out_of_calls() {
+        let debugger = debug("s\ns\n");
+        assert_eq!(at(&debugger), ("outer".to_string(), 4));
+        assert_eq!(stack(&debugger), ["main", "outer"]);
+
+        // next runs the whole of @outer
+        let debugger = debug("s\nn\n");
+        assert_eq!(at(&debugger), ("main".to_string(), 7));
+        assert_eq!(debugger.runtime.cell(1, 0), Some(1));
+
+        let debugger = debug("s\ns\ns\no\n");
+        assert_eq!(at(&debugger), ("outer".to_string(), 4));
+        assert_eq!(stack(&debugger), ["main", "outer"]);
+        assert_eq!(debugger.runtime.cell(1, 0), Some(2));
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let debugger = debug("b inner\nc\n");
+        assert_eq!(at(&debugger), ("inner".to_string(), 2));
+        assert_eq!(stack(&debugger), ["main", "outer", "inner"]);
+
+        // carries on to the second call
+        let debugger = debug("b @inner\nc\nc\n");
+        assert_eq!(at(&debugger), ("inner".to_string(), 2));
+        assert_eq!(debugger.runtime.cell(1, 0), Some(1));
+
+        let debugger = debug("b 8\nc\n");
+        assert_eq!(at(&debugger), ("main".to_string(), 8));
+        assert_eq!(debugger.runtime.cell(1, 0), Some(3));
+
+        // deleted breakpoints are run past, to the end of the program
+        let debugger = debug("b 8\nd 0\nc\n");
+        assert!(debugger.finished);
+    }
+
+    #[test]
+    fn calls_at_the_end_keep_their_caller() {
+        let debugger = debug_src(TAIL_SRC, "b inner\nc\n");
+        assert_eq!(at(&debugger), ("inner".to_string(), 2));
+        assert_eq!(stack(&debugger), ["main", "outer", "inner"]);
+
+        // next runs the whole of @inner, then @outer has nothing left to do
+        let debugger = debug_src(TAIL_SRC, "s\ns\ns\nn\n");
+        assert_eq!(at(&debugger), ("main".to_string(), 7));
+        assert_eq!(stack(&debugger), ["main"]);
+        assert_eq!(debugger.runtime.cell(1, 0), Some(1));
+    }
+
+    #[test]
+    fn stops_at_watchpoints() {
+        // stops after the + which set it, the last of @inner
+        let debugger = debug("s\nw 1 0 2\nc\n");
+        assert_eq!(at(&debugger), ("outer".to_string(), 4));
+        assert_eq!(debugger.runtime.cell(1, 0), Some(2));
+    }
+
+    #[test]
+    fn steps_back() {
+        let debugger = debug("b 8\nc\nrs\n");
+        assert_eq!(at(&debugger), ("main".to_string(), 7));
+        assert_eq!(debugger.runtime.cell(1, 0), Some(2));
+
+        // back into the function which had just returned
+        let debugger = debug("b 8\nc\nrs\nrs\n");
+        assert_eq!(at(&debugger), ("outer".to_string(), 4));
+        assert_eq!(stack(&debugger), ["main", "outer"]);
+        assert_eq!(debugger.runtime.cell(1, 0), Some(3));
+
+        // reverse runs back into the second call to @inner, before its last +,
+        // then back to the start
+        let debugger = debug("c\nb inner\nrc\n");
+        assert_eq!(at(&debugger), ("inner".to_string(), 2));
+        assert_eq!(debugger.runtime.cell(1, 0), Some(2));
+        assert!(!debugger.finished);
+
+        let debugger = debug("n\nn\nrc\n");
+        assert_eq!(at(&debugger), ("main".to_string(), 5));
+        assert_eq!(debugger.runtime.stack_len(), 1);
+    }
+}
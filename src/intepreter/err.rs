@@ -1,4 +1,5 @@
 use std::fmt::{self, Display};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub enum RuntimeError {
@@ -10,6 +11,25 @@ pub enum RuntimeError {
     // Stack Errors
     StackIndexCannotBeNegative,
     StackIndexOutOfBounds(usize, usize),
+
+    // '/' with a 0 cell
+    DivisionByZero,
+
+    // Limits Exceeded
+    StepLimitExceeded(u64),
+    MemoryLimitExceeded(usize, usize),
+    StackDepthExceeded(usize),
+    CallDepthExceeded(usize),
+    Timeout(Duration),
+
+    // Tape too big to allocate, with no limit on memory set
+    OutOfMemory(usize),
+
+    // Snapshot could not be read, or does not fit the program
+    InvalidSnapshot(String),
+
+    // Program asked to stop with an exit code
+    Exit(i32),
     Generic,
 }
 
@@ -17,7 +37,30 @@ pub enum RuntimeError {
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str("\nERROR: ")?;
+        self.describe(f)
+    }
+}
+
+impl RuntimeError {
+    /// Message without the 'ERROR: ' prefix (eg. for traces)
+    pub fn message(&self) -> String {
+        struct Message<'a>(&'a RuntimeError);
+        impl Display for Message<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.describe(f)
+            }
+        }
+        Message(self).to_string()
+    }
+
+    fn describe(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            RuntimeError::FunctionNotDefined(name) => {
+                f.write_fmt(format_args!("Function '@{}' is not defined", name))
+            }
+            RuntimeError::Exit(code) => {
+                f.write_fmt(format_args!("Program exited with code {}", code))
+            }
             RuntimeError::TapeIndexOutOfBounds(size, len) => f.write_fmt(format_args!(
                 "Tape index {} out of bounds of tape size {}",
                 size, len
@@ -26,6 +69,32 @@ impl Display for RuntimeError {
                 "Tape index {} out of bounds of stack size {}",
                 size, len
             )),
+            RuntimeError::DivisionByZero => f.write_str("Division by zero"),
+            RuntimeError::StepLimitExceeded(max) => {
+                f.write_fmt(format_args!("Program ran for more than {} steps", max))
+            }
+            RuntimeError::MemoryLimitExceeded(needed, max) => f.write_fmt(format_args!(
+                "Stack would use {} bytes, over the limit of {} bytes",
+                needed, max
+            )),
+            RuntimeError::StackDepthExceeded(max) => {
+                f.write_fmt(format_args!("Stack grew past {} tapes", max))
+            }
+            RuntimeError::CallDepthExceeded(max) => f.write_fmt(format_args!(
+                "Functions nested more than {} calls deep",
+                max
+            )),
+            RuntimeError::OutOfMemory(size) => f.write_fmt(format_args!(
+                "Not enough memory for a tape of {} cells",
+                size
+            )),
+            RuntimeError::InvalidSnapshot(reason) => {
+                f.write_fmt(format_args!("Invalid snapshot: {}", reason))
+            }
+            RuntimeError::Timeout(max) => f.write_fmt(format_args!(
+                "Program ran for longer than {:.3} seconds",
+                max.as_secs_f64()
+            )),
 
             _ => f.write_fmt(format_args!("{:?}", self)),
         }
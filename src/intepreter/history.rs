@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use super::{Frame, Runtime, Tape};
+use crate::parse::lexer::{Op, StackOp};
+
+/// Part of the state of the runtime as it was before a step changed it
+#[derive(Clone)]
+pub(super) enum Change {
+    /// Values of cells in a tape from an index
+    Cells(usize, usize, Vec<u32>),
+    /// Pointer of a tape
+    Pointer(usize, usize),
+    /// Index of the selected tape
+    Selected(usize),
+    /// Length of the stack, tapes past it were pushed
+    StackLen(usize),
+    /// Tape removed from the stack at an index
+    TapeRemoved(usize, Tape),
+    /// Whole stack and selected tape, for intrinsics which could change any of it
+    Stack(Vec<Tape>, usize),
+    /// Path of the innermost frame
+    Path(Vec<usize>),
+    FramePushed,
+    FramePopped(Frame),
+}
+
+/// Changes made by recent steps, the last being the most recent
+#[derive(Default)]
+pub(super) struct History {
+    pub max: usize,
+    pub steps: VecDeque<Vec<Change>>,
+    /// Changes made so far by the step being run
+    pub pending: Option<Vec<Change>>,
+}
+
+impl Runtime {
+    /// Remembers how to undo the last max steps, so they can be stepped back
+    /// through with step_back (0 stops remembering)
+    pub fn set_history(&mut self, max: usize) {
+        self.history.max = max;
+        while self.history.steps.len() > max {
+            self.history.steps.pop_front();
+        }
+    }
+
+    /// How many steps can be stepped back
+    pub fn history_len(&self) -> usize {
+        self.history.steps.len()
+    }
+
+    /// Undoes the last step, returns false if there is none to undo. Output
+    /// written and input read by the step are not undone
+    pub fn step_back(&mut self) -> bool {
+        let changes = match self.history.steps.pop_back() {
+            Some(changes) => changes,
+            None => return false,
+        };
+
+        for change in changes.into_iter().rev() {
+            match change {
+                Change::Cells(tape, idx, vals) => {
+                    self.stack[tape].vals[idx..idx + vals.len()].copy_from_slice(&vals);
+                }
+                Change::Pointer(tape, curr) => self.stack[tape].curr = curr,
+                Change::Selected(curr) => self.curr = curr,
+                Change::StackLen(len) => self.stack.truncate(len),
+                Change::TapeRemoved(idx, tape) => self.stack.insert(idx, tape),
+                Change::Stack(stack, curr) => {
+                    self.stack = stack;
+                    self.curr = curr;
+                }
+                Change::Path(path) => {
+                    if let Some(frame) = self.frames.last_mut() {
+                        frame.path = path;
+                    }
+                }
+                Change::FramePushed => {
+                    self.frames.pop();
+                }
+                Change::FramePopped(frame) => self.frames.push(frame),
+            }
+        }
+        self.steps = self.steps.saturating_sub(1);
+        true
+    }
+
+    // starts remembering the changes of a step
+    pub(super) fn begin_step(&mut self) {
+        if self.history.max > 0 {
+            self.history.pending = Some(vec![]);
+        }
+    }
+
+    // keeps the changes made by a step, even if it failed part of the way
+    pub(super) fn end_step(&mut self) {
+        if let Some(changes) = self.history.pending.take() {
+            if self.history.steps.len() == self.history.max {
+                self.history.steps.pop_front();
+            }
+            self.history.steps.push_back(changes);
+        }
+    }
+
+    pub(super) fn record(&mut self, change: Change) {
+        if let Some(changes) = self.history.pending.as_mut() {
+            changes.push(change);
+        }
+    }
+
+    pub(super) fn recording(&self) -> bool {
+        self.history.pending.is_some()
+    }
+
+    // number of changes made so far by the step being run
+    pub(super) fn recorded(&self) -> usize {
+        self.history.pending.as_ref().map_or(0, Vec::len)
+    }
+
+    // forgets changes from the first len onwards, for operations which failed
+    // before changing anything
+    pub(super) fn forget(&mut self, len: usize) {
+        if let Some(changes) = self.history.pending.as_mut() {
+            changes.truncate(len);
+        }
+    }
+
+    // records what op is about to change
+    pub(super) fn record_op(&mut self, op: &Op) {
+        let sel = self.curr;
+        let cell = |runtime: &Self, tape: usize| {
+            let tape_ref = runtime.stack.get(tape)?;
+            let val = *tape_ref.vals.get(tape_ref.curr)?;
+            Some(Change::Cells(tape, tape_ref.curr, vec![val]))
+        };
+
+        let mut changes = vec![];
+        match op {
+            Op::Inc | Op::Dec | Op::BitNot | Op::Take => changes.extend(cell(self, sel)),
+            Op::Literal(lit) => {
+                let tape = &self.stack[sel];
+                let start = tape.curr.min(tape.len);
+                let end = (tape.curr + lit.chars().count()).min(tape.len);
+                changes.push(Change::Cells(sel, start, tape.vals[start..end].to_vec()));
+            }
+            Op::PtrRight | Op::PtrLeft => changes.push(Change::Pointer(sel, self.stack[sel].curr)),
+            Op::Dump | Op::Halt | Op::Debug => {}
+
+            Op::PushNew(_) => {
+                changes.push(Change::Selected(sel));
+                changes.push(Change::StackLen(self.stack.len()));
+            }
+            Op::PushOp(op) => {
+                changes.push(Change::Selected(sel));
+                if !matches!(op, StackOp::Default) {
+                    changes.extend(cell(self, sel + 1));
+                }
+            }
+            Op::PopOp(op) => {
+                changes.push(Change::Selected(sel));
+                if !matches!(op, StackOp::Default) && sel > 0 {
+                    changes.extend(cell(self, sel - 1));
+                }
+            }
+            Op::HardPopOp(op) => {
+                changes.push(Change::Selected(sel));
+                changes.push(Change::TapeRemoved(sel, self.stack[sel].clone()));
+                if !matches!(op, StackOp::Default) && sel > 0 {
+                    changes.extend(cell(self, sel - 1));
+                }
+            }
+        }
+
+        for change in changes {
+            self.record(change);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::testing::parse;
+
+    // runs main one step at a time until it fails, then steps all the way back
+    fn run_and_undo(runtime: &mut Runtime) {
+        let start = runtime.dump_stack(None);
+        runtime.set_history(100);
+        runtime.enter_func("main").unwrap();
+        while let Ok(true) = runtime.step() {}
+        while runtime.step_back() {}
+        assert_eq!(runtime.dump_stack(None), start);
+    }
+
+    #[test]
+    fn failed_hard_pop_is_undone() {
+        for src in [
+            "@main { &/ }",
+            "@main { & }",
+            "@main { ^ }",
+            "@main { ^1 + ^1 &/ }",
+        ] {
+            let mut runtime = Runtime::new(parse(src));
+            run_and_undo(&mut runtime);
+            assert_eq!(runtime.stack_len(), 1);
+        }
+    }
+
+    #[test]
+    fn intrinsic_changing_stack_is_undone() {
+        let mut runtime = Runtime::new(parse("@main { ^2 + @!grow }"));
+        runtime.register_intrinsic("grow", |runtime| {
+            runtime.stack.push(Tape::new(3, runtime.cell_mask)?);
+            runtime.curr = runtime.stack.len() - 1;
+            Ok(())
+        });
+        run_and_undo(&mut runtime);
+        assert_eq!(runtime.selected(), (0, 0));
+    }
+}
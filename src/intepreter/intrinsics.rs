@@ -0,0 +1,158 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::err::RuntimeError;
+use super::Runtime;
+
+/// Rust function callable from Migraine as `@!<name>`
+pub type Intrinsic = Rc<dyn Fn(&mut Runtime) -> Result<(), RuntimeError>>;
+
+/// Intrinsics every runtime starts with
+pub fn add_default_intrinsics(runtime: &mut Runtime) {
+    runtime.register_intrinsic("print_num", print_num);
+    runtime.register_intrinsic("read_num", read_num);
+    runtime.register_intrinsic("read_line", read_line);
+    runtime.register_intrinsic("time", time);
+    runtime.register_intrinsic("random", random);
+    runtime.register_intrinsic("exit", exit);
+}
+
+// prints the current cell as a decimal number
+fn print_num(runtime: &mut Runtime) -> Result<(), RuntimeError> {
+    let val = runtime.curr_val()?;
+    write!(runtime.output, "{}", val).unwrap_or_default();
+    runtime.output.flush().unwrap_or_default();
+    Ok(())
+}
+
+// reads up to and including the next newline, byte by byte so nothing after it
+// is taken from the input
+fn line(input: &mut dyn Read) -> String {
+    let mut bytes = vec![];
+    let mut byte = [0u8];
+    while let Ok(1) = input.read(&mut byte) {
+        bytes.push(byte[0]);
+        if byte[0] == b'\n' {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+// reads a line of input as a decimal number into the current cell (0 if invalid)
+fn read_num(runtime: &mut Runtime) -> Result<(), RuntimeError> {
+    let line = line(&mut *runtime.input);
+    runtime.set_curr_val(line.trim().parse().unwrap_or(0))
+}
+
+// reads a line of input into the cells from the current one, followed by a 0 cell
+fn read_line(runtime: &mut Runtime) -> Result<(), RuntimeError> {
+    let line = line(&mut *runtime.input);
+    let line = line.trim_end_matches(&['\r', '\n'][..]);
+    runtime.write_literal(&format!("{}\0", line));
+    Ok(())
+}
+
+// stores seconds since the unix epoch into the current cell
+fn time(runtime: &mut Runtime) -> Result<(), RuntimeError> {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    runtime.set_curr_val(secs as u32)
+}
+
+thread_local! {
+    // only the first RandomState of a thread is seeded randomly (later ones
+    // count up from it), so one seeds a generator which is stepped instead
+    static RANDOM: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish());
+}
+
+// stores a random number into the current cell, from a SplitMix64 generator
+fn random(runtime: &mut Runtime) -> Result<(), RuntimeError> {
+    let val = RANDOM.with(|state| {
+        let next = state.get().wrapping_add(0x9e3779b97f4a7c15);
+        state.set(next);
+        let val = (next ^ (next >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        let val = (val ^ (val >> 27)).wrapping_mul(0x94d049bb133111eb);
+        val ^ (val >> 31)
+    });
+    runtime.set_curr_val(val as u32)
+}
+
+// stops the program, with the current cell as the exit code
+fn exit(runtime: &mut Runtime) -> Result<(), RuntimeError> {
+    Err(RuntimeError::Exit(runtime.curr_val()? as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::err::RuntimeError;
+    use super::super::testing::{self, run_src};
+    use super::super::Runtime;
+
+    #[test]
+    fn numbers_are_read_and_printed() {
+        let (res, out) = run_src("@main { ^1 @!read_num + @!print_num }", "41\nrest");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(out, "42");
+
+        // anything which is not a number reads as 0
+        let (_, out) = run_src("@main { ^1 + @!read_num @!print_num }", "four\n");
+        assert_eq!(out, "0");
+    }
+
+    #[test]
+    fn lines_are_read_up_to_their_newline() {
+        let src = r#"@main { ^16 @!read_line [.>] "|" . @!read_num @!print_num }"#;
+        let (res, out) = run_src(src, "hi there\n7\n");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(out, "hi there|7");
+    }
+
+    #[test]
+    fn random_numbers_differ() {
+        let mut runtime = Runtime::new(testing::parse(
+            "@main { ^4 @!random > @!random > @!random > @!random }",
+        ));
+        testing::run(&mut runtime, "").0.unwrap();
+        let mut vals: Vec<_> = (0..4).map(|idx| runtime.cell(1, idx).unwrap()).collect();
+        vals.sort();
+        vals.dedup();
+        assert_eq!(vals.len(), 4, "{:?}", vals);
+    }
+
+    #[test]
+    fn exit_stops_with_the_cell() {
+        let (res, out) = run_src(r#"@main { ^1 "*" @!exit . }"#, "");
+        assert_eq!(res.unwrap(), '*' as i32);
+        assert_eq!(out, "");
+    }
+
+    #[test]
+    fn registered_intrinsics_can_be_called() {
+        let mut runtime = Runtime::new(testing::parse("@main { ^1 \"!\" @!double . }"));
+        runtime.register_intrinsic("double", |runtime| {
+            let val = runtime.curr_val()?;
+            runtime.set_curr_val(val * 2)
+        });
+        let (res, out) = testing::run(&mut runtime, "");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(out, "B");
+    }
+
+    #[test]
+    fn unregistered_intrinsics_fail_when_called() {
+        // analysis leaves them be, as they are only known at runtime
+        let funcs = testing::parse("@main { ^1 . @!missing }");
+        let (res, _) = testing::run(&mut Runtime::new(funcs), "");
+        assert!(
+            matches!(&res, Err(RuntimeError::FunctionNotDefined(name)) if name == "!missing"),
+            "{:?}",
+            res
+        );
+    }
+}
@@ -0,0 +1,687 @@
+// Compiles hot loops and functions to x86_64 machine code, which runs on the
+// runtime's own tapes. Operations on the current cell are compiled inline,
+// anything else (io, stack operations, '!' and '#') calls back into the
+// runtime. Loops calling intrinsics or recursive functions are left to the
+// interpreter, as is everything on other platforms
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::err::RuntimeError;
+use super::Runtime;
+use crate::compile::x86::{Alu, Asm, Cond, Inst, Label, Mem, Operand, Reg, Size};
+use crate::parse::{
+    lexer::Op,
+    parser::{Func, OpNode},
+};
+
+/// Times a loop is checked, or a function called, before it is compiled
+pub const JIT_THRESHOLD: u64 = 64;
+
+// registers kept by compiled code: the context, then the current tape's
+// cells, pointer and length
+const CTX: Reg = Reg::Rbx;
+const VALS: Reg = Reg::R12;
+const PTR: Reg = Reg::R13;
+const LEN: Reg = Reg::R14;
+
+// what compiled code returns, anything else is an error in the context
+const OK: i64 = 0;
+const OUT_OF_BOUNDS: i64 = 2;
+const NEGATIVE: i64 = 3;
+
+// current tape as compiled code sees it, copied in and out of the runtime
+// around every call back into it
+#[repr(C)]
+struct Ctx {
+    vals: *mut u32,
+    len: usize,
+    curr: usize,
+    runtime: *mut Runtime,
+    err: Option<RuntimeError>,
+}
+
+const VALS_OFFSET: i32 = 0;
+const LEN_OFFSET: i32 = 8;
+const CURR_OFFSET: i32 = 16;
+
+impl Ctx {
+    fn load(&mut self, runtime: &mut Runtime) {
+        let tape = &mut runtime.stack[runtime.curr];
+        self.vals = tape.vals.as_mut_ptr();
+        self.len = tape.len;
+        self.curr = tape.curr;
+    }
+}
+
+// called by compiled code to run an operation it does not compile itself
+extern "sysv64" fn run_op(ctx: *mut Ctx, op: *const Op) -> u32 {
+    // SAFETY: compiled code only calls this with its context, which points to
+    // the runtime running it, and ops kept alive by its code
+    let (ctx, op) = unsafe { (&mut *ctx, &*op) };
+    let runtime = unsafe { &mut *ctx.runtime };
+
+    let curr = runtime.curr;
+    runtime.stack[curr].curr = ctx.curr;
+    let res = runtime.op(op);
+    ctx.load(runtime);
+
+    match res {
+        Ok(_) => OK as u32,
+        Err(err) => {
+            ctx.err = Some(err);
+            1
+        }
+    }
+}
+
+/// Loops and functions compiled so far, or how often they have been run
+#[derive(Default)]
+pub(super) struct Jit {
+    threshold: Option<u64>,
+    units: HashMap<*const OpNode, Unit>,
+}
+
+enum Unit {
+    Cold(u64),
+    Compiled(Rc<Code>),
+    Unsupported,
+}
+
+impl Jit {
+    // forgets everything compiled, for when what it was compiled from changes
+    pub(super) fn clear(&mut self) {
+        self.units.clear();
+    }
+}
+
+impl Runtime {
+    /// Compiles loops and functions to machine code once they have been run
+    /// threshold times (0 compiles them the first time), None only interprets.
+    /// Steps taken by compiled code are not counted, so it is not used while
+    /// there is a limit on steps, time or calls, or while history is kept
+    pub fn set_jit(&mut self, threshold: Option<u64>) {
+        self.jit.threshold = threshold;
+        self.jit.clear();
+    }
+
+    // compiled code for a loop or the root of a function, compiling it if it
+    // has become hot
+    pub(super) fn jit_code(&mut self, node: &OpNode, func: &Rc<Func>) -> Option<Rc<Code>> {
+        let threshold = self.jit.threshold?;
+        if self.history.max > 0
+            || self.limits.max_steps.is_some()
+            || self.limits.timeout.is_some()
+            || self.limits.max_call_depth.is_some()
+        {
+            return None;
+        }
+
+        let unit = self
+            .jit
+            .units
+            .entry(node as *const OpNode)
+            .or_insert(Unit::Cold(0));
+        match unit {
+            Unit::Compiled(code) => return Some(code.clone()),
+            Unit::Unsupported => return None,
+            Unit::Cold(runs) if *runs < threshold => {
+                *runs += 1;
+                return None;
+            }
+            Unit::Cold(_) => {}
+        }
+
+        let code = Compiler::new(&self.funcs, self.cell_mask)
+            .compile(node, func)
+            .and_then(|(asm, funcs)| Code::new(&asm.encode(), funcs))
+            .map(Rc::new);
+        let unit = match &code {
+            Some(code) => Unit::Compiled(code.clone()),
+            None => Unit::Unsupported,
+        };
+        self.jit.units.insert(node as *const OpNode, unit);
+        code
+    }
+
+    // compiled code for the function with name, if it is hot enough
+    pub(super) fn jit_func(&mut self, name: &str) -> Option<Rc<Code>> {
+        self.jit.threshold?;
+        let func = self.funcs.iter().find(|func| func.name == name)?.clone();
+        self.jit_code(&func.node, &func)
+    }
+
+    // runs compiled code on the selected tape
+    pub(super) fn run_code(&mut self, code: &Code) -> Result<(), RuntimeError> {
+        let mut ctx = Ctx {
+            vals: std::ptr::null_mut(),
+            len: 0,
+            curr: 0,
+            runtime: self as *mut Runtime,
+            err: None,
+        };
+        ctx.load(self);
+
+        let status = code.run(&mut ctx);
+        let curr = self.curr;
+        self.stack[curr].curr = ctx.curr;
+
+        match status as i64 {
+            OK => Ok(()),
+            OUT_OF_BOUNDS => Err(RuntimeError::TapeIndexOutOfBounds(ctx.curr, ctx.len)),
+            NEGATIVE => Err(RuntimeError::TapeIndexCannotBeNegative),
+            _ => Err(ctx.err.take().unwrap_or(RuntimeError::Generic)),
+        }
+    }
+}
+
+// turns a loop or function, along with every function it calls, into
+// instructions
+struct Compiler<'a> {
+    funcs: &'a [Rc<Func>],
+    mask: u32,
+    asm: Asm,
+    /// Whether each function (and everything it calls) can be compiled, None
+    /// while it is being checked
+    supported: HashMap<String, Option<bool>>,
+    /// Functions called, with the label of their compiled body
+    called: Vec<(Rc<Func>, Label)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(funcs: &'a [Rc<Func>], mask: u32) -> Self {
+        Self {
+            funcs,
+            mask,
+            asm: Asm::default(),
+            supported: HashMap::new(),
+            called: vec![],
+        }
+    }
+
+    fn func(&self, name: &str) -> Option<&'a Rc<Func>> {
+        self.funcs.iter().find(|func| func.name == name)
+    }
+
+    // whether every function node calls could be compiled, which rules out
+    // intrinsics and recursion
+    fn supports(&mut self, node: &OpNode) -> bool {
+        match node {
+            OpNode::Root(children) | OpNode::Loop(children, _) => {
+                children.iter().all(|child| self.supports(child))
+            }
+            OpNode::Operation(..) => true,
+            OpNode::FuncCall(name, _) => {
+                match self.supported.get(name) {
+                    Some(Some(supported)) => return *supported,
+                    Some(None) => return false,
+                    None => {}
+                }
+                let func = match self.func(name) {
+                    Some(func) => func,
+                    None => return false,
+                };
+
+                self.supported.insert(name.clone(), None);
+                let supported = self.supports(&func.node);
+                self.supported.insert(name.clone(), Some(supported));
+                supported
+            }
+        }
+    }
+
+    // compiles node, which was found in func, into code returning once it is
+    // done, along with the functions the code uses
+    fn compile(mut self, node: &OpNode, func: &Rc<Func>) -> Option<(Asm, Vec<Rc<Func>>)> {
+        if !self.supports(node) {
+            return None;
+        }
+
+        // rust calls the code with the context in rdi
+        for reg in [CTX, VALS, PTR, LEN] {
+            self.asm.push(Inst::Push(reg));
+        }
+        // keeps the stack 16 byte aligned for calls
+        self.sub_rsp();
+        self.mov(Operand::Reg(CTX), Operand::Reg(Reg::Rdi));
+        self.load_ctx();
+
+        let done = self.asm.label();
+        match node {
+            OpNode::Root(children) => self.block(children, done),
+            _ => self.node(node, done),
+        }
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(OK));
+        self.asm.bind(done);
+        self.mov(Operand::Mem(Mem::base(CTX, CURR_OFFSET)), Operand::Reg(PTR));
+        self.add_rsp();
+        for reg in [LEN, PTR, VALS, CTX] {
+            self.asm.push(Inst::Pop(reg));
+        }
+        self.asm.push(Inst::Ret);
+
+        // bodies of called functions, which may call more
+        let mut funcs = vec![func.clone()];
+        let mut compiled = 0;
+        while compiled < self.called.len() {
+            let (func, label) = self.called[compiled].clone();
+            compiled += 1;
+
+            self.asm.bind(label);
+            self.sub_rsp();
+            let done = self.asm.label();
+            if let OpNode::Root(children) = &func.node {
+                self.block(children, done);
+            }
+            self.mov(Operand::Reg(Reg::Rax), Operand::Imm(OK));
+            self.asm.bind(done);
+            self.add_rsp();
+            self.asm.push(Inst::Ret);
+            funcs.push(func);
+        }
+
+        Some((self.asm, funcs))
+    }
+
+    // compiles nodes, jumping to fail with an error in rax
+    fn block(&mut self, nodes: &[OpNode], fail: Label) {
+        let mut idx = 0;
+        while idx < nodes.len() {
+            // runs of the same operation are done at once
+            let run = nodes[idx..]
+                .iter()
+                .take_while(|node| Self::same_run(&nodes[idx], node))
+                .count()
+                .max(1);
+            match &nodes[idx] {
+                OpNode::Operation(Op::Inc, _) | OpNode::Operation(Op::Dec, _) => {
+                    let change =
+                        nodes[idx..idx + run]
+                            .iter()
+                            .fold(0u32, |change, node| match node {
+                                OpNode::Operation(Op::Inc, _) => change.wrapping_add(1),
+                                _ => change.wrapping_sub(1),
+                            });
+                    self.add(change, fail);
+                }
+                OpNode::Operation(Op::PtrRight, _) => {
+                    self.alu(Alu::Add, Size::Qword, Operand::Reg(PTR), run as i64);
+                }
+                OpNode::Operation(Op::PtrLeft, _) => self.ptr_left(run, fail),
+                node => self.node(node, fail),
+            }
+            idx += run;
+        }
+    }
+
+    fn same_run(first: &OpNode, node: &OpNode) -> bool {
+        matches!(
+            (first, node),
+            (
+                OpNode::Operation(Op::Inc | Op::Dec, _),
+                OpNode::Operation(Op::Inc | Op::Dec, _)
+            ) | (
+                OpNode::Operation(Op::PtrRight, _),
+                OpNode::Operation(Op::PtrRight, _)
+            ) | (
+                OpNode::Operation(Op::PtrLeft, _),
+                OpNode::Operation(Op::PtrLeft, _)
+            )
+        )
+    }
+
+    fn node(&mut self, node: &OpNode, fail: Label) {
+        match node {
+            OpNode::Loop(children, _) => {
+                let (start, end) = (self.asm.label(), self.asm.label());
+                self.asm.bind(start);
+                self.load_cell(fail);
+                self.asm.push(Inst::Test(Size::Dword, Reg::Rax, Reg::Rax));
+                self.asm.push(Inst::Jcc(Cond::E, end));
+                self.block(children, fail);
+                self.asm.push(Inst::Jmp(start));
+                self.asm.bind(end);
+            }
+            OpNode::FuncCall(name, _) => {
+                let func = self.func(name).unwrap().clone();
+                let label = match self
+                    .called
+                    .iter()
+                    .find(|(other, _)| Rc::ptr_eq(other, &func))
+                {
+                    Some((_, label)) => *label,
+                    None => {
+                        let label = self.asm.label();
+                        self.called.push((func, label));
+                        label
+                    }
+                };
+                self.asm.push(Inst::Call(label));
+                self.check(fail);
+            }
+            OpNode::Operation(Op::BitNot, _) => {
+                self.load_cell(fail);
+                self.asm
+                    .push(Inst::Not(Size::Dword, Operand::Reg(Reg::Rax)));
+                self.store_cell();
+            }
+            OpNode::Operation(Op::Literal(lit), _) => {
+                // characters past the end of the tape are left out
+                let end = self.asm.label();
+                for (idx, c) in lit.chars().enumerate() {
+                    self.asm.push(Inst::Lea(
+                        Reg::Rax,
+                        Mem {
+                            base: PTR,
+                            index: None,
+                            disp: idx as i32,
+                        },
+                    ));
+                    self.asm.push(Inst::Alu(
+                        Alu::Cmp,
+                        Size::Qword,
+                        Operand::Reg(Reg::Rax),
+                        Operand::Reg(LEN),
+                    ));
+                    self.asm.push(Inst::Jcc(Cond::Ae, end));
+                    self.asm.push(Inst::Mov(
+                        Size::Dword,
+                        Operand::Mem(Mem::indexed(VALS, Reg::Rax, 4)),
+                        Operand::Imm((u32::from(c) & self.mask) as i64),
+                    ));
+                }
+                self.asm.bind(end);
+            }
+            OpNode::Operation(op, _) => self.call_op(op, fail),
+            OpNode::Root(_) => {}
+        }
+    }
+
+    // adds to the current cell
+    fn add(&mut self, change: u32, fail: Label) {
+        self.load_cell(fail);
+        if change != 0 {
+            self.alu(
+                Alu::Add,
+                Size::Dword,
+                Operand::Reg(Reg::Rax),
+                change as i32 as i64,
+            );
+        }
+        self.store_cell();
+    }
+
+    // moves the pointer left, failing with it at 0 if it would go below
+    fn ptr_left(&mut self, run: usize, fail: Label) {
+        let (ok, negative) = (self.asm.label(), self.asm.label());
+        self.alu(Alu::Cmp, Size::Qword, Operand::Reg(PTR), run as i64);
+        self.asm.push(Inst::Jcc(Cond::B, negative));
+        self.alu(Alu::Sub, Size::Qword, Operand::Reg(PTR), run as i64);
+        self.asm.push(Inst::Jmp(ok));
+
+        self.asm.bind(negative);
+        self.mov(Operand::Reg(PTR), Operand::Imm(0));
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(NEGATIVE));
+        self.asm.push(Inst::Jmp(fail));
+        self.asm.bind(ok);
+    }
+
+    // loads the current cell into eax, failing if the pointer is past the end
+    fn load_cell(&mut self, fail: Label) {
+        let ok = self.asm.label();
+        self.asm.push(Inst::Alu(
+            Alu::Cmp,
+            Size::Qword,
+            Operand::Reg(PTR),
+            Operand::Reg(LEN),
+        ));
+        self.asm.push(Inst::Jcc(Cond::B, ok));
+        self.mov(Operand::Reg(Reg::Rax), Operand::Imm(OUT_OF_BOUNDS));
+        self.asm.push(Inst::Jmp(fail));
+        self.asm.bind(ok);
+        self.asm.push(Inst::Mov(
+            Size::Dword,
+            Operand::Reg(Reg::Rax),
+            Operand::Mem(Mem::indexed(VALS, PTR, 4)),
+        ));
+    }
+
+    // stores eax, kept to the size of cells, into the current cell
+    fn store_cell(&mut self) {
+        if self.mask != u32::MAX {
+            self.alu(
+                Alu::And,
+                Size::Dword,
+                Operand::Reg(Reg::Rax),
+                self.mask as i32 as i64,
+            );
+        }
+        self.asm.push(Inst::Mov(
+            Size::Dword,
+            Operand::Mem(Mem::indexed(VALS, PTR, 4)),
+            Operand::Reg(Reg::Rax),
+        ));
+    }
+
+    // runs op in the runtime, with the tape it leaves selected
+    fn call_op(&mut self, op: &Op, fail: Label) {
+        self.mov(Operand::Mem(Mem::base(CTX, CURR_OFFSET)), Operand::Reg(PTR));
+        self.mov(Operand::Reg(Reg::Rdi), Operand::Reg(CTX));
+        self.mov(Operand::Reg(Reg::Rsi), Operand::Imm(op as *const Op as i64));
+        self.mov(
+            Operand::Reg(Reg::Rax),
+            Operand::Imm(run_op as *const () as i64),
+        );
+        self.asm.push(Inst::CallReg(Reg::Rax));
+        self.load_ctx();
+        self.check(fail);
+    }
+
+    // jumps to fail if eax is not OK
+    fn check(&mut self, fail: Label) {
+        self.asm.push(Inst::Test(Size::Dword, Reg::Rax, Reg::Rax));
+        self.asm.push(Inst::Jcc(Cond::Ne, fail));
+    }
+
+    fn load_ctx(&mut self) {
+        for (reg, offset) in [(VALS, VALS_OFFSET), (LEN, LEN_OFFSET), (PTR, CURR_OFFSET)] {
+            self.mov(Operand::Reg(reg), Operand::Mem(Mem::base(CTX, offset)));
+        }
+    }
+
+    fn sub_rsp(&mut self) {
+        self.alu(Alu::Sub, Size::Qword, Operand::Reg(Reg::Rsp), 8);
+    }
+
+    fn add_rsp(&mut self) {
+        self.alu(Alu::Add, Size::Qword, Operand::Reg(Reg::Rsp), 8);
+    }
+
+    fn mov(&mut self, dest: Operand, src: Operand) {
+        self.asm.push(Inst::Mov(Size::Qword, dest, src));
+    }
+
+    fn alu(&mut self, op: Alu, size: Size, dest: Operand, val: i64) {
+        self.asm.push(Inst::Alu(op, size, dest, Operand::Imm(val)));
+    }
+}
+
+/// Machine code in executable memory, along with the functions it was
+/// compiled from (as it points to their operations)
+pub(super) struct Code {
+    ptr: *mut u8,
+    size: usize,
+    _funcs: Vec<Rc<Func>>,
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod mem {
+    use std::ffi::c_void;
+
+    pub const PROT_READ: i32 = 1;
+    pub const PROT_WRITE: i32 = 2;
+    pub const PROT_EXEC: i32 = 4;
+    pub const MAP_PRIVATE: i32 = 2;
+    pub const MAP_ANONYMOUS: i32 = 0x20;
+
+    extern "C" {
+        pub fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: i32,
+            flags: i32,
+            fd: i32,
+            offset: i64,
+        ) -> *mut c_void;
+        pub fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+        pub fn munmap(addr: *mut c_void, len: usize) -> i32;
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl Code {
+    // copies code into pages which are made executable (but no longer
+    // writable), None if they cannot be mapped
+    fn new(code: &[u8], funcs: Vec<Rc<Func>>) -> Option<Self> {
+        use mem::*;
+
+        let size = code.len().max(1);
+        // SAFETY: maps fresh pages, which are only written within their size
+        unsafe {
+            let ptr = mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if ptr as isize == -1 {
+                return None;
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, size, PROT_READ | PROT_EXEC) != 0 {
+                munmap(ptr, size);
+                return None;
+            }
+
+            Some(Self {
+                ptr: ptr as *mut u8,
+                size,
+                _funcs: funcs,
+            })
+        }
+    }
+
+    fn run(&self, ctx: &mut Ctx) -> u32 {
+        // SAFETY: the code was compiled by Compiler, which only touches the
+        // tape in ctx within its bounds
+        unsafe {
+            let code: extern "sysv64" fn(*mut Ctx) -> u32 = std::mem::transmute(self.ptr);
+            code(ctx)
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+impl Drop for Code {
+    fn drop(&mut self) {
+        // SAFETY: the pages were mapped by Code::new, and are not run again
+        unsafe {
+            mem::munmap(self.ptr as *mut std::ffi::c_void, self.size);
+        }
+    }
+}
+
+// machine code cannot be run elsewhere, so everything is interpreted
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+impl Code {
+    fn new(_code: &[u8], _funcs: Vec<Rc<Func>>) -> Option<Self> {
+        None
+    }
+
+    fn run(&self, _ctx: &mut Ctx) -> u32 {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::{limits::Limits, testing};
+
+    // result, output and final stack of a program, interpreted then with
+    // everything compiled as soon as it is reached
+    fn runs(src: &str, input: &str) -> [(Result<i32, String>, String, String); 2] {
+        [None, Some(0)].map(|threshold| {
+            let mut runtime = Runtime::new(testing::parse(src));
+            runtime.set_jit(threshold);
+            let (res, out) = testing::run(&mut runtime, input);
+            let res = res.map_err(|err| err.message());
+            (res, out, runtime.dump_stack(None))
+        })
+    }
+
+    fn assert_same(src: &str, input: &str) {
+        let [interpreted, compiled] = runs(src, input);
+        assert_eq!(compiled, interpreted, "{}", src);
+    }
+
+    #[test]
+    fn programs_run_the_same() {
+        let programs = [
+            // moving values between cells, and a run of - wrapping around
+            "@main { ^4 +++++ [ - > ++ > +++ << ] > [ - > + < ] > @!print_num }",
+            "@main { ^2 +++ [ - > -- < ] > @!print_num }",
+            // io and bitwise not inside loops
+            "@main { ^8 +++ [ - > , . ~ ~ < ] }",
+            // stack operations and calls from compiled code
+            "@main { ^2 ++++ [ ^1 \"a\" . & @twice - ] }\n@twice { ^1 \"b\" . . & }",
+            "@main { ^2 +++ [ ^1 ++ &+ --- ] @!print_num }",
+        ];
+        for src in programs {
+            assert_same(src, "xyz");
+        }
+    }
+
+    #[test]
+    fn test_programs_run_the_same() {
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("test");
+        for name in ["hello_world", "stdlib", "test"] {
+            let path = dir.join(format!("{}.migraine", name));
+            assert_same(&std::fs::read_to_string(path).unwrap(), "12\n");
+        }
+    }
+
+    #[test]
+    fn pointer_past_end_fails_the_same() {
+        assert_same("@main { ^3 + [ > + ] }", "");
+        assert_same("@main { ^3 + [ >>>> ] }", "");
+    }
+
+    #[test]
+    fn pointer_before_start_fails_the_same() {
+        assert_same("@main { ^3 + > + > + [ < ] }", "");
+        assert_same("@main { ^3 + [ <<< ] }", "");
+    }
+
+    #[test]
+    fn halt_in_loop_exits_the_same() {
+        let src = "@main { ^2 + [ \"A\" . ! ] }";
+        let [interpreted, _] = runs(src, "");
+        assert_eq!(interpreted.0, Ok(65));
+        assert_same(src, "");
+        assert_same("@main { ^2 + [ @stop ] }\n@stop { +++++++ ! }", "");
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_frames() {
+        for threshold in [None, Some(0)] {
+            let mut runtime = Runtime::new(testing::parse(testing::COUNTDOWN));
+            runtime.set_jit(threshold);
+            runtime.set_limits(Limits::none().max_call_depth(2));
+            let (res, out) = testing::run(&mut runtime, "");
+            assert_eq!(res.unwrap(), '0' as i32);
+            assert_eq!(out, "0");
+        }
+    }
+}
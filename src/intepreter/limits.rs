@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+/// Limits put on a running program, each failing with its own RuntimeError
+/// once exceeded (None means unlimited)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    /// Steps taken by a single run
+    pub max_steps: Option<u64>,
+    /// Bytes used by every tape in the stack combined
+    pub max_memory: Option<usize>,
+    /// Tapes in the stack
+    pub max_stack_depth: Option<usize>,
+    /// Functions being run at once
+    pub max_call_depth: Option<usize>,
+    /// Time taken by a single run, only checked between steps so a step waiting
+    /// on input (`,` or an intrinsic) is never stopped
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// No limits, same as Limits::default()
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn max_steps(mut self, steps: u64) -> Self {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn max_stack_depth(mut self, tapes: usize) -> Self {
+        self.max_stack_depth = Some(tapes);
+        self
+    }
+
+    pub fn max_call_depth(mut self, frames: usize) -> Self {
+        self.max_call_depth = Some(frames);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
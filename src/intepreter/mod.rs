@@ -1,41 +1,99 @@
-mod err;
+pub mod err;
+mod history;
+pub mod intrinsics;
+pub mod jit;
+pub mod limits;
+pub mod profile;
+pub mod snapshot;
+pub mod trace;
 use err::*;
-use std::io::{stdout, Write};
+use history::{Change, History};
+use intrinsics::Intrinsic;
+use jit::Jit;
+use limits::Limits;
+use std::io::{stderr, stdin, stdout, Read, Write};
+use std::mem::size_of;
+use std::rc::Rc;
+use std::time::Instant;
 
 use crate::parse::{
+    analysis::{ENTRY_FUNC, INTRINSIC_PREFIX},
     lexer::{Op, StackOp},
     parser::Func,
 };
 
 use super::parse::parser::OpNode;
 
+// how many cells either side of a tape's pointer are shown by '#'
+const DEBUG_WINDOW: usize = 8;
+
+// how many steps are taken between checks of the timeout
+const TIMEOUT_INTERVAL: u64 = 1024;
+
+/// What `,` does once input has ended
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Eof {
+    /// Sets the cell to 0
+    #[default]
+    Zero,
+    /// Sets the cell to -1 (every bit set)
+    MinusOne,
+    /// Leaves the cell as it was
+    Unchanged,
+}
+
+#[derive(Clone)]
 struct Tape {
     len: usize,
     curr: usize,
     vals: Vec<u32>,
+    /// Bits cells are kept to, so they wrap around at their size
+    mask: u32,
 }
 
 impl Tape {
-    fn new(size: usize) -> Self {
-        Self {
-            vals: vec![0; size],
+    // fails rather than aborting if there is not enough memory for the cells,
+    // as the size comes straight from the program
+    fn new(size: usize, mask: u32) -> Result<Self, RuntimeError> {
+        let mut vals = Vec::default();
+        vals.try_reserve_exact(size)
+            .map_err(|_| RuntimeError::OutOfMemory(size))?;
+        vals.resize(size, 0);
+        Ok(Self {
+            vals,
             len: size,
             curr: 0,
+            mask,
+        })
+    }
+
+    fn empty(mask: u32) -> Self {
+        Self {
+            vals: vec![],
+            len: 0,
+            curr: 0,
+            mask,
         }
     }
 
+    // cells of a byte or less are read and written as bytes, rather than as
+    // utf8 characters
+    fn bytes(&self) -> bool {
+        self.mask <= u8::MAX as u32
+    }
+
     fn curr_val(&self) -> Result<u32, RuntimeError> {
-        if self.curr == self.len {
+        if self.curr >= self.len {
             return Err(RuntimeError::TapeIndexOutOfBounds(self.curr, self.len));
         }
         Ok(self.vals[self.curr])
     }
 
-    fn inc(&mut self) {
-        self.vals[self.curr] += 1;
+    fn inc(&mut self) -> Result<(), RuntimeError> {
+        self.set(self.curr_val()?.wrapping_add(1))
     }
-    fn dec(&mut self) {
-        self.vals[self.curr] -= 1;
+    fn dec(&mut self) -> Result<(), RuntimeError> {
+        self.set(self.curr_val()?.wrapping_sub(1))
     }
 
     fn ptr_right(&mut self) -> Result<(), RuntimeError> {
@@ -51,170 +109,718 @@ impl Tape {
         Ok(())
     }
 
-    fn literal(&mut self, lit: &String) {
-        for i in (self.curr)..(self.curr + lit.len()).min(self.len) {
-            self.vals[i] = lit.chars().nth(i - self.curr).unwrap().into();
+    fn literal(&mut self, lit: &str) {
+        for (i, c) in ((self.curr)..(self.len)).zip(lit.chars()) {
+            self.vals[i] = u32::from(c) & self.mask;
         }
     }
 
-    fn dump(&self) -> Result<(), RuntimeError> {
+    fn dump(&self, output: &mut dyn Write) -> Result<(), RuntimeError> {
         //print!("Out: {}, {}", unsafe {
         //char::from_u32_unchecked(self.curr_val()?)
         //}, self.curr_val()?);
-        print!("{}", unsafe { char::from_u32_unchecked(self.curr_val()?) });
-        stdout().flush().unwrap_or_default();
+        let val = self.curr_val()?;
+        if self.bytes() {
+            output.write_all(&[val as u8]).unwrap_or_default();
+        } else {
+            write!(output, "{}", unsafe { char::from_u32_unchecked(val) }).unwrap_or_default();
+        }
+        output.flush().unwrap_or_default();
         Ok(())
     }
 
-    fn intake(&mut self) {
-        // TODO
+    // reads a single character from input, with eof deciding what is stored
+    // if there is no more input
+    fn intake(&mut self, eof: Eof, input: &mut dyn Read) -> Result<(), RuntimeError> {
+        self.curr_val()?;
+
+        let mut buf = [0u8; 4];
+        let val = match input.read(&mut buf[..1]) {
+            Ok(1) if self.bytes() => buf[0] as u32,
+            Ok(1) => {
+                // length of utf8 character from its first byte
+                let len = match buf[0] {
+                    0xF0..=0xFF => 4,
+                    0xE0..=0xEF => 3,
+                    0xC0..=0xDF => 2,
+                    _ => 1,
+                };
+                input.read_exact(&mut buf[1..len]).unwrap_or_default();
+                std::str::from_utf8(&buf[..len])
+                    .ok()
+                    .and_then(|s| s.chars().next())
+                    .map_or(buf[0] as u32, |c| c.into())
+            }
+            _ => match eof {
+                Eof::Zero => 0,
+                Eof::MinusOne => u32::MAX,
+                Eof::Unchanged => return Ok(()),
+            },
+        };
+        self.set(val)
+    }
+
+    fn bit_not(&mut self) -> Result<(), RuntimeError> {
+        self.set(!self.curr_val()?)
+    }
+
+    fn set(&mut self, val: u32) -> Result<(), RuntimeError> {
+        self.curr_val()?;
+        self.vals[self.curr] = val & self.mask;
+        Ok(())
+    }
+}
+
+/// Told about every step taken by Runtime::run_observed
+pub trait Observer {
+    /// Called with the runtime about to run its current node
+    fn before_step(&mut self, _runtime: &Runtime) {}
+
+    /// Called once the node has been run
+    fn after_step(&mut self, _runtime: &Runtime) {}
+
+    /// Called instead of after_step when the node returned an error (including
+    /// the program exiting), before the runtime drops its frames
+    fn failed_step(&mut self, _runtime: &Runtime, _err: &RuntimeError) {}
+}
+
+impl Observer for () {}
+
+impl<T: Observer> Observer for Option<T> {
+    fn before_step(&mut self, runtime: &Runtime) {
+        if let Some(observer) = self {
+            observer.before_step(runtime);
+        }
+    }
+
+    fn after_step(&mut self, runtime: &Runtime) {
+        if let Some(observer) = self {
+            observer.after_step(runtime);
+        }
+    }
+
+    fn failed_step(&mut self, runtime: &Runtime, err: &RuntimeError) {
+        if let Some(observer) = self {
+            observer.failed_step(runtime, err);
+        }
     }
+}
 
-    fn bit_not(&mut self) {
-        self.set(!self.vals[self.curr]);
+// lets more than one observer watch the same run
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn before_step(&mut self, runtime: &Runtime) {
+        self.0.before_step(runtime);
+        self.1.before_step(runtime);
     }
 
-    fn set(&mut self, val: u32) {
-        self.vals[self.curr] = val;
+    fn after_step(&mut self, runtime: &Runtime) {
+        self.0.after_step(runtime);
+        self.1.after_step(runtime);
+    }
+
+    fn failed_step(&mut self, runtime: &Runtime, err: &RuntimeError) {
+        self.0.failed_step(runtime, err);
+        self.1.failed_step(runtime, err);
+    }
+}
+
+/// Function being run, and where in it
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub func: Rc<Func>,
+    /// Index into the root of the function, followed by one for each loop entered
+    pub path: Vec<usize>,
+}
+
+impl Frame {
+    fn new(func: Rc<Func>) -> Self {
+        Self {
+            func,
+            path: vec![0],
+        }
+    }
+
+    /// Node that will be run next in this function
+    pub fn node(&self) -> Option<&OpNode> {
+        Runtime::node_at(&self.func.node, &self.path)
     }
 }
 
 pub struct Runtime {
     stack: Vec<Tape>,
     curr: usize,
-    funcs: Vec<Func>,
+    funcs: Vec<Rc<Func>>,
+    frames: Vec<Frame>,
+    intrinsics: Vec<(String, Intrinsic)>,
+    debug: bool,
+    limits: Limits,
+    steps: u64,
+    started: Instant,
+    history: History,
+    cell_mask: u32,
+    eof: Eof,
+    jit: Jit,
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    debug_output: Box<dyn Write>,
 }
 
 impl Runtime {
     pub fn new(funcs: Vec<Func>) -> Self {
-        Self {
-            stack: vec![Tape {
-                len: 0,
-                vals: vec![],
-                curr: 0,
-            }],
+        let mut runtime = Self {
+            stack: vec![Tape::empty(u32::MAX)],
             curr: 0,
-            funcs,
-        }
+            funcs: funcs.into_iter().map(Rc::new).collect(),
+            frames: vec![],
+            intrinsics: vec![],
+            debug: false,
+            limits: Limits::none(),
+            steps: 0,
+            started: Instant::now(),
+            history: History::default(),
+            cell_mask: u32::MAX,
+            eof: Eof::Zero,
+            jit: Jit::default(),
+            input: Box::new(stdin()),
+            output: Box::new(stdout()),
+            debug_output: Box::new(stderr()),
+        };
+        intrinsics::add_default_intrinsics(&mut runtime);
+        runtime
     }
 
     fn curr_tape(&mut self) -> &mut Tape {
         &mut self.stack[self.curr]
     }
 
-    pub fn run_func_with_name(&mut self, name: String) -> Result<(), RuntimeError> {
-        for func in self.funcs.iter() {
-            if func.name == name {
-                #[allow(mutable_borrow_reservation_conflict)]
-                self.run_func(&func.clone())?;
-                return Ok(());
+    /// Adds a Rust function callable as `@!<name>`, replacing any with the same name
+    pub fn register_intrinsic<F>(&mut self, name: &str, func: F)
+    where
+        F: Fn(&mut Runtime) -> Result<(), RuntimeError> + 'static,
+    {
+        self.intrinsics.retain(|(other, _)| other != name);
+        self.intrinsics.push((name.to_string(), Rc::new(func)));
+    }
+
+    /// Names of the intrinsics which can be called, without the `!`
+    pub fn intrinsic_names(&self) -> Vec<&str> {
+        self.intrinsics
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Whether '#' prints the stack (to stderr, unless set_debug_output is
+    /// used), otherwise it does nothing
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Writes what '#' prints to output, rather than stderr
+    pub fn set_debug_output(&mut self, output: Box<dyn Write>) {
+        self.debug_output = output;
+    }
+
+    /// Reads input for ',' (and intrinsics) from input, and writes output of
+    /// '.' to output, rather than stdin and stdout
+    pub fn set_io(&mut self, input: Box<dyn Read>, output: Box<dyn Write>) {
+        self.input = input;
+        self.output = output;
+    }
+
+    /// Limits put on every run from now on
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Makes cells the given number of bits wide (up to 32), wrapping around at
+    /// that size. Cells of 8 bits or less are read and written as bytes, rather
+    /// than as UTF-8 characters
+    pub fn set_cell_bits(&mut self, bits: u32) {
+        self.cell_mask = match bits {
+            0 | 32.. => u32::MAX,
+            _ => (1 << bits) - 1,
+        };
+        // compiled code keeps cells to the size they were
+        self.jit.clear();
+        for tape in self.stack.iter_mut() {
+            tape.mask = self.cell_mask;
+            for val in tape.vals.iter_mut() {
+                *val &= self.cell_mask;
             }
         }
-        Err(RuntimeError::FunctionNotDefined(name))
     }
 
-    fn run_func(&mut self, func: &Func) -> Result<(), RuntimeError> {
-        match &func.node {
-            OpNode::Root(children) => {
-                for child in children {
-                    self.run_node(child)?
+    /// What `,` stores once input has ended
+    pub fn set_eof(&mut self, eof: Eof) {
+        self.eof = eof;
+    }
+
+    /// Steps taken since the last run started
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Value of the current cell in the selected tape
+    pub fn curr_val(&self) -> Result<u32, RuntimeError> {
+        self.stack[self.curr].curr_val()
+    }
+
+    /// Sets the current cell in the selected tape
+    pub fn set_curr_val(&mut self, val: u32) -> Result<(), RuntimeError> {
+        self.curr_tape().set(val)
+    }
+
+    /// Writes characters from the current cell onwards, same as a string literal
+    pub fn write_literal(&mut self, lit: &str) {
+        self.curr_tape().literal(lit);
+    }
+
+    /// Functions the runtime can call
+    pub fn funcs(&self) -> &[Rc<Func>] {
+        &self.funcs
+    }
+
+    /// Adds functions, replacing any already defined with the same name
+    pub fn add_funcs(&mut self, funcs: Vec<Func>) {
+        self.jit.clear();
+        for func in funcs {
+            self.funcs.retain(|other| other.name != func.name);
+            self.funcs.push(Rc::new(func));
+        }
+    }
+
+    /// Clears the stack back to only the root tape and stops running any
+    /// functions (functions are kept)
+    pub fn reset(&mut self) {
+        self.stack = vec![Tape::empty(self.cell_mask)];
+        self.curr = 0;
+        self.frames.clear();
+    }
+
+    /// Formats every tape in the stack, showing the values within window cells of
+    /// each tape's pointer (or every value if None)
+    pub fn dump_stack(&self, window: Option<usize>) -> String {
+        let mut out = String::default();
+
+        for (idx, tape) in self.stack.iter().enumerate() {
+            let selected = if idx == self.curr { '*' } else { ' ' };
+            out += &format!(
+                "{} {:>3} | len {:<5} | ptr {:<5} |",
+                selected, idx, tape.len, tape.curr
+            );
+
+            let (start, end) = match window {
+                Some(window) => (
+                    tape.curr.saturating_sub(window),
+                    (tape.curr + window + 1).min(tape.len),
+                ),
+                None => (0, tape.len),
+            };
+
+            if start > 0 {
+                out += " ...";
+            }
+            for i in start..end {
+                if i == tape.curr {
+                    out += &format!(" [{}]", tape.vals[i]);
+                } else {
+                    out += &format!(" {}", tape.vals[i]);
                 }
             }
+            if end < tape.len {
+                out += " ...";
+            }
+            out += "\n";
+        }
+        out
+    }
 
-            // branch should never be reached
-            _ => return Err(RuntimeError::Generic),
+    /// Runs the main function, returning the exit code of the program
+    pub fn run(&mut self) -> Result<i32, RuntimeError> {
+        self.run_observed(&mut ())
+    }
+
+    /// Runs the main function same as run, telling observer about every step
+    pub fn run_observed(&mut self, observer: &mut dyn Observer) -> Result<i32, RuntimeError> {
+        let res = self.run_until_return(|runtime| runtime.enter_func(ENTRY_FUNC), observer);
+        Self::exit_code(res)
+    }
+
+    /// Carries on running the functions left in the runtime (eg. after a
+    /// snapshot is restored), returning the exit code of the program
+    pub fn resume_observed(&mut self, observer: &mut dyn Observer) -> Result<i32, RuntimeError> {
+        self.started = Instant::now();
+        let res = self.run_to_depth(0, observer);
+        if res.is_err() {
+            self.frames.clear();
+        }
+        Self::exit_code(res)
+    }
+
+    fn exit_code(res: Result<(), RuntimeError>) -> Result<i32, RuntimeError> {
+        match res {
+            Ok(_) => Ok(0),
+            Err(RuntimeError::Exit(code)) => Ok(code),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn run_func_with_name(&mut self, name: String) -> Result<(), RuntimeError> {
+        self.run_until_return(|runtime| runtime.enter_func(&name), &mut ())
+    }
+
+    pub fn run_func(&mut self, func: &Func) -> Result<(), RuntimeError> {
+        let func = Rc::new(func.clone());
+        let start = |runtime: &mut Self| {
+            runtime.frames.push(Frame::new(func));
+            runtime.unwind();
+            Ok(())
         };
+        self.run_until_return(start, &mut ())
+    }
+
+    // runs until every frame entered by start has returned
+    fn run_until_return<T>(
+        &mut self,
+        start: T,
+        observer: &mut dyn Observer,
+    ) -> Result<(), RuntimeError>
+    where
+        T: FnOnce(&mut Self) -> Result<(), RuntimeError>,
+    {
+        let depth = self.frames.len();
+
+        // limits on steps and time are per run, not per nested call
+        if depth == 0 {
+            self.steps = 0;
+            self.started = Instant::now();
+        }
+
+        let res = start(self).and_then(|_| self.run_to_depth(depth, observer));
+
+        // frames entered from here are dropped on error, so the runtime can
+        // still be used afterwards
+        if res.is_err() {
+            self.frames.truncate(depth);
+        }
+        res
+    }
+
+    fn run_to_depth(
+        &mut self,
+        depth: usize,
+        observer: &mut dyn Observer,
+    ) -> Result<(), RuntimeError> {
+        while self.frames.len() > depth {
+            observer.before_step(self);
+            if let Err(err) = self.step() {
+                observer.failed_step(self, &err);
+                return Err(err);
+            }
+            observer.after_step(self);
+        }
         Ok(())
     }
 
-    fn run_node(&mut self, node: &OpNode) -> Result<(), RuntimeError> {
+    /// Starts running function with name, a Rust intrinsic is run straight away
+    pub fn enter_func(&mut self, name: &str) -> Result<(), RuntimeError> {
+        if let Some(func) = self.funcs.iter().find(|func| func.name == name) {
+            if let Some(max) = self.limits.max_call_depth {
+                if self.frames.len() >= max {
+                    return Err(RuntimeError::CallDepthExceeded(max));
+                }
+            }
+            self.frames.push(Frame::new(func.clone()));
+            self.record(Change::FramePushed);
+            self.unwind();
+            return Ok(());
+        }
+
+        // falls back to intrinsics if no function matches
+        if let Some(intrinsic_name) = name.strip_prefix(INTRINSIC_PREFIX) {
+            let intrinsic = self
+                .intrinsics
+                .iter()
+                .find(|(other, _)| other == intrinsic_name)
+                .map(|(_, intrinsic)| intrinsic.clone());
+
+            if let Some(intrinsic) = intrinsic {
+                if self.recording() {
+                    let stack = self.stack.clone();
+                    self.record(Change::Stack(stack, self.curr));
+                }
+                return intrinsic(self);
+            }
+        }
+        Err(RuntimeError::FunctionNotDefined(name.to_string()))
+    }
+
+    /// Functions being run, the last being the innermost
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Node that will be run by the next step
+    pub fn curr_node(&self) -> Option<&OpNode> {
+        self.frames.last()?.node()
+    }
+
+    /// Index of the selected tape, and of the current cell in that tape
+    pub fn selected(&self) -> (usize, usize) {
+        (self.curr, self.stack[self.curr].curr)
+    }
+
+    /// Number of tapes in the stack
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Value of a cell in any tape of the stack
+    pub fn cell(&self, tape: usize, idx: usize) -> Option<u32> {
+        self.stack.get(tape)?.vals.get(idx).copied()
+    }
+
+    /// Runs a single node (an operation, a function call or checking the cell of
+    /// a loop), returns false if there was nothing left to run
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        let frame = match self.frames.last() {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        let func = frame.func.clone();
+
+        // where the step started from, when steps are being remembered
+        let path = (self.history.max > 0).then(|| frame.path.clone());
+
+        self.begin_step();
+        if let Some(path) = path {
+            self.record(Change::Path(path));
+        }
+        let res = self.check_step_limits().and_then(|_| self.step_node(&func));
+        self.end_step();
+        res
+    }
+
+    fn step_node(&mut self, func: &Rc<Func>) -> Result<bool, RuntimeError> {
+        let path = &self.frames.last().unwrap().path;
+        let node = match Self::node_at(&func.node, path) {
+            Some(node) => node,
+            None => return Err(RuntimeError::Generic),
+        };
+
         match node {
-            OpNode::FuncCall(name) => self.run_func_with_name(name.clone())?,
-            OpNode::Operation(op) => self.op(op)?,
-            OpNode::Loop(children) => {
-                while self.curr_tape().curr_val()? != 0 {
-                    for child in children {
-                        self.run_node(child)?;
+            OpNode::Operation(op, _) => {
+                self.op(op)?;
+                self.advance();
+            }
+            OpNode::FuncCall(name, _) => {
+                // caller carries on after the call once the callee returns
+                self.advance();
+                match self.jit_func(name) {
+                    Some(code) => self.run_code(&code)?,
+                    None => {
+                        self.tail_call(name);
+                        self.enter_func(name)?
                     }
                 }
             }
+            OpNode::Loop(children, _) => {
+                // compiled loops are run until they finish
+                if let Some(code) = self.jit_code(node, func) {
+                    self.run_code(&code)?;
+                    self.advance();
+                } else if self.curr_tape().curr_val()? == 0 {
+                    self.advance();
+                } else if !children.is_empty() {
+                    self.frames.last_mut().unwrap().path.push(0);
+                }
+            }
             OpNode::Root(_) => return Err(RuntimeError::Generic),
         };
+
+        self.unwind();
+        Ok(true)
+    }
+
+    fn check_step_limits(&mut self) -> Result<(), RuntimeError> {
+        self.steps += 1;
+
+        if let Some(max) = self.limits.max_steps {
+            if self.steps > max {
+                return Err(RuntimeError::StepLimitExceeded(max));
+            }
+        }
+
+        // the clock is only read every so often, as it is slow next to a step
+        if let Some(max) = self.limits.timeout {
+            if self.steps.is_multiple_of(TIMEOUT_INTERVAL) && self.started.elapsed() > max {
+                return Err(RuntimeError::Timeout(max));
+            }
+        }
         Ok(())
     }
 
+    fn advance(&mut self) {
+        if let Some(frame) = self.frames.last_mut() {
+            if let Some(idx) = frame.path.last_mut() {
+                *idx += 1;
+            }
+        }
+    }
+
+    // leaves loops and functions which have reached their end, so that the
+    // innermost frame always points at the next node to run
+    fn unwind(&mut self) {
+        while let Some(frame) = self.frames.last_mut() {
+            if Self::node_at(&frame.func.node, &frame.path).is_some() {
+                return;
+            }
+
+            if frame.path.len() > 1 {
+                // back to the loop, which checks its cell again
+                let path = frame.path.clone();
+                frame.path.pop();
+                self.record(Change::Path(path));
+            } else if let Some(frame) = self.frames.pop() {
+                self.record(Change::FramePopped(frame));
+            }
+        }
+    }
+
+    // a function calling itself at its very end gives up its frame to the
+    // call, so such recursion runs in constant memory. Other calls keep their
+    // caller, which the debugger and profiler show
+    fn tail_call(&mut self, name: &str) {
+        let done = match self.frames.last() {
+            Some(frame) => {
+                frame.func.name == name
+                    && frame.path.len() == 1
+                    && Self::node_at(&frame.func.node, &frame.path).is_none()
+            }
+            None => false,
+        };
+        if done {
+            if let Some(frame) = self.frames.pop() {
+                self.record(Change::FramePopped(frame));
+            }
+        }
+    }
+
+    fn node_at<'a>(root: &'a OpNode, path: &[usize]) -> Option<&'a OpNode> {
+        let mut children = match root {
+            OpNode::Root(children) => children,
+            _ => return None,
+        };
+
+        for (depth, idx) in path.iter().enumerate() {
+            let node = children.get(*idx)?;
+            if depth + 1 == path.len() {
+                return Some(node);
+            }
+            children = match node {
+                OpNode::Loop(children, _) => children,
+                _ => return None,
+            };
+        }
+        None
+    }
+
+    // operations check everything before changing the runtime, so one which
+    // fails leaves nothing to undo
     fn op(&mut self, op: &Op) -> Result<(), RuntimeError> {
+        let recorded = self.recorded();
+        if self.recording() {
+            self.record_op(op);
+        }
+
+        let res = self.run_op(op);
+        if res.is_err() {
+            self.forget(recorded);
+        }
+        res
+    }
+
+    fn run_op(&mut self, op: &Op) -> Result<(), RuntimeError> {
         match op {
             // Standard Operations
             Op::Literal(lit) => self.curr_tape().literal(lit),
-            Op::Inc => self.curr_tape().inc(),
-            Op::Dec => self.curr_tape().dec(),
+            Op::Inc => self.curr_tape().inc()?,
+            Op::Dec => self.curr_tape().dec()?,
             Op::PtrRight => self.curr_tape().ptr_right()?,
             Op::PtrLeft => self.curr_tape().ptr_left()?,
-            Op::Dump => self.curr_tape().dump()?,
-            Op::Take => self.curr_tape().intake(),
-            Op::BitNot => self.curr_tape().bit_not(),
+            Op::Dump => self.stack[self.curr].dump(&mut *self.output)?,
+            Op::Take => self.stack[self.curr].intake(self.eof, &mut *self.input)?,
+            Op::BitNot => self.curr_tape().bit_not()?,
+            Op::Halt => return Err(RuntimeError::Exit(self.curr_val()? as i32)),
+            Op::Debug => self.debug_dump(),
 
             // Stack Operations
             Op::PushNew(size) => self.push_new(*size)?,
-            Op::PushOp(op) => match op {
-                StackOp::Default => self.push()?,
-                StackOp::Add => self.push_op(|curr, next| curr + next)?,
-                StackOp::Mul => self.push_op(|curr, next| curr * next)?,
-                StackOp::BitAnd => self.push_op(|curr, next| curr & next)?,
-                StackOp::Sub => self.push_op(|curr, next| curr - next)?,
-                StackOp::Div => self.push_op(|curr, next| curr / next)?,
-                StackOp::BitOr => self.push_op(|curr, next| curr | next)?,
-                StackOp::BitXor => self.push_op(|curr, next| curr ^ next)?,
-                StackOp::Set => self.push_op(|curr, _| curr)?,
-            },
-            Op::HardPopOp(op) => match op {
-                StackOp::Default => self.hard_pop()?,
-                StackOp::Add => self.hard_pop_op(|curr, next| curr + next)?,
-                StackOp::Mul => self.hard_pop_op(|curr, next| curr * next)?,
-                StackOp::BitAnd => self.hard_pop_op(|curr, next| curr & next)?,
-                StackOp::Sub => self.hard_pop_op(|curr, next| curr - next)?,
-                StackOp::Div => self.hard_pop_op(|curr, next| curr / next)?,
-                StackOp::BitOr => self.hard_pop_op(|curr, next| curr | next)?,
-                StackOp::BitXor => self.hard_pop_op(|curr, next| curr ^ next)?,
-                StackOp::Set => self.hard_pop_op(|curr, _| curr)?,
-            },
-            Op::PopOp(op) => match op {
-                StackOp::Default => self.pop()?,
-                StackOp::Add => self.pop_op(|curr, next| curr + next)?,
-                StackOp::Mul => self.pop_op(|curr, next| curr * next)?,
-                StackOp::BitAnd => self.pop_op(|curr, next| curr & next)?,
-                StackOp::Sub => self.pop_op(|curr, next| curr - next)?,
-                StackOp::Div => self.pop_op(|curr, next| curr / next)?,
-                StackOp::BitOr => self.pop_op(|curr, next| curr | next)?,
-                StackOp::BitXor => self.pop_op(|curr, next| curr ^ next)?,
-                StackOp::Set => self.pop_op(|curr, _| curr)?,
-            },
+            Op::PushOp(StackOp::Default) => self.push()?,
+            Op::PushOp(op) => self.push_op(op)?,
+            Op::HardPopOp(StackOp::Default) => self.hard_pop()?,
+            Op::HardPopOp(op) => self.hard_pop_op(op)?,
+            Op::PopOp(StackOp::Default) => self.pop()?,
+            Op::PopOp(op) => self.pop_op(op)?,
         }
         Ok(())
     }
 
+    fn debug_dump(&mut self) {
+        if !self.debug {
+            return;
+        }
+
+        let header = match (
+            self.frames.last(),
+            self.curr_node().and_then(|node| node.pos()),
+        ) {
+            (Some(frame), Some(pos)) => format!("# at {} in @{}", pos, frame.func.name),
+            _ => "#".to_string(),
+        };
+        let stack = self.dump_stack(Some(DEBUG_WINDOW));
+        write!(self.debug_output, "{}\n{}", header, stack).unwrap_or_default();
+        self.debug_output.flush().unwrap_or_default();
+    }
+
     // Stack Operation Specific Funcs
     fn push_new(&mut self, size: usize) -> Result<(), RuntimeError> {
-        self.stack.push(Tape::new(size));
+        // checked before the tape is allocated, which could abort the process
+        if let Some(max) = self.limits.max_stack_depth {
+            if self.stack.len() >= max {
+                return Err(RuntimeError::StackDepthExceeded(max));
+            }
+        }
+        if let Some(max) = self.limits.max_memory {
+            let cells: usize = self.stack.iter().map(|tape| tape.len).sum();
+            let needed = cells.saturating_add(size).saturating_mul(size_of::<u32>());
+            if needed > max {
+                return Err(RuntimeError::MemoryLimitExceeded(needed, max));
+            }
+        }
+
+        self.stack.push(Tape::new(size, self.cell_mask)?);
         self.push()?;
         Ok(())
     }
 
     fn push(&mut self) -> Result<(), RuntimeError> {
-        self.curr += 1;
-
         // returns err if pointer exceeds stack length
-        if self.curr >= self.stack.len() {
+        if self.curr + 1 >= self.stack.len() {
             return Err(RuntimeError::StackIndexOutOfBounds(
-                self.curr,
+                self.curr + 1,
                 self.stack.len(),
             ));
         }
+        self.curr += 1;
         Ok(())
     }
 
     fn hard_pop(&mut self) -> Result<(), RuntimeError> {
+        if self.curr == 0 {
+            return Err(RuntimeError::StackIndexCannotBeNegative);
+        }
         // deletes current tape then moves down
         self.stack.remove(self.curr);
         self.pop()?;
@@ -229,40 +835,213 @@ impl Runtime {
         Ok(())
     }
 
-    fn pop_op<T>(&mut self, func: T) -> Result<(), RuntimeError>
-    where
-        T: Fn(u32, u32) -> u32,
-    {
+    // the value of the current tape's cell after a stack operation, with curr
+    // from the tape being moved from and next from the one moved to
+    fn stack_op(op: &StackOp, curr: u32, next: u32) -> Result<u32, RuntimeError> {
+        Ok(match op {
+            StackOp::Default | StackOp::Set => curr,
+            StackOp::Add => curr.wrapping_add(next),
+            StackOp::Sub => curr.wrapping_sub(next),
+            StackOp::Mul => curr.wrapping_mul(next),
+            StackOp::Div => curr.checked_div(next).ok_or(RuntimeError::DivisionByZero)?,
+            StackOp::BitAnd => curr & next,
+            StackOp::BitOr => curr | next,
+            StackOp::BitXor => curr ^ next,
+        })
+    }
+
+    // values are worked out before the stack is changed, so a failed operation
+    // leaves it as it was
+    fn pop_op(&mut self, op: &StackOp) -> Result<(), RuntimeError> {
         let val1 = self.curr_tape().curr_val()?;
-        self.pop()?;
-        let curr = self.curr_tape();
-        let val2 = curr.curr_val()?;
-        curr.set(func(val1, val2));
+        if self.curr == 0 {
+            return Err(RuntimeError::StackIndexCannotBeNegative);
+        }
+        let below = &mut self.stack[self.curr - 1];
+        let val = Self::stack_op(op, val1, below.curr_val()?)?;
+        below.set(val)?;
+        self.curr -= 1;
         Ok(())
     }
 
-    fn hard_pop_op<T>(&mut self, func: T) -> Result<(), RuntimeError>
-    where
-        T: Fn(u32, u32) -> u32,
-    {
+    fn hard_pop_op(&mut self, op: &StackOp) -> Result<(), RuntimeError> {
         let val1 = self.curr_tape().curr_val()?;
-        self.hard_pop()?;
-        let curr = self.curr_tape();
-        let val2 = curr.curr_val()?;
-        curr.set(func(val1, val2));
-        Ok(())
+        if self.curr == 0 {
+            return Err(RuntimeError::StackIndexCannotBeNegative);
+        }
+        let below = &mut self.stack[self.curr - 1];
+        let val = Self::stack_op(op, val1, below.curr_val()?)?;
+        below.set(val)?;
+        self.hard_pop()
     }
 
-    fn push_op<T>(&mut self, func: T) -> Result<(), RuntimeError>
-    where
-        T: Fn(u32, u32) -> u32,
-    {
+    fn push_op(&mut self, op: &StackOp) -> Result<(), RuntimeError> {
         let val1 = self.curr_tape().curr_val()?;
-        self.push()?;
-        let curr = self.curr_tape();
-        let val2 = curr.curr_val()?;
-        curr.set(func(val1, val2));
-        self.pop()?;
-        Ok(())
+        let above = self.curr + 1;
+        if above >= self.stack.len() {
+            return Err(RuntimeError::StackIndexOutOfBounds(above, self.stack.len()));
+        }
+        let above = &mut self.stack[above];
+        let val = Self::stack_op(op, val1, above.curr_val()?)?;
+        above.set(val)
+    }
+}
+
+/// Helpers for running programs in tests with fixed input, keeping their output
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::cell::RefCell;
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::{err::RuntimeError, Runtime};
+    use crate::parse::{self, parser::Func};
+
+    /// Output written by a runtime, which stays readable after it is given away
+    #[derive(Clone, Default)]
+    pub struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        pub fn text(&self) -> String {
+            String::from_utf8_lossy(&self.0.borrow()).to_string()
+        }
+    }
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Counts down from 10000 by calling itself at the end of a function,
+    /// printing "0" and exiting with its code once done, so recursion has to
+    /// run in constant stack
+    pub const COUNTDOWN: &str = "@countdown { - >+< [>-]> [ \"0\" . ! ] << @countdown }
+@main { ^3 \"\u{2710}\" @countdown }";
+
+    /// Parses a program, with includes relative to the crate
+    pub fn parse(src: &str) -> Vec<Func> {
+        parse::parse(src.to_string(), Path::new(env!("CARGO_MANIFEST_DIR"))).unwrap()
+    }
+
+    /// Runs a runtime from main with input, returning its result and output
+    pub fn run(runtime: &mut Runtime, input: &str) -> (Result<i32, RuntimeError>, String) {
+        let output = Output::default();
+        runtime.set_io(
+            Box::new(Cursor::new(input.as_bytes().to_vec())),
+            Box::new(output.clone()),
+        );
+        let res = runtime.run();
+        (res, output.text())
+    }
+
+    /// Parses and runs a program with input, returning its result and output
+    pub fn run_src(src: &str, input: &str) -> (Result<i32, RuntimeError>, String) {
+        run(&mut Runtime::new(parse(src)), input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::err::RuntimeError;
+    use super::limits::Limits;
+    use super::testing::{self, run_src};
+    use super::Runtime;
+
+    #[test]
+    fn division_by_zero_is_an_error() {
+        // the cell of the tape moved to divides the current one
+        for op in ["^1 ^1 + _/", "^1 ^1 + &/", "^1 + ^1 _ ^/"] {
+            let src = format!("@main {{ {} }}", op);
+            let (res, _) = run_src(&src, "");
+            assert!(
+                matches!(res, Err(RuntimeError::DivisionByZero)),
+                "{}: {:?}",
+                op,
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn cells_past_the_end_are_errors() {
+        for ops in ["^1 >+", "^1 >-", "^1 >~", "^1 >>.", "^1 >>,", "^1 > ^1 _+"] {
+            let (res, _) = run_src(&format!("@main {{ {} }}", ops), "x");
+            assert!(
+                matches!(res, Err(RuntimeError::TapeIndexOutOfBounds(..))),
+                "{}: {:?}",
+                ops,
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn tail_calls_do_not_grow_frames() {
+        let mut runtime = Runtime::new(testing::parse(testing::COUNTDOWN));
+        runtime.set_limits(Limits::none().max_call_depth(2));
+        let (res, out) = testing::run(&mut runtime, "");
+        assert_eq!(res.unwrap(), '0' as i32);
+        assert_eq!(out, "0");
+    }
+
+    #[test]
+    fn tapes_too_big_to_allocate_are_errors() {
+        let (res, _) = run_src("@main { ^18446744073709551615 }", "");
+        assert!(
+            matches!(res, Err(RuntimeError::OutOfMemory(usize::MAX))),
+            "{:?}",
+            res
+        );
+
+        // unless a limit on memory is hit first
+        let mut runtime = Runtime::new(testing::parse("@main { ^18446744073709551615 }"));
+        runtime.set_limits(Limits::none().max_memory(1 << 20));
+        let (res, _) = testing::run(&mut runtime, "");
+        assert!(
+            matches!(res, Err(RuntimeError::MemoryLimitExceeded(_, max)) if max == 1 << 20),
+            "{:?}",
+            res
+        );
+    }
+
+    #[test]
+    fn debug_dumps_cells_near_the_pointer() {
+        let src = "@main { ^20 >>>>>>>>>> + # ^3 >> # }";
+        let dump = testing::Output::default();
+
+        let mut runtime = Runtime::new(testing::parse(src));
+        runtime.set_debug_output(Box::new(dump.clone()));
+        testing::run(&mut runtime, "").0.unwrap();
+        assert_eq!(dump.text(), "");
+
+        let mut runtime = Runtime::new(testing::parse(src));
+        runtime.set_debug_output(Box::new(dump.clone()));
+        runtime.set_debug(true);
+        testing::run(&mut runtime, "").0.unwrap();
+        // only the tape of 20 cells is cut down to those near its pointer
+        let expected = "\
+# at 1:26 in @main
+    0 | len 0     | ptr 0     |
+*   1 | len 20    | ptr 10    | ... 0 0 0 0 0 0 0 0 [1] 0 0 0 0 0 0 0 0 ...
+# at 1:34 in @main
+    0 | len 0     | ptr 0     |
+    1 | len 20    | ptr 10    | ... 0 0 0 0 0 0 0 0 [1] 0 0 0 0 0 0 0 0 ...
+*   2 | len 3     | ptr 2     | 0 0 [0]
+";
+        assert_eq!(dump.text(), expected);
+    }
+
+    #[test]
+    fn division_by_cell() {
+        let (res, out) = run_src(r#"@main { ^1 "\n" ^1 "d" _/ . }"#, "");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(out, "\n");
     }
 }
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use super::{Observer, Runtime};
+use crate::parse::err::FilePos;
+use crate::parse::parser::OpNode;
+
+// how many functions and loops are shown in the report
+const REPORT_LEN: usize = 20;
+
+/// Function, or loop inside of one, that steps were taken in
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Scope {
+    Func(String),
+    Loop(String, FilePos),
+}
+
+impl Scope {
+    fn name(&self) -> String {
+        match self {
+            Scope::Func(name) => format!("@{}", name),
+            Scope::Loop(func, pos) => format!("[{}] in @{}", pos, func),
+        }
+    }
+
+    // name used in folded stacks, where ';' separates frames
+    fn folded_name(&self) -> String {
+        match self {
+            Scope::Func(name) => name.clone(),
+            Scope::Loop(_, pos) => format!("[{}]", pos),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    /// Steps taken in the scope, including in functions it calls
+    inclusive: u64,
+    /// Steps taken directly in the scope
+    exclusive: u64,
+    /// Calls for functions, iterations for loops
+    entered: u64,
+}
+
+/// Counts steps taken in every function and loop while running
+#[derive(Debug, Default)]
+pub struct Profiler {
+    steps: u64,
+    counts: HashMap<Scope, Counts>,
+    folded: HashMap<String, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Total number of steps taken
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // functions and loops being run, outermost first
+    fn scopes(runtime: &Runtime) -> Vec<Scope> {
+        let mut scopes = Vec::default();
+
+        for frame in runtime.frames() {
+            let name = &frame.func.name;
+            scopes.push(Scope::Func(name.clone()));
+
+            // every node along the path other than the last is a loop entered,
+            // and the last is one when its cell is being checked
+            for len in 1..=frame.path.len() {
+                if let Some(OpNode::Loop(_, pos)) =
+                    Runtime::node_at(&frame.func.node, &frame.path[..len])
+                {
+                    scopes.push(Scope::Loop(name.clone(), *pos));
+                }
+            }
+        }
+        scopes
+    }
+
+    /// Functions and loops sorted by steps taken (inclusive), with calls and
+    /// loop iterations
+    pub fn report(&self) -> String {
+        let mut report = String::default();
+        let _ = writeln!(report, "Total steps: {}", self.steps);
+
+        let mut funcs = Vec::default();
+        let mut loops = Vec::default();
+        for (scope, counts) in self.counts.iter() {
+            match scope {
+                Scope::Func(_) => funcs.push((scope.name(), *counts)),
+                Scope::Loop(..) => loops.push((scope.name(), *counts)),
+            }
+        }
+
+        for (title, entered, mut rows) in [
+            ("Functions", "calls", funcs),
+            ("Loops", "iterations", loops),
+        ] {
+            rows.sort_by(|(a_name, a), (b_name, b)| {
+                b.inclusive
+                    .cmp(&a.inclusive)
+                    .then(b.exclusive.cmp(&a.exclusive))
+                    .then(a_name.cmp(b_name))
+            });
+
+            let width = rows
+                .iter()
+                .take(REPORT_LEN)
+                .map(|(name, _)| name.len())
+                .max()
+                .unwrap_or(0)
+                .max(title.len());
+
+            let _ = writeln!(
+                report,
+                "\n{:width$}  {:>12}  {:>7}  {:>12}  {:>7}  {:>12}",
+                title,
+                "inclusive",
+                "%",
+                "exclusive",
+                "%",
+                entered,
+                width = width
+            );
+            for (name, counts) in rows.iter().take(REPORT_LEN) {
+                let _ = writeln!(
+                    report,
+                    "{:width$}  {:>12}  {:>6.2}%  {:>12}  {:>6.2}%  {:>12}",
+                    name,
+                    counts.inclusive,
+                    self.percent(counts.inclusive),
+                    counts.exclusive,
+                    self.percent(counts.exclusive),
+                    counts.entered,
+                    width = width
+                );
+            }
+            if rows.len() > REPORT_LEN {
+                let _ = writeln!(report, "({} more)", rows.len() - REPORT_LEN);
+            }
+        }
+        report
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.steps == 0 {
+            return 0.0;
+        }
+        count as f64 * 100.0 / self.steps as f64
+    }
+
+    /// Writes steps taken in every stack of functions and loops, in the folded
+    /// format read by flamegraph.pl and inferno
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut stacks: Vec<(&String, &u64)> = self.folded.iter().collect();
+        stacks.sort();
+
+        for (stack, count) in stacks {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+impl Observer for Profiler {
+    fn before_step(&mut self, runtime: &Runtime) {
+        let node = match runtime.curr_node() {
+            Some(node) => node,
+            None => return,
+        };
+        let scopes = Self::scopes(runtime);
+
+        // the function run first is not called from anywhere
+        if self.steps == 0 {
+            if let Some(scope) = scopes.first() {
+                self.counts.entry(scope.clone()).or_default().entered += 1;
+            }
+        }
+        self.steps += 1;
+
+        // recursive scopes only count once towards their inclusive steps
+        let mut seen = Vec::<&Scope>::default();
+        for scope in scopes.iter() {
+            if !seen.contains(&scope) {
+                seen.push(scope);
+                self.counts.entry(scope.clone()).or_default().inclusive += 1;
+            }
+        }
+        if let Some(scope) = scopes.last() {
+            self.counts.entry(scope.clone()).or_default().exclusive += 1;
+        }
+
+        match node {
+            OpNode::FuncCall(name, _) => {
+                let callee = Scope::Func(name.clone());
+                self.counts.entry(callee).or_default().entered += 1;
+            }
+            OpNode::Loop(..) if runtime.curr_val().unwrap_or(0) != 0 => {
+                if let Some(scope) = scopes.last() {
+                    self.counts.entry(scope.clone()).or_default().entered += 1;
+                }
+            }
+            _ => {}
+        }
+
+        let stack: Vec<String> = scopes.iter().map(Scope::folded_name).collect();
+        *self.folded.entry(stack.join(";")).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::testing;
+
+    fn profile(src: &str) -> Profiler {
+        let mut runtime = Runtime::new(testing::parse(src));
+        let mut profiler = Profiler::new();
+        runtime.set_io(Box::new(io::empty()), Box::new(io::sink()));
+        runtime.run_observed(&mut profiler).unwrap();
+        profiler
+    }
+
+    fn counts(profiler: &Profiler, scope: Scope) -> (u64, u64, u64) {
+        let counts = profiler.counts[&scope];
+        (counts.inclusive, counts.exclusive, counts.entered)
+    }
+
+    #[test]
+    fn counts_calls_and_steps_of_each_function() {
+        let profiler = profile("@leaf { + }\n@mid { @leaf @leaf - }\n@main { ^1 @mid @leaf + }");
+        let func = |name: &str| counts(&profiler, Scope::Func(name.to_string()));
+
+        assert_eq!(profiler.steps(), 10);
+        assert_eq!(func("main"), (10, 4, 1));
+        assert_eq!(func("mid"), (5, 3, 1));
+        assert_eq!(func("leaf"), (3, 3, 3));
+
+        let mut folded = vec![];
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert_eq!(folded, "main 4\nmain;leaf 1\nmain;mid 3\nmain;mid;leaf 2\n");
+    }
+
+    #[test]
+    fn calls_at_the_end_keep_their_caller() {
+        let profiler = profile("@leaf { + }\n@mid { - @leaf }\n@main { ^1 @mid }");
+        let func = |name: &str| counts(&profiler, Scope::Func(name.to_string()));
+        assert_eq!(func("main"), (5, 2, 1));
+        assert_eq!(func("mid"), (3, 2, 1));
+        assert_eq!(func("leaf"), (1, 1, 1));
+
+        let mut folded = vec![];
+        profiler.write_folded(&mut folded).unwrap();
+        let folded = String::from_utf8(folded).unwrap();
+        assert_eq!(folded, "main 2\nmain;mid 2\nmain;mid;leaf 1\n");
+    }
+
+    #[test]
+    fn counts_iterations_of_each_loop() {
+        let profiler = profile("@main { ^1 +++ [ - ] }");
+        let pos = FilePos {
+            line: 1,
+            column: 16,
+        };
+        let scope = Scope::Loop("main".to_string(), pos);
+
+        // the loop's cell is checked 4 times, and is not 0 for 3 of them
+        assert_eq!(counts(&profiler, scope), (7, 7, 3));
+        assert_eq!(
+            counts(&profiler, Scope::Func("main".to_string())),
+            (11, 4, 1)
+        );
+        assert!(profiler.report().contains("[1:16] in @main"));
+    }
+}
@@ -0,0 +1,378 @@
+use std::fmt::{self, Display};
+use std::rc::Rc;
+
+use super::err::RuntimeError;
+use super::{Frame, Observer, Runtime, Tape};
+use crate::parse::parser::OpNode;
+
+// first line of every snapshot, changed whenever the format is
+const HEADER: &str = "migraine snapshot 2";
+
+#[derive(Debug, Clone, PartialEq)]
+struct TapeState {
+    curr: usize,
+    vals: Vec<u32>,
+}
+
+/// Everything needed to carry on running a program from where it was: the
+/// stack of tapes, the selected tape, the functions being run and where in each
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    tapes: Vec<TapeState>,
+    selected: usize,
+    /// Name of each function being run, with the hash of its tree and its path
+    frames: Vec<(String, u64, Vec<usize>)>,
+    steps: u64,
+}
+
+impl Snapshot {
+    /// Reads a snapshot written by its Display impl
+    pub fn parse(src: &str) -> Result<Self, RuntimeError> {
+        let invalid = |line: usize, reason: &str| {
+            RuntimeError::InvalidSnapshot(format!("line {}: {}", line + 1, reason))
+        };
+
+        let mut lines = src.lines().enumerate();
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            _ => return Err(invalid(0, "not a snapshot, or from another version")),
+        }
+
+        let mut snapshot = Self {
+            tapes: vec![],
+            selected: 0,
+            frames: vec![],
+            steps: 0,
+        };
+
+        for (idx, line) in lines {
+            let mut words = line.split_whitespace();
+            let num = |word: Option<&str>| -> Result<usize, RuntimeError> {
+                word.and_then(|word| word.parse().ok())
+                    .ok_or_else(|| invalid(idx, "expected a number"))
+            };
+
+            match words.next() {
+                Some("steps") => snapshot.steps = num(words.next())? as u64,
+                Some("selected") => snapshot.selected = num(words.next())?,
+                Some("tape") => {
+                    let curr = num(words.next())?;
+                    let mut vals = vec![];
+                    for word in words {
+                        // runs of the same value are written as val*count
+                        let (val, count) = match word.split_once('*') {
+                            Some((val, count)) => (val, num(Some(count))?),
+                            None => (word, 1),
+                        };
+                        let val = val
+                            .parse()
+                            .map_err(|_| invalid(idx, "expected a cell value"))?;
+                        // the count comes straight from the file, as tape
+                        // sizes do from programs
+                        vals.try_reserve_exact(count)
+                            .map_err(|_| RuntimeError::OutOfMemory(count))?;
+                        vals.resize(vals.len() + count, val);
+                    }
+                    snapshot.tapes.push(TapeState { curr, vals });
+                }
+                Some("frame") => {
+                    let name = words
+                        .next()
+                        .ok_or_else(|| invalid(idx, "expected a function name"))?;
+                    let hash = words
+                        .next()
+                        .and_then(|word| u64::from_str_radix(word, 16).ok())
+                        .ok_or_else(|| invalid(idx, "expected a function hash"))?;
+                    let path = words
+                        .map(|word| num(Some(word)))
+                        .collect::<Result<_, _>>()?;
+                    snapshot.frames.push((name.to_string(), hash, path));
+                }
+                Some(_) => return Err(invalid(idx, "unknown entry")),
+                None => {}
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+impl Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{}\n", HEADER))?;
+        f.write_fmt(format_args!("steps {}\n", self.steps))?;
+        f.write_fmt(format_args!("selected {}\n", self.selected))?;
+
+        for tape in self.tapes.iter() {
+            f.write_fmt(format_args!("tape {}", tape.curr))?;
+            let mut vals = tape.vals.iter().peekable();
+            while let Some(val) = vals.next() {
+                let mut count = 1;
+                while vals.next_if_eq(&val).is_some() {
+                    count += 1;
+                }
+                match count {
+                    1 => f.write_fmt(format_args!(" {}", val))?,
+                    _ => f.write_fmt(format_args!(" {}*{}", val, count))?,
+                }
+            }
+            f.write_str("\n")?;
+        }
+
+        for (name, hash, path) in self.frames.iter() {
+            f.write_fmt(format_args!("frame {} {:016x}", name, hash))?;
+            for idx in path {
+                f.write_fmt(format_args!(" {}", idx))?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl Runtime {
+    /// Copy of the state of the runtime, which can be restored later
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tapes: self
+                .stack
+                .iter()
+                .map(|tape| TapeState {
+                    curr: tape.curr,
+                    vals: tape.vals.clone(),
+                })
+                .collect(),
+            selected: self.curr,
+            frames: self
+                .frames
+                .iter()
+                .map(|frame| {
+                    let hash = tree_hash(&frame.func.node);
+                    (frame.func.name.clone(), hash, frame.path.clone())
+                })
+                .collect(),
+            steps: self.steps,
+        }
+    }
+
+    /// Puts the runtime back into the state of snapshot, returns err (leaving
+    /// the runtime as it was) if it does not fit the functions of the runtime
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), RuntimeError> {
+        let invalid = |reason: String| Err(RuntimeError::InvalidSnapshot(reason));
+
+        if snapshot.selected >= snapshot.tapes.len() {
+            return invalid(format!(
+                "tape {} is selected but does not exist",
+                snapshot.selected
+            ));
+        }
+        let mut frames = vec![];
+        for (name, hash, path) in snapshot.frames.iter() {
+            let func = match self.funcs.iter().find(|func| func.name == *name) {
+                Some(func) => func,
+                None => return invalid(format!("function '@{}' is not defined", name)),
+            };
+            if tree_hash(&func.node) != *hash {
+                return invalid(format!(
+                    "function '@{}' has changed since the snapshot",
+                    name
+                ));
+            }
+            if !Self::path_fits(&func.node, path) {
+                return invalid(format!("function '@{}' does not match the snapshot", name));
+            }
+            frames.push(Frame {
+                func: Rc::clone(func),
+                path: path.clone(),
+            });
+        }
+
+        // only the innermost function has to be at a node, callers may be
+        // past their last one
+        if let Some(frame) = frames.last() {
+            if frame.node().is_none() {
+                return invalid(format!(
+                    "function '@{}' does not match the snapshot",
+                    frame.func.name
+                ));
+            }
+        }
+
+        self.stack = snapshot
+            .tapes
+            .iter()
+            .map(|tape| Tape {
+                len: tape.vals.len(),
+                curr: tape.curr,
+                vals: tape.vals.iter().map(|val| val & self.cell_mask).collect(),
+                mask: self.cell_mask,
+            })
+            .collect();
+        self.curr = snapshot.selected;
+        self.frames = frames;
+        self.steps = snapshot.steps;
+        Ok(())
+    }
+
+    // every index of path other than the last is of a loop
+    fn path_fits(root: &OpNode, path: &[usize]) -> bool {
+        !path.is_empty()
+            && (1..path.len())
+                .all(|len| matches!(Self::node_at(root, &path[..len]), Some(OpNode::Loop(..))))
+    }
+}
+
+// FNV-1a hash of the nodes of a function as they are written, so moving a
+// function around its file (or editing its comments) keeps the same hash
+fn tree_hash(node: &OpNode) -> u64 {
+    fn write(text: &str, hash: &mut u64) {
+        for byte in text.bytes().chain(Some(b' ')) {
+            *hash ^= byte as u64;
+            *hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_node(node: &OpNode, hash: &mut u64) {
+        match node {
+            OpNode::Operation(op, _) => write(&op.to_string(), hash),
+            OpNode::FuncCall(name, _) => write(&format!("@{}", name), hash),
+            OpNode::Loop(children, _) => {
+                write("[", hash);
+                for child in children {
+                    write_node(child, hash);
+                }
+                write("]", hash);
+            }
+            OpNode::Root(children) => {
+                for child in children {
+                    write_node(child, hash);
+                }
+            }
+        }
+    }
+
+    let mut hash = 0xcbf29ce484222325;
+    write_node(node, &mut hash);
+    hash
+}
+
+/// Writes a snapshot to a file every so many steps, and when the program fails
+pub struct Checkpointer {
+    path: String,
+    every: Option<u64>,
+    err: Option<std::io::Error>,
+}
+
+impl Checkpointer {
+    pub fn new(path: &str, every: Option<u64>) -> Self {
+        Self {
+            path: path.to_string(),
+            every,
+            err: None,
+        }
+    }
+
+    /// Returns the first error had while writing
+    pub fn finish(self) -> std::io::Result<()> {
+        match self.err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // written next to the file then renamed over it, so a snapshot is never
+    // left half written
+    fn write(&mut self, runtime: &Runtime) {
+        let tmp = format!("{}.tmp", self.path);
+        let res = std::fs::write(&tmp, runtime.snapshot().to_string())
+            .and_then(|_| std::fs::rename(&tmp, &self.path));
+        if let Err(err) = res {
+            self.err.get_or_insert(err);
+        }
+    }
+}
+
+impl Observer for Checkpointer {
+    fn before_step(&mut self, runtime: &Runtime) {
+        if let Some(every) = self.every {
+            if runtime.steps() > 0 && runtime.steps().is_multiple_of(every) {
+                self.write(runtime);
+            }
+        }
+    }
+
+    fn failed_step(&mut self, runtime: &Runtime, err: &RuntimeError) {
+        if !matches!(err, RuntimeError::Exit(_)) {
+            self.write(runtime);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::testing::parse;
+
+    // takes a snapshot before the given step
+    struct At(u64, Option<Snapshot>);
+
+    impl Observer for At {
+        fn before_step(&mut self, runtime: &Runtime) {
+            if runtime.steps() == self.0 {
+                self.1 = Some(runtime.snapshot());
+            }
+        }
+    }
+
+    fn snapshot(src: &str, step: u64) -> Snapshot {
+        let mut runtime = Runtime::new(parse(src));
+        runtime.set_io(Box::new(std::io::empty()), Box::new(std::io::sink()));
+        let mut at = At(step, None);
+        runtime.run_observed(&mut at).unwrap();
+        at.1.unwrap()
+    }
+
+    #[test]
+    fn written_snapshot_is_read_back() {
+        let snapshot = snapshot("@main { ^4 +++ [ - @f ] }\n@f { > }", 6);
+        assert_eq!(Snapshot::parse(&snapshot.to_string()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn pointer_past_end_is_restored() {
+        // > moves past the end of a tape, which only fails once a cell is used
+        let src = "@main { ^1 >>> <<< + }";
+        let snapshot = snapshot(src, 4);
+        let snapshot = Snapshot::parse(&snapshot.to_string()).unwrap();
+
+        let mut runtime = Runtime::new(parse(src));
+        runtime.restore(&snapshot).unwrap();
+        runtime.resume_observed(&mut ()).unwrap();
+        assert_eq!(runtime.cell(1, 0), Some(1));
+    }
+
+    #[test]
+    fn huge_tapes_are_not_read() {
+        let src = format!("{}\ntape 0 0*{}\n", HEADER, usize::MAX);
+        let err = Snapshot::parse(&src).unwrap_err();
+        assert!(
+            matches!(err, RuntimeError::OutOfMemory(usize::MAX)),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn changed_function_is_not_restored() {
+        let snapshot = snapshot("@main { ^4 + [ @f ] }\n@f { - }", 4);
+
+        let mut moved = Runtime::new(parse("@f { - }\n\n@main {\n  ^4 +\n  [ @f ]\n}"));
+        assert!(moved.restore(&snapshot).is_ok());
+
+        let mut changed = Runtime::new(parse("@main { ^4 + [ @f ] }\n@f { + }"));
+        let err = changed.restore(&snapshot).unwrap_err();
+        assert_eq!(
+            err.message(),
+            "Invalid snapshot: function '@f' has changed since the snapshot"
+        );
+    }
+}
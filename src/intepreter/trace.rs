@@ -0,0 +1,240 @@
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::{err::RuntimeError, Observer, Runtime};
+use crate::parse::parser::OpNode;
+
+#[derive(Debug, Clone, Copy)]
+pub enum TraceFormat {
+    /// One JSON object per line
+    Json,
+    Csv,
+}
+
+impl TraceFormat {
+    /// Csv for paths ending in '.csv', otherwise Json
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "csv" => TraceFormat::Csv,
+            _ => TraceFormat::Json,
+        }
+    }
+}
+
+const CSV_HEADER: &str =
+    "step,function,line,column,node,call_depth,stack_depth,tape,cell,before,after,error";
+
+// state of the runtime before a step
+struct Record {
+    func: String,
+    line: usize,
+    column: usize,
+    node: String,
+    call_depth: usize,
+    stack_depth: usize,
+    tape: usize,
+    cell: usize,
+    before: Option<u32>,
+}
+
+/// Writes every step taken by the runtime, along with the value of the current
+/// cell before and after
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    step: usize,
+    record: Option<Record>,
+    err: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        let mut tracer = Self {
+            out,
+            format,
+            step: 0,
+            record: None,
+            err: None,
+        };
+        if let TraceFormat::Csv = format {
+            let res = writeln!(tracer.out, "{}", CSV_HEADER);
+            tracer.check(res);
+        }
+        tracer
+    }
+
+    /// Flushes the output, returning the first error had while writing
+    pub fn finish(mut self) -> io::Result<()> {
+        let res = self.out.flush();
+        self.check(res);
+        match self.err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn check(&mut self, res: io::Result<()>) {
+        if let Err(err) = res {
+            self.err.get_or_insert(err);
+        }
+    }
+
+    // err is the error the step ended with, including the program exiting
+    fn write(
+        &mut self,
+        rec: Record,
+        after: Option<u32>,
+        err: Option<&RuntimeError>,
+    ) -> io::Result<()> {
+        let num = |val: Option<u32>| val.map(|val| val.to_string()).unwrap_or_default();
+        let err = err.map(|err| err.message());
+
+        match self.format {
+            TraceFormat::Csv => writeln!(
+                self.out,
+                "{},{},{},{},{},{},{},{},{},{},{},{}",
+                self.step,
+                csv_str(&rec.func),
+                rec.line,
+                rec.column,
+                csv_str(&rec.node),
+                rec.call_depth,
+                rec.stack_depth,
+                rec.tape,
+                rec.cell,
+                num(rec.before),
+                num(after),
+                csv_str(err.as_deref().unwrap_or_default())
+            ),
+            TraceFormat::Json => {
+                let num = |val: Option<u32>| val.map_or("null".to_string(), |val| val.to_string());
+                writeln!(
+                    self.out,
+                    "{{\"step\":{},\"function\":{},\"line\":{},\"column\":{},\"node\":{},\"call_depth\":{},\"stack_depth\":{},\"tape\":{},\"cell\":{},\"before\":{},\"after\":{},\"error\":{}}}",
+                    self.step,
+                    json_str(&rec.func),
+                    rec.line,
+                    rec.column,
+                    json_str(&rec.node),
+                    rec.call_depth,
+                    rec.stack_depth,
+                    rec.tape,
+                    rec.cell,
+                    num(rec.before),
+                    num(after),
+                    err.map_or("null".to_string(), |err| json_str(&err))
+                )
+            }
+        }
+    }
+}
+
+impl<W: Write> Observer for Tracer<W> {
+    fn before_step(&mut self, runtime: &Runtime) {
+        let (frame, node) = match (runtime.frames().last(), runtime.curr_node()) {
+            (Some(frame), Some(node)) => (frame, node),
+            _ => return,
+        };
+        let pos = node.pos().unwrap_or_default();
+        let (tape, cell) = runtime.selected();
+
+        self.record = Some(Record {
+            func: frame.func.name.clone(),
+            line: pos.line,
+            column: pos.column,
+            node: node_str(node),
+            call_depth: runtime.frames().len(),
+            stack_depth: runtime.stack_len(),
+            tape,
+            cell,
+            before: runtime.cell(tape, cell),
+        });
+    }
+
+    fn after_step(&mut self, runtime: &Runtime) {
+        if let Some(rec) = self.record.take() {
+            let after = runtime.cell(rec.tape, rec.cell);
+            let res = self.write(rec, after, None);
+            self.check(res);
+            self.step += 1;
+        }
+    }
+
+    // the step which fails (or halts) is written too, with its error
+    fn failed_step(&mut self, runtime: &Runtime, err: &RuntimeError) {
+        if let Some(rec) = self.record.take() {
+            let after = runtime.cell(rec.tape, rec.cell);
+            let res = self.write(rec, after, Some(err));
+            self.check(res);
+            self.step += 1;
+        }
+    }
+}
+
+/// Node as it is written in source (loops only show their '[')
+pub fn node_str(node: &OpNode) -> String {
+    match node {
+        OpNode::Operation(op, _) => op.to_string(),
+        OpNode::FuncCall(name, _) => format!("@{}", name),
+        OpNode::Loop(..) => "[".to_string(),
+        OpNode::Root(_) => String::default(),
+    }
+}
+
+fn csv_str(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn json_str(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::testing::parse;
+    use serde_json::Value;
+
+    fn trace(src: &str, format: TraceFormat) -> Vec<String> {
+        let mut out = vec![];
+        let mut tracer = Tracer::new(&mut out, format);
+        let mut runtime = Runtime::new(parse(src));
+        runtime.set_io(Box::new(io::empty()), Box::new(io::sink()));
+        let _ = runtime.run_observed(&mut tracer);
+        tracer.finish().unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn failed_step_is_written_with_its_error() {
+        let lines = trace("@main { ^1 + > + }", TraceFormat::Json);
+        assert_eq!(lines.len(), 4);
+        let last: Value = serde_json::from_str(&lines[3]).unwrap();
+        assert_eq!(last["node"], "+");
+        assert_eq!(last["error"], "Tape index 1 out of bounds of tape size 1");
+        let first: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(first["error"], Value::Null);
+    }
+
+    #[test]
+    fn halt_is_written() {
+        let lines = trace("@main { ^1 + ! + }", TraceFormat::Csv);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[3].ends_with(",!,1,2,1,0,1,1,Program exited with code 1"));
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let lines = trace("@main { ^4 \"a\\\"\\\\\\n\" }", TraceFormat::Json);
+        let rec: Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(rec["node"], "\"a\\\"\\\\\\n\"");
+    }
+}
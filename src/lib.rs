@@ -0,0 +1,5 @@
+pub mod compile;
+pub mod intepreter;
+pub mod opt;
+pub mod parse;
+pub mod stdlib;
@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use migraine::intepreter::Runtime;
+use migraine::parse::analysis::{self, StackEffect, Warning, INTRINSIC_PREFIX};
+use migraine::parse::cst::{NodeKind, SyntaxElement, SyntaxKind, SyntaxNode, SyntaxTree};
+use migraine::parse::err::{FilePos, ParserError};
+use migraine::parse::highlight::{self, Highlight};
+use migraine::parse::module::{self, is_private, MODULE_SEP};
+use migraine::parse::parser::Func;
+use migraine::stdlib;
+use serde_json::{json, Value};
+
+use super::text::{self, Text};
+
+// how many includes deep definitions are looked for, in case files include
+// each other
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+// kinds of symbols, completions and diagnostics as numbered by LSP
+const SYMBOL_FUNCTION: u32 = 12;
+const SYMBOL_CONSTANT: u32 = 14;
+const COMPLETION_FUNCTION: u32 = 3;
+const COMPLETION_CONSTANT: u32 = 21;
+const SEVERITY_ERROR: u32 = 1;
+const SEVERITY_WARNING: u32 = 2;
+const TAG_UNNECESSARY: u32 = 1;
+
+/// Modifiers of semantic tokens, each being a bit of a token's modifiers
+pub const TOKEN_MODIFIERS: [&str; 1] = ["declaration"];
+
+/// Types of semantic tokens, in the order they are numbered
+pub fn token_types() -> Vec<&'static str> {
+    let mut types = vec![];
+    for ty in Highlight::ALL
+        .iter()
+        .filter_map(|highlight| highlight.token_type())
+    {
+        if !types.contains(&ty) {
+            types.push(ty);
+        }
+    }
+    types
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SymbolKind {
+    Function,
+    Macro,
+}
+
+/// Name of a function or macro, where it is defined or used
+#[derive(Debug, Clone)]
+struct Symbol {
+    kind: SymbolKind,
+    /// Without the `@` of functions, or the `$` and `!` of macros
+    name: String,
+    /// Bytes of the name in source
+    range: Range<usize>,
+    /// Bytes of the whole definition, if this is where the symbol is defined
+    def: Option<Range<usize>>,
+}
+
+/// `$include <path> [as <module>]`
+struct Include {
+    path: String,
+    module: String,
+    range: Range<usize>,
+}
+
+/// File functions can be defined in, either a document, a file on disk or part
+/// of the standard library
+#[derive(Debug, Clone)]
+struct Source {
+    /// None for the standard library
+    uri: Option<String>,
+    /// Directory includes are relative to
+    dir: PathBuf,
+    text: Text,
+    /// None if source cannot be split into tokens
+    tree: Option<SyntaxTree>,
+}
+
+impl Source {
+    fn new(uri: Option<String>, dir: PathBuf, src: String) -> Self {
+        Self {
+            uri,
+            dir,
+            tree: SyntaxTree::parse(&src).ok(),
+            text: Text::new(src),
+        }
+    }
+
+    // every function and macro named in source, in order
+    fn symbols(&self) -> Vec<Symbol> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return vec![],
+        };
+
+        // names of functions defined at the top level, with their whole definition
+        let mut defs = HashMap::new();
+        for child in tree.root().children {
+            if let SyntaxElement::Node(node) = child {
+                if let (NodeKind::Function, Some((first, last))) = (node.kind, span(&node)) {
+                    let end = tree.tokens()[last].range.end;
+                    defs.insert(first, tree.tokens()[first].range.start..end);
+                }
+            }
+        }
+
+        let mut symbols = vec![];
+        for (idx, token) in tree.tokens().iter().enumerate() {
+            let text = tree.text(token);
+            let start = token.range.start;
+            let symbol = |kind, (offset, name): (usize, &str), def| Symbol {
+                kind,
+                name: name.to_string(),
+                range: start + offset..start + offset + name.len(),
+                def,
+            };
+
+            match token.kind {
+                SyntaxKind::FunctionName => symbols.push(symbol(
+                    SymbolKind::Function,
+                    (1, &text[1..]),
+                    defs.remove(&idx),
+                )),
+                SyntaxKind::MacroCall => symbols.push(symbol(
+                    SymbolKind::Macro,
+                    (1, &text[1..text.len() - 1]),
+                    None,
+                )),
+                SyntaxKind::MacroDef => {
+                    if let Some(name) = words(text).get(1) {
+                        let def = Some(token.range.clone());
+                        symbols.push(symbol(SymbolKind::Macro, *name, def));
+                    }
+                }
+                SyntaxKind::Directive => {
+                    // aliases define a function calling their target
+                    if let [(_, "$alias"), name, target, ..] = words(text).as_slice() {
+                        let def = Some(token.range.clone());
+                        symbols.push(symbol(SymbolKind::Function, *name, def));
+                        symbols.push(symbol(SymbolKind::Function, *target, None));
+                    }
+                }
+                _ => {}
+            }
+        }
+        symbols
+    }
+
+    fn includes(&self) -> Vec<Include> {
+        let tree = match &self.tree {
+            Some(tree) => tree,
+            None => return vec![],
+        };
+
+        let mut includes = vec![];
+        for token in tree.tokens() {
+            if token.kind != SyntaxKind::Directive {
+                continue;
+            }
+            let module = match words(tree.text(token)).as_slice() {
+                [(_, "$include"), (_, path)] => Path::new(path)
+                    .file_stem()
+                    .map(|stem| (*path, stem.to_string_lossy().to_string())),
+                [(_, "$include"), (_, path), (_, "as"), (_, name)] => {
+                    Some((*path, name.to_string()))
+                }
+                _ => None,
+            };
+            if let Some((path, module)) = module {
+                includes.push(Include {
+                    path: path.to_string(),
+                    module,
+                    range: token.range.clone(),
+                });
+            }
+        }
+        includes
+    }
+
+    // symbol with offset inside of it, or just after it
+    fn symbol_at(&self, offset: usize) -> Option<Symbol> {
+        self.symbols()
+            .into_iter()
+            .find(|symbol| symbol.range.start <= offset && offset <= symbol.range.end)
+    }
+
+    fn definition(&self, kind: SymbolKind, name: &str) -> Option<Symbol> {
+        self.symbols()
+            .into_iter()
+            .find(|symbol| symbol.kind == kind && symbol.name == name && symbol.def.is_some())
+    }
+
+    // where a function is defined, following module names into included files
+    fn resolve(
+        &self,
+        name: &str,
+        docs: &HashMap<String, Document>,
+        depth: usize,
+    ) -> Option<(Source, Symbol)> {
+        if let Some(symbol) = self.definition(SymbolKind::Function, name) {
+            return Some((self.clone(), symbol));
+        }
+        if depth >= MAX_INCLUDE_DEPTH {
+            return None;
+        }
+
+        let (module, rest) = name.split_once(MODULE_SEP)?;
+        let include = self
+            .includes()
+            .into_iter()
+            .find(|include| include.module == module)?;
+        self.open(&include.path, docs)?
+            .resolve(rest, docs, depth + 1)
+    }
+
+    // source of an included file, from the open document if there is one
+    fn open(&self, path: &str, docs: &HashMap<String, Document>) -> Option<Source> {
+        if let Some(src) = stdlib::get(path) {
+            return Some(Self::new(None, PathBuf::from("."), src.to_string()));
+        }
+
+        let full = self.dir.join(path).canonicalize().ok()?;
+        let dir = full.parent().map_or(self.dir.clone(), Path::to_path_buf);
+        let open = docs.values().find(|doc| {
+            doc.source.uri.as_deref().and_then(text::uri_to_path) == Some(full.clone())
+        });
+
+        match open {
+            Some(doc) => Some(Self::new(
+                doc.source.uri.clone(),
+                dir,
+                doc.source.text.src().to_string(),
+            )),
+            None => {
+                let src = fs::read_to_string(&full).ok()?;
+                Some(Self::new(Some(text::path_to_uri(&full)), dir, src))
+            }
+        }
+    }
+
+    // comment lines straight above the line offset is on
+    fn doc_comment(&self, offset: usize) -> Option<String> {
+        let mut lines = vec![];
+        let mut line = self.text.line_of(offset);
+        while line > 0 {
+            line -= 1;
+            match self.text.line(line).trim().strip_prefix("//") {
+                Some(comment) => lines.push(comment.strip_prefix(' ').unwrap_or(comment)),
+                None => break,
+            }
+        }
+
+        lines.reverse();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    // range of the token at a position reported by the parser
+    fn range_at(&self, pos: FilePos) -> Range<usize> {
+        let offset = self.text.offset_of(pos);
+        let token = self.tree.as_ref().and_then(|tree| {
+            let token = &tree.tokens()[tree.token_at(offset)?];
+            (!token.kind.is_trivia()).then(|| token.range.clone())
+        });
+
+        token.unwrap_or_else(|| {
+            let len = self.text.src()[offset..]
+                .chars()
+                .next()
+                .map_or(0, char::len_utf8);
+            offset..offset + len
+        })
+    }
+
+    fn location(&self, range: &Range<usize>) -> Option<Value> {
+        Some(json!({
+            "uri": self.uri.as_ref()?,
+            "range": self.text.range(range),
+        }))
+    }
+}
+
+// first and last token of a node
+fn span(node: &SyntaxNode) -> Option<(usize, usize)> {
+    let token = |child: &SyntaxElement| match child {
+        SyntaxElement::Token(idx) => Some((*idx, *idx)),
+        SyntaxElement::Node(node) => span(node),
+    };
+    let first = node.children.iter().find_map(token)?.0;
+    let last = node.children.iter().rev().find_map(token)?.1;
+    Some((first, last))
+}
+
+// whitespace seperated words of a directive or macro definition, along with
+// their offsets (quotes and '@' are trimmed off, as the preprocessor does)
+fn words(text: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (idx, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        match (c.is_whitespace(), start) {
+            (true, Some(from)) => {
+                words.push((from, &text[from..idx]));
+                start = None;
+            }
+            (false, None) => start = Some(idx),
+            _ => {}
+        }
+    }
+
+    words
+        .into_iter()
+        .map(|(offset, word)| {
+            let trimmed = word.trim_start_matches(['"', '@']);
+            (
+                offset + word.len() - trimmed.len(),
+                trimmed.trim_end_matches('"'),
+            )
+        })
+        .collect()
+}
+
+// message of an error or warning, without the prefix and position it is
+// printed with
+fn message(printed: &str, pos: Option<FilePos>) -> String {
+    let msg = printed.split_once(": ").map_or(printed, |(_, msg)| msg);
+    let msg = match pos {
+        Some(pos) => msg.strip_prefix(&format!("{}: ", pos)).unwrap_or(msg),
+        None => msg,
+    };
+    msg.to_string()
+}
+
+/// File open in the editor, analysed every time it changes
+pub struct Document {
+    source: Source,
+    /// Functions from the last time the document parsed, kept so they can
+    /// still be completed while it is being edited
+    funcs: Vec<Func>,
+    diagnostics: Vec<Value>,
+}
+
+impl Document {
+    pub fn new(uri: &str, src: String) -> Self {
+        let dir = text::uri_to_path(uri)
+            .and_then(|path| path.parent().map(Path::to_path_buf))
+            .unwrap_or_else(|| PathBuf::from("."));
+
+        let mut doc = Self {
+            source: Source::new(Some(uri.to_string()), dir, src),
+            funcs: vec![],
+            diagnostics: vec![],
+        };
+        doc.analyse();
+        doc
+    }
+
+    pub fn update(&mut self, src: String) {
+        self.source = Source::new(self.source.uri.clone(), self.source.dir.clone(), src);
+        self.analyse();
+    }
+
+    pub fn text(&self) -> &Text {
+        &self.source.text
+    }
+
+    pub fn diagnostics(&self) -> &[Value] {
+        &self.diagnostics
+    }
+
+    /// Parses the document (and the files it includes) again, finding errors
+    /// and warnings
+    pub fn analyse(&mut self) {
+        self.diagnostics.clear();
+        let src = self.source.text.src().to_string();
+
+        let funcs = match module::load(src, &self.source.dir, &mut vec![]) {
+            Ok(funcs) => funcs,
+            Err(err) => return self.error(&err),
+        };
+        match analysis::analyse(&funcs) {
+            Ok(warnings) => {
+                for warning in warnings {
+                    self.warning(&warning);
+                }
+            }
+            Err(err) => self.error(&err),
+        }
+        self.funcs = funcs;
+    }
+
+    fn error(&mut self, err: &ParserError) {
+        if let ParserError::Multiple(errs) = err {
+            for err in errs {
+                self.error(err);
+            }
+            return;
+        }
+        let range = match (err, err.pos()) {
+            (_, Some(pos)) => self.source.range_at(pos),
+            // errors in included files are shown on the include
+            (ParserError::InFile(path, _), None) => self
+                .source
+                .includes()
+                .into_iter()
+                .find(|include| include.path == *path)
+                .map_or(0..0, |include| include.range),
+            _ => 0..0,
+        };
+
+        self.diagnostics.push(json!({
+            "range": self.source.text.range(&range),
+            "severity": SEVERITY_ERROR,
+            "source": "migraine",
+            "message": message(&err.to_string(), err.pos()),
+        }));
+    }
+
+    fn warning(&mut self, warning: &Warning) {
+        let tags = match warning {
+            Warning::UnusedFunction(..) | Warning::UnreachableFunction(..) => vec![TAG_UNNECESSARY],
+            Warning::Recursion(..) => vec![],
+        };
+
+        self.diagnostics.push(json!({
+            "range": self.source.text.range(&self.source.range_at(warning.pos())),
+            "severity": SEVERITY_WARNING,
+            "source": "migraine",
+            "message": message(&warning.to_string(), Some(warning.pos())),
+            "tags": tags,
+        }));
+    }
+
+    fn stack_effect(&self, name: &str) -> Option<StackEffect> {
+        StackEffect::of(&self.funcs, name)
+    }
+
+    /// Location the function or macro at offset is defined at
+    pub fn definition(&self, offset: usize, docs: &HashMap<String, Document>) -> Option<Value> {
+        let symbol = self.source.symbol_at(offset)?;
+        let (source, def) = match symbol.kind {
+            SymbolKind::Function => self.source.resolve(&symbol.name, docs, 0)?,
+            SymbolKind::Macro => (
+                self.source.clone(),
+                self.source.definition(SymbolKind::Macro, &symbol.name)?,
+            ),
+        };
+        source.location(&def.range)
+    }
+
+    /// Locations the function or macro at offset is named at in the document
+    pub fn references(&self, offset: usize, with_def: bool) -> Vec<Value> {
+        let symbol = match self.source.symbol_at(offset) {
+            Some(symbol) => symbol,
+            None => return vec![],
+        };
+
+        self.source
+            .symbols()
+            .into_iter()
+            .filter(|other| other.kind == symbol.kind && other.name == symbol.name)
+            .filter(|other| with_def || other.def.is_none())
+            .filter_map(|other| self.source.location(&other.range))
+            .collect()
+    }
+
+    /// Stack effect and doc comment of the function at offset, or the code
+    /// of the macro
+    pub fn hover(&self, offset: usize, docs: &HashMap<String, Document>) -> Option<Value> {
+        let symbol = self.source.symbol_at(offset)?;
+
+        let (mut contents, def) = match symbol.kind {
+            SymbolKind::Function => {
+                let mut contents = format!("```migraine\n@{}\n```\n", symbol.name);
+                if symbol.name.starts_with(INTRINSIC_PREFIX) {
+                    contents += "\nIntrinsic, written in Rust\n";
+                }
+
+                match self.stack_effect(&symbol.name) {
+                    Some(effect) => contents += &format!("\nStack effect: `{}`\n", effect),
+                    None if self.funcs.iter().any(|func| func.name == symbol.name) => {
+                        contents += "\nStack effect depends on how many times its loops run\n"
+                    }
+                    None => {}
+                }
+                (contents, self.source.resolve(&symbol.name, docs, 0))
+            }
+            SymbolKind::Macro => {
+                let def = self.source.definition(SymbolKind::Macro, &symbol.name)?;
+                let code = &self.source.text.src()[def.def.clone()?];
+                (
+                    format!("```migraine\n{}\n```\n", code),
+                    Some((self.source.clone(), def)),
+                )
+            }
+        };
+
+        let doc = def.and_then(|(source, def)| {
+            source.doc_comment(def.def.map_or(def.range.start, |range| range.start))
+        });
+        if let Some(doc) = doc {
+            contents += &format!("\n---\n\n{}\n", doc);
+        }
+
+        Some(json!({
+            "contents": { "kind": "markdown", "value": contents },
+            "range": self.source.text.range(&symbol.range),
+        }))
+    }
+
+    /// Functions and macros defined in the document
+    pub fn symbols(&self) -> Vec<Value> {
+        let text = &self.source.text;
+
+        self.source
+            .symbols()
+            .into_iter()
+            .filter_map(|symbol| {
+                let (name, kind, detail) = match symbol.kind {
+                    SymbolKind::Function => (
+                        format!("@{}", symbol.name),
+                        SYMBOL_FUNCTION,
+                        self.stack_effect(&symbol.name)
+                            .map(|effect| effect.to_string()),
+                    ),
+                    SymbolKind::Macro => (format!("${}!", symbol.name), SYMBOL_CONSTANT, None),
+                };
+
+                Some(json!({
+                    "name": name,
+                    "detail": detail.unwrap_or_default(),
+                    "kind": kind,
+                    "range": text.range(&symbol.def?),
+                    "selectionRange": text.range(&symbol.range),
+                }))
+            })
+            .collect()
+    }
+
+    /// Semantic tokens of the document, encoded relative to each other as LSP
+    /// expects
+    pub fn semantic_tokens(&self) -> Vec<u32> {
+        let tree = match &self.source.tree {
+            Some(tree) => tree,
+            None => return vec![],
+        };
+        let text = &self.source.text;
+        let types = token_types();
+
+        let mut data = vec![];
+        let mut prev = (0, 0);
+        for (range, highlight) in highlight::classify(tree) {
+            let ty = match highlight.token_type() {
+                Some(ty) => types.iter().position(|other| *other == ty).unwrap_or(0),
+                None => continue,
+            };
+            let modifiers = (highlight == Highlight::FunctionDef) as usize;
+
+            // tokens over more than one line are split, as not every editor
+            // can show them
+            let mut start = range.start;
+            while start < range.end {
+                let (line, character) = text.line_col(start);
+                let end = range.end.min(text.line_end(line));
+                let len: usize = text.src()[start..end].chars().map(char::len_utf16).sum();
+
+                if len > 0 {
+                    let delta = match line == prev.0 {
+                        true => character - prev.1,
+                        false => character,
+                    };
+                    data.extend([line - prev.0, delta, len, ty, modifiers].map(|n| n as u32));
+                    prev = (line, character);
+                }
+                start = end + 1;
+            }
+        }
+        data
+    }
+
+    /// Functions (or macros, after a `$`) which can be written at offset
+    pub fn completions(&self, offset: usize) -> Vec<Value> {
+        let src = self.source.text.src();
+
+        // replaces the word being written, from its '@' or '$'
+        let word_start = src[..offset]
+            .rfind(char::is_whitespace)
+            .map_or(0, |idx| idx + 1);
+        let word = &src[word_start..offset];
+        let (start, macros) = match (word.starts_with('@'), word.rfind('$')) {
+            (true, _) => (word_start, false),
+            (false, Some(idx)) => (word_start + idx, true),
+            (false, None) => (offset, false),
+        };
+        let range = self.source.text.range(&(start..offset));
+        let item = |label: String, kind: u32, detail: String| {
+            json!({
+                "label": label,
+                "kind": kind,
+                "detail": detail,
+                "textEdit": { "range": range, "newText": label },
+            })
+        };
+
+        let symbols = self.source.symbols();
+        if macros {
+            return symbols
+                .iter()
+                .filter(|symbol| symbol.kind == SymbolKind::Macro && symbol.def.is_some())
+                .map(|symbol| {
+                    item(
+                        format!("${}!", symbol.name),
+                        COMPLETION_CONSTANT,
+                        "macro".to_string(),
+                    )
+                })
+                .collect();
+        }
+
+        // private functions of included files cannot be called from here
+        let mut names: Vec<&str> = self
+            .funcs
+            .iter()
+            .filter(|func| func.module.is_none() || !is_private(&func.name))
+            .map(|func| func.name.as_str())
+            .collect();
+        // along with functions defined since the document last parsed
+        for symbol in symbols.iter() {
+            if symbol.kind == SymbolKind::Function
+                && symbol.def.is_some()
+                && !names.contains(&symbol.name.as_str())
+            {
+                names.push(&symbol.name);
+            }
+        }
+
+        let mut items: Vec<Value> = names
+            .into_iter()
+            .map(|name| {
+                let detail = self
+                    .stack_effect(name)
+                    .map(|effect| effect.to_string())
+                    .unwrap_or_default();
+                item(format!("@{}", name), COMPLETION_FUNCTION, detail)
+            })
+            .collect();
+
+        let runtime = Runtime::new(vec![]);
+        for name in runtime.intrinsic_names() {
+            let label = format!("@{}{}", INTRINSIC_PREFIX, name);
+            items.push(item(label, COMPLETION_FUNCTION, "intrinsic".to_string()));
+        }
+        items
+    }
+}
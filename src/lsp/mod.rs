@@ -0,0 +1,474 @@
+// Language server, speaking LSP over stdin and stdout for editors
+mod document;
+mod text;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::process;
+
+use serde_json::{json, Value};
+
+use document::{Document, TOKEN_MODIFIERS};
+
+// error codes of JSON-RPC
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Default)]
+struct Server {
+    /// Open documents by uri
+    docs: HashMap<String, Document>,
+    shutdown: bool,
+    exit: bool,
+}
+
+/// Serves an editor until it exits
+pub fn run() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut server = Server::default();
+
+    while !server.exit {
+        let body = match read_message(&mut input) {
+            Ok(Some(body)) => body,
+            Ok(None) => break,
+            Err(err) => {
+                eprintln!("ERROR: Cannot read message: {}", err);
+                process::exit(1);
+            }
+        };
+
+        let replies = match serde_json::from_slice(&body) {
+            Ok(msg) => server.handle(&msg),
+            Err(err) => vec![error(Value::Null, PARSE_ERROR, &err.to_string())],
+        };
+        for reply in replies {
+            if let Err(err) = write_message(&mut out, &reply) {
+                eprintln!("ERROR: Cannot write message: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    // exiting without being asked to shut down first is an error
+    process::exit(if server.shutdown { 0 } else { 1 });
+}
+
+// body of the next message, None once input has ended
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, val)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                len = val.trim().parse().ok();
+            }
+        }
+    }
+
+    let len = len.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "message has no Content-Length")
+    })?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message<W: Write>(out: &mut W, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    out.write_fmt(format_args!(
+        "Content-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    ))?;
+    out.flush()
+}
+
+fn error(id: Value, code: i64, msg: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": msg },
+    })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+impl Server {
+    // replies to a message, along with any notifications it causes
+    fn handle(&mut self, msg: &Value) -> Vec<Value> {
+        // responses to requests are ignored, as none are sent
+        let method = match msg["method"].as_str() {
+            Some(method) => method,
+            None => return vec![],
+        };
+        let params = &msg["params"];
+
+        let id = match msg.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notify(method, params),
+        };
+        match self.request(method, params) {
+            Some(result) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            None => vec![error(
+                id,
+                METHOD_NOT_FOUND,
+                &format!("Unknown method '{}'", method),
+            )],
+        }
+    }
+
+    // result of a request, None if the method is not known
+    fn request(&mut self, method: &str, params: &Value) -> Option<Value> {
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": {
+                        "openClose": true,
+                        "change": 1,
+                        "save": { "includeText": false },
+                    },
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                    "completionProvider": { "triggerCharacters": ["@", "$"] },
+                    "semanticTokensProvider": {
+                        "legend": {
+                            "tokenTypes": document::token_types(),
+                            "tokenModifiers": TOKEN_MODIFIERS,
+                        },
+                        "full": true,
+                    },
+                },
+                "serverInfo": {
+                    "name": "migraine",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            }
+            "textDocument/definition" => self
+                .doc_at(params)
+                .and_then(|(doc, offset)| doc.definition(offset, &self.docs))
+                .unwrap_or(Value::Null),
+            "textDocument/references" => {
+                let with_def = params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(true);
+                self.doc_at(params)
+                    .map(|(doc, offset)| json!(doc.references(offset, with_def)))
+                    .unwrap_or(Value::Null)
+            }
+            "textDocument/hover" => self
+                .doc_at(params)
+                .and_then(|(doc, offset)| doc.hover(offset, &self.docs))
+                .unwrap_or(Value::Null),
+            "textDocument/documentSymbol" => self
+                .doc(params)
+                .map(|doc| json!(doc.symbols()))
+                .unwrap_or(Value::Null),
+            "textDocument/completion" => self
+                .doc_at(params)
+                .map(|(doc, offset)| json!(doc.completions(offset)))
+                .unwrap_or(Value::Null),
+            "textDocument/semanticTokens/full" => self
+                .doc(params)
+                .map(|doc| json!({ "data": doc.semantic_tokens() }))
+                .unwrap_or(Value::Null),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn notify(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+
+        match method {
+            "exit" => self.exit = true,
+            "textDocument/didOpen" => {
+                let src = params["textDocument"]["text"].as_str().unwrap_or_default();
+                self.docs
+                    .insert(uri.to_string(), Document::new(uri, src.to_string()));
+                return self.publish(uri);
+            }
+            "textDocument/didChange" => {
+                // the whole document is sent on every change
+                let src = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(doc), Some(src)) = (self.docs.get_mut(uri), src) {
+                    doc.update(src.to_string());
+                    return self.publish(uri);
+                }
+            }
+            "textDocument/didSave" => {
+                // other documents may include the one saved
+                let uris: Vec<String> = self.docs.keys().cloned().collect();
+                let mut replies = vec![];
+                for uri in uris {
+                    if let Some(doc) = self.docs.get_mut(&uri) {
+                        doc.analyse();
+                    }
+                    replies.extend(self.publish(&uri));
+                }
+                return replies;
+            }
+            "textDocument/didClose" => {
+                self.docs.remove(uri);
+                return vec![notification(
+                    "textDocument/publishDiagnostics",
+                    json!({ "uri": uri, "diagnostics": [] }),
+                )];
+            }
+            _ => {}
+        }
+        vec![]
+    }
+
+    fn publish(&self, uri: &str) -> Vec<Value> {
+        match self.docs.get(uri) {
+            Some(doc) => vec![notification(
+                "textDocument/publishDiagnostics",
+                json!({ "uri": uri, "diagnostics": doc.diagnostics() }),
+            )],
+            None => vec![],
+        }
+    }
+
+    fn doc(&self, params: &Value) -> Option<&Document> {
+        self.docs.get(params["textDocument"]["uri"].as_str()?)
+    }
+
+    // document a request is about, with the byte offset of its position
+    fn doc_at(&self, params: &Value) -> Option<(&Document, usize)> {
+        let doc = self.doc(params)?;
+        let offset = doc.text().offset(&params["position"])?;
+        Some((doc, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///main.migraine";
+
+    const SRC: &str = "$macrodef TWO ++ $macroend
+// Pushes a tape of one cell
+@grow { ^1 }
+@main {
+\t@grow $TWO!
+\t@grow & &
+}
+";
+
+    fn open(src: &str) -> Server {
+        let mut server = Server::default();
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "text": src } },
+        }));
+        server
+    }
+
+    // result of a request about the document, at a line and character if given
+    fn request(server: &mut Server, method: &str, at: Option<(u32, u32)>) -> Value {
+        let mut params = json!({ "textDocument": { "uri": URI } });
+        if let Some((line, character)) = at {
+            params["position"] = json!({ "line": line, "character": character });
+            params["context"] = json!({ "includeDeclaration": true });
+        }
+        let mut replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        }));
+        assert_eq!(replies.len(), 1);
+        replies.remove(0)["result"].take()
+    }
+
+    fn range(line: u32, start: u32, end: u32) -> Value {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    #[test]
+    fn reports_errors_and_warnings_on_change() {
+        let mut server = open(SRC);
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": URI },
+                "contentChanges": [{ "text": "@unused { }\n@main { @missing }\n" }],
+            },
+        }));
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["range"], range(1, 8, 16));
+        assert_eq!(diagnostics[0]["severity"], 1);
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": URI },
+                "contentChanges": [{ "text": "@unused { }\n@main { }\n" }],
+            },
+        }));
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["range"], range(0, 0, 7));
+        assert_eq!(diagnostics[0]["severity"], 2);
+    }
+
+    #[test]
+    fn goes_to_definitions() {
+        let mut server = open(SRC);
+        let def = request(&mut server, "textDocument/definition", Some((4, 3)));
+        assert_eq!(def, json!({ "uri": URI, "range": range(2, 1, 5) }));
+
+        let def = request(&mut server, "textDocument/definition", Some((4, 9)));
+        assert_eq!(def["range"]["start"], json!({ "line": 0, "character": 10 }));
+
+        // nothing is defined at an operation
+        let def = request(&mut server, "textDocument/definition", Some((5, 7)));
+        assert_eq!(def, Value::Null);
+    }
+
+    #[test]
+    fn finds_references() {
+        let mut server = open(SRC);
+        let refs = request(&mut server, "textDocument/references", Some((2, 2)));
+        let ranges: Vec<&Value> = refs
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| &r["range"])
+            .collect();
+        assert_eq!(ranges, [&range(2, 1, 5), &range(4, 2, 6), &range(5, 2, 6)]);
+    }
+
+    #[test]
+    fn hovers_with_stack_effect_and_doc_comment() {
+        let mut server = open(SRC);
+        let hover = request(&mut server, "textDocument/hover", Some((5, 2)));
+        let contents = hover["contents"]["value"].as_str().unwrap();
+        assert!(
+            contents.contains("Stack effect: `tapes +1, selected +1`"),
+            "{}",
+            contents
+        );
+        assert!(
+            contents.contains("Pushes a tape of one cell"),
+            "{}",
+            contents
+        );
+        assert_eq!(hover["range"], range(5, 2, 6));
+
+        let hover = request(&mut server, "textDocument/hover", Some((4, 9)));
+        let contents = hover["contents"]["value"].as_str().unwrap();
+        assert!(contents.contains("++"), "{}", contents);
+    }
+
+    #[test]
+    fn completes_functions_and_macros() {
+        let mut server = open("@grow { ^1 }\n@main {\n\t@g\n}\n");
+        let items = request(&mut server, "textDocument/completion", Some((2, 3)));
+        let labels: Vec<&str> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["label"].as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"@grow"), "{:?}", labels);
+        assert!(labels.contains(&"@main"), "{:?}", labels);
+        assert!(labels.contains(&"@!print_num"), "{:?}", labels);
+        let grow = &items[0];
+        assert_eq!(grow["detail"], "tapes +1, selected +1");
+        assert_eq!(grow["textEdit"]["range"], range(2, 1, 3));
+
+        let mut server = open("$macrodef TWO ++ $macroend\n@main { $T }\n");
+        let items = request(&mut server, "textDocument/completion", Some((1, 10)));
+        assert_eq!(items.as_array().unwrap().len(), 1);
+        assert_eq!(items[0]["label"], "$TWO!");
+        assert_eq!(items[0]["textEdit"]["range"], range(1, 8, 10));
+    }
+
+    #[test]
+    fn lists_document_symbols() {
+        let mut server = open(SRC);
+        let symbols = request(&mut server, "textDocument/documentSymbol", None);
+        let symbols: Vec<(&str, &str)> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| {
+                let name = symbol["name"].as_str().unwrap();
+                (name, symbol["detail"].as_str().unwrap())
+            })
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                ("$TWO!", ""),
+                ("@grow", "tapes +1, selected +1"),
+                ("@main", "tapes +0, selected +0"),
+            ]
+        );
+    }
+
+    #[test]
+    fn oversized_tape_is_a_diagnostic() {
+        let mut server = Server::default();
+        let uri = "file:///big.migraine";
+        let replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": {
+                "uri": uri,
+                "text": "@main {\n  ^99999999999999999999999\n}\n",
+            }},
+        }));
+
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics[0]["message"], "Tape size is too large");
+        assert_eq!(
+            diagnostics[0]["range"]["start"],
+            json!({ "line": 1, "character": 2 })
+        );
+
+        // the rest of the server keeps working on the document
+        for method in [
+            "textDocument/semanticTokens/full",
+            "textDocument/documentSymbol",
+        ] {
+            let replies = server.handle(&json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": { "textDocument": { "uri": uri } },
+            }));
+            assert!(replies[0].get("result").is_some());
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use migraine::parse::err::FilePos;
+use serde_json::{json, Value};
+
+/// Source of a document, with where each line starts so positions sent by
+/// editors (lines from 0, characters in UTF-16) can be turned into byte offsets
+#[derive(Debug, Clone)]
+pub struct Text {
+    src: String,
+    starts: Vec<usize>,
+}
+
+impl Text {
+    pub fn new(src: String) -> Self {
+        let starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(idx, _)| idx + 1))
+            .collect();
+        Self { src, starts }
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// Byte offset of the end of a line, before its new line
+    pub fn line_end(&self, line: usize) -> usize {
+        self.starts
+            .get(line + 1)
+            .map_or(self.src.len(), |start| start - 1)
+    }
+
+    /// Line (from 0) an offset is on
+    pub fn line_of(&self, offset: usize) -> usize {
+        self.starts.partition_point(|start| *start <= offset) - 1
+    }
+
+    /// Text of a line (from 0), without its new line
+    pub fn line(&self, line: usize) -> &str {
+        match self.starts.get(line) {
+            Some(start) => &self.src[*start..self.line_end(line)],
+            None => "",
+        }
+    }
+
+    /// Byte offset of an LSP position, positions past the end of a line being
+    /// at its end
+    pub fn offset(&self, pos: &Value) -> Option<usize> {
+        let line = pos["line"].as_u64()? as usize;
+        let character = pos["character"].as_u64()? as usize;
+        let start = *self.starts.get(line)?;
+
+        let mut units = 0;
+        for (idx, c) in self.line(line).char_indices() {
+            if units >= character {
+                return Some(start + idx);
+            }
+            units += c.len_utf16();
+        }
+        Some(self.line_end(line))
+    }
+
+    /// Byte offset of a position reported by the parser (lines and columns from
+    /// 1, counted in chars)
+    pub fn offset_of(&self, pos: FilePos) -> usize {
+        let line = pos.line.saturating_sub(1);
+        let start = match self.starts.get(line) {
+            Some(start) => *start,
+            None => return self.src.len(),
+        };
+        self.line(line)
+            .char_indices()
+            .nth(pos.column.saturating_sub(1))
+            .map_or(self.line_end(line), |(idx, _)| start + idx)
+    }
+
+    /// Line and character (in UTF-16) of a byte offset, both from 0
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_of(offset);
+        let character = self.src[self.starts[line]..offset]
+            .chars()
+            .map(char::len_utf16)
+            .sum();
+        (line, character)
+    }
+
+    /// LSP position of a byte offset
+    pub fn position(&self, offset: usize) -> Value {
+        let (line, character) = self.line_col(offset);
+        json!({ "line": line, "character": character })
+    }
+
+    /// LSP range of a range of bytes
+    pub fn range(&self, range: &Range<usize>) -> Value {
+        json!({
+            "start": self.position(range.start),
+            "end": self.position(range.end),
+        })
+    }
+}
+
+/// Path of a `file://` uri, None for uris of any other scheme
+pub fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+
+    let mut bytes = vec![];
+    let mut idx = 0;
+    while idx < encoded.len() {
+        let escaped = encoded
+            .get(idx + 1..idx + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (encoded[idx], escaped) {
+            (b'%', Some(byte)) => {
+                bytes.push(byte);
+                idx += 3;
+            }
+            (byte, _) => {
+                bytes.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8_lossy(&bytes).to_string()))
+}
+
+/// `file://` uri of a path
+pub fn path_to_uri(path: &Path) -> String {
+    let mut uri = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri += &format!("%{:02X}", byte),
+        }
+    }
+    uri
+}
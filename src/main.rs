@@ -1,12 +1,89 @@
-mod intepreter;
-mod parse;
+mod debugger;
+mod lsp;
+mod repl;
 
 extern crate clap;
 use clap::*;
+use migraine::compile;
+use migraine::intepreter::jit;
+use migraine::intepreter::limits::Limits;
+use migraine::intepreter::profile::Profiler;
+use migraine::intepreter::snapshot::{Checkpointer, Snapshot};
+use migraine::intepreter::trace::{TraceFormat, Tracer};
+use migraine::intepreter::{self, Eof};
+use migraine::opt::{self, Pass};
+use migraine::parse::{
+    self, analysis::CallGraph, bf, err::ParserError, format, highlight, parser::Func,
+};
 use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
 
 fn main() {
-    let matches = App::new("Migraine CLI")
+    let matches = app().get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("grammar") {
+        match matches.value_of("format") {
+            Some("textmate") => {
+                let grammar = highlight::textmate_grammar();
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&grammar).unwrap_or_default()
+                );
+            }
+            Some("tree-sitter") => print!("{}", highlight::tree_sitter_grammar()),
+            _ => print!("{}", highlight::tree_sitter_highlights()),
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("lsp").is_some() {
+        lsp::run();
+        return;
+    }
+
+    if matches.subcommand_matches("repl").is_some() {
+        repl::run();
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("fmt") {
+        let files = matches.values_of("input").unwrap().collect();
+        format_files(files, matches.is_present("check"));
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("build") {
+        build(matches);
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("import-bf") {
+        import_bf(
+            matches.value_of("input").unwrap(),
+            matches.value_of("output"),
+        );
+        return;
+    }
+
+    if let Some(matches) = matches.subcommand_matches("debug") {
+        let funcs = parse_file(matches.value_of("input").unwrap());
+        debugger::Debugger::new(funcs).run();
+        return;
+    }
+
+    if matches.is_present("input") {
+        let input_file_path = matches.value_of("input").unwrap();
+        run_file(input_file_path, &matches);
+    }
+}
+
+// options and subcommands of the command line
+fn app() -> App<'static, 'static> {
+    App::new("Migraine CLI")
         .arg(
             Arg::with_name("input")
                 .long("input")
@@ -14,23 +91,675 @@ fn main() {
                 .takes_value(true)
                 .value_name("INPUT FILE"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("bf")
+                .long("bf")
+                .help("Runs the input as BF, on a tape of 30000 8-bit cells"),
+        )
+        .arg(
+            Arg::with_name("eof")
+                .long("eof")
+                .takes_value(true)
+                .possible_values(&["zero", "minus-one", "unchanged"])
+                .help("What ',' stores once input has ended (defaults to zero)"),
+        )
+        .arg(
+            Arg::with_name("cell-bits")
+                .long("cell-bits")
+                .takes_value(true)
+                .value_name("BITS")
+                .help("Makes cells this many bits wide, wrapping around at that size (defaults to 32)"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .short("d")
+                .help("Makes '#' print the stack"),
+        )
+        .arg(
+            Arg::with_name("jit")
+                .long("jit")
+                .conflicts_with_all(&["trace", "profile", "snapshot"])
+                .help("Compiles hot loops and functions to machine code (x86_64 Linux only)"),
+        )
+        .arg(
+            Arg::with_name("jit-threshold")
+                .long("jit-threshold")
+                .takes_value(true)
+                .value_name("RUNS")
+                .requires("jit")
+                .help("Times a loop or function runs before it is compiled (defaults to 64)"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .takes_value(true)
+                .value_name("TRACE FILE")
+                .help("Writes every step taken to a file"),
+        )
+        .arg(
+            Arg::with_name("trace-format")
+                .long("trace-format")
+                .takes_value(true)
+                .possible_values(&["json", "csv"])
+                .help("Format of the trace file (defaults to csv for .csv files, otherwise json)"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help("Prints the steps taken in every function and loop"),
+        )
+        .arg(
+            Arg::with_name("folded")
+                .long("folded")
+                .takes_value(true)
+                .value_name("FOLDED FILE")
+                .requires("profile")
+                .help("Writes profiled stacks to a file, for flamegraph.pl or inferno"),
+        )
+        .arg(
+            Arg::with_name("max-steps")
+                .long("max-steps")
+                .takes_value(true)
+                .value_name("STEPS")
+                .help("Stops programs taking more steps than this"),
+        )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Stops programs whose tapes use more memory than this"),
+        )
+        .arg(
+            Arg::with_name("max-stack")
+                .long("max-stack")
+                .takes_value(true)
+                .value_name("TAPES")
+                .help("Stops programs pushing more tapes than this"),
+        )
+        .arg(
+            Arg::with_name("max-calls")
+                .long("max-calls")
+                .takes_value(true)
+                .value_name("DEPTH")
+                .help("Stops programs nesting function calls deeper than this"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help("Stops programs running for longer than this"),
+        )
+        .arg(
+            Arg::with_name("snapshot")
+                .long("snapshot")
+                .takes_value(true)
+                .value_name("SNAPSHOT FILE")
+                .help("Saves the state of the program to a file if it fails"),
+        )
+        .arg(
+            Arg::with_name("snapshot-every")
+                .long("snapshot-every")
+                .takes_value(true)
+                .value_name("STEPS")
+                .requires("snapshot")
+                .help("Also saves the state of the program every so many steps"),
+        )
+        .arg(
+            Arg::with_name("restore")
+                .long("restore")
+                .takes_value(true)
+                .value_name("SNAPSHOT FILE")
+                .help("Carries on running the program from a saved state"),
+        )
+        .args(&pass_args())
+        .subcommand(
+            SubCommand::with_name("fmt")
+                .about("Formats source files in place")
+                .arg(
+                    Arg::with_name("check")
+                        .long("check")
+                        .help("Only lists files which are not formatted, exiting with 1 if any"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .multiple(true)
+                        .value_name("INPUT FILE"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Compiles a program into another language")
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .short("t")
+                        .takes_value(true)
+                        .required_unless("emit")
+                        .possible_values(&["bf", "wasm", "x86_64"])
+                        .help("Language to compile to"),
+                )
+                .arg(
+                    Arg::with_name("emit")
+                        .long("emit")
+                        .takes_value(true)
+                        .possible_values(&["wat", "asm", "ir"])
+                        .help("Writes text rather than binary: WAT for --target wasm, GNU assembly for --target x86_64, or the program as parsed and after each pass for ir"),
+                )
+                .args(&pass_args())
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .value_name("OUTPUT FILE")
+                        .help("Writes to a file rather than printing"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .value_name("INPUT FILE"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-bf")
+                .about("Converts a BF file to Migraine")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .value_name("OUTPUT FILE")
+                        .help("Writes to a file rather than printing"),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .value_name("INPUT FILE"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("grammar")
+                .about("Prints a grammar for highlighting Migraine in editors")
+                .arg(
+                    Arg::with_name("format")
+                        .required(true)
+                        .possible_values(&["textmate", "tree-sitter", "highlights"])
+                        .help("TextMate grammar (JSON), tree-sitter grammar.js or tree-sitter highlights.scm"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lsp")
+                .about("Runs a language server over stdin and stdout, for editors"),
+        )
+        .subcommand(SubCommand::with_name("repl").about("Runs lines of Migraine interactively"))
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Steps through a program with breakpoints")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .value_name("INPUT FILE"),
+                ),
+        )
+}
 
-    if matches.is_present("input") {
-        let input_file_path = matches.value_of("input").unwrap();
-        let content = fs::read_to_string(input_file_path).unwrap();
-        let res = parse::parse(content);
-        if let Err(err) = res {
-            eprintln!("ERROR: {:?}", err);
+// options which see the steps a program takes, or the stack it has on the way
+const WATCHING_ARGS: [&str; 9] = [
+    "debug",
+    "trace",
+    "profile",
+    "snapshot",
+    "restore",
+    "max-steps",
+    "max-calls",
+    "max-stack",
+    "max-memory",
+];
+
+// options choosing which optimisation passes run
+fn pass_args() -> Vec<Arg<'static, 'static>> {
+    let names: Vec<&str> = opt::PASSES.iter().map(|pass| pass.name).collect();
+    vec![
+        Arg::with_name("no-opt")
+            .long("no-opt")
+            .help("Runs no optimisation passes, other than those enabled"),
+        Arg::with_name("enable-pass")
+            .long("enable-pass")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PASS")
+            .possible_values(&names)
+            .help("Runs an optimisation pass"),
+        Arg::with_name("disable-pass")
+            .long("disable-pass")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PASS")
+            .possible_values(&names)
+            .help("Skips an optimisation pass"),
+    ]
+}
+
+// optimisation passes to run, all of them unless told otherwise
+fn passes(matches: &ArgMatches) -> Vec<&'static Pass> {
+    let values = |name| matches.values_of(name).into_iter().flatten();
+    let enabled: Vec<&str> = values("enable-pass").collect();
+    let disabled: Vec<&str> = values("disable-pass").collect();
+    let all = !matches.is_present("no-opt") && !watches_steps(matches);
+
+    opt::PASSES
+        .iter()
+        .filter(|pass| all || enabled.contains(&pass.name))
+        .filter(|pass| !disabled.contains(&pass.name))
+        .collect()
+}
+
+// whether the steps a program takes are watched or limited, which passes would
+// change (so only those enabled are run)
+fn watches_steps(matches: &ArgMatches) -> bool {
+    WATCHING_ARGS.iter().any(|name| matches.is_present(name))
+}
+
+// program as parsed, then after each pass, as S-expressions
+fn ir(mut funcs: Vec<Func>, passes: &[&Pass], options: &opt::Options) -> String {
+    let mut last = opt::ir(&funcs);
+    let mut out = format!(";; parsed\n{}", last);
+    for pass in passes {
+        pass.run(&mut funcs, options);
+        let ir = opt::ir(&funcs);
+        if ir == last {
+            out += &format!("\n;; after {} (unchanged)\n", pass.name);
         } else {
-            println!("Running:");
-            let mut runtime = intepreter::Runtime::new(res.unwrap());
-            let res = runtime.run_func_with_name("main".to_string());
-            
-            // if runtime returns an error, print
-            if let Err(res) = res {
-                eprintln!("{}", res);
+            out += &format!("\n;; after {}: {}\n{}", pass.name, pass.about, ir);
+        }
+        last = ir;
+    }
+    out
+}
+
+// parses file, exiting on error
+fn parse_file(input_file_path: &str) -> Vec<Func> {
+    let content = fs::read_to_string(input_file_path).unwrap();
+    let dir = Path::new(input_file_path)
+        .parent()
+        .unwrap_or_else(|| Path::new("."));
+
+    match parse::parse(content, dir) {
+        Ok(funcs) => funcs,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// parses file as BF, exiting on error
+fn parse_bf_file(input_file_path: &str) -> Vec<Func> {
+    let res = fs::read_to_string(input_file_path)
+        .map_err(|err| format!("ERROR: Cannot read '{}': {}", input_file_path, err))
+        .and_then(|src| {
+            bf::parse(&src).map_err(|err| {
+                ParserError::InFile(input_file_path.to_string(), Box::new(err)).to_string()
+            })
+        });
+
+    match res {
+        Ok(funcs) => funcs,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// converts a BF file to Migraine, printing it if not given an output file
+fn import_bf(input_file_path: &str, output: Option<&str>) {
+    let res = fs::read_to_string(input_file_path)
+        .map_err(|err| format!("ERROR: Cannot read '{}': {}", input_file_path, err))
+        .and_then(|src| {
+            bf::import(&src).map_err(|err| {
+                let err = ParserError::InFile(input_file_path.to_string(), Box::new(err));
+                err.to_string()
+            })
+        });
+
+    write_output(res.map(String::into_bytes), output);
+}
+
+// compiles a program for the target asked for, exiting on error
+fn build(matches: &ArgMatches) {
+    let mut funcs = parse_file(matches.value_of("input").unwrap());
+    let passes = passes(matches);
+    // BF inlines every call, so tail recursion is as much a problem as any
+    if matches.value_of("target") == Some("bf") {
+        if let Ok(graph) = CallGraph::new(&funcs) {
+            for warning in graph.tail_recursion() {
+                eprintln!("{}", warning);
             }
         }
     }
+    // BF has cells of 8 bits, other targets 32
+    let options = opt::Options {
+        cell_bits: match matches.value_of("target") {
+            Some("bf") => bf::CELL_BITS,
+            _ => 32,
+        },
+    };
+    if matches.value_of("emit") == Some("ir") {
+        let ir = ir(funcs, &passes, &options);
+        write_output(Ok(ir.into_bytes()), matches.value_of("output"));
+        return;
+    }
+    opt::optimise(&mut funcs, &passes, &options);
+
+    let res = match (matches.value_of("target"), matches.value_of("emit")) {
+        (Some("wasm"), Some("wat")) => {
+            compile::wasm::compile(&funcs).map(|module| module.wat().into_bytes())
+        }
+        (Some("wasm"), None) => compile::wasm::compile(&funcs).map(|module| module.binary()),
+        (Some("x86_64"), Some("asm")) => {
+            compile::native::compile(&funcs).map(|program| program.asm().into_bytes())
+        }
+        (Some("x86_64"), None) => compile::native::compile(&funcs).map(|program| program.elf()),
+        (Some(target), Some(emit)) => {
+            eprintln!(
+                "ERROR: --emit {} cannot be used with --target {}",
+                emit, target
+            );
+            process::exit(1);
+        }
+        (None, Some(emit)) => {
+            eprintln!("ERROR: --emit {} needs a --target", emit);
+            process::exit(1);
+        }
+        _ => compile::bf::compile(&funcs).map(String::into_bytes),
+    };
+    write_output(
+        res.map_err(|err| err.to_string()),
+        matches.value_of("output"),
+    );
+
+    // executables are left runnable
+    #[cfg(unix)]
+    if let (Some("x86_64"), None, Some(path)) = (
+        matches.value_of("target"),
+        matches.value_of("emit"),
+        matches.value_of("output"),
+    ) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap_or_default();
+    }
+}
+
+// writes output to a file, or prints it if not given one, exiting on error
+fn write_output(res: std::result::Result<Vec<u8>, String>, output: Option<&str>) {
+    let src = match res {
+        Ok(src) => src,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+
+    match output {
+        Some(path) => {
+            if let Err(err) = fs::write(path, src) {
+                eprintln!("ERROR: Cannot write '{}': {}", path, err);
+                process::exit(1);
+            }
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            out.write_all(&src)
+                .and_then(|_| out.flush())
+                .unwrap_or_default();
+        }
+    }
+}
+
+// reads limit options
+fn parse_limits(matches: &ArgMatches) -> Limits {
+    Limits {
+        max_steps: num_arg(matches, "max-steps"),
+        max_memory: num_arg(matches, "max-memory"),
+        max_stack_depth: num_arg(matches, "max-stack"),
+        max_call_depth: num_arg(matches, "max-calls"),
+        timeout: timeout_arg(matches),
+    }
+}
+
+// reads an option as a number, exiting if it is not one
+fn num_arg<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    let val = matches.value_of(name)?;
+    match val.parse() {
+        Ok(val) => Some(val),
+        Err(_) => {
+            eprintln!("ERROR: --{} must be a number, not '{}'", name, val);
+            process::exit(1);
+        }
+    }
+}
+
+// reads the timeout in seconds, exiting if it is negative or too large
+fn timeout_arg(matches: &ArgMatches) -> Option<Duration> {
+    let secs = num_arg(matches, "timeout")?;
+    match secs_duration(secs) {
+        Some(timeout) => Some(timeout),
+        None => {
+            eprintln!(
+                "ERROR: --timeout must be a positive number of seconds, not '{}'",
+                matches.value_of("timeout").unwrap_or_default()
+            );
+            process::exit(1);
+        }
+    }
+}
+
+// a timeout of 0 would stop every program before it starts
+fn secs_duration(secs: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+}
+
+// creates file, exiting on error
+fn create_file(path: &str) -> BufWriter<fs::File> {
+    match fs::File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            eprintln!("ERROR: Cannot create '{}': {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+// formats files in place, or with check exits with 1 if any are not formatted
+fn format_files(files: Vec<&str>, check: bool) {
+    let mut failed = false;
+
+    for path in files {
+        let res = fs::read_to_string(path)
+            .map_err(|err| format!("ERROR: Cannot read '{}': {}", path, err))
+            .and_then(|src| match format::format(&src) {
+                Ok(formatted) => Ok((src, formatted)),
+                Err(err) => Err(format!(
+                    "{}",
+                    ParserError::InFile(path.to_string(), Box::new(err))
+                )),
+            });
+
+        let (src, formatted) = match res {
+            Ok(res) => res,
+            Err(err) => {
+                eprintln!("{}", err);
+                failed = true;
+                continue;
+            }
+        };
+        if src == formatted {
+            continue;
+        }
+
+        if check {
+            eprintln!("'{}' is not formatted", path);
+            failed = true;
+        } else if let Err(err) = fs::write(path, formatted) {
+            eprintln!("ERROR: Cannot write '{}': {}", path, err);
+            failed = true;
+        }
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+// reads snapshot file, exiting on error
+fn read_snapshot(path: &str) -> Snapshot {
+    let res = fs::read_to_string(path)
+        .map_err(|err| format!("ERROR: Cannot read '{}': {}", path, err))
+        .and_then(|src| Snapshot::parse(&src).map_err(|err| err.to_string()));
+
+    match res {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn run_file(input_file_path: &str, matches: &ArgMatches) {
+    let bf = matches.is_present("bf");
+    let mut funcs = if bf {
+        parse_bf_file(input_file_path)
+    } else {
+        parse_file(input_file_path)
+    };
+    let cell_bits = match num_arg(matches, "cell-bits") {
+        Some(bits) => bits,
+        None if bf => bf::CELL_BITS,
+        None => 32,
+    };
+    opt::optimise(&mut funcs, &passes(matches), &opt::Options { cell_bits });
+
+    let mut runtime = intepreter::Runtime::new(funcs);
+    runtime.set_cell_bits(cell_bits);
+    runtime.set_eof(match matches.value_of("eof") {
+        Some("minus-one") => Eof::MinusOne,
+        Some("unchanged") => Eof::Unchanged,
+        _ => Eof::Zero,
+    });
+    runtime.set_debug(matches.is_present("debug"));
+    runtime.set_limits(parse_limits(matches));
+    if matches.is_present("jit") {
+        runtime.set_jit(Some(
+            num_arg(matches, "jit-threshold").unwrap_or(jit::JIT_THRESHOLD),
+        ));
+    }
+
+    let tracer = matches.value_of("trace").map(|path| {
+        let format = match matches.value_of("trace-format") {
+            Some("csv") => TraceFormat::Csv,
+            Some(_) => TraceFormat::Json,
+            None => TraceFormat::from_path(Path::new(path)),
+        };
+        Tracer::new(create_file(path), format)
+    });
+    let profiler = matches.is_present("profile").then(Profiler::new);
+    let checkpointer = matches
+        .value_of("snapshot")
+        .map(|path| Checkpointer::new(path, num_arg(matches, "snapshot-every")));
+
+    let mut observers = (tracer, (profiler, checkpointer));
+    let res = match matches.value_of("restore") {
+        Some(path) => {
+            if let Err(err) = runtime.restore(&read_snapshot(path)) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            runtime.resume_observed(&mut observers)
+        }
+        None => runtime.run_observed(&mut observers),
+    };
+    let (tracer, (profiler, checkpointer)) = observers;
+
+    if let Some(tracer) = tracer {
+        if let Err(err) = tracer.finish() {
+            let path = matches.value_of("trace").unwrap_or_default();
+            eprintln!("ERROR: Cannot write trace to '{}': {}", path, err);
+        }
+    }
+
+    if let Some(checkpointer) = checkpointer {
+        if let Err(err) = checkpointer.finish() {
+            let path = matches.value_of("snapshot").unwrap_or_default();
+            eprintln!("ERROR: Cannot write snapshot to '{}': {}", path, err);
+        }
+    }
+
+    if let Some(profiler) = profiler {
+        eprint!("\n{}", profiler.report());
+
+        if let Some(path) = matches.value_of("folded") {
+            let mut out = create_file(path);
+            if let Err(err) = profiler.write_folded(&mut out).and_then(|_| out.flush()) {
+                eprintln!("ERROR: Cannot write profile to '{}': {}", path, err);
+            }
+        }
+    }
+
+    // exit code of program is passed on, errors exit with 1
+    match res {
+        Ok(code) => process::exit(code),
+        Err(res) => {
+            eprintln!("{}", res);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_timeouts_are_rejected() {
+        assert_eq!(secs_duration(1.5), Some(Duration::from_millis(1500)));
+        assert_eq!(secs_duration(-1.0), None);
+        assert_eq!(secs_duration(0.0), None);
+        assert_eq!(secs_duration(f64::INFINITY), None);
+        assert_eq!(secs_duration(f64::NAN), None);
+    }
+
+    fn pass_names(args: &[&str]) -> Vec<&'static str> {
+        let args = ["migraine", "-i", "main.migraine"].iter().chain(args);
+        let matches = app().get_matches_from(args);
+        passes(&matches).iter().map(|pass| pass.name).collect()
+    }
+
+    #[test]
+    fn passes_are_off_while_steps_are_watched() {
+        assert_eq!(pass_names(&[]).len(), opt::PASSES.len());
+        for args in [
+            &["--trace", "trace.json"][..],
+            &["--profile"],
+            &["--snapshot", "main.snap"],
+            &["--restore", "main.snap"],
+            &["--max-steps", "100"],
+        ] {
+            assert!(pass_names(args).is_empty(), "{:?}", args);
+        }
+        assert_eq!(
+            pass_names(&["--profile", "--enable-pass", "fold"]),
+            ["fold"]
+        );
+    }
 }
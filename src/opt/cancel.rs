@@ -0,0 +1,65 @@
+// Removes moves straight after those they undo, `><`
+
+use super::Options;
+use crate::parse::lexer::Op;
+use crate::parse::parser::{Func, OpNode};
+
+pub fn run(funcs: &mut Vec<Func>, _options: &Options) {
+    for func in funcs {
+        cancel(&mut func.node);
+    }
+}
+
+fn cancel(node: &mut OpNode) {
+    let children = match node {
+        OpNode::Root(children) | OpNode::Loop(children, _) => children,
+        _ => return,
+    };
+
+    // undone operations are popped, so `++--` cancels out completely
+    let mut kept: Vec<OpNode> = Vec::with_capacity(children.len());
+    for mut child in children.drain(..) {
+        cancel(&mut child);
+        if let (Some(OpNode::Operation(last, _)), OpNode::Operation(op, _)) = (kept.last(), &child)
+        {
+            if undoes(last, op) {
+                kept.pop();
+                continue;
+            }
+        }
+        kept.push(child);
+    }
+    *children = kept;
+}
+
+// whether op undoes last, run straight after it. Only `><` always can: `<>`
+// fails at the start of the tape, and `+-` or `~~` fail off the end of it
+fn undoes(last: &Op, op: &Op) -> bool {
+    matches!((last, op), (Op::PtrRight, Op::PtrLeft))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::testing::{body, check};
+
+    #[test]
+    fn moves_cancel() {
+        let funcs = check("@main { ^2 + >>< < + [ >< - ] ^1 + . }", "cancel");
+        assert_eq!(body(&funcs, "main").unwrap(), "^2 + + [ - ] ^1 + .");
+    }
+
+    #[test]
+    fn failing_pairs_are_kept() {
+        // the root tape has no cells, and `<` fails at the start of a tape
+        for (src, kept) in [
+            ("@main { +- }", "+ -"),
+            ("@main { -+ }", "- +"),
+            ("@main { ~~ }", "~ ~"),
+            ("@main { ^1 <> }", "^1 < >"),
+            ("@main { ^1 > +- }", "^1 > + -"),
+        ] {
+            let funcs = check(src, "cancel");
+            assert_eq!(body(&funcs, "main").unwrap(), kept);
+        }
+    }
+}
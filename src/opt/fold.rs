@@ -0,0 +1,360 @@
+// Runs the start of main while it can be known without running the program
+// (until it first reads or writes, calls an intrinsic or fails), then replaces
+// what it ran with operations building the stack it left behind
+
+use super::Options;
+use crate::parse::analysis::ENTRY_FUNC;
+use crate::parse::err::FilePos;
+use crate::parse::lexer::{Op, StackOp};
+use crate::parse::parser::{Func, OpNode};
+
+// most steps and cells run before giving up, so folding stays quick
+const MAX_STEPS: u64 = 1 << 20;
+const MAX_CELLS: usize = 1 << 16;
+
+// deepest calls are followed
+const MAX_DEPTH: usize = 256;
+
+// takes a Vec as every pass does, though functions are only changed
+#[allow(clippy::ptr_arg)]
+pub fn run(funcs: &mut Vec<Func>, options: &Options) {
+    let main = match funcs.iter().position(|func| func.name == ENTRY_FUNC) {
+        Some(main) => main,
+        None => return,
+    };
+    let nodes = match &funcs[main].node {
+        OpNode::Root(nodes) => nodes,
+        _ => return,
+    };
+
+    // how many nodes of main run through, then the stack they leave (found by
+    // running them again, rather than copying the stack before every node)
+    let mut sim = Sim::new(funcs, options.mask());
+    let known = nodes
+        .iter()
+        .take_while(|node| sim.node(node, 0).is_ok())
+        .count();
+    if known == 0 {
+        return;
+    }
+
+    let body = if known == nodes.len() {
+        // nothing it does can be seen
+        vec![]
+    } else {
+        let mut sim = Sim::new(funcs, options.mask());
+        for node in &nodes[..known] {
+            sim.node(node, 0).ok();
+        }
+        let pos = nodes[0].pos().unwrap_or_default();
+        match sim.build(pos) {
+            // only worth it if building the stack takes fewer steps
+            Some(build) if (build.len() as u64) < sim.steps => build
+                .into_iter()
+                .chain(nodes[known..].iter().cloned())
+                .collect(),
+            _ => return,
+        }
+    };
+    funcs[main].node = OpNode::Root(body);
+}
+
+#[derive(Clone)]
+struct Tape {
+    vals: Vec<u32>,
+    ptr: usize,
+}
+
+// stops running, as what happens next cannot be known
+struct Unknown;
+
+struct Sim<'a> {
+    funcs: &'a [Func],
+    stack: Vec<Tape>,
+    curr: usize,
+    mask: u32,
+    steps: u64,
+    cells: usize,
+}
+
+impl<'a> Sim<'a> {
+    fn new(funcs: &'a [Func], mask: u32) -> Self {
+        Self {
+            funcs,
+            stack: vec![Tape {
+                vals: vec![],
+                ptr: 0,
+            }],
+            curr: 0,
+            mask,
+            steps: 0,
+            cells: 0,
+        }
+    }
+
+    fn node(&mut self, node: &OpNode, depth: usize) -> Result<(), Unknown> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(Unknown);
+        }
+
+        match node {
+            OpNode::Loop(children, _) => {
+                while self.val()? != 0 {
+                    for child in children {
+                        self.node(child, depth)?;
+                    }
+                    self.steps += 1;
+                    if self.steps > MAX_STEPS {
+                        return Err(Unknown);
+                    }
+                }
+            }
+            // intrinsics are not functions, so are unknown too
+            OpNode::FuncCall(name, _) => {
+                let func = self.funcs.iter().find(|func| &func.name == name);
+                match func.map(|func| &func.node) {
+                    Some(OpNode::Root(children)) if depth < MAX_DEPTH => {
+                        for child in children {
+                            self.node(child, depth + 1)?;
+                        }
+                    }
+                    _ => return Err(Unknown),
+                }
+            }
+            OpNode::Root(children) => {
+                for child in children {
+                    self.node(child, depth)?;
+                }
+            }
+            OpNode::Operation(op, _) => self.op(op)?,
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op) -> Result<(), Unknown> {
+        let mask = self.mask;
+        match op {
+            Op::Inc => self.set(self.val()?.wrapping_add(1))?,
+            Op::Dec => self.set(self.val()?.wrapping_sub(1))?,
+            Op::BitNot => self.set(!self.val()?)?,
+            Op::PtrRight => self.stack[self.curr].ptr += 1,
+            Op::PtrLeft => {
+                let tape = &mut self.stack[self.curr];
+                tape.ptr = tape.ptr.checked_sub(1).ok_or(Unknown)?;
+            }
+            Op::Literal(lit) => {
+                let tape = &mut self.stack[self.curr];
+                for (val, c) in tape.vals.iter_mut().skip(tape.ptr).zip(lit.chars()) {
+                    *val = u32::from(c) & mask;
+                }
+            }
+            // these read, write or exit
+            Op::Dump | Op::Take | Op::Halt | Op::Debug => return Err(Unknown),
+
+            Op::PushNew(size) => {
+                self.cells += size;
+                if self.cells > MAX_CELLS {
+                    return Err(Unknown);
+                }
+                self.stack.push(Tape {
+                    vals: vec![0; *size],
+                    ptr: 0,
+                });
+                self.push()?;
+            }
+            Op::PushOp(StackOp::Default) => self.push()?,
+            Op::PopOp(StackOp::Default) => self.pop()?,
+            Op::HardPopOp(StackOp::Default) => {
+                self.stack.remove(self.curr);
+                self.pop()?;
+            }
+            Op::PushOp(op) => {
+                let val1 = self.val()?;
+                self.push()?;
+                let val2 = self.val()?;
+                self.set(combine(op, val1, val2)?)?;
+                self.pop()?;
+            }
+            Op::PopOp(op) => {
+                let val1 = self.val()?;
+                self.pop()?;
+                let val2 = self.val()?;
+                self.set(combine(op, val1, val2)?)?;
+            }
+            Op::HardPopOp(op) => {
+                let val1 = self.val()?;
+                self.stack.remove(self.curr);
+                self.pop()?;
+                let val2 = self.val()?;
+                self.set(combine(op, val1, val2)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn val(&self) -> Result<u32, Unknown> {
+        let tape = &self.stack[self.curr];
+        tape.vals.get(tape.ptr).copied().ok_or(Unknown)
+    }
+
+    fn set(&mut self, val: u32) -> Result<(), Unknown> {
+        let mask = self.mask;
+        let tape = &mut self.stack[self.curr];
+        *tape.vals.get_mut(tape.ptr).ok_or(Unknown)? = val & mask;
+        Ok(())
+    }
+
+    fn push(&mut self) -> Result<(), Unknown> {
+        if self.curr + 1 >= self.stack.len() {
+            return Err(Unknown);
+        }
+        self.curr += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<(), Unknown> {
+        self.curr = self.curr.checked_sub(1).ok_or(Unknown)?;
+        Ok(())
+    }
+
+    // operations building the stack from the root tape alone, None if a value
+    // cannot be written in a few operations
+    fn build(&self, pos: FilePos) -> Option<Vec<OpNode>> {
+        let mut ops = vec![];
+        let moves = |ops: &mut Vec<Op>, from: usize, to: usize| {
+            let op = if to > from { Op::PtrRight } else { Op::PtrLeft };
+            ops.extend((0..from.abs_diff(to)).map(|_| op.clone()));
+        };
+
+        // the root tape has no cells, but its pointer can still have moved
+        moves(&mut ops, 0, self.stack[0].ptr);
+
+        // tapes are pushed in order, each selecting the one it pushed
+        for tape in &self.stack[1..] {
+            ops.push(Op::PushNew(tape.vals.len()));
+
+            let len = tape
+                .vals
+                .iter()
+                .rposition(|val| *val != 0)
+                .map_or(0, |idx| idx + 1);
+            let mut lit = String::default();
+            let mut fixes = vec![];
+            for (idx, val) in tape.vals[..len].iter().enumerate() {
+                let (c, fix) = write_val(*val, self.mask)?;
+                lit.push(c);
+                if !fix.is_empty() {
+                    fixes.push((idx, fix));
+                }
+            }
+            if !lit.is_empty() {
+                ops.push(Op::Literal(lit));
+            }
+
+            let mut ptr = 0;
+            for (idx, fix) in fixes {
+                moves(&mut ops, ptr, idx);
+                ops.extend(fix);
+                ptr = idx;
+            }
+            moves(&mut ops, ptr, tape.ptr);
+        }
+
+        // then the selected tape is gone back down to
+        let top = self.stack.len() - 1;
+        ops.extend((self.curr..top).map(|_| Op::PopOp(StackOp::Default)));
+
+        Some(
+            ops.into_iter()
+                .map(|op| OpNode::Operation(op, pos))
+                .collect(),
+        )
+    }
+}
+
+// result of a stack operation, or Unknown if it would fail
+fn combine(op: &StackOp, val1: u32, val2: u32) -> Result<u32, Unknown> {
+    Ok(match op {
+        StackOp::Default | StackOp::Set => val1,
+        StackOp::Add => val1.wrapping_add(val2),
+        StackOp::Sub => val1.wrapping_sub(val2),
+        StackOp::Mul => val1.wrapping_mul(val2),
+        StackOp::Div => val1.checked_div(val2).ok_or(Unknown)?,
+        StackOp::BitAnd => val1 & val2,
+        StackOp::BitOr => val1 | val2,
+        StackOp::BitXor => val1 ^ val2,
+    })
+}
+
+// character writing a value in a literal, and operations fixing it up
+// afterwards if it is not a character itself
+fn write_val(val: u32, mask: u32) -> Option<(char, Vec<Op>)> {
+    if let Some(c) = char::from_u32(val) {
+        return Some((c, vec![]));
+    }
+    if let Some(c) = char::from_u32(!val & mask) {
+        return Some((c, vec![Op::BitNot]));
+    }
+
+    // surrogates are counted to from the characters either side
+    match val {
+        0xD800..=0xDBFF => Some(('\u{D7FF}', vec![Op::Inc; (val - 0xD7FF) as usize])),
+        0xDC00..=0xDFFF => Some(('\u{E000}', vec![Op::Dec; (0xE000 - val) as usize])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::intepreter::testing::run_src;
+    use crate::opt::testing::{body, check};
+
+    #[test]
+    fn prefix_is_built() {
+        let src = format!("@main {{ ^3 > {} < ~ > . }}", "+".repeat(65));
+        let funcs = check(&src, "fold");
+        assert_eq!(body(&funcs, "main").unwrap(), "^3 \"\\0A\" ~ > .");
+
+        // nothing is seen, so nothing needs to run
+        let funcs = check("@main { ^2 +++ [ - > + < ] }", "fold");
+        assert_eq!(body(&funcs, "main").unwrap(), "");
+    }
+
+    #[test]
+    fn errors_are_run() {
+        // the error is left to happen when the program runs
+        for (src, folded) in [
+            ("@main { ^1 + + < }", "^1 \"\u{2}\" <"),
+            ("@main { ^1 + + + [ - > ] }", "^1 \"\u{3}\" [ - > ]"),
+            ("@main { ^1 ^1 + + _/ }", "^1 ^1 \"\u{2}\" _/"),
+        ] {
+            assert!(run_src(src, "").0.is_err(), "{}", src);
+            let funcs = check(src, "fold");
+            assert_eq!(body(&funcs, "main").unwrap(), folded);
+        }
+    }
+
+    #[test]
+    fn long_prefixes_are_cut_off() {
+        // the outer loop runs count times, and the inner one 0x400 times each,
+        // which goes past MAX_STEPS once count is 0x400
+        let src = |count: u32| {
+            format!(
+                "@main {{ ^2 \"{}\" + [ - > \"\u{400}\" [ - ] < ] . }}",
+                char::from_u32(count - 1).unwrap()
+            )
+        };
+        let funcs = check(&src(0x10), "fold");
+        assert_eq!(body(&funcs, "main").unwrap(), "^2 .");
+        let funcs = check(&src(0x400), "fold");
+        assert_eq!(
+            body(&funcs, "main").unwrap(),
+            "^2 \"\u{400}\" [ - > \"\u{400}\" [ - ] < ] ."
+        );
+
+        // cells of every tape pushed count
+        let funcs = check("@main { ^1 + + ^65536 . }", "fold");
+        assert_eq!(body(&funcs, "main").unwrap(), "^1 \"\u{2}\" ^65536 .");
+    }
+}
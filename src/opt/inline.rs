@@ -0,0 +1,143 @@
+// Replaces calls to small functions, and to functions called from only one
+// place, with the body of the function, saving the call and its lookup
+
+use super::Options;
+use crate::parse::analysis::CallGraph;
+use crate::parse::parser::{Func, OpNode};
+
+// functions of up to this many nodes (once their own calls are inlined) are
+// inlined wherever they are called
+const MAX_SIZE: usize = 16;
+
+// takes a Vec as every pass does, though functions are only changed
+#[allow(clippy::ptr_arg)]
+pub fn run(funcs: &mut Vec<Func>, _options: &Options) {
+    // recursive functions are left as they are, so inlining always ends
+    let graph = match CallGraph::new(funcs) {
+        Ok(graph) => graph,
+        Err(_) => return,
+    };
+
+    let mut inliner = Inliner {
+        funcs,
+        recursive: funcs
+            .iter()
+            .map(|func| graph.is_recursive(&func.name))
+            .collect(),
+        sites: funcs
+            .iter()
+            .map(|func| call_sites(funcs, &func.name))
+            .collect(),
+        bodies: vec![None; funcs.len()],
+    };
+    let bodies: Vec<Vec<OpNode>> = (0..funcs.len()).map(|idx| inliner.body(idx)).collect();
+
+    for (func, body) in funcs.iter_mut().zip(bodies) {
+        func.node = OpNode::Root(body);
+    }
+}
+
+struct Inliner<'a> {
+    funcs: &'a [Func],
+    recursive: Vec<bool>,
+    /// Number of places each function is called from
+    sites: Vec<usize>,
+    /// Body of each function with calls inlined, once worked out
+    bodies: Vec<Option<Vec<OpNode>>>,
+}
+
+impl<'a> Inliner<'a> {
+    fn body(&mut self, idx: usize) -> Vec<OpNode> {
+        if let Some(body) = &self.bodies[idx] {
+            return body.clone();
+        }
+        let body = match &self.funcs[idx].node {
+            OpNode::Root(children) => self.nodes(children),
+            _ => vec![],
+        };
+        self.bodies[idx] = Some(body.clone());
+        body
+    }
+
+    fn nodes(&mut self, nodes: &[OpNode]) -> Vec<OpNode> {
+        let mut out = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            match node {
+                OpNode::Loop(children, pos) => out.push(OpNode::Loop(self.nodes(children), *pos)),
+                OpNode::FuncCall(name, _) => match self.inlined(name) {
+                    Some(body) => out.extend(body),
+                    None => out.push(node.clone()),
+                },
+                _ => out.push(node.clone()),
+            }
+        }
+        out
+    }
+
+    // body a call is replaced with, None if the call is kept
+    fn inlined(&mut self, name: &str) -> Option<Vec<OpNode>> {
+        let idx = self.funcs.iter().position(|func| func.name == name)?;
+        if self.recursive[idx] {
+            return None;
+        }
+        let body = self.body(idx);
+        (self.sites[idx] == 1 || size(&body) <= MAX_SIZE).then_some(body)
+    }
+}
+
+// nodes in a body, counting those inside loops
+fn size(nodes: &[OpNode]) -> usize {
+    nodes
+        .iter()
+        .map(|node| match node {
+            OpNode::Loop(children, _) => 1 + size(children),
+            _ => 1,
+        })
+        .sum()
+}
+
+// number of calls to a function throughout the program
+fn call_sites(funcs: &[Func], name: &str) -> usize {
+    fn count(node: &OpNode, name: &str) -> usize {
+        match node {
+            OpNode::Root(children) | OpNode::Loop(children, _) => {
+                children.iter().map(|child| count(child, name)).sum()
+            }
+            OpNode::FuncCall(other, _) => (other == name) as usize,
+            OpNode::Operation(..) => 0,
+        }
+    }
+    funcs.iter().map(|func| count(&func.node, name)).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::intepreter::testing::COUNTDOWN;
+    use crate::opt::testing::{body, check};
+
+    #[test]
+    fn small_functions_are_inlined() {
+        let funcs = check(
+            "@two { ++ } @four { @two @two } @main { ^1 @four @two . }",
+            "inline",
+        );
+        assert_eq!(body(&funcs, "main").unwrap(), "^1 + + + + + + .");
+    }
+
+    #[test]
+    fn large_functions_are_inlined_once_called() {
+        let big = "@big { ^1 +++++ +++++ +++++ +++++ . & }";
+        let funcs = check(&format!("{} @main {{ @big }}", big), "inline");
+        assert!(!body(&funcs, "main").unwrap().contains('@'));
+
+        let funcs = check(&format!("{} @main {{ @big @big }}", big), "inline");
+        assert_eq!(body(&funcs, "main").unwrap(), "@big @big");
+    }
+
+    #[test]
+    fn recursive_functions_are_called() {
+        let funcs = check(COUNTDOWN, "inline");
+        assert!(body(&funcs, "main").unwrap().ends_with("@countdown"));
+        assert!(body(&funcs, "countdown").unwrap().ends_with("@countdown"));
+    }
+}
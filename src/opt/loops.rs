@@ -0,0 +1,49 @@
+// Removes loops which can never run, as they come straight after another loop,
+// which only ends once the cell it checks is 0
+
+use super::Options;
+use crate::parse::parser::{Func, OpNode};
+
+pub fn run(funcs: &mut Vec<Func>, _options: &Options) {
+    for func in funcs {
+        remove(&mut func.node);
+    }
+}
+
+fn remove(node: &mut OpNode) {
+    let children = match node {
+        OpNode::Root(children) | OpNode::Loop(children, _) => children,
+        _ => return,
+    };
+
+    let mut kept: Vec<OpNode> = Vec::with_capacity(children.len());
+    for mut child in children.drain(..) {
+        if matches!(kept.last(), Some(OpNode::Loop(..))) && matches!(child, OpNode::Loop(..)) {
+            continue;
+        }
+        remove(&mut child);
+        kept.push(child);
+    }
+    *children = kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::testing::{body, check};
+
+    #[test]
+    fn loops_after_loops_are_removed() {
+        let funcs = check(
+            "@main { ^2 + [ [ - ] [ + ] ] [ < ] [ . ] + [ - ] . }",
+            "loops",
+        );
+        assert_eq!(body(&funcs, "main").unwrap(), "^2 + [ [ - ] ] + [ - ] .");
+    }
+
+    #[test]
+    fn failing_loops_are_kept() {
+        // the first loop fails rather than ending
+        let funcs = check("@main { ^1 + [ > ] [ + ] }", "loops");
+        assert_eq!(body(&funcs, "main").unwrap(), "^1 + [ > ]");
+    }
+}
@@ -0,0 +1,161 @@
+// Passes rewriting parsed programs into ones which do the same, but faster
+
+pub mod cancel;
+pub mod fold;
+pub mod inline;
+pub mod loops;
+pub mod unused;
+
+use crate::parse::parser::Func;
+
+/// Rewrite of a whole program, which must not change what it does
+pub struct Pass {
+    /// Name the pass is enabled and disabled by
+    pub name: &'static str,
+    /// What the pass does, in a few words
+    pub about: &'static str,
+    run: fn(&mut Vec<Func>, &Options),
+}
+
+impl Pass {
+    pub fn run(&self, funcs: &mut Vec<Func>, options: &Options) {
+        (self.run)(funcs, options)
+    }
+}
+
+/// How programs will be run, which passes must keep to
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Width of cells, which wrap around at that size (as in Runtime::set_cell_bits)
+    pub cell_bits: u32,
+}
+
+impl Options {
+    fn mask(&self) -> u32 {
+        match self.cell_bits {
+            0 | 32.. => u32::MAX,
+            bits => (1 << bits) - 1,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self { cell_bits: 32 }
+    }
+}
+
+/// Every pass, in the order they are run
+pub const PASSES: [Pass; 5] = [
+    Pass {
+        name: "cancel",
+        about: "removes moves undone by the next one, ><",
+        run: cancel::run,
+    },
+    Pass {
+        name: "inline",
+        about: "replaces calls to small or once called functions with their body",
+        run: inline::run,
+    },
+    Pass {
+        name: "fold",
+        about: "runs the start of main ahead of time, until it reads or writes",
+        run: fold::run,
+    },
+    Pass {
+        name: "loops",
+        about: "removes loops straight after another, which never run",
+        run: loops::run,
+    },
+    Pass {
+        name: "unused",
+        about: "removes functions never called from main",
+        run: unused::run,
+    },
+];
+
+/// Pass with the given name
+pub fn find(name: &str) -> Option<&'static Pass> {
+    PASSES.iter().find(|pass| pass.name == name)
+}
+
+/// Runs passes over a program, in order
+pub fn optimise(funcs: &mut Vec<Func>, passes: &[&Pass], options: &Options) {
+    for pass in passes {
+        pass.run(funcs, options);
+    }
+}
+
+/// Program as S-expressions, each function separated by a blank line
+pub fn ir(funcs: &[Func]) -> String {
+    let funcs: Vec<String> = funcs.iter().map(Func::to_string).collect();
+    funcs.join("\n\n") + "\n"
+}
+
+/// Helpers for checking a pass leaves programs doing what they did
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{find, Options};
+    use crate::intepreter::{testing, Runtime};
+    use crate::parse::parser::{Func, OpNode};
+
+    /// Program after running a pass over it, checked to print the same and
+    /// end the same way (with the same exit code or error) as it did before
+    pub fn check(src: &str, pass: &str) -> Vec<Func> {
+        let funcs = testing::parse(src);
+        let mut passed = funcs.clone();
+        find(pass).unwrap().run(&mut passed, &Options::default());
+
+        let expected = testing::run(&mut Runtime::new(funcs), "");
+        let actual = testing::run(&mut Runtime::new(passed.clone()), "");
+        assert_eq!(
+            format!("{:?}", actual),
+            format!("{:?}", expected),
+            "{}",
+            src
+        );
+        passed
+    }
+
+    /// Body of a function written out as source, or None if it is not there
+    pub fn body(funcs: &[Func], name: &str) -> Option<String> {
+        fn write(nodes: &[OpNode]) -> Vec<String> {
+            nodes
+                .iter()
+                .map(|node| match node {
+                    OpNode::Loop(children, _) => format!("[ {} ]", write(children).join(" ")),
+                    OpNode::Root(children) => write(children).join(" "),
+                    OpNode::Operation(op, _) => op.to_string(),
+                    OpNode::FuncCall(name, _) => format!("@{}", name),
+                })
+                .collect()
+        }
+        let func = funcs.iter().find(|func| func.name == name)?;
+        Some(write(std::slice::from_ref(&func.node)).join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::check, PASSES};
+
+    #[test]
+    fn errors_and_exit_codes_are_kept() {
+        for src in [
+            "@main { +- }",
+            "@main { ~~ }",
+            "@main { ^1 <> }",
+            "@main { ^1 ^1 + _/ }",
+            "@main { ^1 _ _ }",
+            "@main { ^1 ^ }",
+            "@main { ^1 > > + }",
+            "@main { ^1 +++ ! }",
+            "@f { ^1 + _ } @main { ^1 @f @f }",
+            "@f { < } @main { ^1 + [ @f ] }",
+        ] {
+            for pass in &PASSES {
+                check(src, pass.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Removes functions which are never called, however indirectly, from main
+
+use super::Options;
+use crate::parse::analysis::ENTRY_FUNC;
+use crate::parse::parser::{Func, OpNode};
+
+pub fn run(funcs: &mut Vec<Func>, _options: &Options) {
+    let mut used = vec![ENTRY_FUNC.to_string()];
+    let mut idx = 0;
+    while idx < used.len() {
+        if let Some(func) = funcs.iter().find(|func| func.name == used[idx]) {
+            calls(&func.node, &mut used);
+        }
+        idx += 1;
+    }
+
+    funcs.retain(|func| used.contains(&func.name));
+}
+
+// adds every function node calls which is not yet in used
+fn calls(node: &OpNode, used: &mut Vec<String>) {
+    match node {
+        OpNode::Root(children) | OpNode::Loop(children, _) => {
+            for child in children {
+                calls(child, used);
+            }
+        }
+        OpNode::FuncCall(name, _) if !used.contains(name) => used.push(name.clone()),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::testing::check;
+
+    #[test]
+    fn unreachable_functions_are_removed() {
+        // calls from functions never called do not count
+        let src = "@a { @b } @b { + } @c { @b } @d { @e } @e { @d } @main { ^1 @a . }";
+        let funcs = check(src, "unused");
+        let names: Vec<&str> = funcs.iter().map(|func| func.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "main"]);
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use super::err::{FilePos, ParserError};
+use super::lexer::{Op, StackOp};
+use super::module::is_private;
+use super::parser::{Func, OpNode};
+
+/// Function every program starts running from
+pub const ENTRY_FUNC: &str = "main";
+
+/// Calls starting with this are to Rust intrinsics (eg. `@!print_num`), which
+/// are only known at runtime
+pub const INTRINSIC_PREFIX: char = '!';
+
+#[derive(Debug)]
+pub enum Warning {
+    UnusedFunction(String, FilePos),
+    UnreachableFunction(String, FilePos),
+    Recursion(Vec<String>, FilePos),
+}
+
+impl Warning {
+    pub fn pos(&self) -> FilePos {
+        match self {
+            Warning::UnusedFunction(_, pos)
+            | Warning::UnreachableFunction(_, pos)
+            | Warning::Recursion(_, pos) => *pos,
+        }
+    }
+}
+
+// Custom Display for warnings
+impl Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WARNING: ")?;
+        match self {
+            Warning::UnusedFunction(name, pos) => f.write_fmt(format_args!(
+                "{}: Function '@{}' is never called",
+                pos, name
+            )),
+            Warning::UnreachableFunction(name, pos) => f.write_fmt(format_args!(
+                "{}: Function '@{}' is never reached from '@{}'",
+                pos, name, ENTRY_FUNC
+            )),
+            Warning::Recursion(names, pos) if names.len() == 1 => f.write_fmt(format_args!(
+                "{}: Function '@{}' calls itself recursively",
+                pos, names[0]
+            )),
+            Warning::Recursion(names, pos) => f.write_fmt(format_args!(
+                "{}: Functions '@{}' call each other recursively",
+                pos,
+                names.join("', '@")
+            )),
+        }
+    }
+}
+
+/// Which functions call which, indexed in definition order
+#[derive(Debug, Default)]
+pub struct CallGraph {
+    names: Vec<String>,
+    positions: Vec<FilePos>,
+    modules: Vec<Option<String>>,
+    calls: Vec<Vec<(usize, FilePos)>>,
+    reach: Vec<Vec<bool>>,
+    // functions only calling themselves at their very end, which the
+    // interpreter and the wasm and x86_64 targets run in constant stack (BF
+    // inlines every call, so cannot compile them)
+    tail_only: Vec<bool>,
+}
+
+impl CallGraph {
+    /// Builds graph, returns every duplicate definition and undefined callee
+    /// as err
+    pub fn new(funcs: &[Func]) -> Result<Self, ParserError> {
+        let mut indices = HashMap::<&str, usize>::default();
+        let mut errs = vec![];
+
+        for (idx, func) in funcs.iter().enumerate() {
+            if indices.contains_key(func.name.as_str()) {
+                errs.push(ParserError::DuplicateFunctionNames(
+                    func.name.clone(),
+                    func.pos,
+                ));
+                continue;
+            }
+            indices.insert(func.name.as_str(), idx);
+        }
+
+        let mut calls = Vec::default();
+        for func in funcs.iter() {
+            let mut found = Vec::default();
+            Self::collect_calls(&func.node, &mut found);
+
+            let mut edges = Vec::default();
+            for (name, pos) in found {
+                if name.starts_with(INTRINSIC_PREFIX) && !indices.contains_key(name.as_str()) {
+                    continue;
+                }
+                match indices.get(name.as_str()) {
+                    Some(idx) => edges.push((*idx, pos)),
+                    None => errs.push(ParserError::UndefinedFunction(name, pos)),
+                }
+            }
+            calls.push(edges);
+        }
+
+        match errs.len() {
+            0 => {}
+            1 => return Err(errs.remove(0)),
+            _ => return Err(ParserError::Multiple(errs)),
+        }
+
+        let mut graph = Self {
+            names: funcs.iter().map(|func| func.name.clone()).collect(),
+            positions: funcs.iter().map(|func| func.pos).collect(),
+            modules: funcs.iter().map(|func| func.module.clone()).collect(),
+            tail_only: funcs.iter().map(Self::is_tail_only).collect(),
+            calls,
+            reach: Default::default(),
+        };
+        graph.reach = (0..graph.names.len())
+            .map(|idx| graph.reachable_from(idx))
+            .collect();
+        Ok(graph)
+    }
+
+    fn collect_calls(node: &OpNode, found: &mut Vec<(String, FilePos)>) {
+        match node {
+            OpNode::FuncCall(name, pos) => found.push((name.clone(), *pos)),
+            OpNode::Loop(children, _) | OpNode::Root(children) => {
+                for child in children {
+                    Self::collect_calls(child, found);
+                }
+            }
+            OpNode::Operation(..) => {}
+        }
+    }
+
+    fn is_tail_only(func: &Func) -> bool {
+        let mut found = Vec::default();
+        Self::collect_calls(&func.node, &mut found);
+        let own = found.iter().filter(|(name, _)| *name == func.name).count();
+        let last = match &func.node {
+            OpNode::Root(children) => children.last(),
+            _ => None,
+        };
+        own == 1 && matches!(last, Some(OpNode::FuncCall(name, _)) if *name == func.name)
+    }
+
+    // functions reachable through at least one call
+    fn reachable_from(&self, start: usize) -> Vec<bool> {
+        let mut seen = vec![false; self.names.len()];
+        let mut todo: Vec<usize> = self.calls[start].iter().map(|(idx, _)| *idx).collect();
+
+        while let Some(idx) = todo.pop() {
+            if seen[idx] {
+                continue;
+            }
+            seen[idx] = true;
+            todo.extend(self.calls[idx].iter().map(|(idx, _)| *idx));
+        }
+        seen
+    }
+
+    fn index_of(&self, name: &str) -> Option<usize> {
+        self.names.iter().position(|other| other == name)
+    }
+
+    /// Whether a function can end up calling itself, however indirectly
+    pub fn is_recursive(&self, name: &str) -> bool {
+        self.index_of(name).is_some_and(|idx| self.reach[idx][idx])
+    }
+
+    /// Warnings for unused, unreachable and recursive functions
+    pub fn warnings(&self) -> Vec<Warning> {
+        let mut warnings = Vec::default();
+        let entry = self.index_of(ENTRY_FUNC);
+
+        for idx in 0..self.names.len() {
+            if Some(idx) == entry {
+                continue;
+            }
+            let name = &self.names[idx];
+            let pos = self.positions[idx];
+
+            // public functions of included files are free to go unused
+            let included = self.modules[idx].is_some();
+            if included && !is_private(name) {
+                continue;
+            }
+
+            // calls to itself do not count as a use
+            let called = self
+                .calls
+                .iter()
+                .enumerate()
+                .any(|(caller, edges)| caller != idx && edges.iter().any(|(c, _)| *c == idx));
+
+            if !called {
+                warnings.push(Warning::UnusedFunction(name.clone(), pos));
+            } else if let Some(entry) = entry {
+                if !included && !self.reach[entry][idx] {
+                    warnings.push(Warning::UnreachableFunction(name.clone(), pos));
+                }
+            }
+        }
+
+        // every cycle is reported once, at its first defined function
+        let mut reported = vec![false; self.names.len()];
+        for idx in 0..self.names.len() {
+            if reported[idx] || !self.reach[idx][idx] {
+                continue;
+            }
+            let cycle = self.cycle(idx);
+            if cycle == [idx] && self.tail_only[idx] {
+                continue;
+            }
+
+            for other in cycle.iter() {
+                reported[*other] = true;
+            }
+
+            let names = cycle.iter().map(|i| self.names[*i].clone()).collect();
+            warnings.push(Warning::Recursion(names, self.positions[idx]));
+        }
+
+        warnings
+    }
+
+    /// Warnings for functions only calling themselves at their very end, which
+    /// warnings() leaves out, for targets that cannot run them in constant stack
+    pub fn tail_recursion(&self) -> Vec<Warning> {
+        (0..self.names.len())
+            .filter(|idx| self.tail_only[*idx] && self.cycle(*idx) == [*idx])
+            .map(|idx| Warning::Recursion(vec![self.names[idx].clone()], self.positions[idx]))
+            .collect()
+    }
+
+    // functions calling idx and called by it, however indirectly
+    fn cycle(&self, idx: usize) -> Vec<usize> {
+        (0..self.names.len())
+            .filter(|other| self.reach[idx][*other] && self.reach[*other][idx])
+            .collect()
+    }
+}
+
+/// How running code changes the stack, in tapes created and how far the
+/// selection moves
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    /// Tapes created minus tapes deleted
+    pub tapes: isize,
+    /// Tapes moved up by the selection (negative if down)
+    pub selected: isize,
+}
+
+impl StackEffect {
+    /// Effect of calling a function, None if it is not defined or depends on
+    /// how many times a loop runs (or recursion)
+    pub fn of(funcs: &[Func], name: &str) -> Option<Self> {
+        Self::of_func(funcs, name, &mut vec![])
+    }
+
+    fn of_func(funcs: &[Func], name: &str, calling: &mut Vec<String>) -> Option<Self> {
+        let func = match funcs.iter().find(|func| func.name == name) {
+            Some(func) => func,
+            // intrinsics only change the current tape
+            None if name.starts_with(INTRINSIC_PREFIX) => return Some(Self::default()),
+            None => return None,
+        };
+        if calling.iter().any(|other| other == name) {
+            return None;
+        }
+
+        calling.push(name.to_string());
+        let effect = Self::of_node(funcs, &func.node, calling);
+        calling.pop();
+        effect
+    }
+
+    fn of_node(funcs: &[Func], node: &OpNode, calling: &mut Vec<String>) -> Option<Self> {
+        match node {
+            OpNode::Operation(op, _) => Some(Self::of_op(op)),
+            OpNode::FuncCall(name, _) => Self::of_func(funcs, name, calling),
+            OpNode::Root(children) => Self::of_nodes(funcs, children, calling),
+            // loops which change the stack do so by however many times they run
+            OpNode::Loop(children, _) => {
+                Self::of_nodes(funcs, children, calling).filter(|body| *body == Self::default())
+            }
+        }
+    }
+
+    fn of_nodes(funcs: &[Func], nodes: &[OpNode], calling: &mut Vec<String>) -> Option<Self> {
+        let mut effect = Self::default();
+        for node in nodes {
+            let next = Self::of_node(funcs, node, calling)?;
+            effect.tapes += next.tapes;
+            effect.selected += next.selected;
+        }
+        Some(effect)
+    }
+
+    fn of_op(op: &Op) -> Self {
+        let (tapes, selected) = match op {
+            Op::PushNew(_) => (1, 1),
+            Op::PushOp(StackOp::Default) => (0, 1),
+            Op::PopOp(_) => (0, -1),
+            Op::HardPopOp(_) => (-1, -1),
+            _ => (0, 0),
+        };
+        Self { tapes, selected }
+    }
+}
+
+impl Display for StackEffect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "tapes {:+}, selected {:+}",
+            self.tapes, self.selected
+        ))
+    }
+}
+
+/// Semantic pass run after parsing, returns warnings found
+pub fn analyse(funcs: &[Func]) -> Result<Vec<Warning>, ParserError> {
+    Ok(CallGraph::new(funcs)?.warnings())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::module;
+    use std::path::Path;
+
+    fn analyse_src(src: &str) -> Result<Vec<Warning>, ParserError> {
+        let funcs = module::load(src.to_string(), Path::new("."), &mut vec![]).unwrap();
+        analyse(&funcs)
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let err = analyse_src("@a { }\n@a { }\n@main { @x @y @a }").unwrap_err();
+        let errs = match err {
+            ParserError::Multiple(errs) => errs,
+            err => panic!("expected every error, got {}", err),
+        };
+        assert!(matches!(&errs[0], ParserError::DuplicateFunctionNames(name, _) if name == "a"));
+        assert!(matches!(&errs[1], ParserError::UndefinedFunction(name, _) if name == "x"));
+        assert!(matches!(&errs[2], ParserError::UndefinedFunction(name, _) if name == "y"));
+        assert_eq!(errs.len(), 3);
+    }
+
+    fn recursive(src: &str) -> Vec<Vec<String>> {
+        let warnings = analyse_src(src).unwrap();
+        warnings
+            .into_iter()
+            .filter_map(|warning| match warning {
+                Warning::Recursion(names, _) => Some(names),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tail_recursion_is_not_reported() {
+        assert_eq!(recursive("@a { - [ @a ] }\n@main { @a }").len(), 1);
+        assert_eq!(recursive("@a { @a + }\n@main { @a }").len(), 1);
+        assert_eq!(recursive("@a { - [ @a ] @a }\n@main { @a }").len(), 1);
+        assert_eq!(recursive("@a { @b }\n@b { @a }\n@main { @a }").len(), 1);
+        assert!(recursive("@a { - [ - ] @a }\n@main { @a }").is_empty());
+        assert!(recursive(&std::fs::read_to_string("test/countdown.migraine").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn tail_recursion_is_reported_on_request() {
+        let tail_recursive = |src: &str| {
+            let funcs = module::load(src.to_string(), Path::new("."), &mut vec![]).unwrap();
+            let warnings = CallGraph::new(&funcs).unwrap().tail_recursion();
+            warnings.iter().map(Warning::to_string).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tail_recursive("@a { - [ - ] @a }\n@main { @a }"),
+            ["WARNING: 1:1: Function '@a' calls itself recursively"]
+        );
+        assert!(tail_recursive("@a { - [ @a ] }\n@main { @a }").is_empty());
+        assert!(tail_recursive("@a { @b }\n@b { @a }\n@main { @a }").is_empty());
+    }
+
+    #[test]
+    fn single_error_is_not_wrapped() {
+        let err = analyse_src("@main { @x }").unwrap_err();
+        assert!(matches!(err, ParserError::UndefinedFunction(name, _) if name == "x"));
+    }
+}
@@ -0,0 +1,172 @@
+use super::analysis::ENTRY_FUNC;
+use super::err::{FilePos, ParserError};
+use super::format;
+use super::lexer::{Op, Token};
+use super::parser::{Func, OpNode};
+
+/// Characters which are commands in BF, anything else is a comment
+pub const COMMANDS: [char; 8] = ['+', '-', '<', '>', '.', ',', '[', ']'];
+
+/// Cells on the tape BF programs are given
+pub const TAPE_SIZE: usize = 30000;
+
+/// Bits in each cell of BF programs, wrapping around at 255
+pub const CELL_BITS: u32 = 8;
+
+/// Parses BF source into a main function, which pushes the tape the program
+/// runs on before running it
+pub fn parse(src: &str) -> Result<Vec<Func>, ParserError> {
+    let start = FilePos::new();
+    let mut blocks = vec![(
+        vec![OpNode::Operation(Op::PushNew(TAPE_SIZE), start)],
+        start,
+    )];
+
+    for (c, pos) in commands(src) {
+        let op = match c {
+            '+' => Op::Inc,
+            '-' => Op::Dec,
+            '<' => Op::PtrLeft,
+            '>' => Op::PtrRight,
+            '.' => Op::Dump,
+            ',' => Op::Take,
+            '[' => {
+                blocks.push((vec![], pos));
+                continue;
+            }
+            _ => {
+                if blocks.len() == 1 {
+                    return Err(ParserError::UnexpectedToken(Token::LoopEnd, pos));
+                }
+                let (body, start) = blocks.pop().unwrap();
+                blocks.last_mut().unwrap().0.push(OpNode::Loop(body, start));
+                continue;
+            }
+        };
+        blocks
+            .last_mut()
+            .unwrap()
+            .0
+            .push(OpNode::Operation(op, pos));
+    }
+
+    if blocks.len() > 1 {
+        return Err(ParserError::LoopNotEnded(blocks.pop().unwrap().1));
+    }
+
+    Ok(vec![Func {
+        name: ENTRY_FUNC.to_string(),
+        pos: start,
+        node: OpNode::Root(blocks.pop().unwrap().0),
+        module: None,
+    }])
+}
+
+/// Converts BF source into Migraine, keeping its lines and moving any text
+/// which is not a command into comments
+pub fn import(src: &str) -> Result<String, ParserError> {
+    // checks loops are matched
+    parse(src)?;
+
+    let mut out = String::default();
+    out += "// imported from BF, run with --cell-bits 8 for its 8-bit cells\n";
+    out += &format!("@{} {{\n^{}\n", ENTRY_FUNC, TAPE_SIZE);
+
+    for line in src.lines() {
+        let (code, comment): (String, String) = line.chars().partition(|c| COMMANDS.contains(c));
+        let code = code.replace("[-]", "\"\\0\"").replace("[+]", "\"\\0\"");
+        // '$' would start a directive or macro, even in a comment
+        let comment = comment.replace('$', "");
+        let comment = comment.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        match (code.is_empty(), comment.is_empty()) {
+            (true, true) => out += "\n",
+            (true, false) => out += &format!("// {}\n", comment),
+            (false, true) => out += &format!("{}\n", code),
+            (false, false) => out += &format!("{} // {}\n", code, comment),
+        }
+    }
+    out += "}\n";
+
+    format::format(&out)
+}
+
+// commands of source with their positions, skipping everything else
+fn commands(src: &str) -> impl Iterator<Item = (char, FilePos)> + '_ {
+    src.lines().enumerate().flat_map(|(line, text)| {
+        text.chars()
+            .enumerate()
+            .filter(|(_, c)| COMMANDS.contains(c))
+            .map(move |(column, c)| {
+                let pos = FilePos {
+                    line: line + 1,
+                    column: column + 1,
+                };
+                (c, pos)
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intepreter::testing;
+    use crate::intepreter::Runtime;
+
+    const HELLO_WORLD: &str = include_str!("../../test/hello_world.bf");
+
+    // runs a Migraine program with 8-bit cells, as imported programs expect
+    fn run_imported(src: &str, input: &str) -> String {
+        let mut runtime = Runtime::new(testing::parse(src));
+        runtime.set_cell_bits(CELL_BITS);
+        let (res, out) = testing::run(&mut runtime, input);
+        assert_eq!(res.unwrap(), 0);
+        out
+    }
+
+    #[test]
+    fn imported_programs_run_the_same() {
+        let imported = import(HELLO_WORLD).unwrap();
+        assert!(imported.contains("// Hello World in BF (run with the bf flag)"));
+        assert_eq!(run_imported(&imported, ""), "Hello World!\n");
+
+        let mut runtime = Runtime::new(parse(HELLO_WORLD).unwrap());
+        runtime.set_cell_bits(CELL_BITS);
+        let (res, out) = testing::run(&mut runtime, "");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(out, "Hello World!\n");
+    }
+
+    #[test]
+    fn imported_programs_read_input_and_wrap_cells() {
+        // echoes input until it ends, then wraps 0 around to 255 and on to '0'
+        let src = format!(",[.[-],] - {} . $ cat", "+".repeat(49));
+        let imported = import(&src).unwrap();
+        assert!(imported.contains("\"\\0\""));
+        assert!(!imported.contains('$'));
+        assert_eq!(run_imported(&imported, "cat"), "cat0");
+    }
+
+    #[test]
+    fn unbalanced_brackets_are_errors() {
+        let err = import("+[>+\n[-]").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ParserError::LoopNotEnded(FilePos { line: 1, column: 2 })
+            ),
+            "{:?}",
+            err
+        );
+
+        let err = import("+[-]]").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ParserError::UnexpectedToken(Token::LoopEnd, FilePos { line: 1, column: 5 })
+            ),
+            "{:?}",
+            err
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use std::fmt::{self, Display};
+use std::ops::Range;
+
+use super::err::{FilePos, ParserError};
+use super::lexer::{OPS, STACK_OPS, STACK_OP_SUFFIXES};
+
+pub const MACRO_DEF_WORD: &str = "$macrodef";
+pub const MACRO_END_WORD: &str = "$macroend";
+pub const DIRECTIVE_WORDS: [&str; 2] = ["$include", "$alias"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxKind {
+    // Trivia
+    Whitespace,
+    Newline,
+    Comment,
+
+    // Preprocessor
+    /// `$include` or `$alias` along with the rest of their line
+    Directive,
+    /// `$macrodef` up to and including its `$macroend`
+    MacroDef,
+    /// `$NAME!`, replaced with the macro's content before lexing
+    MacroCall,
+
+    /// Single character operation (eg. `+`, `.`)
+    Op,
+    /// `^`, `_` or `&`, followed by a size or a second character
+    StackOp,
+    Literal,
+    /// `@name`, both where functions are defined and called
+    FunctionName,
+    BraceOpen,
+    BraceClose,
+    LoopStart,
+    LoopEnd,
+
+    /// Anything that is not valid Migraine
+    Unknown,
+}
+
+impl SyntaxKind {
+    /// Whitespace and comments, which do not change what code does
+    pub fn is_trivia(self) -> bool {
+        matches!(
+            self,
+            SyntaxKind::Whitespace | SyntaxKind::Newline | SyntaxKind::Comment
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxToken {
+    pub kind: SyntaxKind,
+    /// Byte range of the token in source
+    pub range: Range<usize>,
+    pub pos: FilePos,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Root,
+    /// `@name` followed by its block
+    Function,
+    /// `{` up to its `}`
+    Block,
+    /// `[` up to its `]`
+    Loop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    /// Index of a token in SyntaxTree::tokens
+    Token(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+/// Lossless syntax tree of source, with every character (including whitespace
+/// and comments) belonging to exactly one token
+#[derive(Debug, Clone)]
+pub struct SyntaxTree {
+    src: String,
+    tokens: Vec<SyntaxToken>,
+}
+
+impl SyntaxTree {
+    /// Splits source into tokens, returns err on literals or macro definitions
+    /// which never end
+    pub fn parse(src: &str) -> Result<Self, ParserError> {
+        let tokens = Scanner::new(src).scan()?;
+        Ok(Self {
+            src: src.to_string(),
+            tokens,
+        })
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// Every token in order, trivia included
+    pub fn tokens(&self) -> &[SyntaxToken] {
+        &self.tokens
+    }
+
+    /// Source text of a token
+    pub fn text(&self, token: &SyntaxToken) -> &str {
+        &self.src[token.range.clone()]
+    }
+
+    /// Index of the token containing a byte offset
+    pub fn token_at(&self, offset: usize) -> Option<usize> {
+        self.tokens
+            .iter()
+            .position(|token| token.range.contains(&offset))
+    }
+
+    /// Position just after the end of source
+    pub fn end_pos(&self) -> FilePos {
+        let mut pos = self.tokens.last().map_or(FilePos::new(), |token| token.pos);
+        if let Some(token) = self.tokens.last() {
+            for c in self.text(token).chars() {
+                pos.advance(Some(c));
+            }
+        }
+        pos
+    }
+
+    /// Tokens nested into functions, blocks and loops (brackets which are not
+    /// closed or opened are left as tokens of the node they are in)
+    pub fn root(&self) -> SyntaxNode {
+        let mut stack = vec![SyntaxNode {
+            kind: NodeKind::Root,
+            children: vec![],
+        }];
+
+        for (idx, token) in self.tokens.iter().enumerate() {
+            match token.kind {
+                SyntaxKind::BraceOpen | SyntaxKind::LoopStart => {
+                    let kind = match token.kind {
+                        SyntaxKind::BraceOpen => NodeKind::Block,
+                        _ => NodeKind::Loop,
+                    };
+                    stack.push(SyntaxNode {
+                        kind,
+                        children: vec![SyntaxElement::Token(idx)],
+                    });
+                    continue;
+                }
+                SyntaxKind::BraceClose | SyntaxKind::LoopEnd => {
+                    let opens = match token.kind {
+                        SyntaxKind::BraceClose => NodeKind::Block,
+                        _ => NodeKind::Loop,
+                    };
+                    if stack.len() > 1 && stack.last().unwrap().kind == opens {
+                        let mut node = stack.pop().unwrap();
+                        node.children.push(SyntaxElement::Token(idx));
+                        self.add_child(stack.last_mut().unwrap(), node);
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            let parent = stack.last_mut().unwrap();
+            parent.children.push(SyntaxElement::Token(idx));
+        }
+
+        // anything left open is closed by the end of the source
+        while stack.len() > 1 {
+            let node = stack.pop().unwrap();
+            self.add_child(stack.last_mut().unwrap(), node);
+        }
+        stack.pop().unwrap()
+    }
+
+    // blocks straight after a function name (with only trivia in between)
+    // become a function along with the name
+    fn add_child(&self, parent: &mut SyntaxNode, node: SyntaxNode) {
+        if node.kind == NodeKind::Block {
+            let name = parent.children.iter().rposition(|child| match child {
+                SyntaxElement::Token(idx) => !self.tokens[*idx].kind.is_trivia(),
+                SyntaxElement::Node(_) => true,
+            });
+
+            if let Some(name) = name {
+                if let SyntaxElement::Token(idx) = parent.children[name] {
+                    if self.tokens[idx].kind == SyntaxKind::FunctionName {
+                        let mut children: Vec<SyntaxElement> =
+                            parent.children.drain(name..).collect();
+                        children.push(SyntaxElement::Node(node));
+                        parent.children.push(SyntaxElement::Node(SyntaxNode {
+                            kind: NodeKind::Function,
+                            children,
+                        }));
+                        return;
+                    }
+                }
+            }
+        }
+        parent.children.push(SyntaxElement::Node(node));
+    }
+}
+
+// writes the source back out, exactly as it was
+impl Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.tokens.iter() {
+            f.write_str(self.text(token))?;
+        }
+        Ok(())
+    }
+}
+
+struct Scanner<'a> {
+    src: &'a str,
+    chars: Vec<(usize, char)>,
+    idx: usize,
+    pos: FilePos,
+    tokens: Vec<SyntaxToken>,
+}
+
+impl<'a> Scanner<'a> {
+    fn new(src: &'a str) -> Self {
+        Self {
+            src,
+            chars: src.char_indices().collect(),
+            idx: 0,
+            pos: FilePos::new(),
+            tokens: vec![],
+        }
+    }
+
+    fn scan(mut self) -> Result<Vec<SyntaxToken>, ParserError> {
+        while let Some(c) = self.peek(0) {
+            let start = self.idx;
+            let rest = &self.src[self.offset(start)..];
+            let at_line_start = self
+                .tokens
+                .iter()
+                .rev()
+                .find(|token| token.kind != SyntaxKind::Whitespace)
+                .is_none_or(|token| token.kind == SyntaxKind::Newline);
+
+            let (kind, end) = match c {
+                '\n' => (SyntaxKind::Newline, start + 1),
+                _ if c.is_whitespace() => (
+                    SyntaxKind::Whitespace,
+                    self.find_from(start, |c| !c.is_whitespace() || c == '\n'),
+                ),
+                '/' if self.peek(1) == Some('/') => {
+                    (SyntaxKind::Comment, self.find_from(start, |c| c == '\n'))
+                }
+                '{' => (SyntaxKind::BraceOpen, start + 1),
+                '}' => (SyntaxKind::BraceClose, start + 1),
+                '[' => (SyntaxKind::LoopStart, start + 1),
+                ']' => (SyntaxKind::LoopEnd, start + 1),
+                '"' => (SyntaxKind::Literal, self.literal_end()?),
+                '@' => (
+                    SyntaxKind::FunctionName,
+                    self.find_from(start, char::is_whitespace),
+                ),
+                '$' if rest.starts_with(MACRO_DEF_WORD) => {
+                    let end = match rest.find(MACRO_END_WORD) {
+                        Some(len) => self.index_of(self.offset(start) + len),
+                        None => return Err(ParserError::NoMacroDef(self.pos)),
+                    };
+                    (SyntaxKind::MacroDef, end + MACRO_END_WORD.len())
+                }
+                '$' if at_line_start && DIRECTIVE_WORDS.iter().any(|w| rest.starts_with(w)) => {
+                    (SyntaxKind::Directive, self.find_from(start, |c| c == '\n'))
+                }
+                '$' => {
+                    let end = self.find_from(start, |c| c == '!' || c.is_whitespace());
+                    match self.peek(end - start) {
+                        Some('!') => (SyntaxKind::MacroCall, end + 1),
+                        _ => (SyntaxKind::Unknown, end),
+                    }
+                }
+                _ if STACK_OPS.contains(&c) => (SyntaxKind::StackOp, self.stack_op_end()),
+                _ if OPS.iter().any(|(op, _)| *op == c) => (SyntaxKind::Op, start + 1),
+                _ => (SyntaxKind::Unknown, start + 1),
+            };
+
+            self.tokens.push(SyntaxToken {
+                kind,
+                range: self.offset(start)..self.offset(end),
+                pos: self.pos,
+            });
+            while self.idx < end {
+                self.pos.advance(self.peek(0));
+                self.idx += 1;
+            }
+        }
+        Ok(self.tokens)
+    }
+
+    fn peek(&self, ahead: usize) -> Option<char> {
+        self.chars.get(self.idx + ahead).map(|(_, c)| *c)
+    }
+
+    // byte offset of a char index
+    fn offset(&self, idx: usize) -> usize {
+        self.chars
+            .get(idx)
+            .map_or(self.src.len(), |(offset, _)| *offset)
+    }
+
+    // char index of a byte offset
+    fn index_of(&self, offset: usize) -> usize {
+        self.chars
+            .iter()
+            .position(|(other, _)| *other == offset)
+            .unwrap_or(self.chars.len())
+    }
+
+    // index of the first char after start matching pred, or the end of source
+    fn find_from<P: Fn(char) -> bool>(&self, start: usize, pred: P) -> usize {
+        self.chars[start + 1..]
+            .iter()
+            .position(|(_, c)| pred(*c))
+            .map_or(self.chars.len(), |len| start + 1 + len)
+    }
+
+    // index after the closing quote of a literal
+    fn literal_end(&self) -> Result<usize, ParserError> {
+        let mut end = self.idx + 1;
+        while let Some((_, c)) = self.chars.get(end) {
+            match c {
+                '\\' => end += 2,
+                '"' => return Ok(end + 1),
+                _ => end += 1,
+            }
+        }
+
+        // error is at the end of source, same as the lexer reported it
+        let mut pos = self.pos;
+        for (_, c) in self.chars[self.idx..].iter() {
+            pos.advance(Some(*c));
+        }
+        Err(ParserError::LiteralNotEnded(pos))
+    }
+
+    fn stack_op_end(&self) -> usize {
+        match self.peek(1) {
+            // push new (eg. ^10)
+            Some(c) if self.peek(0) == Some('^') && c.is_ascii_digit() => {
+                self.find_from(self.idx, |c| !c.is_ascii_digit())
+            }
+            Some(c) if STACK_OP_SUFFIXES.iter().any(|(suffix, _)| *suffix == c) => self.idx + 2,
+            _ => self.idx + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &[&str] = &[
+        include_str!("../../test/countdown.migraine"),
+        include_str!("../../test/hello_world.migraine"),
+        include_str!("../../test/stdlib.migraine"),
+        include_str!("../../test/test.migraine"),
+        include_str!("../../test/triangle.migraine"),
+        include_str!("../stdlib/io.migraine"),
+        include_str!("../stdlib/math.migraine"),
+        include_str!("../stdlib/mem.migraine"),
+    ];
+
+    const EDGE_CASES: &[&str] = &[
+        "",
+        "\n\n\t  \r\n",
+        "// comment // inside a comment\n// \"not a literal\" @not_a_call",
+        "@main { \"// not a comment\" \"\\\"\" \"\\\\\" }",
+        "$macrodef TWO ++ $macroend\n$macrodef MANY\n\t+ // comment\n\t-\n$macroend @main { $TWO! $MANY! }",
+        "$include std/io\n  $alias show io::println_num\n@main { @show }",
+        "@main { ^100 _+ &/ ^ _ & ^= # ! ~ , . < > }",
+        "@main { é 😀 % } trailing",
+        "@main { [ ] ] [",
+        "@unterminated",
+    ];
+
+    // tokens cover source in order, with no gaps and none empty
+    fn check_lossless(src: &str) {
+        let tree = SyntaxTree::parse(src).unwrap();
+        assert_eq!(tree.to_string(), src);
+
+        let mut offset = 0;
+        for token in tree.tokens() {
+            assert_eq!(token.range.start, offset, "{:?} in {:?}", token, src);
+            assert!(!token.range.is_empty(), "{:?} in {:?}", token, src);
+            offset = token.range.end;
+        }
+        assert_eq!(offset, src.len());
+    }
+
+    #[test]
+    fn source_is_written_back_exactly() {
+        for src in FIXTURES.iter().chain(EDGE_CASES) {
+            check_lossless(src);
+        }
+    }
+
+    #[test]
+    fn tokens_are_of_the_right_kind() {
+        let src = "// a // b\n$macrodef X + $macroend @main { \"//\" $X! }";
+        let tree = SyntaxTree::parse(src).unwrap();
+        let kinds: Vec<(SyntaxKind, &str)> = tree
+            .tokens()
+            .iter()
+            .filter(|token| token.kind != SyntaxKind::Whitespace)
+            .map(|token| (token.kind, tree.text(token)))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                (SyntaxKind::Comment, "// a // b"),
+                (SyntaxKind::Newline, "\n"),
+                (SyntaxKind::MacroDef, "$macrodef X + $macroend"),
+                (SyntaxKind::FunctionName, "@main"),
+                (SyntaxKind::BraceOpen, "{"),
+                (SyntaxKind::Literal, "\"//\""),
+                (SyntaxKind::MacroCall, "$X!"),
+                (SyntaxKind::BraceClose, "}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unended_literals_and_macros_are_errors() {
+        let err = SyntaxTree::parse("@main {\n\t\"never ended }").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ParserError::LiteralNotEnded(FilePos {
+                    line: 2,
+                    column: 16
+                })
+            ),
+            "{:?}",
+            err
+        );
+        let err = SyntaxTree::parse("@main { \"ends in a \\").unwrap_err();
+        assert!(matches!(err, ParserError::LiteralNotEnded(_)), "{:?}", err);
+
+        let err = SyntaxTree::parse("$macrodef X +\n@main { }").unwrap_err();
+        assert!(matches!(err, ParserError::NoMacroDef(_)), "{:?}", err);
+    }
+}
@@ -1,6 +1,8 @@
+use std::fmt::{self, Display};
+
 use super::lexer::Token;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct FilePos {
     pub line: usize,
     pub column: usize,
@@ -23,24 +25,164 @@ impl FilePos {
     }
 }
 
+impl Default for FilePos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Display for FilePos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{}:{}", self.line, self.column))
+    }
+}
+
 #[derive(Debug)]
 pub enum ParserError {
     // Preprocessor
     NoSubMacros(FilePos),
     NoMacroDef(FilePos),
+    InvalidDirective(FilePos),
+    IncludeNotFound(String, FilePos),
+    CyclicInclude(String, FilePos),
 
     // Lexer
     IllegalCharacter(char, FilePos),
     LiteralNotEnded(FilePos),
     FunctionMustEndWithWhitespace(FilePos),
     MacroNotDefined(FilePos),
+    TapeTooLarge(FilePos),
 
     // Parser
     UnexpectedToken(Token, FilePos),
     FunctionCallMustBeInFunction(String, FilePos),
     NoNestedFunctionDefines(FilePos),
     LoopNotEnded(FilePos),
-    DuplicateFunctionNames(FilePos),
+
+    // Semantic Analysis
+    DuplicateFunctionNames(String, FilePos),
+    UndefinedFunction(String, FilePos),
+    PrivateFunction(String, FilePos),
+
+    // Error inside of an included file
+    InFile(String, Box<ParserError>),
+    // Every error found by a pass which carries on after the first
+    Multiple(Vec<ParserError>),
     #[allow(dead_code)]
     Generic,
 }
+
+// Custom Display for errors
+impl Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let ParserError::Multiple(errs) = self {
+            for (idx, err) in errs.iter().enumerate() {
+                if idx > 0 {
+                    f.write_str("\n")?;
+                }
+                err.fmt(f)?;
+            }
+            return Ok(());
+        }
+        f.write_str("ERROR: ")?;
+        self.describe(f)
+    }
+}
+
+impl ParserError {
+    /// Where the error is, None for errors inside of included files
+    pub fn pos(&self) -> Option<FilePos> {
+        match self {
+            ParserError::NoSubMacros(pos)
+            | ParserError::NoMacroDef(pos)
+            | ParserError::InvalidDirective(pos)
+            | ParserError::IncludeNotFound(_, pos)
+            | ParserError::CyclicInclude(_, pos)
+            | ParserError::IllegalCharacter(_, pos)
+            | ParserError::LiteralNotEnded(pos)
+            | ParserError::FunctionMustEndWithWhitespace(pos)
+            | ParserError::MacroNotDefined(pos)
+            | ParserError::TapeTooLarge(pos)
+            | ParserError::UnexpectedToken(_, pos)
+            | ParserError::FunctionCallMustBeInFunction(_, pos)
+            | ParserError::NoNestedFunctionDefines(pos)
+            | ParserError::LoopNotEnded(pos)
+            | ParserError::DuplicateFunctionNames(_, pos)
+            | ParserError::UndefinedFunction(_, pos)
+            | ParserError::PrivateFunction(_, pos) => Some(*pos),
+            ParserError::Multiple(errs) => errs.first()?.pos(),
+            ParserError::InFile(..) | ParserError::Generic => None,
+        }
+    }
+
+    // message without the 'ERROR: ' prefix, so errors in included files can be nested
+    fn describe(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParserError::NoSubMacros(pos) => f.write_fmt(format_args!(
+                "{}: Cannot define a macro inside of another macro",
+                pos
+            )),
+            ParserError::NoMacroDef(pos) => f.write_fmt(format_args!(
+                "{}: Macro end without a matching macro define",
+                pos
+            )),
+            ParserError::InvalidDirective(pos) => {
+                f.write_fmt(format_args!("{}: Invalid preprocessor directive", pos))
+            }
+            ParserError::IncludeNotFound(path, pos) => f.write_fmt(format_args!(
+                "{}: Cannot read included file '{}'",
+                pos, path
+            )),
+            ParserError::CyclicInclude(path, pos) => {
+                f.write_fmt(format_args!("{}: File '{}' includes itself", pos, path))
+            }
+            ParserError::IllegalCharacter(c, pos) => {
+                f.write_fmt(format_args!("{}: Illegal character '{}'", pos, c))
+            }
+            ParserError::LiteralNotEnded(pos) => {
+                f.write_fmt(format_args!("{}: String literal not ended", pos))
+            }
+            ParserError::FunctionMustEndWithWhitespace(pos) => f.write_fmt(format_args!(
+                "{}: Function name must end with whitespace",
+                pos
+            )),
+            ParserError::MacroNotDefined(pos) => {
+                f.write_fmt(format_args!("{}: Macro not defined", pos))
+            }
+            ParserError::TapeTooLarge(pos) => {
+                f.write_fmt(format_args!("{}: Tape size is too large", pos))
+            }
+            ParserError::UnexpectedToken(tok, pos) => {
+                f.write_fmt(format_args!("{}: Unexpected token {:?}", pos, tok))
+            }
+            ParserError::FunctionCallMustBeInFunction(name, pos) => f.write_fmt(format_args!(
+                "{}: Call to '@{}' must be inside of a function",
+                pos, name
+            )),
+            ParserError::NoNestedFunctionDefines(pos) => f.write_fmt(format_args!(
+                "{}: Functions cannot be defined inside of other functions",
+                pos
+            )),
+            ParserError::LoopNotEnded(pos) => f.write_fmt(format_args!("{}: Loop not ended", pos)),
+            ParserError::DuplicateFunctionNames(name, pos) => f.write_fmt(format_args!(
+                "{}: Function '@{}' is already defined",
+                pos, name
+            )),
+            ParserError::UndefinedFunction(name, pos) => f.write_fmt(format_args!(
+                "{}: Call to undefined function '@{}'",
+                pos, name
+            )),
+
+            ParserError::PrivateFunction(name, pos) => f.write_fmt(format_args!(
+                "{}: Function '@{}' is private to the file it is defined in",
+                pos, name
+            )),
+            ParserError::InFile(path, err) => {
+                f.write_fmt(format_args!("{}:", path))?;
+                err.describe(f)
+            }
+
+            _ => f.write_fmt(format_args!("{:?}", self)),
+        }
+    }
+}
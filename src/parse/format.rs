@@ -0,0 +1,407 @@
+use super::cst::{SyntaxKind, SyntaxTree, MACRO_DEF_WORD, MACRO_END_WORD};
+use super::err::ParserError;
+
+/// Each level of nesting is indented by one of these
+pub const INDENT: &str = "\t";
+
+/// Lines longer than this are wrapped where they can be (tabs counting as 4)
+pub const MAX_WIDTH: usize = 80;
+
+const TAB_WIDTH: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    /// Operation, function call or anything else written in a line of code
+    Word,
+    /// Calls a macro (`$NAME!`), which is pasted into the code around it
+    MacroCall,
+    Open,
+    Close,
+    Comment,
+    /// `$include` or `$alias` along with the rest of their line
+    Directive,
+    /// `$macrodef` up to and including its `$macroend`
+    MacroDef,
+    Newline,
+}
+
+#[derive(Debug, Clone)]
+struct Piece {
+    kind: Kind,
+    text: String,
+    /// Whether whitespace came before this on the same line
+    spaced: bool,
+}
+
+/// Formats source, indenting blocks by how deeply they are nested and tidying
+/// the whitespace in between operations (comments are kept as they are)
+pub fn format(src: &str) -> Result<String, ParserError> {
+    let tree = SyntaxTree::parse(src)?;
+    Ok(layout(&pieces(&tree)))
+}
+
+// tokens of the tree, with whitespace other than new lines dropped
+fn pieces(tree: &SyntaxTree) -> Vec<Piece> {
+    let mut pieces = vec![];
+    let mut spaced = false;
+
+    for token in tree.tokens() {
+        let kind = match token.kind {
+            SyntaxKind::Whitespace => {
+                spaced = true;
+                continue;
+            }
+            SyntaxKind::Newline => Kind::Newline,
+            SyntaxKind::Comment => Kind::Comment,
+            SyntaxKind::Directive => Kind::Directive,
+            SyntaxKind::MacroDef => Kind::MacroDef,
+            SyntaxKind::MacroCall => Kind::MacroCall,
+            SyntaxKind::BraceOpen | SyntaxKind::LoopStart => Kind::Open,
+            SyntaxKind::BraceClose | SyntaxKind::LoopEnd => Kind::Close,
+            _ => Kind::Word,
+        };
+        pieces.push(Piece {
+            kind,
+            text: tree.text(token).to_string(),
+            spaced,
+        });
+        spaced = false;
+    }
+    pieces
+}
+
+// a line of pieces along with the depth it starts at
+struct Line<'a> {
+    pieces: Vec<&'a Piece>,
+    depth: usize,
+}
+
+fn layout(pieces: &[Piece]) -> String {
+    // splits into lines, recording the nesting at the start of each
+    let mut lines = vec![];
+    let mut depth: usize = 0;
+    let mut curr = Line {
+        pieces: vec![],
+        depth,
+    };
+    for piece in pieces {
+        match piece.kind {
+            Kind::Newline => {
+                let next = Line {
+                    pieces: vec![],
+                    depth,
+                };
+                lines.push(std::mem::replace(&mut curr, next));
+                continue;
+            }
+            Kind::Open => depth += 1,
+            Kind::Close => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        curr.pieces.push(piece);
+    }
+    lines.push(curr);
+
+    // None for blank lines
+    let mut out: Vec<Option<OutLine>> = vec![];
+    let mut blank = false;
+    let mut prev: Option<&Line> = None;
+
+    for line in lines.iter() {
+        if line.pieces.is_empty() {
+            blank = true;
+            continue;
+        }
+
+        if let Some(prev) = prev {
+            let opened = prev
+                .pieces
+                .last()
+                .is_some_and(|piece| piece.kind == Kind::Open);
+            let closing = line.pieces[0].kind == Kind::Close;
+
+            // functions are kept apart by a single blank line, and blocks never
+            // start or end with one
+            let after_func = line.depth == 0
+                && prev.depth > 0
+                && prev.pieces.iter().any(|piece| piece.kind == Kind::Close);
+            if (blank || after_func) && !opened && !closing {
+                out.push(None);
+            }
+        }
+
+        out.extend(write_line(&line.pieces, line.depth).into_iter().map(Some));
+
+        blank = false;
+        prev = Some(line);
+    }
+    align_comments(&mut out);
+
+    let mut src = String::default();
+    for line in out {
+        if let Some(line) = line {
+            src += &line.code;
+            if let Some(comment) = line.comment {
+                src += &comment;
+            }
+        }
+        src += "\n";
+    }
+    src
+}
+
+// line of output, with its trailing comment kept apart so it can be aligned
+struct OutLine {
+    code: String,
+    comment: Option<String>,
+}
+
+fn width(s: &str) -> usize {
+    s.chars()
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+// lines next to each other with comments after their code have the comments
+// lined up, one space after the longest line
+fn align_comments(lines: &mut [Option<OutLine>]) {
+    let trailing = |line: &Option<OutLine>| {
+        line.as_ref().is_some_and(|line| {
+            line.comment.is_some() && !line.code.trim().is_empty() && !line.code.contains('\n')
+        })
+    };
+
+    let mut idx = 0;
+    while idx < lines.len() {
+        let len = lines[idx..]
+            .iter()
+            .take_while(|line| trailing(line))
+            .count();
+        let group = &mut lines[idx..idx + len];
+        let column = group
+            .iter()
+            .flatten()
+            .map(|line| width(&line.code))
+            .max()
+            .unwrap_or(0);
+
+        for line in group.iter_mut().flatten() {
+            let pad = column - width(&line.code) + 1;
+            line.code += &" ".repeat(pad);
+        }
+        idx += len.max(1);
+    }
+}
+
+// indent of a line at depth starting with pieces, which is dedented by the
+// closing brackets it starts with
+fn indent(pieces: &[&Piece], depth: usize) -> String {
+    let closes = pieces
+        .iter()
+        .take_while(|piece| piece.kind == Kind::Close)
+        .count();
+    INDENT.repeat(depth.saturating_sub(closes))
+}
+
+// lays out a line starting at depth, wrapped if it is too long (with each
+// wrapped line indented as it would be had it been written on its own)
+fn write_line(pieces: &[&Piece], mut depth: usize) -> Vec<OutLine> {
+    let mut out = vec![];
+    let mut prefix = indent(pieces, depth);
+
+    let mut line = prefix.clone();
+    let mut empty = true;
+    let mut prev: Option<&Piece> = None;
+
+    for (idx, piece) in pieces.iter().enumerate() {
+        if piece.kind == Kind::MacroDef {
+            write_macro_def(&mut line, piece, &prefix);
+            empty = false;
+            prev = Some(piece);
+            continue;
+        }
+
+        let text = match piece.kind {
+            Kind::Directive => piece.text.split_whitespace().collect::<Vec<_>>().join(" "),
+            _ => piece.text.clone(),
+        };
+        if piece.kind == Kind::Comment && !empty {
+            out.push(OutLine {
+                code: line.trim_end().to_string(),
+                comment: Some(text),
+            });
+            return out;
+        }
+
+        let glued = prev.is_some_and(|prev| !piece.spaced && glued(prev, piece));
+        let space = !empty && piece.spaced;
+
+        let too_long = width(&line) + space as usize + width(&text) > MAX_WIDTH;
+        if !empty && too_long && !glued {
+            out.push(OutLine {
+                code: line.trim_end().to_string(),
+                comment: None,
+            });
+            prefix = indent(&pieces[idx..], depth);
+            line = prefix.clone();
+        } else if space {
+            line += " ";
+        }
+
+        match piece.kind {
+            Kind::Open => depth += 1,
+            Kind::Close => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        line += &text;
+        empty = false;
+        prev = Some(piece);
+    }
+
+    out.push(OutLine {
+        code: line.trim_end().to_string(),
+        comment: None,
+    });
+    out
+}
+
+// whether two pieces written next to each other have to stay that way
+fn glued(prev: &Piece, next: &Piece) -> bool {
+    // macros are pasted straight into the code next to them
+    prev.kind == Kind::MacroCall || next.kind == Kind::MacroCall
+}
+
+fn write_macro_def(line: &mut String, piece: &Piece, prefix: &str) {
+    let inner = &piece.text[MACRO_DEF_WORD.len()..piece.text.len() - MACRO_END_WORD.len()];
+    let inner = inner.trim_start_matches([' ', '\t']);
+    let name_len = inner.find(char::is_whitespace).unwrap_or(inner.len());
+    let (name, body) = inner.split_at(name_len);
+
+    if !body.trim().contains('\n') {
+        *line += &format!(
+            "{} {} {} {}",
+            MACRO_DEF_WORD,
+            name,
+            body.trim(),
+            MACRO_END_WORD
+        );
+        return;
+    }
+
+    // bodies over more than one line are indented once, keeping the
+    // indentation of their lines relative to each other
+    let lines: Vec<&str> = body
+        .trim_end()
+        .lines()
+        .map(str::trim_end)
+        .skip_while(|line| line.is_empty())
+        .collect();
+    let common = lines
+        .iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    *line += &format!("{} {}\n", MACRO_DEF_WORD, name);
+    for body_line in lines {
+        let body_line = &body_line[common.min(body_line.len())..];
+        if !body_line.is_empty() {
+            *line += prefix;
+            *line += INDENT;
+            *line += body_line;
+        }
+        *line += "\n";
+    }
+    *line += prefix;
+    *line += MACRO_END_WORD;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::intepreter::testing::{self, run_src};
+    use crate::parse::parser::{Func, OpNode};
+
+    const FIXTURES: &[&str] = &[
+        include_str!("../../test/countdown.migraine"),
+        include_str!("../../test/hello_world.migraine"),
+        include_str!("../../test/stdlib.migraine"),
+        include_str!("../../test/test.migraine"),
+        include_str!("../../test/triangle.migraine"),
+        include_str!("../stdlib/io.migraine"),
+        include_str!("../stdlib/math.migraine"),
+        include_str!("../stdlib/mem.migraine"),
+    ];
+
+    const SNIPPETS: &[&str] = &[
+        "@main { ^1 \"a  b\".&}",
+        "@main { ^3 + [ - > [ + ] < ] // loops\n}\n\n\n\n@other {}",
+        "$macrodef TWO ++\n$macroend\n@main { ^1 $TWO! // two\n\t\t. // one\n}",
+        "$include std/io\n$alias show io::println_num\n@main { ^4 @show }",
+        "@main { ^1 + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + + . }",
+        "// only a comment",
+        "",
+    ];
+
+    // program without the positions of its nodes, which formatting moves
+    fn shape(funcs: &[Func]) -> Vec<String> {
+        fn write(node: &OpNode) -> String {
+            match node {
+                OpNode::Root(children) | OpNode::Loop(children, _) => {
+                    let children: Vec<String> = children.iter().map(write).collect();
+                    format!("[{}]", children.join(" "))
+                }
+                OpNode::Operation(op, _) => op.to_string(),
+                OpNode::FuncCall(name, _) => format!("@{}", name),
+            }
+        }
+        funcs
+            .iter()
+            .map(|func| format!("{} {}", func.name, write(&func.node)))
+            .collect()
+    }
+
+    #[test]
+    fn formatting_twice_changes_nothing() {
+        for src in FIXTURES.iter().chain(SNIPPETS) {
+            let once = format(src).unwrap();
+            assert_eq!(format(&once).unwrap(), once, "{}", src);
+        }
+    }
+
+    #[test]
+    fn formatted_programs_are_the_same() {
+        for src in FIXTURES.iter().chain(SNIPPETS) {
+            let formatted = format(src).unwrap();
+            let funcs = testing::parse(src);
+            assert_eq!(shape(&testing::parse(&formatted)), shape(&funcs), "{}", src);
+        }
+
+        // those quick to run give the same output too
+        for src in &FIXTURES[1..4] {
+            let formatted = format(src).unwrap();
+            let (res, out) = run_src(src, "12\n");
+            let (formatted_res, formatted_out) = run_src(&formatted, "12\n");
+            assert_eq!(formatted_res.unwrap(), res.unwrap());
+            assert_eq!(formatted_out, out);
+        }
+    }
+
+    #[test]
+    fn blocks_are_indented() {
+        let src = "@main {\n^3 + [\n- > [ + ] <\n] // loops\n   }";
+        assert_eq!(
+            format(src).unwrap(),
+            "@main {\n\t^3 + [\n\t\t- > [ + ] <\n\t] // loops\n}\n"
+        );
+    }
+
+    #[test]
+    fn wrapped_lines_are_indented_by_their_depth() {
+        let src = format!("@main {{ ^1{} . }}", " +".repeat(42));
+        let first = format!("@main {{ ^1{}", " +".repeat(35));
+        let rest = format!("\t{} . }}", ["+"; 7].join(" "));
+        assert_eq!(format(&src).unwrap(), format!("{}\n{}\n", first, rest));
+    }
+}
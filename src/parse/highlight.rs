@@ -0,0 +1,474 @@
+use std::ops::Range;
+
+use serde_json::{json, Map, Value};
+
+use super::cst::{
+    NodeKind, SyntaxElement, SyntaxKind, SyntaxTree, DIRECTIVE_WORDS, MACRO_DEF_WORD,
+    MACRO_END_WORD,
+};
+use super::lexer::{OPS, STACK_OPS, STACK_OP_SUFFIXES};
+
+/// What a token is highlighted as by editors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Highlight {
+    Comment,
+    /// `$include`, `$alias`, `$macrodef` and `$macroend`
+    Directive,
+    /// Name of a macro, where it is defined or called
+    Macro,
+    Op,
+    StackOp,
+    Literal,
+    /// Name of a function where it is defined
+    FunctionDef,
+    FunctionCall,
+    Bracket,
+}
+
+impl Highlight {
+    pub const ALL: [Highlight; 9] = [
+        Highlight::Comment,
+        Highlight::Directive,
+        Highlight::Macro,
+        Highlight::Op,
+        Highlight::StackOp,
+        Highlight::Literal,
+        Highlight::FunctionDef,
+        Highlight::FunctionCall,
+        Highlight::Bracket,
+    ];
+
+    /// TextMate scope, which most themes have colours for
+    pub fn scope(self) -> &'static str {
+        match self {
+            Highlight::Comment => "comment.line.double-slash.migraine",
+            Highlight::Directive => "keyword.control.directive.migraine",
+            Highlight::Macro => "entity.name.function.preprocessor.migraine",
+            Highlight::Op => "keyword.operator.migraine",
+            Highlight::StackOp => "keyword.other.stack.migraine",
+            Highlight::Literal => "string.quoted.double.migraine",
+            Highlight::FunctionDef => "entity.name.function.migraine",
+            Highlight::FunctionCall => "entity.name.function.call.migraine",
+            Highlight::Bracket => "punctuation.section.block.migraine",
+        }
+    }
+
+    /// Name captured by tree-sitter highlight queries
+    pub fn capture(self) -> &'static str {
+        match self {
+            Highlight::Comment => "comment",
+            Highlight::Directive => "keyword.directive",
+            Highlight::Macro => "function.macro",
+            Highlight::Op => "operator",
+            Highlight::StackOp => "keyword",
+            Highlight::Literal => "string",
+            Highlight::FunctionDef => "function",
+            Highlight::FunctionCall => "function.call",
+            Highlight::Bracket => "punctuation.bracket",
+        }
+    }
+
+    /// Type of LSP semantic token, None for tokens left to the grammar
+    pub fn token_type(self) -> Option<&'static str> {
+        match self {
+            Highlight::Comment => Some("comment"),
+            Highlight::Directive | Highlight::StackOp => Some("keyword"),
+            Highlight::Macro => Some("macro"),
+            Highlight::Op => Some("operator"),
+            Highlight::Literal => Some("string"),
+            Highlight::FunctionDef | Highlight::FunctionCall => Some("function"),
+            Highlight::Bracket => None,
+        }
+    }
+}
+
+/// Byte ranges of source with what they are highlighted as, in order
+/// (whitespace and anything invalid is left out)
+pub fn classify(tree: &SyntaxTree) -> Vec<(Range<usize>, Highlight)> {
+    // names of functions defined at the top level
+    let defs: Vec<usize> = tree
+        .root()
+        .children
+        .iter()
+        .filter_map(|child| match child {
+            SyntaxElement::Node(node) if node.kind == NodeKind::Function => {
+                node.children.iter().find_map(|child| match child {
+                    SyntaxElement::Token(idx) => Some(*idx),
+                    SyntaxElement::Node(_) => None,
+                })
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut highlights = vec![];
+    for (idx, token) in tree.tokens().iter().enumerate() {
+        let text = tree.text(token);
+        let start = token.range.start;
+
+        let highlight = match token.kind {
+            SyntaxKind::Whitespace | SyntaxKind::Newline | SyntaxKind::Unknown => continue,
+            SyntaxKind::Comment => Highlight::Comment,
+            SyntaxKind::Directive => {
+                let word = DIRECTIVE_WORDS.iter().find(|word| text.starts_with(*word));
+                let len = word.map_or(text.len(), |word| word.len());
+                highlights.push((start..start + len, Highlight::Directive));
+                continue;
+            }
+            SyntaxKind::MacroDef => {
+                highlights.extend(classify_macro_def(text, start));
+                continue;
+            }
+            SyntaxKind::MacroCall => Highlight::Macro,
+            SyntaxKind::Op => Highlight::Op,
+            SyntaxKind::StackOp => Highlight::StackOp,
+            SyntaxKind::Literal => Highlight::Literal,
+            SyntaxKind::FunctionName if defs.contains(&idx) => Highlight::FunctionDef,
+            SyntaxKind::FunctionName => Highlight::FunctionCall,
+            SyntaxKind::BraceOpen
+            | SyntaxKind::BraceClose
+            | SyntaxKind::LoopStart
+            | SyntaxKind::LoopEnd => Highlight::Bracket,
+        };
+        highlights.push((token.range.clone(), highlight));
+    }
+    highlights
+}
+
+// keywords and name of a macro definition, along with the code inside of it
+fn classify_macro_def(text: &str, start: usize) -> Vec<(Range<usize>, Highlight)> {
+    let end = text.len() - MACRO_END_WORD.len();
+    let inner = &text[MACRO_DEF_WORD.len()..end];
+    let name_start = MACRO_DEF_WORD.len() + inner.len() - inner.trim_start().len();
+    let name_len = text[name_start..end]
+        .find(char::is_whitespace)
+        .unwrap_or(end - name_start);
+
+    let mut highlights = vec![(start..start + MACRO_DEF_WORD.len(), Highlight::Directive)];
+    if name_len > 0 {
+        let name = start + name_start;
+        highlights.push((name..name + name_len, Highlight::Macro));
+    }
+
+    // bodies which are not whole tokens (eg. half of a literal) are left as they are
+    let body = name_start + name_len;
+    if let Ok(tree) = SyntaxTree::parse(&text[body..end]) {
+        let offset = start + body;
+        highlights.extend(
+            classify(&tree)
+                .into_iter()
+                .map(|(range, highlight)| (range.start + offset..range.end + offset, highlight)),
+        );
+    }
+
+    highlights.push((start + end..start + text.len(), Highlight::Directive));
+    highlights
+}
+
+// regex matching any one of chars
+fn char_class<I: IntoIterator<Item = char>>(chars: I) -> String {
+    let mut class = "[".to_string();
+    for c in chars {
+        if matches!(c, '\\' | ']' | '[' | '^' | '-' | '/') {
+            class.push('\\');
+        }
+        class.push(c);
+    }
+    class + "]"
+}
+
+// regex matching word exactly
+fn escape(word: &str) -> String {
+    let mut escaped = String::default();
+    for c in word.chars() {
+        if "\\.^$|?*+()[]{}/".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// regexes of tokens, shared by every grammar
+fn op_regex() -> String {
+    char_class(OPS.iter().map(|(c, _)| *c))
+}
+
+fn stack_op_regex() -> String {
+    // push (the first) followed by a size creates a new tape
+    let push_new = format!("{}[0-9]+", escape(&STACK_OPS[0].to_string()));
+    let stack_op = format!(
+        "{}{}?",
+        char_class(STACK_OPS),
+        char_class(STACK_OP_SUFFIXES.iter().map(|(c, _)| *c))
+    );
+    format!("{}|{}", push_new, stack_op)
+}
+
+const FUNCTION_NAME_REGEX: &str = r"@[^\s]+";
+const MACRO_CALL_REGEX: &str = r"\$[^\s!]+!";
+
+/// TextMate grammar (written as JSON), for VS Code, Sublime Text and most other
+/// editors
+pub fn textmate_grammar() -> Value {
+    let rule =
+        |highlight: Highlight, regex: String| json!({ "name": highlight.scope(), "match": regex });
+    let directives: Vec<String> = DIRECTIVE_WORDS.iter().map(|word| escape(word)).collect();
+
+    let mut repository = Map::new();
+    repository.insert("comment".into(), rule(Highlight::Comment, "//.*$".into()));
+    repository.insert(
+        "directive".into(),
+        json!({
+            "match": format!(r"^\s*({})\b", directives.join("|")),
+            "captures": { "1": { "name": Highlight::Directive.scope() } },
+        }),
+    );
+    repository.insert(
+        "macro-definition".into(),
+        json!({
+            "begin": format!(r"({})\s+(\S+)", escape(MACRO_DEF_WORD)),
+            "beginCaptures": {
+                "1": { "name": Highlight::Directive.scope() },
+                "2": { "name": Highlight::Macro.scope() },
+            },
+            "end": escape(MACRO_END_WORD),
+            "endCaptures": { "0": { "name": Highlight::Directive.scope() } },
+            "patterns": [{ "include": "#code" }],
+        }),
+    );
+    repository.insert(
+        "code".into(),
+        json!({
+            "patterns": [
+                { "include": "#comment" },
+                { "include": "#macro-call" },
+                { "include": "#function-definition" },
+                { "include": "#function-call" },
+                { "include": "#literal" },
+                { "include": "#stack-op" },
+                { "include": "#op" },
+                { "include": "#bracket" },
+            ],
+        }),
+    );
+    repository.insert(
+        "macro-call".into(),
+        rule(Highlight::Macro, MACRO_CALL_REGEX.into()),
+    );
+    // only names followed by a block on the same line can be told apart
+    repository.insert(
+        "function-definition".into(),
+        rule(
+            Highlight::FunctionDef,
+            format!(r"{}(?=\s*\{{)", FUNCTION_NAME_REGEX),
+        ),
+    );
+    repository.insert(
+        "function-call".into(),
+        rule(Highlight::FunctionCall, FUNCTION_NAME_REGEX.into()),
+    );
+    repository.insert(
+        "literal".into(),
+        json!({
+            "name": Highlight::Literal.scope(),
+            "begin": "\"",
+            "end": "\"",
+            "patterns": [{ "name": "constant.character.escape.migraine", "match": r"\\." }],
+        }),
+    );
+    repository.insert(
+        "stack-op".into(),
+        rule(Highlight::StackOp, stack_op_regex()),
+    );
+    repository.insert("op".into(), rule(Highlight::Op, op_regex()));
+    repository.insert(
+        "bracket".into(),
+        rule(Highlight::Bracket, char_class(['{', '}', '[', ']'])),
+    );
+
+    json!({
+        "name": "Migraine",
+        "scopeName": "source.migraine",
+        "fileTypes": ["migraine"],
+        "patterns": [
+            { "include": "#directive" },
+            { "include": "#macro-definition" },
+            { "include": "#code" },
+        ],
+        "repository": repository,
+    })
+}
+
+/// grammar.js of a tree-sitter parser, generated with `tree-sitter generate`
+pub fn tree_sitter_grammar() -> String {
+    let quote = |word: &str| format!("'{}'", word);
+    let directives: Vec<String> = DIRECTIVE_WORDS.iter().map(|word| quote(word)).collect();
+
+    let rules = [
+        (
+            "source_file",
+            "repeat(choice($.directive, $.macro_definition, $.function_definition, $._code))"
+                .to_string(),
+        ),
+        ("comment", "token(seq('//', /.*/))".to_string()),
+        (
+            "directive",
+            format!(
+                "seq(choice({}), field('arguments', $.arguments))",
+                directives.join(", ")
+            ),
+        ),
+        // the rest of the line, straight after the directive
+        ("arguments", r"token.immediate(/[ \t]+[^\n]*/)".to_string()),
+        (
+            "macro_definition",
+            format!(
+                "seq({}, field('name', $.macro_name), repeat($._macro_body), {})",
+                quote(MACRO_DEF_WORD),
+                quote(MACRO_END_WORD)
+            ),
+        ),
+        ("macro_name", r"/[^\s]+/".to_string()),
+        // bodies are pasted in as they are, so hold any tokens up to the end
+        // word, without brackets having to match
+        (
+            "_macro_body",
+            "choice($.function_call, $.macro_call, $.literal, $.stack_op, $.op, $.macro_text, \
+             '{', '}', '[', ']')"
+                .to_string(),
+        ),
+        // anything else, such as the size in `$macrodef MAX 100 $macroend`,
+        // only when no other token matches
+        ("macro_text", r"token(prec(-1, /[^\s$]+|\$/))".to_string()),
+        (
+            "function_definition",
+            "seq(field('name', $.function_name), $.block)".to_string(),
+        ),
+        ("block", "seq('{', repeat($._code), '}')".to_string()),
+        ("loop", "seq('[', repeat($._code), ']')".to_string()),
+        (
+            "_code",
+            "choice($.loop, $.function_call, $.macro_call, $.literal, $.stack_op, $.op)"
+                .to_string(),
+        ),
+        ("function_call", "$.function_name".to_string()),
+        ("function_name", format!("/{}/", FUNCTION_NAME_REGEX)),
+        ("macro_call", format!("/{}/", MACRO_CALL_REGEX)),
+        (
+            "literal",
+            r#"token(seq('"', repeat(choice(/[^"\\]/, /\\./)), '"'))"#.to_string(),
+        ),
+        ("stack_op", format!("/{}/", stack_op_regex())),
+        ("op", format!("/{}/", op_regex())),
+    ];
+
+    let mut grammar = "// generated by `migraine grammar tree-sitter`\n".to_string();
+    grammar += "module.exports = grammar({\n";
+    grammar += "  name: 'migraine',\n\n";
+    grammar += "  extras: $ => [/\\s/, $.comment],\n\n";
+    grammar += "  rules: {\n";
+    for (name, rule) in rules.iter() {
+        grammar += &format!("    {}: $ => {},\n", name, rule);
+    }
+    grammar += "  },\n});\n";
+    grammar
+}
+
+/// highlights.scm of the tree-sitter grammar, as used by Neovim, Helix and Zed
+pub fn tree_sitter_highlights() -> String {
+    let mut keywords: Vec<String> = DIRECTIVE_WORDS
+        .iter()
+        .chain([MACRO_DEF_WORD, MACRO_END_WORD].iter())
+        .map(|word| format!("\"{}\"", word))
+        .collect();
+    keywords.sort();
+
+    let queries = [
+        ("(comment) @".to_string(), Highlight::Comment),
+        (format!("[{}] @", keywords.join(" ")), Highlight::Directive),
+        (
+            "(macro_definition name: (macro_name) @)".to_string(),
+            Highlight::Macro,
+        ),
+        ("(macro_call) @".to_string(), Highlight::Macro),
+        (
+            "(function_definition name: (function_name) @)".to_string(),
+            Highlight::FunctionDef,
+        ),
+        ("(function_call) @".to_string(), Highlight::FunctionCall),
+        ("(literal) @".to_string(), Highlight::Literal),
+        ("(stack_op) @".to_string(), Highlight::StackOp),
+        ("(op) @".to_string(), Highlight::Op),
+        (
+            "[\"{\" \"}\" \"[\" \"]\"] @".to_string(),
+            Highlight::Bracket,
+        ),
+    ];
+
+    // '@' marks where the capture goes
+    let mut highlights = "; generated by `migraine grammar highlights`\n".to_string();
+    for (query, highlight) in queries.iter() {
+        highlights += &query.replacen('@', &format!("@{}", highlight.capture()), 1);
+        highlights += "\n";
+    }
+    highlights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // alternative of the tree-sitter grammar a token in a macro body is parsed as
+    fn rule(kind: SyntaxKind) -> &'static str {
+        match kind {
+            SyntaxKind::FunctionName => "$.function_call",
+            SyntaxKind::MacroCall => "$.macro_call",
+            SyntaxKind::Literal => "$.literal",
+            SyntaxKind::StackOp => "$.stack_op",
+            SyntaxKind::Op => "$.op",
+            SyntaxKind::BraceOpen => "'{'",
+            SyntaxKind::BraceClose => "'}'",
+            SyntaxKind::LoopStart => "'['",
+            SyntaxKind::LoopEnd => "']'",
+            _ => "$.macro_text",
+        }
+    }
+
+    #[test]
+    fn tree_sitter_macro_bodies_take_any_token() {
+        let grammar = tree_sitter_grammar();
+        let body = grammar
+            .lines()
+            .find(|line| line.trim_start().starts_with("_macro_body:"))
+            .unwrap();
+        assert!(grammar.contains("repeat($._macro_body)"));
+
+        for src in [
+            include_str!("../../test/test.migraine"),
+            "$macrodef OPEN [ @f \"]\" $X! ^1 _+ . } 100 $ $macroend",
+        ] {
+            let tree = SyntaxTree::parse(src).unwrap();
+            for def in tree
+                .tokens()
+                .iter()
+                .filter(|token| token.kind == SyntaxKind::MacroDef)
+            {
+                let text = tree.text(def);
+                let inner = &text[MACRO_DEF_WORD.len()..text.len() - MACRO_END_WORD.len()];
+                let inner = inner.trim_start();
+                let name_len = inner.find(char::is_whitespace).unwrap_or(inner.len());
+
+                let body_tree = SyntaxTree::parse(&inner[name_len..]).unwrap();
+                for token in body_tree
+                    .tokens()
+                    .iter()
+                    .filter(|token| !token.kind.is_trivia())
+                {
+                    let text = body_tree.text(token);
+                    assert!(body.contains(rule(token.kind)), "{:?} in {}", text, body);
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use string_builder::Builder;
+use std::fmt::{self, Display};
 
+use super::cst::{SyntaxKind, SyntaxTree};
 use super::err::{FilePos, ParserError};
 
 #[derive(Debug, Clone)]
@@ -13,6 +14,7 @@ pub enum Token {
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Op {
     Inc,
     Dec,
@@ -22,6 +24,8 @@ pub enum Op {
     PtrRight,
     PtrLeft,
     BitNot,
+    Halt,
+    Debug,
 
     // Stack Operations
     PushNew(usize),
@@ -45,243 +49,239 @@ pub enum StackOp {
     BitXor,
 }
 
-enum StackType {
-    Pop,
-    Push,
-    HardPop,
-}
-
-pub struct Lexer {
-    src: Box<String>,
-    idx: usize,
-    file_pos: FilePos,
-    next_char: Option<char>,
-    tokens: Vec<(Token, FilePos)>,
-}
-
-impl Lexer {
-    pub fn new(src: Box<String>) -> Self {
-        Self {
-            idx: 0,
-            file_pos: FilePos::new(),
-            next_char: src.chars().nth(0),
-            src,
-            tokens: Default::default(),
+/// Single character operations, along with the character they are written as
+pub const OPS: [(char, Op); 9] = [
+    ('.', Op::Dump),
+    (',', Op::Take),
+    ('>', Op::PtrRight),
+    ('<', Op::PtrLeft),
+    ('+', Op::Inc),
+    ('-', Op::Dec),
+    ('~', Op::BitNot),
+    ('!', Op::Halt),
+    ('#', Op::Debug),
+];
+
+/// Characters starting stack operations: push (`^`), pop (`_`) and hard pop (`&`)
+pub const STACK_OPS: [char; 3] = ['^', '_', '&'];
+
+/// Characters which can follow a stack operation (eg. `_+`), combining the
+/// cells of the two tapes moved between
+pub const STACK_OP_SUFFIXES: [(char, StackOp); 8] = [
+    ('=', StackOp::Set),
+    ('+', StackOp::Add),
+    ('-', StackOp::Sub),
+    ('*', StackOp::Mul),
+    ('/', StackOp::Div),
+    ('&', StackOp::BitAnd),
+    ('|', StackOp::BitOr),
+    ('^', StackOp::BitXor),
+];
+
+// Displays operations as they are written in source
+impl Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Inc => f.write_str("+"),
+            Op::Dec => f.write_str("-"),
+            Op::Dump => f.write_str("."),
+            Op::Take => f.write_str(","),
+            Op::Literal(lit) => {
+                f.write_str("\"")?;
+                for c in lit.chars() {
+                    match c {
+                        '\n' => f.write_str("\\n")?,
+                        '\t' => f.write_str("\\t")?,
+                        '\0' => f.write_str("\\0")?,
+                        '"' | '\\' => f.write_fmt(format_args!("\\{}", c))?,
+                        _ => f.write_fmt(format_args!("{}", c))?,
+                    }
+                }
+                f.write_str("\"")
+            }
+            Op::PtrRight => f.write_str(">"),
+            Op::PtrLeft => f.write_str("<"),
+            Op::BitNot => f.write_str("~"),
+            Op::Halt => f.write_str("!"),
+            Op::Debug => f.write_str("#"),
+            Op::PushNew(size) => f.write_fmt(format_args!("^{}", size)),
+            Op::PushOp(op) => f.write_fmt(format_args!("^{}", op)),
+            Op::PopOp(op) => f.write_fmt(format_args!("_{}", op)),
+            Op::HardPopOp(op) => f.write_fmt(format_args!("&{}", op)),
         }
     }
+}
 
-    pub fn advance(&mut self) {
-        self.idx += 1;
-        self.next_char = self.src.chars().nth(self.idx);
-        self.file_pos.advance(self.next_char);
+impl Display for StackOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StackOp::Default => "",
+            StackOp::Set => "=",
+            StackOp::Add => "+",
+            StackOp::Sub => "-",
+            StackOp::Mul => "*",
+            StackOp::Div => "/",
+            StackOp::BitAnd => "&",
+            StackOp::BitOr => "|",
+            StackOp::BitXor => "^",
+        })
     }
+}
 
-    pub fn regress(&mut self) {
-        self.idx -= 1;
-        self.next_char = self.src.chars().nth(self.idx);
-
-        // recalculates filepos
-        self.file_pos = FilePos::new();
+pub struct Lexer {
+    src: String,
+}
 
-        for i in 0..(self.idx) {
-            self.file_pos.advance(self.src.chars().nth(i));
-        }
+impl Lexer {
+    pub fn new(src: String) -> Self {
+        Self { src }
     }
 
     /// Consumes self
-    pub fn tokenize(mut self) -> Result<Vec<(Token, FilePos)>, ParserError> {
-        while let Some(curr) = self.next_char {
-            // Skip whitespace
-            if curr.is_whitespace() {
-                self.advance();
-                continue;
-            }
-            match curr {
-                '$' => return Err(ParserError::MacroNotDefined(self.file_pos)),
-                // non ops
-                '@' => self.function_call()?,
-                '{' => {
-                    self.tokens.push((Token::FunctionDefine, self.file_pos));
-                    self.advance();
-                }
-                '}' => {
-                    self.tokens.push((Token::FunctionEnd, self.file_pos));
-                    self.advance();
-                }
-                '[' => {
-                    self.tokens.push((Token::LoopStart, self.file_pos));
-                    self.advance();
-                }
-                ']' => {
-                    self.tokens.push((Token::LoopEnd, self.file_pos));
-                    self.advance();
+    pub fn tokenize(self) -> Result<Vec<(Token, FilePos)>, ParserError> {
+        let tree = SyntaxTree::parse(&self.src)?;
+        let mut tokens = vec![];
+
+        for syntax in tree.tokens() {
+            let text = tree.text(syntax);
+            let pos = syntax.pos;
+
+            let token = match syntax.kind {
+                // Skip whitespace and comments
+                SyntaxKind::Whitespace | SyntaxKind::Newline | SyntaxKind::Comment => continue,
+                SyntaxKind::Directive | SyntaxKind::MacroDef | SyntaxKind::MacroCall => {
+                    return Err(ParserError::MacroNotDefined(pos))
                 }
 
-                // comments
-                '/' => {
-                    self.advance();
-                    if let Some(curr) = self.next_char {
-                        // single line comments with '//'
-                        if curr == '/' {
-                            while let Some(curr) = self.next_char {
-                                if curr == '\n' {
-                                    break;
-                                }
-                                self.advance();
-                            }
-                            continue;
-                        }
+                // non ops
+                SyntaxKind::FunctionName => {
+                    // the name has to be ended by whitespace
+                    if syntax.range.end == self.src.len() {
+                        return Err(ParserError::FunctionMustEndWithWhitespace(tree.end_pos()));
                     }
-                    return Err(ParserError::IllegalCharacter(curr, self.file_pos));
+                    Token::FunctionCall(text[1..].to_string())
+                }
+                SyntaxKind::BraceOpen => Token::FunctionDefine,
+                SyntaxKind::BraceClose => Token::FunctionEnd,
+                SyntaxKind::LoopStart => Token::LoopStart,
+                SyntaxKind::LoopEnd => Token::LoopEnd,
+
+                SyntaxKind::Op => Token::Op(Self::op(text, pos)?),
+                SyntaxKind::Literal => Token::Op(Op::Literal(Self::unescape(text))),
+                SyntaxKind::StackOp => Token::Op(Self::stack_op(text, pos)?),
+
+                // a `$` not starting a macro or directive is taken for a macro
+                // which was never defined
+                SyntaxKind::Unknown if text.starts_with('$') => {
+                    return Err(ParserError::MacroNotDefined(pos))
                 }
-
-                // Unary Char Operations
-                '.' => self.op(Op::Dump),
-                ',' => self.op(Op::Take),
-                '>' => self.op(Op::PtrRight),
-                '<' => self.op(Op::PtrLeft),
-                '+' => self.op(Op::Inc),
-                '-' => self.op(Op::Dec),
-                '~' => self.op(Op::BitNot),
-
-                // String Literal
-                '"' => self.str_literal()?,
-
-                // Stack Operations
-                '_' => self.stack_op(StackType::Pop),
-                '&' => self.stack_op(StackType::HardPop),
-                '^' => self.stack_op(StackType::Push),
-
                 // invalid characters
-                _ => return Err(ParserError::IllegalCharacter(curr, self.file_pos)),
-            }
+                SyntaxKind::Unknown => {
+                    let c = text.chars().next().unwrap_or_default();
+                    return Err(ParserError::IllegalCharacter(c, pos));
+                }
+            };
+            tokens.push((token, pos));
         }
-        Ok(self.tokens)
+        Ok(tokens)
     }
 
-    // Used for stack operations toi do with 'pop'
-    fn stack_op(&mut self, stack: StackType) {
-        self.advance();
-        let stack_op = if let Some(curr) = self.next_char {
-            // special case for push for push new ( ^10)
-            if match stack {
-                StackType::Push => true,
-                _ => false,
-            } {
-                // if first letter after is ascii, keep reading
-                if curr.is_ascii_digit() {
-                    let mut builder = Builder::default();
-
-                    // keeps reading character digits until no longer digits
-                    while let Some(curr) = self.next_char {
-                        if curr.is_ascii_digit() {
-                            builder.append(curr);
-                            self.advance();
-                        } else {
-                            // break when hits a character that isn't a digit
-                            break;
-                        }
-                    }
-                    // turns read number into u32
-                    let size: usize = builder.string().unwrap().parse().unwrap();
-                    self.tokens
-                        .push((Token::Op(Op::PushNew(size)), self.file_pos));
-                    return;
-                }
-            }
-
-            self.advance();
+    // the scanner only marks characters in OPS as operations, though should
+    // it not, the character is reported rather than run as something else
+    fn op(text: &str, pos: FilePos) -> Result<Op, ParserError> {
+        OPS.iter()
+            .find(|(c, _)| text.starts_with(*c))
+            .map(|(_, op)| op.clone())
+            .ok_or_else(|| {
+                let c = text.chars().next().unwrap_or_default();
+                ParserError::IllegalCharacter(c, pos)
+            })
+    }
 
-            // checks if next char creates a dualchar op (_+, &=)
-            match curr {
-                '+' => StackOp::Add,
-                '-' => StackOp::Sub,
-                '*' => StackOp::Mul,
-                '/' => StackOp::Div,
-                '=' => StackOp::Set,
+    // Used for stack operations (eg. ^10, _+, &)
+    fn stack_op(text: &str, pos: FilePos) -> Result<Op, ParserError> {
+        let (kind, rest) = text.split_at(1);
 
-                // Bitwise
-                '&' => StackOp::BitAnd,
-                '|' => StackOp::BitOr,
-                '^' => StackOp::BitXor,
+        // special case for push for push new ( ^10)
+        if kind == "^" && !rest.is_empty() && rest.chars().all(|c| c.is_ascii_digit()) {
+            let size = rest.parse().map_err(|_| ParserError::TapeTooLarge(pos))?;
+            return Ok(Op::PushNew(size));
+        }
 
-                // go back if next char isn't part of a dualchar op
-                _ => {
-                    self.regress();
-                    StackOp::Default
-                }
-            }
-        } else {
-            // Default stack operation (single '&' or '_') if EOF
-            StackOp::Default
-        };
+        let stack_op = STACK_OP_SUFFIXES
+            .iter()
+            .find(|(c, _)| rest.starts_with(*c))
+            .map_or(StackOp::Default, |(_, op)| op.clone());
 
-        let next_tok = Token::Op(match stack {
-            StackType::Pop => Op::PopOp(stack_op),
-            StackType::HardPop => Op::HardPopOp(stack_op),
-            StackType::Push => Op::PushOp(stack_op),
-        });
-        self.tokens.push((next_tok, self.file_pos));
+        Ok(match kind {
+            "_" => Op::PopOp(stack_op),
+            "&" => Op::HardPopOp(stack_op),
+            _ => Op::PushOp(stack_op),
+        })
     }
 
-    // Used to read function calls as well as function defines (eg. @main, @main {})
-    fn function_call(&mut self) -> Result<(), ParserError> {
-        self.advance();
-        let mut builder = Builder::default();
+    // contents of a literal, without its quotes and with escapes replaced
+    fn unescape(text: &str) -> String {
+        let mut literal = String::default();
+        let mut chars = text[1..text.len() - 1].chars();
 
-        while let Some(curr) = self.next_char {
-            if curr.is_whitespace() {
-                let func_name = builder.string().unwrap();
-                self.advance();
-                self.tokens
-                    .push((Token::FunctionCall(func_name), self.file_pos));
-                return Ok(());
-            }
-            builder.append(curr);
-            self.advance();
-        }
-        Err(ParserError::FunctionMustEndWithWhitespace(self.file_pos))
-    }
-
-    fn str_literal(&mut self) -> Result<(), ParserError> {
-        self.advance();
-        let mut builder = Builder::default();
-        while let Some(curr) = self.next_char {
+        while let Some(curr) = chars.next() {
             // escaped characters
             if curr == '\\' {
-                self.advance();
-                if let Some(curr) = self.next_char {
-                    builder.append(match curr {
-                        'n' => '\n',
-                        't' => '\t',
-                        '0' => '\0',
-                        _ => curr,
-                    });
-                    self.advance();
-                } else {
-                    // returns error if reaches end of file after escape char
-                    return Err(ParserError::LiteralNotEnded(self.file_pos));
-                }
+                literal.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some(c) => c,
+                    None => break,
+                });
                 continue;
             }
-
-            // termination
-
-            if curr == '"' {
-                // finalizes buidler & adds operation
-                let literal = builder.string().unwrap();
-                self.op(Op::Literal(literal));
-                return Ok(());
-            }
-
-            builder.append(curr);
-            self.advance();
+            literal.push(curr);
         }
-        Err(ParserError::LiteralNotEnded(self.file_pos))
+        literal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stray_dollar_is_an_undefined_macro() {
+        let err = Lexer::new("@main { + $ - }".to_string())
+            .tokenize()
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ParserError::MacroNotDefined(FilePos {
+                    line: 1,
+                    column: 11
+                })
+            ),
+            "{:?}",
+            err
+        );
+
+        let err = Lexer::new("@main { + % }".to_string())
+            .tokenize()
+            .unwrap_err();
+        assert!(
+            matches!(err, ParserError::IllegalCharacter('%', _)),
+            "{:?}",
+            err
+        );
     }
 
-    pub fn op(&mut self, op: Op) {
-        self.tokens.push((Token::Op(op), self.file_pos));
-        self.advance();
+    #[test]
+    fn ops_are_not_guessed() {
+        assert!(matches!(Lexer::op("+", FilePos::default()), Ok(Op::Inc)));
+        let err = Lexer::op("%", FilePos::default()).unwrap_err();
+        assert!(
+            matches!(err, ParserError::IllegalCharacter('%', _)),
+            "{:?}",
+            err
+        );
     }
 }
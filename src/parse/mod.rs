@@ -1,20 +1,24 @@
+pub mod analysis;
+pub mod bf;
+pub mod cst;
 pub mod err;
+pub mod format;
+pub mod highlight;
 pub mod lexer;
+pub mod module;
 pub mod parser;
 pub mod preproc;
+use std::path::Path;
+
 use err::*;
 use parser::Func;
 
-use self::{lexer::Lexer, parser::Parser};
-
-pub fn parse(content: String) -> Result<Vec<Func>, ParserError> {
-    let processed = preproc::process(content)?;
-    println!("Preprocess Done");
-
-    let tokens = Lexer::new(processed).tokenize()?;
-    println!("Tokenization done");
+/// Parses source, with includes being relative to dir
+pub fn parse(content: String, dir: &Path) -> Result<Vec<Func>, ParserError> {
+    let functions = module::load(content, dir, &mut vec![])?;
 
-    let functions = Parser::new(tokens).create_functions()?;
-    println!("Parsing done");
+    for warning in analysis::analyse(&functions)? {
+        eprintln!("{}", warning);
+    }
     Ok(functions)
 }
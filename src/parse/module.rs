@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::err::*;
+use super::lexer::Lexer;
+use super::parser::{Func, OpNode, Parser};
+use super::preproc::{self, Macro};
+use crate::stdlib;
+
+/// Seperates a module name from a function name (eg. `@io::print_str`)
+pub const MODULE_SEP: &str = "::";
+
+/// Functions whose name starts with '_' can only be called from their own file
+pub fn is_private(name: &str) -> bool {
+    name.split(MODULE_SEP).any(|part| part.starts_with('_'))
+}
+
+/// Parses functions of a file along with all files it includes, paths starting
+/// with `std/` are included from the standard library
+pub fn load(
+    content: String,
+    dir: &Path,
+    loading: &mut Vec<PathBuf>,
+) -> Result<Vec<Func>, ParserError> {
+    load_with(content, dir, loading, &mut vec![])
+}
+
+/// Same as load, but with macros defined beforehand, which are replaced by
+/// every macro known by the end of the file once it has loaded
+pub fn load_with(
+    content: String,
+    dir: &Path,
+    loading: &mut Vec<PathBuf>,
+    macros: &mut Vec<Macro>,
+) -> Result<Vec<Func>, ParserError> {
+    let processed = preproc::process_with(content, macros)?;
+    let tokens = Lexer::new(processed.src).tokenize()?;
+    let mut funcs = Parser::new(tokens).create_functions()?;
+
+    for alias in processed.aliases {
+        funcs.push(Func {
+            name: alias.name,
+            pos: alias.pos,
+            node: OpNode::Root(vec![OpNode::FuncCall(alias.target, alias.pos)]),
+            module: None,
+        });
+    }
+
+    for func in funcs.iter() {
+        check_private(&func.node)?;
+    }
+
+    // files already included under the same name are only loaded once
+    let mut loaded: Vec<(PathBuf, String)> = vec![];
+
+    for include in processed.includes {
+        let (full, content) = match stdlib::get(&include.path) {
+            Some(src) => (PathBuf::from(&include.path), src.to_string()),
+            None => {
+                let full = dir
+                    .join(&include.path)
+                    .canonicalize()
+                    .map_err(|_| ParserError::IncludeNotFound(include.path.clone(), include.pos))?;
+                let content = fs::read_to_string(&full)
+                    .map_err(|_| ParserError::IncludeNotFound(include.path.clone(), include.pos))?;
+                (full, content)
+            }
+        };
+
+        if loading.contains(&full) {
+            return Err(ParserError::CyclicInclude(include.path, include.pos));
+        }
+
+        // module is named after the file if not given a name
+        let name = match &include.name {
+            Some(name) => name.clone(),
+            None => full
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .ok_or(ParserError::InvalidDirective(include.pos))?,
+        };
+
+        if loaded.contains(&(full.clone(), name.clone())) {
+            continue;
+        }
+        loaded.push((full.clone(), name.clone()));
+
+        loading.push(full.clone());
+        let included = load(content, full.parent().unwrap_or(dir), loading)
+            .map_err(|err| ParserError::InFile(include.path.clone(), Box::new(err)))?;
+        loading.pop();
+
+        funcs.extend(into_module(included, &name));
+    }
+
+    *macros = processed.macros;
+    Ok(funcs)
+}
+
+// calls to private functions of other modules are not allowed
+fn check_private(node: &OpNode) -> Result<(), ParserError> {
+    match node {
+        OpNode::FuncCall(name, pos) => {
+            if name.contains(MODULE_SEP) && is_private(name) {
+                return Err(ParserError::PrivateFunction(name.clone(), *pos));
+            }
+        }
+        OpNode::Loop(children, _) | OpNode::Root(children) => {
+            for child in children {
+                check_private(child)?;
+            }
+        }
+        OpNode::Operation(..) => {}
+    }
+    Ok(())
+}
+
+// prefixes all functions with the module name, as well as calls between them
+fn into_module(mut funcs: Vec<Func>, module: &str) -> Vec<Func> {
+    let local: Vec<String> = funcs.iter().map(|func| func.name.clone()).collect();
+
+    for func in funcs.iter_mut() {
+        func.name = format!("{}{}{}", module, MODULE_SEP, func.name);
+        func.module = Some(match &func.module {
+            Some(inner) => format!("{}{}{}", module, MODULE_SEP, inner),
+            None => module.to_string(),
+        });
+        prefix_calls(&mut func.node, module, &local);
+    }
+    funcs
+}
+
+fn prefix_calls(node: &mut OpNode, module: &str, local: &[String]) {
+    match node {
+        OpNode::FuncCall(name, _) => {
+            if local.contains(name) {
+                *name = format!("{}{}{}", module, MODULE_SEP, name);
+            }
+        }
+        OpNode::Loop(children, _) | OpNode::Root(children) => {
+            for child in children.iter_mut() {
+                prefix_calls(child, module, local);
+            }
+        }
+        OpNode::Operation(..) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(src: &str) -> Vec<String> {
+        let funcs = load(src.to_string(), Path::new("."), &mut vec![]).unwrap();
+        funcs.into_iter().map(|func| func.name).collect()
+    }
+
+    #[test]
+    fn same_include_is_loaded_once() {
+        let once = names("$include std/io\n@main { }");
+        let twice = names("$include std/io\n$include std/io\n@main { }");
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn include_under_other_name_is_loaded_again() {
+        let funcs = names("$include std/io\n$include std/io as out\n@main { }");
+        assert!(funcs.iter().any(|name| name == "io::print_str"));
+        assert!(funcs.iter().any(|name| name == "out::print_str"));
+    }
+}
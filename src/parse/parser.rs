@@ -1,3 +1,4 @@
+use std::fmt::{self, Display};
 use std::slice::Iter;
 
 use super::err::*;
@@ -6,21 +7,81 @@ use super::lexer::{Op, Token};
 #[derive(Debug)]
 struct UnparsedFunc {
     name: String,
+    pos: FilePos,
     content: Vec<(Token, FilePos)>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Func {
     pub name: String,
+    pub pos: FilePos,
     pub node: OpNode,
+    /// Module the function was included from, None if defined in the main file
+    pub module: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 pub enum OpNode {
-    Loop(Vec<OpNode>),
+    Loop(Vec<OpNode>, FilePos),
     Root(Vec<OpNode>),
-    Operation(Op),
-    FuncCall(String),
+    Operation(Op, FilePos),
+    FuncCall(String, FilePos),
+}
+
+impl OpNode {
+    /// Position in source, None for the root of a function
+    pub fn pos(&self) -> Option<FilePos> {
+        match self {
+            OpNode::Loop(_, pos) | OpNode::Operation(_, pos) | OpNode::FuncCall(_, pos) => {
+                Some(*pos)
+            }
+            OpNode::Root(_) => None,
+        }
+    }
+}
+
+/// Function as an S-expression, one node per line:
+/// `(fn main 1:1 (op 1:9 +) (loop 1:10 (call 1:11 print)))`
+impl Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("(fn {} {}", self.name, self.pos))?;
+        if let OpNode::Root(children) = &self.node {
+            write_children(f, children, 1)?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl Display for OpNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_node(f, self, 0)
+    }
+}
+
+fn write_node(f: &mut fmt::Formatter<'_>, node: &OpNode, depth: usize) -> fmt::Result {
+    match node {
+        OpNode::Loop(children, pos) => {
+            f.write_fmt(format_args!("(loop {}", pos))?;
+            write_children(f, children, depth + 1)?;
+            f.write_str(")")
+        }
+        OpNode::Root(children) => {
+            f.write_str("(root")?;
+            write_children(f, children, depth + 1)?;
+            f.write_str(")")
+        }
+        OpNode::Operation(op, pos) => f.write_fmt(format_args!("(op {} {})", pos, op)),
+        OpNode::FuncCall(name, pos) => f.write_fmt(format_args!("(call {} {})", pos, name)),
+    }
+}
+
+// writes each node on its own line, indented by depth
+fn write_children(f: &mut fmt::Formatter<'_>, children: &[OpNode], depth: usize) -> fmt::Result {
+    for child in children {
+        f.write_fmt(format_args!("\n{:1$}", "", depth * 2))?;
+        write_node(f, child, depth)?;
+    }
+    Ok(())
 }
 
 pub struct Parser {
@@ -38,11 +99,13 @@ impl Parser {
         for unparsed in self.get_unparsed_functions()? {
             funcs.push(Func {
                 name: unparsed.name,
+                pos: unparsed.pos,
                 node: OpNode::Root(Self::create_parse_tree(
                     &mut unparsed.content.iter(),
                     false,
                     FilePos::new(),
                 )?),
+                module: None,
             })
         }
 
@@ -57,26 +120,20 @@ impl Parser {
         let mut nodes = Vec::<OpNode>::default();
         while let Some((tok, pos)) = tok_iter.next() {
             // break if at end of loop
-            if allow_loop_end
-                && match tok {
-                    Token::LoopEnd => true,
-                    _ => false,
-                }
-            {
+            if allow_loop_end && matches!(tok, Token::LoopEnd) {
                 return Ok(nodes);
             }
 
             match tok {
                 Token::LoopStart => {
-                    nodes.push(OpNode::Loop(Self::create_parse_tree(
-                        tok_iter,
-                        true,
-                        pos.clone(),
-                    )?));
+                    nodes.push(OpNode::Loop(
+                        Self::create_parse_tree(tok_iter, true, *pos)?,
+                        *pos,
+                    ));
                 }
-                Token::Op(op) => nodes.push(OpNode::Operation(op.clone())),
-                Token::FunctionCall(name) => nodes.push(OpNode::FuncCall(name.clone())),
-                _ => return Err(ParserError::UnexpectedToken(tok.clone(), FilePos::new())),
+                Token::Op(op) => nodes.push(OpNode::Operation(op.clone(), *pos)),
+                Token::FunctionCall(name) => nodes.push(OpNode::FuncCall(name.clone(), *pos)),
+                _ => return Err(ParserError::UnexpectedToken(tok.clone(), *pos)),
             }
         }
 
@@ -96,14 +153,11 @@ impl Parser {
                 Token::FunctionCall(name) => {
                     // checks next token if it is a func define
                     if let Some((tok, _)) = tok_iter.next() {
-                        if match tok {
-                            Token::FunctionDefine => true,
-                            _ => false,
-                        } {
+                        if matches!(tok, Token::FunctionDefine) {
                             let mut toks = Vec::default();
 
                             // reads everything until function end token
-                            while let Some((tok, pos)) = tok_iter.next() {
+                            for (tok, pos) in tok_iter.by_ref() {
                                 match tok {
                                     // Nested function definition error
                                     Token::FunctionDefine => {
@@ -115,6 +169,7 @@ impl Parser {
                             }
                             funcs.push(UnparsedFunc {
                                 name: name.clone(),
+                                pos: *file_pos,
                                 content: toks,
                             });
                             continue;
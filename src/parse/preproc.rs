@@ -3,15 +3,60 @@ use string_builder::Builder;
 use substring::Substring;
 const MACRO_DEF_WORD: &str = "$macrodef ";
 const MACRO_END_WORD: &str = "$macroend\n";
+const INCLUDE_WORD: &str = "$include ";
+const ALIAS_WORD: &str = "$alias ";
 
-pub fn process(src: String) -> Result<Box<String>, ParserError> {
+pub fn process(src: String) -> Result<Processed, ParserError> {
     PreProccessor::new(src).process()
 }
 
-#[derive(Debug)]
-struct Macro {
-    name: String,
-    content: String,
+/// Processes src with macros defined beforehand (eg. on earlier lines of the
+/// REPL), which are called as if defined at the start of src
+pub fn process_with(src: String, macros: &[Macro]) -> Result<Processed, ParserError> {
+    let mut preproc = PreProccessor::new(src);
+    for r#macro in macros {
+        if !preproc
+            .macros
+            .iter()
+            .any(|other| other.name == r#macro.name)
+        {
+            preproc.macros.push(r#macro.clone());
+        }
+    }
+    preproc.process()
+}
+
+/// `$include <path> [as <name>]`, functions in path are used as `@name::func`
+#[derive(Debug, Clone)]
+pub struct Include {
+    pub path: String,
+    pub name: Option<String>,
+    pub pos: FilePos,
+}
+
+/// `$alias <name> <target>`, makes `@name` call `@target`
+#[derive(Debug, Clone)]
+pub struct Alias {
+    pub name: String,
+    pub target: String,
+    pub pos: FilePos,
+}
+
+/// Preprocessed source along with the directives read from it
+#[derive(Debug, Default)]
+pub struct Processed {
+    pub src: String,
+    pub includes: Vec<Include>,
+    pub aliases: Vec<Alias>,
+    /// Every macro which could be called by the end of src
+    pub macros: Vec<Macro>,
+}
+
+/// `$macrodef <name> <content> $macroend`, called as `$<name>!`
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub content: String,
 }
 
 struct PreProccessor {
@@ -22,6 +67,8 @@ struct PreProccessor {
     next: Option<char>,
     macros: Vec<Macro>,
     defining: Option<(String, usize)>,
+    includes: Vec<Include>,
+    aliases: Vec<Alias>,
 }
 
 impl PreProccessor {
@@ -37,6 +84,8 @@ impl PreProccessor {
             out: Default::default(),
             defining: None,
             macros,
+            includes: Default::default(),
+            aliases: Default::default(),
         }
     }
 
@@ -48,18 +97,20 @@ impl PreProccessor {
     }
 
     fn advance(&mut self) {
+        self.file_pos.advance(self.next);
         self.idx += 1;
         self.next = self.src.chars().nth(self.idx);
-        self.file_pos.advance(self.next);
     }
 
     /// Consumes self
-    pub fn process(mut self) -> Result<Box<String>, ParserError> {
+    pub fn process(mut self) -> Result<Processed, ParserError> {
         // Processes Keywords
         while let Some(curr) = self.next {
             // continue if char matches first letter of macro keyword
             if self.macro_def_read(self.idx)?
                 || self.macro_end_read(self.idx)?
+                || self.include_read(self.idx)?
+                || self.alias_read(self.idx)?
                 || self.macro_call_read(self.idx)?
             {
                 continue;
@@ -67,12 +118,81 @@ impl PreProccessor {
 
             if self.defining.is_none() {
                 self.out.append(curr);
+            } else if curr == '\n' {
+                // keeps line numbers intact for positions reported after a macro
+                self.out.append(curr);
             }
             self.advance();
         }
 
         let finished = self.out.string().unwrap();
-        Ok(Box::new(finished))
+        Ok(Processed {
+            src: finished,
+            includes: self.includes,
+            aliases: self.aliases,
+            macros: self.macros,
+        })
+    }
+
+    fn include_read(&mut self, idx: usize) -> Result<bool, ParserError> {
+        if self.defining.is_some() || !self.read_word(INCLUDE_WORD, idx) {
+            return Ok(false);
+        }
+        let pos = self.file_pos;
+        self.skip(INCLUDE_WORD.len());
+
+        let include = match self.directive_args().as_slice() {
+            [path] => Include {
+                path: path.clone(),
+                name: None,
+                pos,
+            },
+            [path, as_word, name] if as_word == "as" => Include {
+                path: path.clone(),
+                name: Some(name.clone()),
+                pos,
+            },
+            _ => return Err(ParserError::InvalidDirective(pos)),
+        };
+        self.includes.push(include);
+        Ok(true)
+    }
+
+    fn alias_read(&mut self, idx: usize) -> Result<bool, ParserError> {
+        if self.defining.is_some() || !self.read_word(ALIAS_WORD, idx) {
+            return Ok(false);
+        }
+        let pos = self.file_pos;
+        self.skip(ALIAS_WORD.len());
+
+        let alias = match self.directive_args().as_slice() {
+            [name, target] => Alias {
+                name: name.trim_start_matches('@').to_string(),
+                target: target.trim_start_matches('@').to_string(),
+                pos,
+            },
+            _ => return Err(ParserError::InvalidDirective(pos)),
+        };
+        self.aliases.push(alias);
+        Ok(true)
+    }
+
+    // reads the rest of the line as whitespace seperated words (leaves the newline)
+    fn directive_args(&mut self) -> Vec<String> {
+        let mut line = Builder::default();
+        while let Some(curr) = self.next {
+            if curr == '\n' {
+                break;
+            }
+            line.append(curr);
+            self.advance();
+        }
+
+        line.string()
+            .unwrap()
+            .split_whitespace()
+            .map(|word| word.trim_matches('"').to_string())
+            .collect()
     }
 
     fn macro_call_read(&mut self, idx: usize) -> Result<bool, ParserError> {
@@ -107,14 +227,11 @@ impl PreProccessor {
                 content: self.src.substring(start_idx, end_idx - 1).to_string(),
             };
 
-            println!(
-                "Macro defined (name: {}, content:`{}`)",
-                r#macro.name, r#macro.content
-            );
             self.macros.push(r#macro);
 
-            // skips end word
+            // skips end word (keeping its newline)
             self.skip(MACRO_END_WORD.len());
+            self.out.append('\n');
             return Ok(true);
         }
 
@@ -136,6 +253,9 @@ impl PreProccessor {
             while let Some(curr) = self.next {
                 self.advance();
                 if curr.is_whitespace() {
+                    if curr == '\n' {
+                        self.out.append(curr);
+                    }
                     break;
                 }
                 name.append(curr);
@@ -160,11 +280,11 @@ impl PreProccessor {
     fn read_word(&self, word: &str, idx: usize) -> bool {
         // if length of word is greater than how much the file is left to read,
         // return false for no macro
-        if idx + word.len() >= self.src.len() {
+        if idx + word.len() > self.src.len() {
             return false;
         }
 
         let next_chars = self.src.substring(idx, idx + word.len());
-        return next_chars == word;
+        next_chars == word
     }
 }
@@ -0,0 +1,267 @@
+use std::fs;
+use std::io::{stdin, stdout, Write};
+use std::path::Path;
+
+use migraine::intepreter::{err::RuntimeError, Runtime};
+use migraine::parse::cst::{NodeKind, SyntaxElement, SyntaxKind, SyntaxTree};
+use migraine::parse::{err::ParserError, module, preproc::Macro};
+
+// name of the function each line is run inside of
+const LINE_FUNC: &str = "<repl>";
+
+// how many cells either side of a tape's pointer are shown after each line
+const WINDOW: usize = 5;
+
+const HELP: &str = "\
+Functions (@name { ... }) and preprocessor directives ($include std/io) on a
+line are kept for later lines to use, and the rest of the line is run straight
+away. Lines with unclosed brackets are continued on the next line.
+
+:stack        Shows every cell of every tape in the stack
+:funcs        Lists defined functions
+:load <file>  Defines the functions of a file
+:reset        Clears the stack (functions are kept)
+:help         Shows this message
+:quit         Exits the REPL";
+
+// what is kept between lines: the runtime (with its functions), and macros
+// defined so far
+struct Repl {
+    runtime: Runtime,
+    macros: Vec<Macro>,
+}
+
+/// Runs the REPL until input ends or `:quit`
+pub fn run() {
+    let mut repl = Repl {
+        runtime: Runtime::new(vec![]),
+        macros: vec![],
+    };
+    println!("Migraine REPL (:help for commands)");
+
+    while let Some(line) = read_input() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(cmd) = line.strip_prefix(':') {
+            if !command(&mut repl, cmd) {
+                break;
+            }
+            continue;
+        }
+
+        eval(&mut repl, line);
+        print!("\n{}", repl.runtime.dump_stack(Some(WINDOW)));
+    }
+}
+
+// returns false when the REPL should exit
+fn command(repl: &mut Repl, cmd: &str) -> bool {
+    let runtime = &mut repl.runtime;
+    let mut args = cmd.split_whitespace();
+    match args.next().unwrap_or_default() {
+        "stack" => print!("{}", runtime.dump_stack(None)),
+        "funcs" => {
+            for func in runtime.funcs() {
+                println!("@{}", func.name);
+            }
+        }
+        "load" => match args.next() {
+            Some(path) => load(repl, path),
+            None => eprintln!("ERROR: :load needs a file"),
+        },
+        "reset" => runtime.reset(),
+        "help" => println!("{}", HELP),
+        "quit" | "q" => return false,
+        other => eprintln!("ERROR: Unknown command ':{}' (:help for commands)", other),
+    }
+    true
+}
+
+fn load(repl: &mut Repl, path: &str) {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) => return eprintln!("ERROR: Cannot read '{}': {}", path, err),
+    };
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("."));
+
+    match module::load_with(content, dir, &mut vec![], &mut repl.macros) {
+        Ok(funcs) => {
+            println!("Loaded {} functions", funcs.len());
+            repl.runtime.add_funcs(funcs);
+        }
+        Err(err) => eprintln!("{}", err),
+    }
+}
+
+fn eval(repl: &mut Repl, src: &str) {
+    let (mut src, ops) = match split(src) {
+        Ok(split) => split,
+        Err(err) => return eprintln!("{}", err),
+    };
+    if !ops.trim().is_empty() {
+        src.push_str(&format!("@{} {{\n{}\n}}", LINE_FUNC, ops));
+    }
+
+    let runtime = &mut repl.runtime;
+    let mut funcs = match module::load_with(src, Path::new("."), &mut vec![], &mut repl.macros) {
+        Ok(funcs) => funcs,
+        Err(err) => return eprintln!("{}", err),
+    };
+
+    // the line itself is run but not kept around
+    let line = funcs
+        .iter()
+        .position(|func| func.name == LINE_FUNC)
+        .map(|idx| funcs.remove(idx));
+    runtime.add_funcs(funcs);
+
+    if let Some(line) = line {
+        match runtime.run_func(&line) {
+            Ok(_) => {}
+            Err(RuntimeError::Exit(code)) => println!("\nProgram exited with code {}", code),
+            Err(err) => eprintln!("{}", err),
+        }
+    }
+}
+
+// splits a line into what it defines (functions, macros and directives) and
+// the operations left to run
+fn split(src: &str) -> Result<(String, String), ParserError> {
+    let tree = SyntaxTree::parse(src)?;
+    let (mut defs, mut ops) = (String::default(), String::default());
+
+    for child in tree.root().children {
+        let (first, last, defines) = match &child {
+            SyntaxElement::Token(idx) => {
+                let kind = tree.tokens()[*idx].kind;
+                let defines = matches!(kind, SyntaxKind::MacroDef | SyntaxKind::Directive);
+                (*idx, *idx, defines)
+            }
+            SyntaxElement::Node(node) => {
+                let (first, last) = span(&child).unwrap_or_default();
+                (first, last, node.kind == NodeKind::Function)
+            }
+        };
+        let range = tree.tokens()[first].range.start..tree.tokens()[last].range.end;
+
+        if defines {
+            // directives such as $macroend need the line to be ended
+            defs.push_str(&src[range]);
+            defs.push('\n');
+        } else {
+            ops.push_str(&src[range]);
+        }
+    }
+    Ok((defs, ops))
+}
+
+// first and last token of an element
+fn span(element: &SyntaxElement) -> Option<(usize, usize)> {
+    match element {
+        SyntaxElement::Token(idx) => Some((*idx, *idx)),
+        SyntaxElement::Node(node) => {
+            let first = node.children.iter().find_map(span)?.0;
+            let last = node.children.iter().rev().find_map(span)?.1;
+            Some((first, last))
+        }
+    }
+}
+
+// reads a line, continuing onto the next lines while brackets are unclosed
+fn read_input() -> Option<String> {
+    let mut input = String::default();
+    let mut prompt = "> ";
+
+    loop {
+        print!("{}", prompt);
+        stdout().flush().unwrap_or_default();
+
+        match stdin().read_line(&mut input) {
+            Ok(0) | Err(_) if input.is_empty() => return None,
+            Ok(0) | Err(_) => return Some(input),
+            _ => {}
+        }
+
+        if depth(&input) <= 0 {
+            return Some(input);
+        }
+        prompt = ". ";
+    }
+}
+
+// how many '{' and '[' are left unclosed, ignoring literals and comments
+fn depth(src: &str) -> i32 {
+    let mut depth = 0;
+    let mut chars = src.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '[' => depth += 1,
+            '}' | ']' => depth -= 1,
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repl() -> Repl {
+        Repl {
+            runtime: Runtime::new(vec![]),
+            macros: vec![],
+        }
+    }
+
+    #[test]
+    fn errors_leave_the_repl_running() {
+        let mut repl = repl();
+        eval(&mut repl, "^1 >+");
+        eval(&mut repl, ">>.");
+        eval(&mut repl, "<<< +");
+        assert_eq!(repl.runtime.cell(1, 0), Some(1));
+    }
+
+    #[test]
+    fn lines_define_and_run() {
+        let mut repl = repl();
+        eval(&mut repl, "^1");
+        eval(&mut repl, "@f { + } @f @g { @f @f } @g");
+        assert_eq!(repl.runtime.cell(1, 0), Some(3));
+
+        // both functions are kept
+        eval(&mut repl, "@g @f");
+        assert_eq!(repl.runtime.cell(1, 0), Some(6));
+    }
+
+    #[test]
+    fn macros_are_kept_between_lines() {
+        let mut repl = repl();
+        eval(&mut repl, "$macrodef THREE +++ $macroend");
+        eval(&mut repl, "^1 $THREE! $THREE!");
+        assert_eq!(repl.runtime.cell(1, 0), Some(6));
+    }
+}
@@ -0,0 +1,42 @@
+/// Prefix of paths which are included from the standard library (eg. `std/io`)
+pub const STD_PREFIX: &str = "std/";
+
+/// Migraine sources bundled with the interpreter
+const LIBS: &[(&str, &str)] = &[
+    ("io", include_str!("io.migraine")),
+    ("math", include_str!("math.migraine")),
+    ("mem", include_str!("mem.migraine")),
+];
+
+/// Source of standard library module with path (eg. `std/io`)
+pub fn get(path: &str) -> Option<&'static str> {
+    let name = path.strip_prefix(STD_PREFIX)?;
+    let name = name.strip_suffix(".migraine").unwrap_or(name);
+
+    LIBS.iter()
+        .find(|(lib, _)| *lib == name)
+        .map(|(_, src)| *src)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::intepreter::testing::run_src;
+
+    #[test]
+    fn runs_stdlib_example() {
+        let (res, out) = run_src(include_str!("../../test/stdlib.migraine"), "12\n");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(
+            out,
+            "Hello\n0\n122\n0\n1\n10\n91\n100\n4294967295\n1\n0\n1\n1\nCopy\n144\n"
+        );
+    }
+
+    #[test]
+    fn read_num_reads_one_line() {
+        let src = "$include std/io\n@main { ^4 @io::read_num @io::println_num @io::read_num @io::println_num }";
+        let (res, out) = run_src(src, "3\n41\n");
+        assert_eq!(res.unwrap(), 0);
+        assert_eq!(out, "3\n41\n");
+    }
+}